@@ -0,0 +1,135 @@
+//! Async access to a Kindle, for tokio applications.
+//!
+//! libmtp handles can't move between threads, so [`AsyncKindle`] opens the
+//! device on a thread of its own and runs every call there, one at a time.
+//! The futures only wait for that thread's answer, so they never block the
+//! executor and need no particular runtime flavour.
+
+use crate::device::{
+    CancelToken, FileEntry, Kindle, KindleInfo, StorageInfo, TransferOptions, WalkEntry,
+};
+use crate::error::{Error, Result};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce(&mut Kindle) + Send>;
+
+/// A [`Kindle`] owned by a dedicated thread. Dropping it closes the device
+/// once calls already queued have finished.
+pub struct AsyncKindle {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl AsyncKindle {
+    /// Opens the device like [`Kindle::detect`].
+    pub async fn detect() -> Result<Self> {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let (opened_tx, opened) = oneshot::channel();
+        std::thread::Builder::new()
+            .name("kindle-mtp device".to_string())
+            .spawn(move || {
+                let mut kindle = match Kindle::detect() {
+                    Ok(kindle) => kindle,
+                    Err(e) => {
+                        let _ = opened_tx.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = opened_tx.send(Ok(()));
+                for job in queue {
+                    job(&mut kindle);
+                }
+            })?;
+        opened.await.map_err(|_| device_thread_gone())??;
+        Ok(Self { jobs })
+    }
+
+    /// Runs `f` on the device thread, for anything the methods below don't
+    /// cover. Calls run in the order they were made.
+    pub async fn with<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Kindle) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (result_tx, result) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move |kindle| {
+                let _ = result_tx.send(f(kindle));
+            }))
+            .map_err(|_| device_thread_gone())?;
+        result.await.map_err(|_| device_thread_gone())?
+    }
+
+    pub async fn info(&self) -> Result<KindleInfo> {
+        self.with(|kindle| Ok(kindle.info())).await
+    }
+
+    pub async fn storage_info(&self) -> Result<StorageInfo> {
+        self.with(|kindle| kindle.storage_info()).await
+    }
+
+    pub async fn set_transfer_options(&self, transfer: TransferOptions) -> Result<()> {
+        self.with(move |kindle| {
+            kindle.set_transfer_options(transfer);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Lets `token` stop later transfers and walks; cancel it from any task.
+    pub async fn set_cancel_token(&self, token: CancelToken) -> Result<()> {
+        self.with(move |kindle| {
+            kindle.set_cancel_token(token);
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn list_files(&self, path: &str) -> Result<Vec<FileEntry>> {
+        let path = path.to_string();
+        self.with(move |kindle| kindle.list_files(&path)).await
+    }
+
+    pub async fn stat(&self, path: &str) -> Result<FileEntry> {
+        let path = path.to_string();
+        self.with(move |kindle| kindle.stat(&path)).await
+    }
+
+    pub async fn walk(&self, root: &str) -> Result<Vec<WalkEntry>> {
+        let root = root.to_string();
+        self.with(move |kindle| kindle.walk(&root)).await
+    }
+
+    pub async fn download_file(&self, remote_path: &str, local_path: impl Into<PathBuf>) -> Result<()> {
+        let (remote_path, local_path) = (remote_path.to_string(), local_path.into());
+        self.with(move |kindle| kindle.download_file(&remote_path, &local_path))
+            .await
+    }
+
+    pub async fn upload_file(&self, local_path: impl Into<PathBuf>, remote_path: &str) -> Result<()> {
+        let (local_path, remote_path) = (local_path.into(), remote_path.to_string());
+        self.with(move |kindle| kindle.upload_file(&local_path, &remote_path))
+            .await
+    }
+
+    pub async fn create_folder_all(&self, remote_path: &str) -> Result<()> {
+        let remote_path = remote_path.to_string();
+        self.with(move |kindle| kindle.create_folder_all(&remote_path))
+            .await
+    }
+
+    pub async fn delete(&self, remote_path: &str) -> Result<()> {
+        let remote_path = remote_path.to_string();
+        self.with(move |kindle| kindle.delete(&remote_path)).await
+    }
+
+    pub async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let (from, to) = (from.to_string(), to.to_string());
+        self.with(move |kindle| kindle.rename(&from, &to)).await
+    }
+}
+
+fn device_thread_gone() -> Error {
+    Error::Mtp("the device thread stopped".to_string())
+}
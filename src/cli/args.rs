@@ -1,4 +1,8 @@
+use crate::cli::OutputFormat;
+use crate::commands::{CatalogFormat, OverwritePolicy, PathFilter, Pattern, SendFormat, VerifyMode};
+use crate::config::Config;
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(name = "kindle-mtp")]
@@ -8,17 +12,96 @@ pub struct Args {
     #[command(subcommand)]
     pub command: Command,
 
-    /// Output in JSON format
+    /// Output in JSON format (same as --format json)
     #[arg(long, global = true)]
     pub json: bool,
 
-    /// Verbose output
-    #[arg(short, long, global = true)]
-    pub verbose: bool,
+    /// One JSON object per line, streaming entries and transfers as they
+    /// happen (same as --format ndjson)
+    #[arg(long, global = true, conflicts_with = "json")]
+    pub ndjson: bool,
+
+    /// Output format; overrides `format` in the config file
+    #[arg(long, global = true, value_enum, conflicts_with_all = ["json", "ndjson"])]
+    pub format: Option<OutputFormat>,
+
+    /// Serial number of the Kindle to use when several are connected
+    #[arg(long, global = true)]
+    pub serial: Option<String>,
+
+    /// Use an MTP device with this USB vendor ID instead of a Kindle, e.g.
+    /// 2237 for Kobo (hex)
+    #[arg(long, global = true, value_name = "ID", value_parser = parse_usb_id)]
+    pub vendor_id: Option<u16>,
+
+    /// Only use a device with this USB product ID (hex)
+    #[arg(long, global = true, value_name = "ID", value_parser = parse_usb_id)]
+    pub product_id: Option<u16>,
+
+    /// Use the first MTP device found, whatever its vendor. Kindle-only
+    /// commands refuse other devices
+    #[arg(long, global = true, conflicts_with = "vendor_id")]
+    pub any_device: bool,
+
+    /// Log device operations to stderr; -vv also logs every transfer chunk
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Append a debug log to this file (for reporting flaky connections)
+    #[arg(long, global = true, value_name = "PATH")]
+    pub log_file: Option<String>,
+
+    /// Color human output: auto (terminals only, unless NO_COLOR is set),
+    /// always or never
+    #[arg(long, global = true, value_enum, value_name = "WHEN", default_value_t = clap::ColorChoice::Auto)]
+    pub color: clap::ColorChoice,
 
     /// Suppress non-error output
     #[arg(short, long, global = true)]
     pub quiet: bool,
+
+    /// Show what rm, push, sync, restore and clean-sdr would change without
+    /// changing it
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// After pull, push and sync, report files, bytes, elapsed time,
+    /// throughput and the time each file took
+    #[arg(long, global = true)]
+    pub stats: bool,
+
+    /// Wait for a Kindle to be connected before running, optionally with a
+    /// timeout: --wait or --wait=2m
+    #[arg(long, global = true, value_name = "TIMEOUT", num_args = 0..=1, require_equals = true, value_parser = parse_duration)]
+    pub wait: Option<Option<chrono::Duration>>,
+
+    /// Wait for another kindle-mtp using the device instead of failing
+    #[arg(long, global = true)]
+    pub wait_lock: bool,
+
+    /// Unmount the Kindle from GNOME's gvfs before connecting, if it holds it
+    #[arg(long, global = true)]
+    pub claim: bool,
+
+    /// Transfer buffer size, e.g. 512K or 4M (default: 1M)
+    #[arg(long, global = true, value_parser = parse_size)]
+    pub chunk_size: Option<u64>,
+
+    /// Cap the average transfer rate in bytes per second, e.g. 5M
+    #[arg(long, global = true, value_name = "RATE", value_parser = parse_size)]
+    pub limit_rate: Option<u64>,
+
+    /// Stop any single transfer or walk that takes longer, e.g. 90s or 10m
+    #[arg(long, global = true, value_parser = parse_duration)]
+    pub timeout: Option<chrono::Duration>,
+
+    /// Retry transient device errors this many times (default: 3, 0 disables)
+    #[arg(long, global = true, value_name = "N")]
+    pub retries: Option<u32>,
+
+    /// Pause before the first retry, doubled after each one, e.g. 500ms or 2s
+    #[arg(long, global = true, value_parser = parse_duration)]
+    pub retry_delay: Option<chrono::Duration>,
 }
 
 #[derive(Subcommand)]
@@ -29,29 +112,1161 @@ pub enum Command {
     /// Show detailed device information
     Info,
 
+    /// Interactive first-run setup that writes the config file
+    Init,
+
+    /// Print the JSON Schema of every JSON output, or of one by name
+    /// (e.g. `ls`, `sync.file`, `error`)
+    Schema { name: Option<String> },
+
+    /// Print a shell completion script, e.g. `source <(kindle-mtp completions bash)`
+    Completions {
+        /// Shell to generate for; bash and zsh also complete device paths
+        shell: clap_complete::Shell,
+    },
+
+    /// Print completion candidates for the command line up to the cursor
+    /// (used by the bash and zsh scripts)
+    #[command(name = "__complete", hide = true)]
+    Complete {
+        /// Escape candidates and cut them at the last `:`, as bash expects
+        #[arg(long)]
+        bash: bool,
+
+        #[arg(allow_hyphen_values = true)]
+        line: String,
+    },
+
     /// List directory contents
     Ls {
-        /// Path to list (default: root)
-        #[arg(default_value = "/")]
-        path: String,
+        /// Path to list (default: `remote_root` from the config, else /)
+        path: Option<String>,
 
         /// Long format with sizes
         #[arg(short, long)]
         long: bool,
+
+        /// List the whole subtree with full paths
+        #[arg(short = 'R', long)]
+        recursive: bool,
+
+        /// Sort by size, largest first
+        #[arg(short = 'S', conflicts_with = "time")]
+        size: bool,
+
+        /// Sort by modification time, newest first
+        #[arg(short = 't')]
+        time: bool,
+
+        /// Reverse the sort order
+        #[arg(short, long)]
+        reverse: bool,
+
+        /// Only files with these extensions, e.g. --ext azw3,pdf
+        #[arg(long, value_delimiter = ',', value_name = "EXT")]
+        ext: Vec<String>,
+
+        /// Only folders
+        #[arg(long, conflicts_with_all = ["only_files", "ext"])]
+        only_dirs: bool,
+
+        /// Only files
+        #[arg(long)]
+        only_files: bool,
+    },
+
+    /// Show size, type and modification time of a file or folder
+    Stat {
+        /// Path on the device
+        path: String,
+
+        /// Also dump the raw MTP object properties the device supports
+        #[arg(long)]
+        props: bool,
+
+        /// Also show title, author, ASIN and language from a book's header
+        #[arg(long)]
+        meta: bool,
+    },
+
+    /// Save the device's thumbnail of a file (a book's cover) without downloading it
+    Thumb {
+        /// File on the device
+        remote: String,
+
+        /// Where to write the image; `-` for stdout
+        local: String,
+    },
+
+    /// Save the cover image embedded in a book (MOBI, AZW3, EPUB) without
+    /// downloading the book
+    Cover {
+        /// Book on the device
+        remote: String,
+
+        /// Where to write the image; `-` for stdout
+        local: String,
     },
 
     /// Download file(s) from device
     Pull {
+        /// Remote paths on Kindle, then local destination (default: current
+        /// directory), a directory when several remote paths are given.
+        /// With --stdout, every path is a remote path.
+        #[arg(required = true, num_args = 1.., value_name = "REMOTE... [LOCAL]")]
+        paths: Vec<String>,
+
+        /// Recursive download
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Verify the local copy after download (size, or hash to re-read both copies)
+        #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "size")]
+        verify: Option<VerifyMode>,
+
+        /// Write to stdout: raw bytes for one file, a tar stream for several paths or folders
+        #[arg(long, conflicts_with = "verify")]
+        stdout: bool,
+
+        /// Keep the partly written file when a download fails or is interrupted
+        #[arg(long, conflicts_with = "stdout")]
+        keep_partial: bool,
+
+        /// Leave out `.sdr` folders and sidecar files (annotations, page numbers)
+        #[arg(long, requires = "recursive")]
+        skip_sdr: bool,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        #[command(flatten)]
+        overwrite: OverwriteArgs,
+    },
+
+    /// Upload a file to device
+    Push {
+        /// Local file to upload, or `-` for stdin (the remote path must then
+        /// name the file)
+        local: String,
+
+        /// Upload a folder with everything in it, creating folders as needed
+        #[arg(short, long)]
+        recursive: bool,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        /// Remote destination folder or file path (default: `remote_root`
+        /// from the config, else /documents)
+        remote: Option<String>,
+
+        /// Verify the device copy after upload (size, or hash to re-read both copies)
+        #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "size")]
+        verify: Option<VerifyMode>,
+
+        #[command(flatten)]
+        overwrite: OverwriteArgs,
+
+        /// Make the device file name safe: transliterate to ASCII, drop
+        /// characters the Kindle rejects and shorten long names
+        #[arg(long)]
+        sanitize: bool,
+
+        /// Upload as `Book (1).epub` if the destination exists, instead of
+        /// failing or replacing it
+        #[arg(long, conflicts_with_all = ["force", "no_clobber", "skip_existing"])]
+        rename_on_conflict: bool,
+
+        /// Write straight to the final name instead of uploading to a
+        /// temporary one and renaming it once complete
+        #[arg(long)]
+        no_atomic: bool,
+    },
+
+    /// Upload e-books, converting ones the Kindle can't open (EPUB, DOCX,
+    /// FB2, ...) with Calibre's ebook-convert first
+    Send {
+        /// Local e-books
+        #[arg(required = true)]
+        files: Vec<String>,
+
+        /// Device folder (default: `remote_root` from the config, else
+        /// /documents)
+        #[arg(long)]
+        to: Option<String>,
+
+        /// Format to convert to
+        #[arg(long = "as", value_name = "FORMAT", value_enum, default_value = "azw3")]
+        format: SendFormat,
+
+        /// Converter to run instead of ebook-convert, called as
+        /// `<converter> <input> <output>`
+        #[arg(long, value_name = "PROGRAM")]
+        converter: Option<PathBuf>,
+
+        /// Upload books as they are, without converting any
+        #[arg(long, conflicts_with = "converter")]
+        no_convert: bool,
+
+        #[command(flatten)]
+        overwrite: OverwriteArgs,
+    },
+
+    /// Work with a calibre library
+    Calibre {
+        #[command(subcommand)]
+        action: CalibreAction,
+    },
+
+    /// Delete files or folders from the device
+    Rm {
+        /// Remote paths to delete
+        #[arg(required = true)]
+        paths: Vec<String>,
+
+        /// Delete folders and everything in them
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Keep a local copy of what is deleted, for `trash restore`
+        /// (default: `trash` from the config)
+        #[arg(long, conflicts_with = "no_trash")]
+        trash: bool,
+
+        /// Delete without keeping a copy, whatever the config says
+        #[arg(long)]
+        no_trash: bool,
+    },
+
+    /// List or restore what `rm --trash` deleted
+    Trash {
+        #[command(subcommand)]
+        action: TrashAction,
+    },
+
+    /// Mirror a folder one way, copying new and changed files only
+    Sync {
+        /// Source folder; prefix with `kindle:` to sync from the device.
+        /// Omit both paths to run the [[sync]] pairs from the config file
+        #[arg(requires = "destination")]
+        source: Option<String>,
+
+        /// Destination folder (created if missing); unprefixed paths after a
+        /// local source are on the device
+        destination: Option<String>,
+
+        /// Make uploaded file and folder names safe for the device, as
+        /// `push --sanitize` does
+        #[arg(long)]
+        sanitize: bool,
+
+        /// Keep destination files modified after their source, even when
+        /// the sizes differ
+        #[arg(long)]
+        newer: bool,
+
+        /// Delete destination files and folders that are gone from the
+        /// source; preview with --dry-run. Sidecars of books that stay, and
+        /// whatever --include/--exclude leave out, are kept
+        #[arg(long)]
+        delete: bool,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+
+    /// Upload e-books (epub, pdf, azw3) as they appear in a local folder
+    Watch {
+        /// Local folder to watch
+        local: String,
+
+        /// Destination folder on the device (default: `remote_root` from
+        /// the config, else /documents)
+        remote: Option<String>,
+    },
+
+    /// Run hooks when a Kindle is plugged in or removed
+    Monitor {
+        /// Shell command to run on connect (device in KINDLE_MTP_* env vars)
+        #[arg(long, value_name = "CMD")]
+        on_connect: Option<String>,
+
+        /// Shell command to run on disconnect
+        #[arg(long, value_name = "CMD")]
+        on_disconnect: Option<String>,
+    },
+
+    /// Keep the device open and serve ls/info/pull/push to other invocations
+    Daemon,
+
+    /// Interactive prompt that keeps one device connection open
+    Shell,
+
+    /// Full-screen file browser that keeps one device connection open
+    Tui {
+        /// Folder `g`/F5 downloads into (default: `download_dir` from the
+        /// config, else the current folder)
+        #[arg(long, value_name = "DIR")]
+        download_dir: Option<PathBuf>,
+    },
+
+    /// Run shell commands from a script over one device connection
+    Batch {
+        /// Script with one command per line (`#` comments); `-` reads stdin
+        script: String,
+
+        /// Stop at the first failing command (default)
+        #[arg(long, conflicts_with = "keep_going")]
+        stop_on_error: bool,
+
+        /// Run the remaining commands after a failure
+        #[arg(long = "continue")]
+        keep_going: bool,
+    },
+
+    /// Serve a REST API over HTTP (info, file listings, download, upload)
+    Serve {
+        /// Address to listen on; anyone who can reach it controls the device
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        addr: String,
+    },
+
+    /// Build an OPDS catalog of the books on the device, for other reading
+    /// apps to browse and download from
+    Opds {
+        /// Write the feed (catalog.xml), the books and their covers into
+        /// this directory, for any static web server
+        #[arg(long, value_name = "DIR", required_unless_present = "serve", conflicts_with = "serve")]
+        out: Option<String>,
+
+        /// Serve the feed over HTTP instead, reading books and covers from
+        /// the device on request
+        #[arg(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = "127.0.0.1:8080")]
+        serve: Option<String>,
+
+        /// Device folder with the books
+        #[arg(long, default_value = "/documents")]
+        path: String,
+    },
+
+    /// Mount the device as a filesystem (needs a build with `--features mount`)
+    Mount {
+        /// Empty directory to mount on
+        mountpoint: String,
+    },
+
+    /// Compare a local folder with a device folder (preview of a sync)
+    Diff {
+        /// Local folder
+        local: String,
+
+        /// Folder on the device
+        remote: String,
+
+        /// Read the device side from the saved index instead of the device
+        #[arg(long)]
+        index: bool,
+
+        /// Rebuild the saved index from the device before comparing
+        #[arg(long)]
+        refresh: bool,
+    },
+
+    /// Back up a device folder into a new local snapshot
+    Backup {
+        /// Local backup directory
+        backup_dir: String,
+
+        /// Remote folder to back up
+        #[arg(default_value = "/")]
+        path: String,
+
+        /// Transfer every file instead of hardlinking unchanged ones from the previous snapshot
+        #[arg(long)]
+        full: bool,
+
+        /// Leave out `.sdr` folders and sidecar files (annotations, page numbers)
+        #[arg(long)]
+        skip_sdr: bool,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+
+    /// Walk the whole device and save its tree as the offline index
+    Index,
+
+    /// Find files by name in the saved index, without the device
+    Search {
+        /// Text to look for in file names (case-insensitive)
+        query: String,
+
+        /// Treat the query as a regular expression
+        #[arg(long)]
+        regex: bool,
+
+        /// Only search below this device folder
+        #[arg(long, default_value = "/")]
+        path: String,
+    },
+
+    /// List the books on the device with their format, sidecar files, and
+    /// title and author
+    Books {
+        /// Device folder to list
+        #[arg(long, default_value = "/documents")]
+        path: String,
+
+        /// Take titles and authors from file names instead of reading each
+        /// book's header (faster on large libraries)
+        #[arg(long)]
+        from_names: bool,
+    },
+
+    /// Write an inventory of the books on the device (path, title, author,
+    /// ASIN, size, date added) to a CSV or JSON file
+    Catalog {
+        /// Output file; the extension picks the format unless --as names it
+        file: String,
+
+        /// File format
+        #[arg(long = "as", value_name = "FORMAT", value_enum)]
+        format: Option<CatalogFormat>,
+
+        /// Device folder to list
+        #[arg(long, default_value = "/documents")]
+        path: String,
+
+        /// Take titles and authors from file names instead of reading each
+        /// book's header (faster on large libraries)
+        #[arg(long)]
+        from_names: bool,
+    },
+
+    /// Show what takes up space on the device, by top-level folder and by
+    /// kind of file
+    Usage {
+        /// Device folder to add up
+        #[arg(default_value = "/")]
+        path: String,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+
+    /// List the largest files on the device
+    Largest {
+        /// Device folder to look in
+        #[arg(default_value = "/")]
+        path: String,
+
+        /// How many files to list
+        #[arg(short = 'n', long, default_value_t = 20)]
+        count: usize,
+    },
+
+    /// Delete .sdr folders and sidecar files (.apnx, .mbp, ...) whose book
+    /// is gone; asks first unless --yes
+    CleanSdr {
+        /// Device folder to clean
+        #[arg(default_value = "/documents")]
+        path: String,
+
+        /// Delete without asking
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Print or export the highlights, notes and bookmarks in My Clippings.txt
+    Clippings {
+        /// Export to this .json, .md or .csv file instead of printing;
+        /// the extension picks the format
+        file: Option<String>,
+
+        /// Group the clippings by book
+        #[arg(long)]
+        by_book: bool,
+
+        /// Clippings file on the device
+        #[arg(long, default_value = "/documents/My Clippings.txt")]
+        path: String,
+    },
+
+    /// List and edit collections (firmware that keeps them in
+    /// /system/collections.json)
+    Collections {
+        #[command(subcommand)]
+        action: CollectionsAction,
+    },
+
+    /// List, pull and push audiobooks; pulls of large files resume where
+    /// an interrupted one stopped
+    Audio {
+        #[command(subcommand)]
+        action: AudioAction,
+    },
+
+    /// Install and list dictionaries
+    Dict {
+        #[command(subcommand)]
+        action: DictAction,
+    },
+
+    /// Manage custom screensavers (jailbroken Kindles with the ScreenSavers hack)
+    Screensaver {
+        #[command(subcommand)]
+        action: ScreensaverAction,
+
+        /// Device folder the hack reads images from
+        #[arg(long, global = true, default_value = "/linkss/screensavers")]
+        folder: String,
+    },
+
+    /// Export highlights and notes from My Clippings.txt as Markdown notes
+    Highlights {
+        #[command(subcommand)]
+        action: HighlightsAction,
+    },
+
+    /// Export the words looked up in Vocabulary Builder
+    Vocab {
+        #[command(subcommand)]
+        action: VocabAction,
+    },
+
+    /// Export the device tree for offline use
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotAction,
+    },
+
+    /// Push a backup (or one subtree of it) back to the device
+    Restore {
+        /// Backup directory (newest snapshot) or a specific snapshot
+        backup_dir: String,
+
+        /// Only restore this remote path (default: everything in the backup)
+        path: Option<String>,
+    },
+
+    /// Describe every command and flag (use --json for wrappers)
+    Introspect,
+
+    /// Show transfer and error trends from the local run history
+    Stats {
+        /// How far back to look, e.g. 12h, 30d or 8w
+        #[arg(long, default_value = "30d", value_parser = parse_duration)]
+        last: chrono::Duration,
+    },
+
+    /// Check the setup; --tune benchmarks the device and suggests settings
+    Doctor {
+        /// Measure transfer rates and suggest a [performance] config section
+        #[arg(long)]
+        tune: bool,
+    },
+
+    /// Check device state; exits with code 8 if any check fails
+    Assert {
+        /// Require at least this much free space, e.g. 500M or 1G
+        #[arg(long, value_parser = parse_size)]
+        free_space_min: Option<u64>,
+
+        /// Require this path to exist (repeatable)
+        #[arg(long, value_name = "PATH")]
+        exists: Vec<String>,
+
+        /// Require this path not to exist (repeatable)
+        #[arg(long, value_name = "PATH")]
+        missing: Vec<String>,
+
+        /// Require a folder to hold at most N entries, e.g. /documents:2000 (repeatable)
+        #[arg(long, value_name = "PATH:N", value_parser = parse_path_count)]
+        count_max: Vec<(String, usize)>,
+
+        /// Require a folder to hold at least N entries (repeatable)
+        #[arg(long, value_name = "PATH:N", value_parser = parse_path_count)]
+        count_min: Vec<(String, usize)>,
+    },
+
+    /// Measure transfer speed and latency with a temporary file on the device
+    Bench {
+        /// Size of the temporary file, e.g. 4M or 100M
+        #[arg(long, default_value = "16M", value_parser = parse_size)]
+        size: u64,
+
+        /// Times to write and read the file
+        #[arg(long, default_value_t = 3)]
+        rounds: u32,
+
+        /// Device folder to write the temporary file under
+        #[arg(long, default_value = "/documents")]
+        dir: String,
+    },
+
+    /// Checksum a file on the device without downloading it
+    Hash {
         /// Remote path on Kindle
         remote: String,
 
-        /// Local destination path
+        /// Use SHA-256 (default)
+        #[arg(long, conflicts_with = "md5")]
+        sha256: bool,
+
+        /// Use MD5
+        #[arg(long)]
+        md5: bool,
+    },
+
+    /// Upload a firmware update (update*.bin) to the device root after
+    /// checking it is for the connected model
+    FwUpdate {
+        /// The update file downloaded from Amazon
+        file: String,
+
+        /// Upload even if the file's header doesn't name this model
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Show the MTP operations, events and formats the device supports, or
+    /// issue one raw operation (needs the ptp build)
+    MtpDebug {
+        /// Operation code to issue, hex as in the MTP spec, e.g. 1001
+        #[arg(long, value_parser = parse_mtp_code)]
+        op: Option<u16>,
+
+        /// Operation parameter, hex with 0x or decimal; repeat for up to five
+        #[arg(long = "param", value_name = "PARAM", requires = "op", value_parser = parse_mtp_param)]
+        params: Vec<u32>,
+
+        /// Write the operation's data phase to this file instead of printing it
+        #[arg(long, requires = "op")]
+        out: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SnapshotAction {
+    /// Write every file and folder with its metadata to a .json or .sqlite
+    /// file (SQLite exports accumulate, one snapshot per run)
+    Export {
+        /// Output file; the extension picks the format
+        file: String,
+
+        /// Remote folder to export
+        #[arg(long, default_value = "/")]
+        path: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CollectionsAction {
+    /// List the collections and the books in them
+    List,
+
+    /// Add books to a collection, creating it if need be
+    Add {
+        /// Collection name
+        name: String,
+
+        /// Books on the device
+        #[arg(required = true)]
+        books: Vec<String>,
+    },
+
+    /// Take books out of a collection, or remove the collection if no
+    /// books are given (the books stay on the device)
+    Remove {
+        /// Collection name
+        name: String,
+
+        /// Books on the device
+        books: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum AudioAction {
+    /// List audio files with sizes and durations
+    Ls {
+        /// Device folder; the audible, audiobooks and music folders by default
+        path: Option<String>,
+    },
+
+    /// Download an audiobook, or every one in a folder, resuming an
+    /// interrupted download
+    Pull {
+        /// Audio file or folder on the device
+        remote: String,
+
+        /// Local file or directory
         #[arg(default_value = ".")]
         local: String,
+    },
 
-        /// Recursive download
+    /// Upload audiobooks, skipping ones already on the device
+    Push {
+        /// Local audio files (.aax, .aa, .m4b, .m4a, .mp3)
+        #[arg(required = true)]
+        files: Vec<String>,
+
+        /// Device folder; the existing audiobook folder, or /audible
+        #[arg(long)]
+        to: Option<String>,
+
+        /// Upload even if a file of the same name and size is there
         #[arg(short, long)]
-        recursive: bool,
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CalibreAction {
+    /// Send books picked from a calibre library, converting ones without a
+    /// Kindle format, and record them in the device's metadata.calibre
+    #[command(group(clap::ArgGroup::new("pick").required(true).multiple(true)))]
+    Import {
+        /// Words that must all appear in the title or an author's name
+        #[arg(group = "pick")]
+        query: Option<String>,
+
+        /// Library book ID (repeatable)
+        #[arg(long = "id", value_name = "ID", group = "pick")]
+        ids: Vec<i64>,
+
+        /// Author name, or part of one (repeatable; all must match)
+        #[arg(long = "author", value_name = "NAME", group = "pick")]
+        authors: Vec<String>,
+
+        /// Tag (repeatable; all must match)
+        #[arg(long = "tag", value_name = "TAG", group = "pick")]
+        tags: Vec<String>,
+
+        /// Series name, or part of one
+        #[arg(long, group = "pick")]
+        series: Option<String>,
+
+        /// Send every book in the library
+        #[arg(long, group = "pick")]
+        all: bool,
+
+        /// Library folder, the one holding metadata.db (default:
+        /// ~/Calibre Library)
+        #[arg(long, value_name = "DIR")]
+        library: Option<PathBuf>,
+
+        /// Device folder (default: `remote_root` from the config, else
+        /// /documents)
+        #[arg(long)]
+        to: Option<String>,
+
+        /// Format to convert to
+        #[arg(long = "as", value_name = "FORMAT", value_enum, default_value = "azw3")]
+        format: SendFormat,
+
+        /// Converter to run instead of ebook-convert, called as
+        /// `<converter> <input> <output>`
+        #[arg(long, value_name = "PROGRAM")]
+        converter: Option<PathBuf>,
+
+        /// Upload books as they are, without converting any
+        #[arg(long, conflicts_with = "converter")]
+        no_convert: bool,
+
+        #[command(flatten)]
+        overwrite: OverwriteArgs,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TrashAction {
+    /// List what is in the trash, for every Kindle, newest first
+    List,
+
+    /// Put items back where they were deleted from on the connected Kindle
+    Restore {
+        /// IDs from `trash list`
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DictAction {
+    /// Upload a MOBI dictionary to documents/dictionaries after checking it
+    /// is one
+    Install {
+        /// Local dictionary file (.mobi, .azw or .prc)
+        file: String,
+
+        /// Replace a dictionary of the same name
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// List installed dictionaries with their languages
+    List,
+}
+
+#[derive(Subcommand)]
+pub enum ScreensaverAction {
+    /// Scale an image to the screen, convert it to grayscale PNG and upload it
+    Push {
+        /// Local image (PNG, JPEG, GIF, BMP or WebP)
+        image: String,
+
+        /// Screen size instead of the one the model has, e.g. 1072x1448
+        #[arg(long, value_name = "WxH", value_parser = parse_resolution)]
+        size: Option<(u32, u32)>,
+
+        /// Fit the whole image on a white background instead of cropping
+        /// it to fill the screen
+        #[arg(long)]
+        fit: bool,
+    },
+
+    /// List the screensaver images
+    List,
+
+    /// Delete screensaver images by name
+    Rm {
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum HighlightsAction {
+    /// Write one Markdown note per book into a folder (an Obsidian vault,
+    /// say), adding only new highlights to notes already there
+    Export {
+        /// Local folder for the notes
+        dir: String,
+
+        /// Rewrite every note instead of adding new highlights to it
+        #[arg(long)]
+        full: bool,
+
+        /// Clippings file on the device
+        #[arg(long, default_value = "/documents/My Clippings.txt")]
+        path: String,
     },
+}
+
+#[derive(Subcommand)]
+pub enum VocabAction {
+    /// Write every word with the sentences it was looked up in and their
+    /// books to a .json, .csv or .txt file (Anki import)
+    Export {
+        /// Output file; the extension picks the format
+        file: String,
+
+        /// Vocabulary Builder database on the device
+        #[arg(long, default_value = "/system/vocabulary/vocab.db")]
+        path: String,
+    },
+}
+
+impl Command {
+    /// Expands `alias:path` in every device path argument.
+    pub fn expand_aliases(&mut self, config: &Config) {
+        let apply = |path: &mut String| *path = config.expand_alias(path);
+        match self {
+            Command::Ls { path, .. } | Command::Restore { path, .. } => {
+                path.iter_mut().for_each(apply);
+            }
+            Command::Collections {
+                action: CollectionsAction::Add { books, .. } | CollectionsAction::Remove { books, .. },
+            } => books.iter_mut().for_each(apply),
+            Command::Pull { paths, stdout, .. } => {
+                // Without --stdout the last of several paths is the local destination
+                let remotes = if *stdout { paths.len() } else { paths.len().saturating_sub(1).max(1) };
+                paths.iter_mut().take(remotes).for_each(apply);
+            }
+            Command::Push { remote, .. } | Command::Watch { remote, .. } => {
+                remote.iter_mut().for_each(apply);
+            }
+            Command::Send { to, .. }
+            | Command::Calibre {
+                action: CalibreAction::Import { to, .. },
+            } => to.iter_mut().for_each(apply),
+            Command::Rm { paths, .. } => paths.iter_mut().for_each(apply),
+            Command::Stat { path, .. } => apply(path),
+            Command::Thumb { remote, .. } | Command::Cover { remote, .. } => apply(remote),
+            Command::Diff { remote, .. } | Command::Hash { remote, .. } => apply(remote),
+            Command::Bench { dir, .. } => apply(dir),
+            Command::Backup { path, .. }
+            | Command::Search { path, .. }
+            | Command::Books { path, .. }
+            | Command::Catalog { path, .. }
+            | Command::Usage { path, .. }
+            | Command::Largest { path, .. }
+            | Command::CleanSdr { path, .. }
+            | Command::Clippings { path, .. }
+            | Command::Opds { path, .. }
+            | Command::Screensaver { folder: path, .. } => apply(path),
+            Command::Audio {
+                action: AudioAction::Ls { path },
+            } => path.iter_mut().for_each(apply),
+            Command::Audio {
+                action: AudioAction::Pull { remote, .. },
+            } => apply(remote),
+            Command::Audio {
+                action: AudioAction::Push { to, .. },
+            } => to.iter_mut().for_each(apply),
+            Command::Snapshot {
+                action: SnapshotAction::Export { path, .. },
+            }
+            | Command::Highlights {
+                action: HighlightsAction::Export { path, .. },
+            }
+            | Command::Vocab {
+                action: VocabAction::Export { path, .. },
+            } => apply(path),
+            Command::Sync {
+                source,
+                destination,
+                ..
+            } => {
+                for endpoint in [source, destination].into_iter().flatten() {
+                    *endpoint = config.expand_sync_endpoint(endpoint);
+                }
+            }
+            Command::Assert {
+                exists,
+                missing,
+                count_max,
+                count_min,
+                ..
+            } => {
+                exists.iter_mut().chain(missing.iter_mut()).for_each(apply);
+                count_max
+                    .iter_mut()
+                    .chain(count_min.iter_mut())
+                    .for_each(|(path, _)| apply(path));
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the command talks to the device at all; `--wait` is a no-op
+    /// for the others.
+    pub fn needs_device(&self) -> bool {
+        !matches!(
+            self,
+            Command::Init
+                | Command::Completions { .. }
+                | Command::Schema { .. }
+                | Command::Complete { .. }
+                | Command::Introspect
+                | Command::Monitor { .. }
+                | Command::Stats { .. }
+                | Command::Doctor { tune: false }
+                | Command::Search { .. }
+                | Command::Diff {
+                    index: true,
+                    refresh: false,
+                    ..
+                }
+        )
+    }
+
+    /// Whether the command reports `--stats`.
+    pub fn reports_stats(&self) -> bool {
+        matches!(self, Command::Pull { .. } | Command::Push { .. } | Command::Sync { .. })
+    }
+
+    /// Whether Ctrl-C should stop the command's transfers cleanly instead of
+    /// killing the process mid-file.
+    pub fn cancels_on_interrupt(&self) -> bool {
+        matches!(
+            self,
+            Command::Pull { .. }
+                | Command::Push { .. }
+                | Command::Sync { .. }
+                | Command::Backup { .. }
+                | Command::Restore { .. }
+                | Command::Bench { .. }
+        )
+    }
+}
+
+/// What to do when a destination file already exists. Without a flag the
+/// config file's `overwrite` applies, and failing that `--no-clobber`.
+#[derive(clap::Args)]
+#[group(multiple = false)]
+pub struct OverwriteArgs {
+    /// Replace existing destination files
+    #[arg(short, long)]
+    force: bool,
+
+    /// Fail if a destination file exists (default)
+    #[arg(short, long)]
+    no_clobber: bool,
+
+    /// Keep existing destination files and continue with the rest
+    #[arg(long)]
+    skip_existing: bool,
+
+    /// Replace existing destination files unless they are up to date: the
+    /// same size and no older, or modified after the source
+    #[arg(long)]
+    newer: bool,
+}
+
+impl OverwriteArgs {
+    pub fn policy(&self, default: OverwritePolicy) -> OverwritePolicy {
+        if self.force {
+            OverwritePolicy::Force
+        } else if self.skip_existing {
+            OverwritePolicy::SkipExisting
+        } else if self.newer {
+            OverwritePolicy::Newer
+        } else if self.no_clobber {
+            OverwritePolicy::NoClobber
+        } else {
+            default
+        }
+    }
+}
+
+/// Which files a recursive command takes, by `.gitignore`-style patterns
+/// matched against paths below the folder it starts from.
+#[derive(clap::Args)]
+pub struct FilterArgs {
+    /// Only take files matching PATTERN, e.g. '*.azw3', or in folders
+    /// matching it (repeatable)
+    #[arg(long, value_name = "PATTERN", value_parser = Pattern::parse)]
+    include: Vec<Pattern>,
+
+    /// Leave out files and folders matching PATTERN, e.g. '*.sdr'
+    /// (repeatable)
+    #[arg(long, value_name = "PATTERN", value_parser = Pattern::parse)]
+    exclude: Vec<Pattern>,
+}
+
+impl FilterArgs {
+    pub fn filter(self) -> PathFilter {
+        PathFilter::new(self.include, self.exclude)
+    }
+}
+
+/// Parses a byte count with an optional K/M/G suffix (decimal units, matching
+/// how sizes are displayed).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let multiplier = match c.to_ascii_uppercase() {
+                'K' => 1_000,
+                'M' => 1_000_000,
+                'G' => 1_000_000_000,
+                'B' => 1,
+                _ => return Err(format!("unknown size suffix '{}'", c)),
+            };
+            (&s[..i], multiplier)
+        }
+        _ => (s, 1),
+    };
+    let value: f64 = digits
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", s))?;
+    if value < 0.0 {
+        return Err(format!("invalid size '{}'", s));
+    }
+    Ok((value * multiplier as f64) as u64)
+}
+
+/// Parses `PATH:N`, splitting at the last colon so paths may contain colons.
+pub fn parse_path_count(s: &str) -> Result<(String, usize), String> {
+    let (path, count) = s
+        .rsplit_once(':')
+        .ok_or_else(|| format!("expected PATH:N, got '{}'", s))?;
+    let count = count
+        .parse()
+        .map_err(|_| format!("invalid count '{}' in '{}'", count, s))?;
+    Ok((path.to_string(), count))
+}
+
+/// Parses a USB vendor or product ID, hex as lsusb shows it, with or
+/// without `0x`: `1949` or `0x1949`.
+pub fn parse_usb_id(s: &str) -> Result<u16, String> {
+    let s = s.trim();
+    let digits = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid USB ID '{}' (expected hex, e.g. 1949)", s))
+}
+
+/// Parses an MTP operation code, hex as the spec writes it, with or
+/// without `0x`: `1001` or `0x9805`.
+pub fn parse_mtp_code(s: &str) -> Result<u16, String> {
+    let s = s.trim();
+    let digits = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid MTP code '{}' (expected hex, e.g. 1001)", s))
+}
+
+/// Parses an MTP operation parameter: hex with `0x`, decimal otherwise.
+pub fn parse_mtp_param(s: &str) -> Result<u32, String> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(digits) => u32::from_str_radix(digits, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("invalid MTP parameter '{}' (expected e.g. 0x10001 or 65537)", s))
+}
+
+/// Parses a screen size such as `1072x1448`.
+pub fn parse_resolution(s: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("invalid size '{}' (expected WIDTHxHEIGHT, e.g. 1072x1448)", s);
+    let (width, height) = s.trim().split_once(['x', 'X']).ok_or_else(invalid)?;
+    match (width.parse(), height.parse()) {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses a duration with an s/m/h/d/w suffix, e.g. `30d`.
+pub fn parse_duration(s: &str) -> Result<chrono::Duration, String> {
+    let s = s.trim();
+    if let Some(millis) = s.strip_suffix("ms") {
+        return millis
+            .parse()
+            .map(chrono::Duration::milliseconds)
+            .map_err(|_| format!("invalid duration '{}' (expected e.g. 500ms)", s));
+    }
+    let (digits, unit) = s.split_at(s.len() - s.chars().last().map_or(0, |c| c.len_utf8()));
+    let value: i64 = digits
+        .parse()
+        .map_err(|_| format!("invalid duration '{}' (expected e.g. 30d)", s))?;
+    match unit {
+        "s" => Ok(chrono::Duration::seconds(value)),
+        "m" => Ok(chrono::Duration::minutes(value)),
+        "h" => Ok(chrono::Duration::hours(value)),
+        "d" => Ok(chrono::Duration::days(value)),
+        "w" => Ok(chrono::Duration::weeks(value)),
+        _ => Err(format!("unknown duration unit in '{}' (use ms, s, m, h, d or w)", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_takes_decimal_suffixes() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("10B"), Ok(10));
+        assert_eq!(parse_size("256k"), Ok(256_000));
+        assert_eq!(parse_size("1.5M"), Ok(1_500_000));
+        assert_eq!(parse_size(" 2G "), Ok(2_000_000_000));
+    }
 
+    #[test]
+    fn parse_size_rejects_garbage() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("5X").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("1.2.3K").is_err());
+    }
 }
@@ -0,0 +1,43 @@
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// Sets up diagnostic logging. `-v` logs device operations and path
+/// resolution to stderr, `-vv` adds every transfer chunk. A log file gets at
+/// least the `-v` level, with timestamps and threads, and is appended to so
+/// several runs of a flaky connection end up in one place.
+pub fn init_logging(verbosity: u8, log_file: Option<&Path>) -> std::io::Result<()> {
+    let level = match verbosity {
+        0 => LevelFilter::OFF,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    let stderr = (verbosity > 0).then(|| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .without_time()
+            .with_target(false)
+            .with_filter(level)
+    });
+
+    let file = match log_file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_writer(Mutex::new(file))
+                    .with_ansi(false)
+                    .with_thread_names(true)
+                    .with_filter(level.max(LevelFilter::DEBUG)),
+            )
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry().with(stderr).with(file).init();
+    Ok(())
+}
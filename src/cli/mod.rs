@@ -1,5 +1,10 @@
 mod args;
+mod logging;
 mod output;
+pub mod style;
+mod timing;
 
-pub use args::{Args, Command};
-pub use output::{HumanReadable, Output};
+pub use args::{parse_size, Args, AudioAction, CalibreAction, CollectionsAction, Command, DictAction, HighlightsAction, OverwriteArgs, ScreensaverAction, SnapshotAction, TrashAction, VocabAction};
+pub use logging::init_logging;
+pub use output::{HumanReadable, Output, OutputFormat, SCHEMA_VERSION};
+pub use timing::{FileTiming, TransferStats};
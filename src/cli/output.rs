@@ -1,42 +1,207 @@
-use serde::Serialize;
+use super::timing::Timings;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::io::Write;
+use std::time::Instant;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
 pub enum OutputFormat {
     Human,
     Json,
+    /// One compact JSON object per line, items streamed as they happen
+    Ndjson,
 }
 
+/// Version of the JSON output contract, sent as `schema_version` in every
+/// result, event and error. Bumped when a field is removed, renamed or
+/// changes meaning; new fields don't bump it.
+pub const SCHEMA_VERSION: u32 = 1;
+
 pub struct Output {
     format: OutputFormat,
     quiet: bool,
+    warnings: RefCell<Vec<String>>,
+    warning_count: Cell<usize>,
+    /// JSON results held back for the caller instead of printed
+    captured: Option<RefCell<Vec<serde_json::Value>>>,
+    /// File timings for `--stats`, attached to the next result
+    timings: Option<RefCell<Timings>>,
 }
 
 impl Output {
-    pub fn new(json: bool, quiet: bool) -> Self {
+    pub fn new(format: OutputFormat, quiet: bool) -> Self {
         Self {
-            format: if json {
-                OutputFormat::Json
-            } else {
-                OutputFormat::Human
-            },
+            format,
             quiet,
+            warnings: RefCell::new(Vec::new()),
+            warning_count: Cell::new(0),
+            captured: None,
+            timings: None,
+        }
+    }
+
+    /// Times the transfers reported through [`Output::timed`] and attaches
+    /// the totals to each result as `stats`.
+    pub fn with_stats(mut self, enabled: bool) -> Self {
+        self.timings = enabled.then(|| RefCell::new(Timings::new()));
+        self
+    }
+
+    /// Runs one file transfer to or from the device path `path`, which
+    /// returns the bytes it moved, timing it if `--stats` is on. Failed
+    /// transfers aren't counted.
+    pub fn timed<E>(&self, path: &str, transfer: impl FnOnce() -> Result<u64, E>) -> Result<u64, E> {
+        let Some(timings) = &self.timings else {
+            return transfer();
+        };
+        let started = Instant::now();
+        let bytes = transfer()?;
+        timings.borrow_mut().record(path, bytes, started.elapsed());
+        Ok(bytes)
+    }
+
+    /// A JSON output that collects results instead of printing them, so a
+    /// command running others (`batch`) can report them as one document.
+    /// Progress notes still follow `quiet`.
+    pub fn capturing(&self) -> Self {
+        Self {
+            captured: Some(RefCell::new(Vec::new())),
+            ..Self::new(OutputFormat::Json, self.quiet)
         }
     }
 
+    /// Results collected since the last call; empty unless [`Output::capturing`].
+    pub fn take_captured(&self) -> Vec<serde_json::Value> {
+        self.captured.as_ref().map(RefCell::take).unwrap_or_default()
+    }
+
+    /// Prints the command result. Warnings recorded so far are attached: as a
+    /// `warnings` array in JSON, or after the result on stderr for humans.
+    /// In NDJSON mode the result is the last line, after any events.
     pub fn print<T: Serialize + HumanReadable>(&self, item: &T) {
-        if self.quiet {
+        let warnings = self.warnings.take();
+        let stats = self.timings.as_ref().map(|t| t.borrow_mut().take());
+        match self.format {
+            OutputFormat::Human => {
+                if !self.quiet {
+                    println!("{}", item.to_human());
+                    if let Some(stats) = &stats {
+                        println!("{}", stats.to_human());
+                    }
+                }
+                self.print_warnings(&warnings);
+            }
+            OutputFormat::Json | OutputFormat::Ndjson => {
+                if self.quiet && self.captured.is_none() {
+                    return;
+                }
+                let mut value = serde_json::to_value(item).unwrap_or_default();
+                if let serde_json::Value::Object(map) = &mut value {
+                    map.insert("schema_version".to_string(), SCHEMA_VERSION.into());
+                    if !warnings.is_empty() {
+                        map.insert("warnings".to_string(), warnings.into());
+                    }
+                    if let Some(stats) = stats {
+                        map.insert("stats".to_string(), serde_json::to_value(stats).unwrap_or_default());
+                    }
+                }
+                if let Some(captured) = &self.captured {
+                    captured.borrow_mut().push(value);
+                    return;
+                }
+                self.print_value(&value);
+            }
+        }
+    }
+
+    /// Reports one item of a long or recursive operation (a listed entry, a
+    /// transferred file) as soon as it is known, as `{"event": <event>, ...}`.
+    /// Only NDJSON prints these; the other formats report the final result.
+    pub fn event<T: Serialize>(&self, event: &str, item: &T) {
+        if self.format != OutputFormat::Ndjson || self.quiet {
             return;
         }
+        let mut value = serde_json::json!({ "event": event, "schema_version": SCHEMA_VERSION });
+        if let (serde_json::Value::Object(map), Ok(serde_json::Value::Object(fields))) =
+            (&mut value, serde_json::to_value(item))
+        {
+            map.extend(fields);
+        }
+        self.print_value(&value);
+    }
+
+    /// Records a non-fatal problem; the command carries on.
+    pub fn warn(&self, message: impl Into<String>) {
+        self.warnings.borrow_mut().push(message.into());
+        self.warning_count.set(self.warning_count.get() + 1);
+    }
+
+    /// Prints a progress note for long-running commands immediately, on
+    /// stderr so stdout stays parseable.
+    pub fn info(&self, message: impl AsRef<str>) {
+        if !self.quiet {
+            eprintln!("{}", message.as_ref());
+        }
+    }
+
+    /// Number of warnings raised during the run, whether printed yet or not.
+    pub fn warning_count(&self) -> usize {
+        self.warning_count.get()
+    }
+
+    /// Reports warnings that were never attached to a result, e.g. because
+    /// the command failed part-way.
+    pub fn finish(&self) {
+        let warnings = self.warnings.take();
         match self.format {
-            OutputFormat::Human => println!("{}", item.to_human()),
-            OutputFormat::Json => {
-                println!("{}", serde_json::to_string_pretty(item).unwrap_or_default())
+            OutputFormat::Human => self.print_warnings(&warnings),
+            OutputFormat::Json | OutputFormat::Ndjson => {
+                if !warnings.is_empty() {
+                    let value = serde_json::json!({ "warnings": warnings });
+                    if let Some(captured) = &self.captured {
+                        captured.borrow_mut().push(value);
+                        return;
+                    }
+                    let text = match self.format {
+                        OutputFormat::Ndjson => serde_json::to_string(&value),
+                        _ => serde_json::to_string_pretty(&value),
+                    };
+                    eprintln!("{}", text.unwrap_or_default());
+                }
             }
         }
     }
 
+    /// Whether results are JSON, pretty or line-delimited.
     pub fn is_json(&self) -> bool {
-        matches!(self.format, OutputFormat::Json)
+        matches!(self.format, OutputFormat::Json | OutputFormat::Ndjson)
+    }
+
+    /// Whether list results are streamed as events; commands then leave the
+    /// streamed items out of the final result.
+    pub fn is_ndjson(&self) -> bool {
+        self.format == OutputFormat::Ndjson
+    }
+
+    fn print_value(&self, value: &serde_json::Value) {
+        if self.format == OutputFormat::Ndjson {
+            // Flushed per line so consumers see each event as it happens
+            let mut stdout = std::io::stdout().lock();
+            let _ = writeln!(stdout, "{}", serde_json::to_string(value).unwrap_or_default());
+            let _ = stdout.flush();
+        } else {
+            println!("{}", serde_json::to_string_pretty(value).unwrap_or_default());
+        }
+    }
+
+    fn print_warnings(&self, warnings: &[String]) {
+        if self.quiet {
+            return;
+        }
+        for warning in warnings {
+            eprintln!("Warning: {}", warning);
+        }
     }
 }
 
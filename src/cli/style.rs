@@ -0,0 +1,43 @@
+use clap::ColorChoice;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+pub const BOLD_BLUE: &str = "1;34";
+pub const GREEN: &str = "32";
+
+/// Decides once at startup whether human output is colored. `auto` colors
+/// only a terminal, and only when `NO_COLOR` is unset or empty.
+pub fn set_color(choice: ColorChoice) {
+    let enabled = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            std::io::stdout().is_terminal()
+                && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+        }
+    };
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+/// Wraps `text` in an SGR color `code` when color is on.
+pub fn paint(text: &str, code: &str) -> String {
+    if COLOR.load(Ordering::Relaxed) {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Width to lay out columns in: `COLUMNS` if set, else the terminal's.
+/// `None` when stdout isn't a terminal, so pipes get one item per line.
+pub fn terminal_width() -> Option<usize> {
+    if !std::io::stdout().is_terminal() {
+        return None;
+    }
+    std::env::var("COLUMNS")
+        .ok()
+        .and_then(|c| c.parse().ok())
+        .or_else(|| crossterm::terminal::size().ok().map(|(w, _)| w as usize))
+}
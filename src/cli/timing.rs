@@ -0,0 +1,109 @@
+use super::output::HumanReadable;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// `--stats`: what a pull, push or sync moved and how fast, attached to its
+/// result as `stats`.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct TransferStats {
+    pub files: usize,
+    pub bytes: u64,
+    /// Wall-clock time of the command so far, opening the device included
+    pub elapsed_ms: u64,
+    /// Time spent moving file data, the sum of `per_file`
+    pub transfer_ms: u64,
+    /// Bytes per second over `transfer_ms`; `None` if nothing was moved
+    pub throughput: Option<u64>,
+    pub per_file: Vec<FileTiming>,
+}
+
+/// One file transferred, as the device path.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct FileTiming {
+    pub path: String,
+    pub bytes: u64,
+    pub elapsed_ms: u64,
+}
+
+/// Collects `FileTiming`s until the next result is printed.
+pub(crate) struct Timings {
+    since: Instant,
+    files: Vec<FileTiming>,
+}
+
+impl Timings {
+    pub fn new() -> Self {
+        Self {
+            since: Instant::now(),
+            files: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, path: &str, bytes: u64, elapsed: Duration) {
+        self.files.push(FileTiming {
+            path: path.to_string(),
+            bytes,
+            elapsed_ms: elapsed.as_millis() as u64,
+        });
+    }
+
+    /// The stats since the last call, starting the next period.
+    pub fn take(&mut self) -> TransferStats {
+        let per_file = std::mem::take(&mut self.files);
+        let elapsed = std::mem::replace(&mut self.since, Instant::now()).elapsed();
+        let bytes = per_file.iter().map(|f| f.bytes).sum();
+        let transfer_ms = per_file.iter().map(|f| f.elapsed_ms).sum();
+        TransferStats {
+            files: per_file.len(),
+            bytes,
+            elapsed_ms: elapsed.as_millis() as u64,
+            transfer_ms,
+            throughput: per_second(bytes, transfer_ms),
+            per_file,
+        }
+    }
+}
+
+fn per_second(bytes: u64, ms: u64) -> Option<u64> {
+    (bytes > 0).then(|| bytes * 1000 / ms.max(1))
+}
+
+impl HumanReadable for TransferStats {
+    fn to_human(&self) -> String {
+        let mut lines: Vec<String> = self
+            .per_file
+            .iter()
+            .map(|f| {
+                format!(
+                    "{:>8} {:>12} bytes {:>10}  {}",
+                    seconds(f.elapsed_ms),
+                    f.bytes,
+                    rate(per_second(f.bytes, f.elapsed_ms)),
+                    f.path
+                )
+            })
+            .collect();
+        lines.push(format!(
+            "{} file{}, {} bytes in {} ({} transferring, {})",
+            self.files,
+            if self.files == 1 { "" } else { "s" },
+            self.bytes,
+            seconds(self.elapsed_ms),
+            seconds(self.transfer_ms),
+            rate(self.throughput)
+        ));
+        lines.join("\n")
+    }
+}
+
+fn seconds(ms: u64) -> String {
+    format!("{:.2}s", ms as f64 / 1000.0)
+}
+
+fn rate(bytes_per_second: Option<u64>) -> String {
+    match bytes_per_second {
+        Some(rate) => format!("{:.1} MB/s", rate as f64 / 1_000_000.0),
+        None => "-".to_string(),
+    }
+}
@@ -0,0 +1,124 @@
+use crate::device::{FileEntry, Kindle};
+use crate::error::{Error, Result};
+use std::io::{self, Write};
+use tar::{EntryType, Header};
+
+const BLOCK_SIZE: u64 = 512;
+
+/// Writes a tar archive straight from the device, one member at a time, so
+/// `pull --stdout` never stages files on the local disk.
+pub struct TarStream<W: Write + Send> {
+    out: W,
+}
+
+impl<W: Write + Send> TarStream<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    pub fn append_dir(&mut self, archive_path: &str, entry: &FileEntry) -> Result<()> {
+        let path = format!("{}/", archive_path.trim_end_matches('/'));
+        self.write_header(&path, 0, entry, EntryType::Directory)?;
+        Ok(())
+    }
+
+    pub fn append_remote_file(
+        &mut self,
+        kindle: &Kindle,
+        remote: &str,
+        archive_path: &str,
+        entry: &FileEntry,
+    ) -> Result<()> {
+        self.write_header(archive_path, entry.size, entry, EntryType::Regular)?;
+
+        // The header already promised `entry.size` bytes; never write more
+        let mut remaining = entry.size;
+        let out = &mut self.out;
+        kindle.read_file(remote, |chunk| {
+            let take = (chunk.len() as u64).min(remaining) as usize;
+            remaining -= take as u64;
+            out.write_all(&chunk[..take])
+        })?;
+
+        if remaining > 0 {
+            // Keep the archive structurally valid before reporting the short read
+            write_zeros(&mut self.out, remaining)?;
+            pad_block(&mut self.out, entry.size)?;
+            return Err(Error::TransferFailed(format!(
+                "{}: device returned {} fewer bytes than reported",
+                remote, remaining
+            )));
+        }
+
+        pad_block(&mut self.out, entry.size)?;
+        Ok(())
+    }
+
+    /// Writes the end-of-archive marker and flushes.
+    pub fn finish(mut self) -> Result<()> {
+        write_zeros(&mut self.out, BLOCK_SIZE * 2)?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn write_header(
+        &mut self,
+        path: &str,
+        size: u64,
+        entry: &FileEntry,
+        kind: EntryType,
+    ) -> io::Result<()> {
+        let mut header = Header::new_gnu();
+        if header.set_path(path).is_err() {
+            // GNU long-name extension: a pseudo-entry carrying the full path,
+            // followed by the real header with a truncated name
+            let mut long = Header::new_gnu();
+            long.set_path("././@LongLink")?;
+            long.set_entry_type(EntryType::GNULongName);
+            long.set_mode(0o644);
+            long.set_size(path.len() as u64 + 1);
+            long.set_cksum();
+            self.out.write_all(long.as_bytes())?;
+            self.out.write_all(path.as_bytes())?;
+            self.out.write_all(&[0])?;
+            pad_block(&mut self.out, path.len() as u64 + 1)?;
+
+            header.set_path(truncate_utf8(path, 99))?;
+        }
+        header.set_entry_type(kind);
+        header.set_mode(if kind.is_dir() { 0o755 } else { 0o644 });
+        header.set_size(size);
+        header.set_mtime(entry.modified.timestamp().max(0) as u64);
+        header.set_cksum();
+        self.out.write_all(header.as_bytes())
+    }
+}
+
+fn pad_block<W: Write>(out: &mut W, written: u64) -> io::Result<()> {
+    let rem = written % BLOCK_SIZE;
+    if rem == 0 {
+        return Ok(());
+    }
+    write_zeros(out, BLOCK_SIZE - rem)
+}
+
+fn write_zeros<W: Write>(out: &mut W, mut count: u64) -> io::Result<()> {
+    let zeros = [0u8; BLOCK_SIZE as usize];
+    while count > 0 {
+        let n = count.min(BLOCK_SIZE) as usize;
+        out.write_all(&zeros[..n])?;
+        count -= n as u64;
+    }
+    Ok(())
+}
+
+fn truncate_utf8(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
@@ -0,0 +1,126 @@
+use crate::cli::{HumanReadable, Output};
+use crate::daemon::Session;
+use crate::device::TransferOptions;
+use crate::error::{Error, Result};
+use serde::Serialize;
+use schemars::JsonSchema;
+
+/// The conditions to check, as given on the command line.
+#[derive(Debug, Default)]
+pub struct Assertions {
+    pub free_space_min: Option<u64>,
+    pub exists: Vec<String>,
+    pub missing: Vec<String>,
+    pub count_max: Vec<(String, usize)>,
+    pub count_min: Vec<(String, usize)>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct AssertOutput {
+    pub passed: bool,
+    pub checks: Vec<CheckResult>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct CheckResult {
+    pub check: String,
+    pub passed: bool,
+    pub actual: String,
+}
+
+impl HumanReadable for AssertOutput {
+    fn to_human(&self) -> String {
+        self.checks
+            .iter()
+            .map(|c| {
+                format!(
+                    "{}  {} ({})",
+                    if c.passed { "ok  " } else { "FAIL" },
+                    c.check,
+                    c.actual
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Evaluates every assertion, prints the results, and fails with
+/// [`Error::AssertionFailed`] (exit code 8) if any did not hold. All checks
+/// run even after a failure so one run reports everything that is wrong.
+pub fn run_assert(output: &Output, assertions: &Assertions) -> Result<()> {
+    let session = Session::open(TransferOptions::default())?;
+    let mut checks = Vec::new();
+
+    if let Some(min) = assertions.free_space_min {
+        let free = session.info()?.1.free_bytes;
+        checks.push(CheckResult {
+            check: format!("free space >= {} bytes", min),
+            passed: free >= min,
+            actual: format!("{} bytes free", free),
+        });
+    }
+
+    for path in &assertions.exists {
+        let found = exists(&session, path)?;
+        checks.push(CheckResult {
+            check: format!("{} exists", path),
+            passed: found,
+            actual: if found { "found" } else { "not found" }.to_string(),
+        });
+    }
+
+    for path in &assertions.missing {
+        let found = exists(&session, path)?;
+        checks.push(CheckResult {
+            check: format!("{} does not exist", path),
+            passed: !found,
+            actual: if found { "found" } else { "not found" }.to_string(),
+        });
+    }
+
+    for (path, max) in &assertions.count_max {
+        let count = session.list_files(path)?.len();
+        checks.push(CheckResult {
+            check: format!("{} has at most {} entries", path, max),
+            passed: count <= *max,
+            actual: format!("{} entries", count),
+        });
+    }
+
+    for (path, min) in &assertions.count_min {
+        let count = session.list_files(path)?.len();
+        checks.push(CheckResult {
+            check: format!("{} has at least {} entries", path, min),
+            passed: count >= *min,
+            actual: format!("{} entries", count),
+        });
+    }
+
+    if checks.is_empty() {
+        return Err(Error::InvalidPath("No assertions given".to_string()));
+    }
+
+    let failed = checks.iter().filter(|c| !c.passed).count();
+    let total = checks.len();
+    output.print(&AssertOutput {
+        passed: failed == 0,
+        checks,
+    });
+
+    if failed > 0 {
+        return Err(Error::AssertionFailed(format!("{} of {} checks failed", failed, total)));
+    }
+    Ok(())
+}
+
+fn exists(session: &Session, path: &str) -> Result<bool> {
+    if path.trim_matches('/').is_empty() {
+        return Ok(true);
+    }
+    match session.stat(path) {
+        Ok(_) => Ok(true),
+        Err(Error::FileNotFound(_)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,388 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::ls::format_size;
+use crate::commands::push::upload_atomically;
+use crate::commands::space::precheck;
+use crate::commands::usage::AUDIO_EXTENSIONS;
+use crate::daemon::Session;
+use crate::device::{CancelToken, Kindle, TransferOptions, WalkEntry};
+use crate::error::{Error, Result};
+use crate::metadata::read_audio_duration;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Top-level folders audiobooks are kept in, matched ignoring case.
+const AUDIO_FOLDERS: &[&str] = &["audible", ".audible", "audiobooks", "music"];
+/// Where `audio push` puts files when the device has none of those yet.
+const DEFAULT_AUDIO_FOLDER: &str = "/audible";
+/// Bytes read per partial read while pulling; the `.part` file grows by
+/// this much at a time.
+const PULL_CHUNK: u32 = 4 * 1024 * 1024;
+/// Bytes at the end of a `.part` file compared with the device before
+/// resuming, to catch a file that changed in between.
+const RESUME_CHECK: u64 = 64 * 1024;
+
+#[derive(Serialize, JsonSchema)]
+pub struct AudioLsOutput {
+    /// Folders that were searched
+    pub folders: Vec<String>,
+    pub files: Vec<AudioFile>,
+    pub total_bytes: u64,
+    /// Sum of the durations that could be read
+    pub total_seconds: u64,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct AudioFile {
+    pub path: String,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+    /// From the MP4 header; absent for `.aa` and MP3 files, or through the
+    /// daemon
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_seconds: Option<u64>,
+}
+
+impl HumanReadable for AudioLsOutput {
+    fn to_human(&self) -> String {
+        if self.files.is_empty() {
+            return if self.folders.is_empty() {
+                "No audiobook folders on the device".to_string()
+            } else {
+                format!("No audio files in {}", self.folders.join(", "))
+            };
+        }
+        let mut lines: Vec<String> = self
+            .files
+            .iter()
+            .map(|file| {
+                format!(
+                    "{:>8}  {:>8}  {}",
+                    format_size(file.size),
+                    file.duration_seconds.map_or_else(|| "-".to_string(), format_duration),
+                    file.path
+                )
+            })
+            .collect();
+        lines.push(format!(
+            "{:>8}  {:>8}  total ({} file{})",
+            format_size(self.total_bytes),
+            format_duration(self.total_seconds),
+            self.files.len(),
+            if self.files.len() == 1 { "" } else { "s" }
+        ));
+        lines.join("\n")
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct AudioPullOutput {
+    pub remote: String,
+    pub local: String,
+    pub bytes: u64,
+    /// Bytes already there from an interrupted pull; 0 for a fresh one
+    pub resumed_from: u64,
+    /// The local file was already complete
+    pub skipped: bool,
+}
+
+impl HumanReadable for AudioPullOutput {
+    fn to_human(&self) -> String {
+        if self.skipped {
+            return format!("Skipped {} ({} is complete)", self.remote, self.local);
+        }
+        let resumed = if self.resumed_from > 0 {
+            format!(", resumed at {}", format_size(self.resumed_from))
+        } else {
+            String::new()
+        };
+        format!("Downloaded {} -> {} ({}{})", self.remote, self.local, format_size(self.bytes), resumed)
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct AudioPushOutput {
+    pub local: String,
+    pub remote: String,
+    pub bytes: u64,
+    /// A file of the same name and size was already on the device
+    pub skipped: bool,
+    pub replaced: bool,
+}
+
+impl HumanReadable for AudioPushOutput {
+    fn to_human(&self) -> String {
+        if self.skipped {
+            return format!("Skipped {} ({} is on the device)", self.local, self.remote);
+        }
+        format!(
+            "{} {} -> {} ({})",
+            if self.replaced { "Replaced" } else { "Uploaded" },
+            self.local,
+            self.remote,
+            format_size(self.bytes)
+        )
+    }
+}
+
+/// Lists the audio files in `path`, or in every audiobook folder at the top
+/// of the device, with their sizes and, for MP4 audiobooks, durations.
+pub fn run_audio_ls(output: &Output, path: Option<&str>, transfer: TransferOptions) -> Result<()> {
+    let session = Session::open(transfer)?;
+    let folders = match path {
+        Some(path) => vec![path.to_string()],
+        None => audio_folders(&session)?,
+    };
+    let kindle = match session.direct("reading audiobook durations") {
+        Ok(kindle) => Some(kindle),
+        Err(e) => {
+            output.warn(format!("{}; durations aren't shown", e));
+            None
+        }
+    };
+
+    let mut files = Vec::new();
+    for folder in &folders {
+        for item in session.walk(folder)?.into_iter().filter(is_audio) {
+            let duration_seconds = match kindle {
+                Some(kindle) => read_audio_duration(kindle, &item.path).unwrap_or_else(|e| {
+                    output.warn(format!("{}: {}", item.path, e));
+                    None
+                }),
+                None => None,
+            };
+            files.push(AudioFile {
+                path: item.path,
+                size: item.entry.size,
+                modified: item.entry.modified,
+                duration_seconds,
+            });
+        }
+    }
+    files.sort_by_cached_key(|file| file.path.to_lowercase());
+    output.print(&AudioLsOutput {
+        folders,
+        total_bytes: files.iter().map(|file| file.size).sum(),
+        total_seconds: files.iter().filter_map(|file| file.duration_seconds).sum(),
+        files,
+    });
+    Ok(())
+}
+
+/// Downloads the audio file `remote`, or every audio file in the folder
+/// `remote`, into `local`. Each goes to a `.part` file first; a pull that
+/// was interrupted carries on from where that file ends, once the last
+/// bytes it has still match the device's.
+pub fn run_audio_pull(output: &Output, remote: &str, local: &str, transfer: TransferOptions) -> Result<()> {
+    let session = Session::open(transfer)?;
+    let kindle = session.direct("audio pull")?;
+    let entry = kindle.stat(remote)?;
+    let (items, into_dir) = if entry.is_folder {
+        (kindle.walk(remote)?.into_iter().filter(is_audio).collect(), true)
+    } else {
+        let item = WalkEntry {
+            path: remote.to_string(),
+            entry,
+        };
+        (vec![item], Path::new(local).is_dir())
+    };
+    if into_dir {
+        std::fs::create_dir_all(local)?;
+    }
+
+    for item in items {
+        let dest = if into_dir {
+            Path::new(local).join(&item.entry.name)
+        } else {
+            PathBuf::from(local)
+        };
+        let dest_display = dest.display().to_string();
+        if std::fs::metadata(&dest).is_ok_and(|meta| meta.is_file() && meta.len() == item.entry.size) {
+            output.print(&AudioPullOutput {
+                remote: item.path,
+                local: dest_display,
+                bytes: item.entry.size,
+                resumed_from: 0,
+                skipped: true,
+            });
+            continue;
+        }
+        let mut resumed_from = 0;
+        output.timed(&item.path, || {
+            resumed_from = pull_resumable(output, kindle, &item.path, item.entry.size, &dest)?;
+            Ok::<_, Error>(item.entry.size - resumed_from)
+        })?;
+        output.print(&AudioPullOutput {
+            remote: item.path,
+            local: dest_display,
+            bytes: item.entry.size,
+            resumed_from,
+            skipped: false,
+        });
+    }
+    Ok(())
+}
+
+/// Uploads the local audio files `locals` into `folder`, or the device's
+/// audiobook folder. MTP has no way to append to a file on the device, so
+/// an interrupted upload starts over, but files already there at the same
+/// size are skipped unless `force`.
+pub fn run_audio_push(
+    output: &Output,
+    locals: &[String],
+    folder: Option<&str>,
+    force: bool,
+    transfer: TransferOptions,
+) -> Result<()> {
+    let mut sizes = Vec::with_capacity(locals.len());
+    for local in locals {
+        let meta = std::fs::metadata(local).map_err(|_| Error::FileNotFound(local.clone()))?;
+        if !meta.is_file() {
+            return Err(Error::InvalidPath(format!("'{}' is not a file", local)));
+        }
+        if !has_audio_extension(local) {
+            output.warn(format!("{} isn't an audiobook or audio file the Kindle plays", local));
+        }
+        sizes.push(meta.len());
+    }
+
+    let session = Session::open(transfer)?;
+    let kindle = session.direct("audio push")?;
+    let folder = match folder {
+        Some(folder) => folder.trim_end_matches('/').to_string(),
+        None => audio_folders(&session)?
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| DEFAULT_AUDIO_FOLDER.to_string()),
+    };
+    kindle.create_folder_all(&folder)?;
+
+    let mut uploads = Vec::new();
+    for (local, size) in locals.iter().zip(sizes) {
+        let name = Path::new(local).file_name().and_then(|n| n.to_str()).unwrap_or(local);
+        let remote = format!("{}/{}", folder, name);
+        let existing = match kindle.stat(&remote) {
+            Ok(entry) if entry.is_folder => {
+                return Err(Error::InvalidPath(format!("'{}' exists on the device and is a directory", remote)));
+            }
+            Ok(entry) => Some(entry.size),
+            Err(Error::FileNotFound(_)) => None,
+            Err(e) => return Err(e),
+        };
+        if existing == Some(size) && !force {
+            output.print(&AudioPushOutput {
+                local: local.clone(),
+                remote,
+                bytes: size,
+                skipped: true,
+                replaced: false,
+            });
+            continue;
+        }
+        uploads.push((local, remote, size, existing.is_some()));
+    }
+    let needed = uploads.iter().map(|(_, _, size, _)| size).sum();
+    precheck(output, kindle.storage_info()?.free_bytes, needed, uploads.len(), false)?;
+
+    for (local, remote, size, replace) in uploads {
+        output.info(format!("Uploading {} ({})", local, format_size(size)));
+        upload_atomically(&session, &remote, replace, |path| {
+            output.timed(&remote, || session.upload_file(Path::new(local), path).map(|()| size))?;
+            Ok(())
+        })?;
+        output.print(&AudioPushOutput {
+            local: local.clone(),
+            remote,
+            bytes: size,
+            skipped: false,
+            replaced: replace,
+        });
+    }
+    Ok(())
+}
+
+/// Downloads `remote` to `dest` through `dest.part` with partial reads,
+/// picking up an existing `.part` file. Returns the bytes it already had.
+fn pull_resumable(output: &Output, kindle: &Kindle, remote: &str, size: u64, dest: &Path) -> Result<u64> {
+    let mut part_name = dest.as_os_str().to_owned();
+    part_name.push(".part");
+    let part_path = PathBuf::from(part_name);
+    let mut part = OpenOptions::new().read(true).append(true).create(true).open(&part_path)?;
+
+    let mut offset = part.metadata()?.len();
+    if offset > 0 && !part_matches(kindle, remote, size, &mut part, offset)? {
+        output.warn(format!("{} changed on the device since it was partly pulled; starting over", remote));
+        part.set_len(0)?;
+        offset = 0;
+    }
+    let resumed_from = offset;
+    if resumed_from > 0 {
+        output.info(format!(
+            "Resuming {} at {} of {}",
+            remote,
+            format_size(resumed_from),
+            format_size(size)
+        ));
+    }
+
+    let cancel = Kindle::default_cancel_token();
+    while offset < size {
+        if cancel.is_some_and(CancelToken::is_cancelled) {
+            return Err(Error::Cancelled);
+        }
+        let len = u32::try_from(size - offset).unwrap_or(u32::MAX).min(PULL_CHUNK);
+        let data = kindle.read_range(remote, offset, len)?;
+        if data.is_empty() {
+            return Err(Error::TransferFailed(format!(
+                "{} ended at {} bytes, not the {} the device lists",
+                remote, offset, size
+            )));
+        }
+        part.write_all(&data)?;
+        offset += data.len() as u64;
+    }
+    part.sync_all()?;
+    drop(part);
+    std::fs::rename(&part_path, dest)?;
+    Ok(resumed_from)
+}
+
+/// Whether the `len` bytes of `part` can be continued from: no longer than
+/// the file on the device, and ending with the same bytes it has there.
+fn part_matches(kindle: &Kindle, remote: &str, size: u64, part: &mut File, len: u64) -> Result<bool> {
+    if len > size {
+        return Ok(false);
+    }
+    let check = len.min(RESUME_CHECK);
+    let mut local = Vec::with_capacity(check as usize);
+    part.seek(SeekFrom::Start(len - check))?;
+    Read::by_ref(part).take(check).read_to_end(&mut local)?;
+    Ok(kindle.read_range(remote, len - check, check as u32)? == local)
+}
+
+/// The audiobook folders at the top of the device, in the order listed.
+fn audio_folders(session: &Session) -> Result<Vec<String>> {
+    Ok(session
+        .list_files("/")?
+        .into_iter()
+        .filter(|entry| entry.is_folder && AUDIO_FOLDERS.iter().any(|f| f.eq_ignore_ascii_case(&entry.name)))
+        .map(|entry| format!("/{}", entry.name))
+        .collect())
+}
+
+fn is_audio(item: &WalkEntry) -> bool {
+    !item.entry.is_folder && has_audio_extension(&item.entry.name)
+}
+
+fn has_audio_extension(name: &str) -> bool {
+    name.rsplit_once('.')
+        .is_some_and(|(_, extension)| AUDIO_EXTENSIONS.iter().any(|a| a.eq_ignore_ascii_case(extension)))
+}
+
+/// `h:mm:ss`, e.g. `12:04:51` for a long audiobook.
+fn format_duration(seconds: u64) -> String {
+    format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
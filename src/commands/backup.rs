@@ -0,0 +1,278 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::books::is_sidecar_path;
+use crate::commands::filter::PathFilter;
+use crate::commands::safe_path::{join_under, prepare_under};
+use crate::commands::sync::{download_preserving_mtime, normalize_remote_dir};
+use crate::commands::writers::{with_writers, FileJob, POOLED_FILE_MAX};
+use crate::device::{Kindle, TransferOptions};
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+pub(crate) const MANIFEST_FILE: &str = "manifest.json";
+pub(crate) const FILES_DIR: &str = "files";
+const MANIFEST_VERSION: u32 = 1;
+
+/// Describes one backup snapshot: `manifest.json` plus a `files/` tree that
+/// mirrors the device paths listed here. Snapshots live side by side in the
+/// backup directory, named by UTC timestamp so they sort chronologically.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    pub version: u32,
+    pub created: DateTime<Utc>,
+    pub serial: String,
+    pub model: String,
+    pub root: String,
+    pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub is_folder: bool,
+    pub modified: DateTime<Utc>,
+}
+
+impl Manifest {
+    pub fn load(backup_dir: &Path) -> Result<Self> {
+        let path = backup_dir.join(MANIFEST_FILE);
+        let data = std::fs::read(&path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => Error::FileNotFound(path.display().to_string()),
+            _ => Error::Io(e),
+        })?;
+        let manifest: Manifest = serde_json::from_slice(&data)
+            .map_err(|e| Error::InvalidPath(format!("{}: {}", path.display(), e)))?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(Error::InvalidPath(format!(
+                "{}: unsupported manifest version {}",
+                path.display(),
+                manifest.version
+            )));
+        }
+        Ok(manifest)
+    }
+
+    fn save(&self, backup_dir: &Path) -> Result<()> {
+        let data = serde_json::to_vec_pretty(self)
+            .map_err(|e| Error::Io(std::io::Error::other(e)))?;
+        std::fs::write(backup_dir.join(MANIFEST_FILE), data)?;
+        Ok(())
+    }
+}
+
+/// Finds the snapshot to read from: `dir` itself if it holds a manifest,
+/// otherwise the newest snapshot inside it.
+pub(crate) fn resolve_snapshot(dir: &Path) -> Result<PathBuf> {
+    if dir.join(MANIFEST_FILE).is_file() {
+        return Ok(dir.to_path_buf());
+    }
+    latest_snapshot(dir)?.ok_or_else(|| {
+        Error::FileNotFound(format!("no backup snapshots in {}", dir.display()))
+    })
+}
+
+fn latest_snapshot(dir: &Path) -> Result<Option<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+    let mut snapshots: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.join(MANIFEST_FILE).is_file())
+        .collect();
+    snapshots.sort();
+    Ok(snapshots.pop())
+}
+
+/// Local location of a device path inside a backup snapshot. Fails for paths
+/// that would land outside the snapshot's `files/` tree.
+pub(crate) fn local_file_path(backup_dir: &Path, remote: &str) -> Result<PathBuf> {
+    join_under(&backup_dir.join(FILES_DIR), remote)
+}
+
+/// NDJSON event for one file stored in the snapshot.
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct BackupEvent<'a> {
+    pub path: &'a str,
+    pub bytes: u64,
+    /// Hard-linked from the previous snapshot instead of downloaded
+    pub linked: bool,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct BackupOutput {
+    pub root: String,
+    pub snapshot: String,
+    pub previous: Option<String>,
+    pub files: usize,
+    pub linked: usize,
+    pub folders: usize,
+    pub bytes: u64,
+}
+
+impl HumanReadable for BackupOutput {
+    fn to_human(&self) -> String {
+        format!(
+            "Backed up {} -> {} ({} files transferred, {} unchanged, {} folders, {} bytes)",
+            self.root, self.snapshot, self.files, self.linked, self.folders, self.bytes
+        )
+    }
+}
+
+/// Writes a new snapshot into `backup_dir`. Unless `full` is set, files whose
+/// size and timestamp match the previous snapshot's manifest are hardlinked
+/// from it instead of transferred again. With `skip_sdr`, `.sdr` folders and
+/// sidecar files are left out of the snapshot, as is whatever `filter`
+/// doesn't take.
+pub fn run_backup(
+    output: &Output,
+    backup_dir: &str,
+    root: &str,
+    full: bool,
+    skip_sdr: bool,
+    filter: &PathFilter,
+    transfer: TransferOptions,
+) -> Result<()> {
+    let backup_root = Path::new(backup_dir);
+    let previous = if full {
+        None
+    } else {
+        match latest_snapshot(backup_root)? {
+            Some(dir) => Some((Manifest::load(&dir)?, dir)),
+            None => None,
+        }
+    };
+
+    let kindle = Kindle::open(transfer)?;
+    let info = kindle.info();
+
+    let root = normalize_remote_dir(root);
+    let created = Utc::now();
+    let snapshot = backup_root.join(created.format("%Y%m%dT%H%M%SZ").to_string());
+    std::fs::create_dir_all(snapshot.join(FILES_DIR))?;
+
+    // Only reuse files from a snapshot of the same device
+    let previous = previous.filter(|(manifest, _)| manifest.serial == info.serial);
+    let previous_entries: HashMap<&str, &ManifestEntry> = previous
+        .as_ref()
+        .map(|(manifest, _)| {
+            manifest
+                .entries
+                .iter()
+                .filter(|e| !e.is_folder)
+                .map(|e| (e.path.as_str(), e))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut manifest = Manifest {
+        version: MANIFEST_VERSION,
+        created,
+        serial: info.serial.clone(),
+        model: info.model,
+        root: root.clone(),
+        entries: Vec::new(),
+    };
+    let mut backup_output = BackupOutput {
+        root,
+        snapshot: snapshot.display().to_string(),
+        previous: previous.as_ref().map(|(_, dir)| dir.display().to_string()),
+        files: 0,
+        linked: 0,
+        folders: 0,
+        bytes: 0,
+    };
+
+    let files_root = snapshot.join(FILES_DIR);
+    let walk_root = manifest.root.clone();
+    // Files are fetched as their folder is listed, small ones handed to
+    // writer threads so the device never waits for the disk
+    with_writers(kindle.transfer_options().io_threads, |writers| {
+        kindle.walk_each(&walk_root, |item| {
+            if skip_sdr && is_sidecar_path(&item.path, item.entry.is_folder) {
+                return Ok(());
+            }
+            // Folders holding kept files are still created for them below
+            let relative = item.path.strip_prefix(walk_root.as_str()).unwrap_or(&item.path).trim_start_matches('/');
+            let taken = if item.entry.is_folder { filter.keeps_folder(relative) } else { filter.keeps(relative) };
+            if !taken {
+                return Ok(());
+            }
+            let local_path = match local_file_path(&snapshot, &item.path)
+                .and_then(|p| prepare_under(&files_root, &p).map(|_| p))
+            {
+                Ok(p) => p,
+                Err(e) => {
+                    output.warn(format!("skipped: {}", e.display_chain()));
+                    return Ok(());
+                }
+            };
+            if item.entry.is_folder {
+                std::fs::create_dir_all(&local_path)?;
+                backup_output.folders += 1;
+            } else {
+                let unchanged = previous_entries
+                    .get(item.path.as_str())
+                    .is_some_and(|e| e.size == item.entry.size && e.modified == item.entry.modified);
+                let linked = unchanged
+                    && previous.as_ref().is_some_and(|(_, dir)| {
+                        local_file_path(dir, &item.path)
+                            .is_ok_and(|src| std::fs::hard_link(src, &local_path).is_ok())
+                    });
+
+                if linked {
+                    backup_output.linked += 1;
+                } else {
+                    let downloaded = if item.entry.size <= POOLED_FILE_MAX {
+                        let mut data = Vec::with_capacity(item.entry.size as usize);
+                        kindle.download_to_writer(&item.path, &mut data).map(|_| Some(data))
+                    } else {
+                        download_preserving_mtime(&kindle, &item.path, &local_path, &item.entry).map(|()| None)
+                    };
+                    match downloaded {
+                        Ok(Some(data)) => writers.write(FileJob {
+                            path: local_path,
+                            data,
+                            modified: Some(item.entry.modified),
+                        })?,
+                        Ok(None) => {}
+                        // One unreadable object (often a sidecar being rewritten
+                        // by the reader) shouldn't abort a whole-device backup
+                        Err(e) => {
+                            output.warn(format!("skipped: {}", e.display_chain()));
+                            let _ = std::fs::remove_file(&local_path);
+                            return Ok(());
+                        }
+                    }
+                    backup_output.files += 1;
+                    backup_output.bytes += item.entry.size;
+                }
+                output.event(
+                    "file",
+                    &BackupEvent {
+                        path: &item.path,
+                        bytes: item.entry.size,
+                        linked,
+                    },
+                );
+            }
+            manifest.entries.push(ManifestEntry {
+                path: item.path,
+                size: item.entry.size,
+                is_folder: item.entry.is_folder,
+                modified: item.entry.modified,
+            });
+            Ok(())
+        })
+    })?;
+
+    // Written last so an interrupted backup is never picked as the base of
+    // the next incremental run
+    manifest.save(&snapshot)?;
+
+    output.print(&backup_output);
+    Ok(())
+}
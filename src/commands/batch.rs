@@ -0,0 +1,148 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::shell::{execute, resolve, ShellCommand, ShellLine};
+use crate::config::Config;
+use crate::daemon::Session;
+use crate::device::TransferOptions;
+use crate::error::{Error, Result};
+use clap::Parser;
+use serde::Serialize;
+use serde_json::Value;
+use schemars::JsonSchema;
+use std::io::Read;
+
+#[derive(Serialize, JsonSchema)]
+pub struct BatchOutput {
+    pub script: String,
+    pub succeeded: usize,
+    pub failed: usize,
+    /// Commands not run because an earlier one failed
+    pub skipped: usize,
+    pub steps: Vec<BatchStep>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct BatchStep {
+    pub line: usize,
+    pub command: String,
+    pub ok: bool,
+    /// The command's own JSON output (`--json` only)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub results: Vec<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl HumanReadable for BatchOutput {
+    fn to_human(&self) -> String {
+        let mut summary = format!(
+            "{} commands: {} succeeded, {} failed",
+            self.succeeded + self.failed + self.skipped,
+            self.succeeded,
+            self.failed
+        );
+        if self.skipped > 0 {
+            summary.push_str(&format!(", {} skipped", self.skipped));
+        }
+        for step in self.steps.iter().filter(|s| !s.ok) {
+            summary.push_str(&format!(
+                "\n  line {}: {}: {}",
+                step.line,
+                step.command,
+                step.error.as_deref().unwrap_or("failed")
+            ));
+        }
+        summary
+    }
+}
+
+/// Runs the shell commands in `script` (`-` for stdin) over one device
+/// connection. The whole script is parsed before the device is opened, so a
+/// typo on the last line doesn't leave the first ones half-done. A failing
+/// command stops the script unless `keep_going`; either way the run fails
+/// with the first error after the report is printed.
+pub fn run_batch(
+    output: &Output,
+    script: &str,
+    keep_going: bool,
+    config: &Config,
+    transfer: TransferOptions,
+) -> Result<()> {
+    let text = if script == "-" {
+        let mut text = String::new();
+        std::io::stdin().read_to_string(&mut text)?;
+        text
+    } else {
+        std::fs::read_to_string(script).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => Error::FileNotFound(script.to_string()),
+            _ => Error::Io(e),
+        })?
+    };
+
+    let mut commands = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parse_error =
+            |message: String| Error::InvalidPath(format!("{} line {}: {}", script, i + 1, message));
+        let words = shell_words::split(line).map_err(|e| parse_error(e.to_string()))?;
+        let parsed = ShellLine::try_parse_from(words).map_err(|e| {
+            let message = e.to_string();
+            parse_error(message.lines().next().unwrap_or_default().to_string())
+        })?;
+        commands.push((i + 1, line.to_string(), parsed.command));
+    }
+
+    let session = Session::open(transfer)?;
+    let capture = output.is_json().then(|| output.capturing());
+    let step_output = capture.as_ref().unwrap_or(output);
+
+    let mut cwd = resolve("/", config.remote_root().unwrap_or("/"));
+    let mut steps = Vec::new();
+    let mut first_error = None;
+    let mut skipped = 0;
+    for (line, command_text, command) in commands {
+        if matches!(command, ShellCommand::Exit) {
+            break;
+        }
+        if first_error.is_some() && !keep_going {
+            skipped += 1;
+            continue;
+        }
+
+        let result = execute(step_output, &session, &mut cwd, command, config);
+        step_output.finish();
+        let error = result.as_ref().err().map(Error::display_chain);
+        steps.push(BatchStep {
+            line,
+            command: command_text.clone(),
+            ok: result.is_ok(),
+            results: step_output.take_captured(),
+            error,
+        });
+        if let Err(e) = result
+            && first_error.is_none()
+        {
+            first_error = Some(Error::Operation {
+                operation: "run",
+                path: format!("{} line {} ({})", script, line, command_text),
+                source: Box::new(e),
+            });
+        }
+    }
+
+    let failed = steps.iter().filter(|s| !s.ok).count();
+    output.print(&BatchOutput {
+        script: script.to_string(),
+        succeeded: steps.len() - failed,
+        failed,
+        skipped,
+        steps,
+    });
+
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,198 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::ls::format_size;
+use crate::commands::space::precheck;
+use crate::commands::sync::join_remote;
+use crate::device::{Kindle, TransferOptions};
+use crate::error::{Error, Result};
+use serde::Serialize;
+use schemars::JsonSchema;
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+/// Folder listings timed for the latency figure.
+const LATENCY_SAMPLES: usize = 20;
+
+#[derive(Serialize, JsonSchema)]
+pub struct BenchOutput {
+    /// Device folder the temporary file was written to
+    pub dir: String,
+    /// Size of the temporary file
+    pub bytes: u64,
+    pub rounds: u32,
+    pub write: Throughput,
+    pub read: Throughput,
+    /// Round trip of listing a folder with one file in it
+    pub latency: Latency,
+}
+
+/// Bytes per second over `rounds` transfers of the same file.
+#[derive(Serialize, JsonSchema)]
+pub struct Throughput {
+    pub mean: u64,
+    pub best: u64,
+    pub worst: u64,
+    /// Each round's time
+    pub round_ms: Vec<u64>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct Latency {
+    pub samples: usize,
+    pub min_us: u64,
+    pub median_us: u64,
+    pub max_us: u64,
+}
+
+impl HumanReadable for BenchOutput {
+    fn to_human(&self) -> String {
+        let rate = |bytes_per_second: u64| format!("{}/s", format_size(bytes_per_second));
+        let ms = |us: u64| format!("{:.1} ms", us as f64 / 1000.0);
+        [
+            format!("{} x {} in {}", format_size(self.bytes), self.rounds, self.dir),
+            format!(
+                "Write:   {} (best {}, worst {})",
+                rate(self.write.mean),
+                rate(self.write.best),
+                rate(self.write.worst)
+            ),
+            format!(
+                "Read:    {} (best {}, worst {})",
+                rate(self.read.mean),
+                rate(self.read.best),
+                rate(self.read.worst)
+            ),
+            format!(
+                "Latency: {} median ({} to {}) per folder listing",
+                ms(self.latency.median_us),
+                ms(self.latency.min_us),
+                ms(self.latency.max_us)
+            ),
+        ]
+        .join("\n")
+    }
+}
+
+/// Measures this host, cable and device: uploads a `bytes`-long file of
+/// random data into a temporary folder under `dir` and reads it back,
+/// `rounds` times, then times folder listings for latency. The folder is
+/// removed afterwards, also when a round fails. `--limit-rate` is ignored
+/// so the numbers show what the connection can do.
+pub fn run_bench(output: &Output, dir: &str, bytes: u64, rounds: u32, transfer: TransferOptions) -> Result<()> {
+    if bytes == 0 || rounds == 0 {
+        return Err(Error::InvalidPath("--size and --rounds must be above zero".to_string()));
+    }
+    let kindle = Kindle::open(TransferOptions {
+        rate_limit: None,
+        ..transfer
+    })?;
+    precheck(output, kindle.storage_info()?.free_bytes, bytes, 1, false)?;
+
+    let folder = join_remote(dir, &format!(".kindle-mtp-bench-{}", std::process::id()));
+    let file = join_remote(&folder, "bench.bin");
+    kindle.create_folder_all(&folder)?;
+    let result = bench(&kindle, &folder, &file, bytes, rounds);
+    // A failed first upload may not have created the file
+    let cleanup = match kindle.stat(&file) {
+        Ok(_) => kindle.delete(&file),
+        Err(_) => Ok(()),
+    }
+    .and_then(|()| kindle.delete(&folder));
+    let (write, read, latency) = result?;
+    cleanup?;
+
+    output.print(&BenchOutput {
+        dir: dir.to_string(),
+        bytes,
+        rounds,
+        write: throughput(bytes, &write),
+        read: throughput(bytes, &read),
+        latency,
+    });
+    Ok(())
+}
+
+/// Write and read times of every round, and the listing latency.
+fn bench(
+    kindle: &Kindle,
+    folder: &str,
+    file: &str,
+    bytes: u64,
+    rounds: u32,
+) -> Result<(Vec<Duration>, Vec<Duration>, Latency)> {
+    let (mut write, mut read) = (Vec::new(), Vec::new());
+    for round in 0..rounds {
+        if round > 0 {
+            kindle.delete(file)?;
+        }
+        let mut data = RandomData::new(bytes, round);
+        let started = Instant::now();
+        kindle.upload_from_reader(file, &mut data, bytes)?;
+        write.push(started.elapsed());
+
+        let started = Instant::now();
+        let received = kindle.read_file(file, |_| Ok(()))?;
+        read.push(started.elapsed());
+        if received != bytes {
+            return Err(Error::VerificationFailed(format!(
+                "read back {} of {} bytes",
+                received, bytes
+            )));
+        }
+    }
+
+    let mut samples: Vec<u64> = Vec::with_capacity(LATENCY_SAMPLES);
+    for _ in 0..LATENCY_SAMPLES {
+        let started = Instant::now();
+        kindle.list_files(folder)?;
+        samples.push(started.elapsed().as_micros() as u64);
+    }
+    samples.sort_unstable();
+    let latency = Latency {
+        samples: samples.len(),
+        min_us: samples[0],
+        median_us: samples[samples.len() / 2],
+        max_us: samples[samples.len() - 1],
+    };
+    Ok((write, read, latency))
+}
+
+fn throughput(bytes: u64, rounds: &[Duration]) -> Throughput {
+    let rate = |elapsed: &Duration| (bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON)) as u64;
+    let total: Duration = rounds.iter().sum();
+    Throughput {
+        mean: (bytes as f64 * rounds.len() as f64 / total.as_secs_f64().max(f64::EPSILON)) as u64,
+        best: rounds.iter().map(rate).max().unwrap_or(0),
+        worst: rounds.iter().map(rate).min().unwrap_or(0),
+        round_ms: rounds.iter().map(|d| d.as_millis() as u64).collect(),
+    }
+}
+
+/// `len` bytes of xorshift output, so nothing on the way can compress or
+/// deduplicate the upload.
+struct RandomData {
+    state: u64,
+    remaining: u64,
+}
+
+impl RandomData {
+    fn new(len: u64, seed: u32) -> Self {
+        Self {
+            state: 0x9E37_79B9_7F4A_7C15 ^ u64::from(seed),
+            remaining: len,
+        }
+    }
+}
+
+impl Read for RandomData {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.remaining as usize);
+        for chunk in buf[..n].chunks_mut(8) {
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 7;
+            self.state ^= self.state << 17;
+            chunk.copy_from_slice(&self.state.to_le_bytes()[..chunk.len()]);
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
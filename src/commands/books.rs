@@ -0,0 +1,350 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::ls::format_size;
+use crate::daemon::Session;
+use crate::device::{Kindle, TransferOptions, WalkEntry};
+use crate::error::Result;
+use crate::metadata::read_metadata;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// Files the Kindle keeps next to a book, by extension: page numbers,
+/// last position, annotations and highlights of older firmware.
+const SIDECAR_EXTENSIONS: &[&str] = &[
+    "apnx", "azw3f", "azw3r", "mbp", "mbp1", "mbs", "pdr", "tan", "han", "ea", "phl",
+];
+/// Widest title and author columns in the human table.
+const TITLE_WIDTH: usize = 40;
+const AUTHOR_WIDTH: usize = 24;
+
+/// A book's file type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BookFormat {
+    Azw3,
+    /// `.azw`, `.azw4` and `.prc`: Mobipocket with Amazon's extensions
+    Azw,
+    Kfx,
+    Mobi,
+    Pdf,
+    Epub,
+    Txt,
+}
+
+impl BookFormat {
+    pub(crate) fn from_extension(extension: &str) -> Option<Self> {
+        Some(match extension.to_ascii_lowercase().as_str() {
+            "azw3" => Self::Azw3,
+            "azw" | "azw4" | "prc" => Self::Azw,
+            "kfx" => Self::Kfx,
+            "mobi" => Self::Mobi,
+            "pdf" => Self::Pdf,
+            "epub" => Self::Epub,
+            "txt" => Self::Txt,
+            _ => return None,
+        })
+    }
+
+    /// The media type OPDS feeds and downloads announce the format with.
+    pub(crate) fn mime_type(self) -> &'static str {
+        match self {
+            Self::Azw3 => "application/x-mobi8-ebook",
+            Self::Azw => "application/vnd.amazon.ebook",
+            Self::Kfx => "application/octet-stream",
+            Self::Mobi => "application/x-mobipocket-ebook",
+            Self::Pdf => "application/pdf",
+            Self::Epub => "application/epub+zip",
+            Self::Txt => "text/plain; charset=utf-8",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Azw3 => "AZW3",
+            Self::Azw => "AZW",
+            Self::Kfx => "KFX",
+            Self::Mobi => "MOBI",
+            Self::Pdf => "PDF",
+            Self::Epub => "EPUB",
+            Self::Txt => "TXT",
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct BooksOutput {
+    pub path: String,
+    pub books: Vec<Book>,
+    /// Paths of books that share an ASIN, or a title and author
+    pub duplicates: Vec<Vec<String>>,
+}
+
+#[derive(Clone, Serialize, JsonSchema)]
+pub struct Book {
+    pub path: String,
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// Amazon's ID, for books bought from the store
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asin: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    pub format: BookFormat,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+    /// The `.sdr` folder and other files the Kindle keeps for the book
+    pub sidecars: Vec<String>,
+    /// Bytes in `sidecars`, `.sdr` contents included
+    pub sidecar_bytes: u64,
+}
+
+impl HumanReadable for BooksOutput {
+    fn to_human(&self) -> String {
+        if self.books.is_empty() {
+            return format!("No books in {}", self.path);
+        }
+        let title_width = column_width(self.books.iter().map(|b| b.title.as_str()), "TITLE", TITLE_WIDTH);
+        let author_width = column_width(
+            self.books.iter().map(|b| b.author.as_deref().unwrap_or("-")),
+            "AUTHOR",
+            AUTHOR_WIDTH,
+        );
+        let mut lines = vec![format!(
+            "{:<tw$}  {:<aw$}  {:<6}  {:>8}  {:>8}",
+            "TITLE",
+            "AUTHOR",
+            "FORMAT",
+            "SIZE",
+            "SIDECARS",
+            tw = title_width,
+            aw = author_width
+        )];
+        for book in &self.books {
+            lines.push(format!(
+                "{:<tw$}  {:<aw$}  {:<6}  {:>8}  {:>8}",
+                truncate(&book.title, title_width),
+                truncate(book.author.as_deref().unwrap_or("-"), author_width),
+                book.format.label(),
+                format_size(book.size),
+                if book.sidecars.is_empty() {
+                    "-".to_string()
+                } else {
+                    format_size(book.sidecar_bytes)
+                },
+                tw = title_width,
+                aw = author_width
+            ));
+        }
+        let total: u64 = self.books.iter().map(|b| b.size + b.sidecar_bytes).sum();
+        lines.push(format!(
+            "{} book{}, {}",
+            self.books.len(),
+            if self.books.len() == 1 { "" } else { "s" },
+            format_size(total)
+        ));
+        if !self.duplicates.is_empty() {
+            lines.push(String::new());
+            lines.push("Possible duplicates:".to_string());
+            for group in &self.duplicates {
+                lines.push(format!("  {}", group.join(", ")));
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+/// Lists the books under `path` (normally `/documents`) with their sidecar
+/// files, title and author. Those come from each book's header unless
+/// `from_names`, or the daemon holds the device; then the file names are
+/// all there is.
+pub fn run_books(output: &Output, path: &str, from_names: bool) -> Result<()> {
+    let session = Session::open(TransferOptions::default())?;
+    let entries = session.walk(path)?;
+    let mut books = books(&entries);
+    if !from_names {
+        match session.direct("reading book headers") {
+            Ok(kindle) => read_headers(output, kindle, &mut books),
+            Err(e) => output.warn(format!("{}; titles are taken from file names", e)),
+        }
+        books.sort_by_cached_key(|b| (b.title.to_lowercase(), b.path.clone()));
+    }
+    output.print(&BooksOutput {
+        path: path.to_string(),
+        duplicates: duplicates(&books),
+        books,
+    });
+    Ok(())
+}
+
+/// Replaces what the file names said with each book's header, where it
+/// has one. A book that can't be read keeps its name-based details.
+pub(crate) fn read_headers(output: &Output, kindle: &Kindle, books: &mut [Book]) {
+    for book in books {
+        match read_metadata(kindle, &book.path) {
+            Ok(Some(metadata)) => {
+                if !metadata.title.is_empty() {
+                    book.title = metadata.title;
+                }
+                book.author = metadata.author.or_else(|| book.author.take());
+                book.asin = metadata.asin.or_else(|| book.asin.take());
+                book.language = metadata.language;
+            }
+            Ok(None) => {}
+            Err(e) => output.warn(format!("{}: {}", book.path, e)),
+        }
+    }
+}
+
+/// Groups of books with the same ASIN, or failing that the same title and
+/// author, ignoring case.
+fn duplicates(books: &[Book]) -> Vec<Vec<String>> {
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for book in books {
+        let key = match &book.asin {
+            Some(asin) => format!("asin:{}", asin),
+            None => format!(
+                "title:{}\0{}",
+                book.title.to_lowercase(),
+                book.author.as_deref().unwrap_or_default().to_lowercase()
+            ),
+        };
+        groups.entry(key).or_default().push(book.path.clone());
+    }
+    groups.into_values().filter(|paths| paths.len() > 1).collect()
+}
+
+/// Sidecar bytes and paths by the folder and file stem of the book they
+/// belong to.
+pub(crate) type SidecarGroups<'a> = HashMap<(&'a str, &'a str), (u64, Vec<String>)>;
+
+/// Groups `entries` into books, attaching each `.sdr` folder and sidecar
+/// file to the book of the same name in the same folder. Sorted by title.
+pub(crate) fn books(entries: &[WalkEntry]) -> Vec<Book> {
+    let mut sidecars = sidecar_groups(entries);
+    let mut books: Vec<Book> = entries
+        .iter()
+        .filter(|item| !item.entry.is_folder)
+        .filter_map(|item| {
+            let (folder, name) = split_path(&item.path);
+            if enclosing_sdr(&item.path).is_some() {
+                return None;
+            }
+            let (stem, extension) = name.rsplit_once('.')?;
+            let format = BookFormat::from_extension(extension)?;
+            let (sidecar_bytes, sidecars) = sidecars.remove(&(folder, stem)).unwrap_or_default();
+            let (title, author, asin) = parse_name(stem);
+            Some(Book {
+                path: item.path.clone(),
+                title,
+                author,
+                asin,
+                language: None,
+                format,
+                size: item.entry.size,
+                modified: item.entry.modified,
+                sidecars,
+                sidecar_bytes,
+            })
+        })
+        .collect();
+    books.sort_by_cached_key(|b| (b.title.to_lowercase(), b.path.clone()));
+    books
+}
+
+/// Every `.sdr` folder and sidecar file in `entries`, grouped by the book
+/// they belong to, whether that book is there or not.
+pub(crate) fn sidecar_groups(entries: &[WalkEntry]) -> SidecarGroups<'_> {
+    let mut groups = SidecarGroups::new();
+    let mut sdr_sizes: BTreeMap<&str, u64> = BTreeMap::new();
+    for item in entries.iter().filter(|item| !item.entry.is_folder) {
+        if let Some(sdr) = enclosing_sdr(&item.path) {
+            *sdr_sizes.entry(sdr).or_default() += item.entry.size;
+        }
+    }
+    for item in entries {
+        let (folder, name) = split_path(&item.path);
+        if enclosing_sdr(&item.path).is_some() {
+            continue;
+        }
+        let sidecar = match name.rsplit_once('.') {
+            Some((stem, "sdr")) if item.entry.is_folder => {
+                Some((stem, sdr_sizes.get(item.path.as_str()).copied().unwrap_or(0)))
+            }
+            Some((stem, extension)) if !item.entry.is_folder && is_sidecar(extension) => {
+                Some((stem, item.entry.size))
+            }
+            _ => None,
+        };
+        if let Some((stem, bytes)) = sidecar {
+            let group = groups.entry((folder, stem)).or_default();
+            group.0 += bytes;
+            group.1.push(item.path.clone());
+        }
+    }
+    groups
+}
+
+/// Whether `extension` marks a file the Kindle keeps next to a book.
+pub(crate) fn is_sidecar(extension: &str) -> bool {
+    SIDECAR_EXTENSIONS.iter().any(|s| s.eq_ignore_ascii_case(extension))
+}
+
+/// Whether `path` is sidecar data: a `.sdr` folder, anything inside one,
+/// or a sidecar file next to a book. Used by `--skip-sdr`.
+pub(crate) fn is_sidecar_path(path: &str, is_folder: bool) -> bool {
+    if enclosing_sdr(path).is_some() {
+        return true;
+    }
+    match split_path(path).1.rsplit_once('.') {
+        Some((_, extension)) if is_folder => extension.eq_ignore_ascii_case("sdr"),
+        Some((_, extension)) => is_sidecar(extension),
+        None => false,
+    }
+}
+
+/// The `.sdr` folder `path` is inside of, if any.
+pub(crate) fn enclosing_sdr(path: &str) -> Option<&str> {
+    let end = path.find(".sdr/").map(|i| i + 4)?;
+    Some(&path[..end])
+}
+
+pub(crate) fn split_path(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+/// Title, author and ASIN from a book's file name. Books from Amazon are
+/// named `Title_ASIN`, calibre names them `Title - Author`.
+pub(crate) fn parse_name(stem: &str) -> (String, Option<String>, Option<String>) {
+    let (stem, asin) = match stem.rsplit_once('_') {
+        Some((title, asin)) if is_asin(asin) => (title, Some(asin.to_string())),
+        _ => (stem, None),
+    };
+    match stem.rsplit_once(" - ") {
+        Some((title, author)) if !title.trim().is_empty() && !author.trim().is_empty() => {
+            (title.trim().to_string(), Some(author.trim().to_string()), asin)
+        }
+        _ => (stem.trim().to_string(), None, asin),
+    }
+}
+
+/// Whether `s` looks like an ASIN: ten capital letters and digits, `B0`
+/// first for Kindle books.
+fn is_asin(s: &str) -> bool {
+    s.len() == 10 && s.starts_with("B0") && s.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+}
+
+fn column_width<'a>(values: impl Iterator<Item = &'a str>, header: &str, max: usize) -> usize {
+    values.map(|v| v.chars().count()).max().unwrap_or(0).clamp(header.len(), max)
+}
+
+/// `s` cut to `width` characters, with `…` marking the cut.
+fn truncate(s: &str, width: usize) -> String {
+    if s.chars().count() <= width {
+        return s.to_string();
+    }
+    let mut cut: String = s.chars().take(width.saturating_sub(1)).collect();
+    cut.push('…');
+    cut
+}
@@ -0,0 +1,341 @@
+use crate::cli::Output;
+use crate::commands::books::{parse_name, BookFormat};
+use crate::commands::push::upload_atomically;
+use crate::commands::send::{send, SendOptions};
+use crate::commands::staging::StagingDir;
+use crate::daemon::Session;
+use crate::device::TransferOptions;
+use crate::error::{Error, Result};
+use crate::metadata::read_local_metadata;
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::{Connection, OpenFlags};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Where calibre's Kindle driver keeps what it knows about the books on the
+/// device, as a JSON list with one object per book.
+const METADATA_PATH: &str = "/metadata.calibre";
+/// The device's identity for calibre, written when it first connects.
+const DRIVEINFO_PATH: &str = "/driveinfo.calibre";
+/// calibre's library database, in the library folder.
+const LIBRARY_DB: &str = "metadata.db";
+/// Formats `calibre import` sends, best first; the Kindle opens these as
+/// they are. Books with none of them are converted by `send`.
+const PREFERRED_FORMATS: &[&str] = &["AZW3", "MOBI", "AZW", "PDF", "EPUB"];
+
+/// Every book in a calibre library with its authors, tags and series.
+const BOOKS_QUERY: &str = "
+SELECT b.id, b.title, b.path, b.uuid,
+    (SELECT group_concat(a.name, char(31)) FROM books_authors_link l JOIN authors a ON a.id = l.author WHERE l.book = b.id),
+    (SELECT group_concat(t.name, char(31)) FROM books_tags_link l JOIN tags t ON t.id = l.tag WHERE l.book = b.id),
+    (SELECT s.name FROM books_series_link l JOIN series s ON s.id = l.series WHERE l.book = b.id)
+FROM books b
+ORDER BY b.sort
+";
+
+/// What the device's `metadata.calibre` records about a book beyond its
+/// file: known for books picked from a calibre library, read from the
+/// book's header otherwise.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DeviceBook {
+    pub title: String,
+    pub authors: Vec<String>,
+    /// The library's ID for the book, which lets calibre match the device
+    /// copy to it
+    pub uuid: Option<String>,
+    pub tags: Vec<String>,
+    pub series: Option<String>,
+    pub asin: Option<String>,
+}
+
+/// The device's `metadata.calibre`, kept in step with what kindle-mtp
+/// uploads and deletes so calibre's device view stays right.
+pub(crate) struct DeviceMetadata {
+    books: Vec<Value>,
+    exists: bool,
+    changed: bool,
+}
+
+impl DeviceMetadata {
+    /// Reads `metadata.calibre`; `None` when calibre has never managed the
+    /// device, which has neither it nor `driveinfo.calibre` then.
+    pub(crate) fn load(session: &Session) -> Result<Option<Self>> {
+        if !exists(session, METADATA_PATH)? {
+            return Ok(exists(session, DRIVEINFO_PATH)?.then(|| Self {
+                books: Vec::new(),
+                exists: false,
+                changed: false,
+            }));
+        }
+        let staging = StagingDir::new("calibre")?;
+        let staged = staging.join("metadata.calibre");
+        session.download_file(METADATA_PATH, &staged)?;
+        let books = serde_json::from_slice(&std::fs::read(&staged)?)
+            .map_err(|e| Error::InvalidPath(format!("{}: {}", METADATA_PATH, e)))?;
+        Ok(Some(Self {
+            books,
+            exists: true,
+            changed: false,
+        }))
+    }
+
+    /// Records the book uploaded from `local` to `remote`, replacing what
+    /// was recorded for that path. Files that aren't books are left out,
+    /// as calibre does.
+    pub(crate) fn record_upload(&mut self, remote: &str, local: &Path, book: Option<&DeviceBook>) {
+        let Some(format) = remote
+            .rsplit_once('.')
+            .and_then(|(_, extension)| BookFormat::from_extension(extension))
+        else {
+            return;
+        };
+        let book = book.cloned().unwrap_or_else(|| local_book(local));
+        let meta = std::fs::metadata(local).ok();
+        let modified: DateTime<Utc> = meta
+            .as_ref()
+            .and_then(|meta| meta.modified().ok())
+            .map_or_else(Utc::now, DateTime::from);
+        let lpath = remote.trim_start_matches('/');
+
+        let mut fields = Map::new();
+        fields.insert("lpath".into(), json!(lpath));
+        fields.insert("title".into(), json!(book.title));
+        fields.insert("title_sort".into(), json!(book.title));
+        fields.insert("authors".into(), json!(book.authors));
+        fields.insert("author_sort".into(), json!(book.authors.join(" & ")));
+        fields.insert("size".into(), json!(meta.map_or(0, |meta| meta.len())));
+        fields.insert("mime".into(), json!(format.mime_type().split(';').next().unwrap_or_default()));
+        fields.insert(
+            "last_modified".into(),
+            json!(modified.to_rfc3339_opts(SecondsFormat::Secs, false)),
+        );
+        fields.insert("tags".into(), json!(book.tags));
+        fields.insert("series".into(), json!(book.series));
+        if let Some(uuid) = book.uuid {
+            fields.insert("uuid".into(), json!(uuid));
+        }
+        if let Some(asin) = book.asin {
+            fields.insert("identifiers".into(), json!({ "mobi-asin": asin }));
+        }
+
+        match self.books.iter_mut().find(|entry| entry["lpath"].as_str() == Some(lpath)) {
+            // Keep what calibre stored that kindle-mtp doesn't know about
+            Some(Value::Object(entry)) => entry.extend(fields),
+            _ => self.books.push(Value::Object(fields)),
+        }
+        self.changed = true;
+    }
+
+    /// Forgets the book at `path`, or every book under it for a folder.
+    pub(crate) fn record_removal(&mut self, path: &str) {
+        let lpath = path.trim_matches('/');
+        let folder = format!("{}/", lpath);
+        let before = self.books.len();
+        self.books.retain(|entry| {
+            entry["lpath"]
+                .as_str()
+                .is_none_or(|book| book != lpath && !book.starts_with(&folder))
+        });
+        self.changed |= self.books.len() != before;
+    }
+
+    /// Writes `metadata.calibre` back if anything changed.
+    pub(crate) fn save(self, session: &Session) -> Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        let data = serde_json::to_vec_pretty(&self.books).map_err(|e| Error::Io(std::io::Error::other(e)))?;
+        let staging = StagingDir::new("calibre")?;
+        let staged = staging.write("metadata.calibre", &data)?;
+        upload_atomically(session, METADATA_PATH, self.exists, |path| session.upload_file(&staged, path))
+    }
+}
+
+/// Applies `change` to the device's `metadata.calibre` if calibre manages
+/// the device. The files were transferred by then, so a failure here is
+/// only a warning.
+pub(crate) fn update_device_metadata(output: &Output, session: &Session, change: impl FnOnce(&mut DeviceMetadata)) {
+    let result = DeviceMetadata::load(session).and_then(|metadata| match metadata {
+        Some(mut metadata) => {
+            change(&mut metadata);
+            metadata.save(session)
+        }
+        None => Ok(()),
+    });
+    if let Err(e) = result {
+        output.warn(format!("could not update {}: {}", METADATA_PATH, e));
+    }
+}
+
+/// Which books of a calibre library `calibre import` sends.
+#[derive(Debug, Clone, Default)]
+pub struct LibraryFilter {
+    /// Words that must all appear in the title or an author's name
+    pub query: Option<String>,
+    pub ids: Vec<i64>,
+    pub authors: Vec<String>,
+    pub tags: Vec<String>,
+    pub series: Option<String>,
+}
+
+/// A book in a calibre library and the file of it to send.
+struct LibraryBook {
+    id: i64,
+    book: DeviceBook,
+    file: PathBuf,
+}
+
+/// Picks books from the calibre library in `library` and sends them as
+/// `send` does, converting the ones without a Kindle format. The device's
+/// `metadata.calibre` gets their library IDs, so calibre shows them as
+/// being on the device.
+pub fn run_calibre_import(
+    output: &Output,
+    library: &Path,
+    filter: &LibraryFilter,
+    remote: &str,
+    options: SendOptions,
+    transfer: TransferOptions,
+) -> Result<()> {
+    let books = read_library(library)
+        .map_err(|e| Error::Io(std::io::Error::other(format!("{}: {}", library.join(LIBRARY_DB).display(), e))))?;
+    let picked: Vec<LibraryBook> = books.into_iter().filter(|book| filter.matches(book)).collect();
+    if picked.is_empty() {
+        return Err(Error::FileNotFound(format!("no book in {} matches", library.display())));
+    }
+    for book in &picked {
+        if !book.file.is_file() {
+            return Err(Error::FileNotFound(format!(
+                "{} (book {} in the library)",
+                book.file.display(),
+                book.id
+            )));
+        }
+    }
+
+    let files: Vec<(String, Option<DeviceBook>)> = picked
+        .into_iter()
+        .map(|book| (book.file.to_string_lossy().into_owned(), Some(book.book)))
+        .collect();
+    let session = Session::open(transfer)?;
+    send(output, &session, &files, remote, &options)
+}
+
+impl LibraryFilter {
+    fn matches(&self, book: &LibraryBook) -> bool {
+        let contains = |haystack: &str, needle: &str| haystack.to_lowercase().contains(&needle.to_lowercase());
+        let query = self.query.as_deref().unwrap_or_default();
+        let words_match = query.split_whitespace().all(|word| {
+            contains(&book.book.title, word) || book.book.authors.iter().any(|a| contains(a, word))
+        });
+        words_match
+            && (self.ids.is_empty() || self.ids.contains(&book.id))
+            && self
+                .authors
+                .iter()
+                .all(|author| book.book.authors.iter().any(|a| contains(a, author)))
+            && self
+                .tags
+                .iter()
+                .all(|tag| book.book.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)))
+            && self
+                .series
+                .as_deref()
+                .is_none_or(|series| book.book.series.as_deref().is_some_and(|s| contains(s, series)))
+    }
+}
+
+/// The books in the library with the file of each to send, read from a
+/// read-only connection so a running calibre isn't disturbed. Books with no
+/// files are left out.
+fn read_library(library: &Path) -> rusqlite::Result<Vec<LibraryBook>> {
+    let conn = Connection::open_with_flags(library.join(LIBRARY_DB), OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let mut formats: HashMap<i64, Vec<(String, String)>> = HashMap::new();
+    let mut statement = conn.prepare("SELECT book, format, name FROM data")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        formats
+            .entry(row.get(0)?)
+            .or_default()
+            .push((row.get::<_, String>(1)?.to_uppercase(), row.get(2)?));
+    }
+    let mut asins: HashMap<i64, String> = HashMap::new();
+    let mut statement = conn.prepare("SELECT book, val FROM identifiers WHERE type IN ('mobi-asin', 'amazon')")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        asins.insert(row.get(0)?, row.get(1)?);
+    }
+
+    let split = |list: Option<String>| -> Vec<String> {
+        list.map(|list| list.split('\u{1f}').map(str::to_string).collect())
+            .unwrap_or_default()
+    };
+    let mut books = Vec::new();
+    let mut statement = conn.prepare(BOOKS_QUERY)?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let id: i64 = row.get(0)?;
+        let Some((format, name)) = formats.get(&id).and_then(|formats| preferred_format(formats)) else {
+            continue;
+        };
+        let folder: String = row.get(2)?;
+        books.push(LibraryBook {
+            id,
+            file: library.join(folder).join(format!("{}.{}", name, format.to_lowercase())),
+            book: DeviceBook {
+                title: row.get(1)?,
+                authors: split(row.get(4)?),
+                uuid: row.get(3)?,
+                tags: split(row.get(5)?),
+                series: row.get(6)?,
+                asin: asins.remove(&id),
+            },
+        });
+    }
+    Ok(books)
+}
+
+/// The format to send out of a book's `(format, file name)` pairs: the
+/// first of `PREFERRED_FORMATS` it has, or else any.
+fn preferred_format(formats: &[(String, String)]) -> Option<&(String, String)> {
+    PREFERRED_FORMATS
+        .iter()
+        .find_map(|preferred| formats.iter().find(|(format, _)| format == preferred))
+        .or_else(|| formats.first())
+}
+
+/// Title and authors of a local book from its header, or its file name
+/// when it has none.
+fn local_book(local: &Path) -> DeviceBook {
+    let stem = local.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+    let (title, author, asin) = parse_name(stem);
+    let mut book = DeviceBook {
+        title,
+        authors: author.into_iter().collect(),
+        asin,
+        ..DeviceBook::default()
+    };
+    if let Ok(Some(metadata)) = read_local_metadata(local) {
+        if !metadata.title.is_empty() {
+            book.title = metadata.title;
+        }
+        if let Some(author) = metadata.author {
+            book.authors = author.split('&').map(|a| a.trim().to_string()).filter(|a| !a.is_empty()).collect();
+        }
+        book.asin = metadata.asin.or(book.asin);
+    }
+    if book.authors.is_empty() {
+        book.authors.push("Unknown".to_string());
+    }
+    book
+}
+
+fn exists(session: &Session, path: &str) -> Result<bool> {
+    match session.stat(path) {
+        Ok(_) => Ok(true),
+        Err(Error::FileNotFound(_)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
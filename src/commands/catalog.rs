@@ -0,0 +1,141 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::books::{books, read_headers, Book};
+use crate::commands::clippings::csv_field;
+use crate::daemon::Session;
+use crate::device::TransferOptions;
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::path::Path;
+
+/// File format of a catalog, named with `--as` or chosen by extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum CatalogFormat {
+    Csv,
+    Json,
+}
+
+impl CatalogFormat {
+    fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase).as_deref() {
+            Some("csv") => Ok(Self::Csv),
+            Some("json") => Ok(Self::Json),
+            _ => Err(Error::InvalidPath(format!(
+                "'{}': use a .csv or .json file, or name the format with --as",
+                path.display()
+            ))),
+        }
+    }
+}
+
+/// A book as the catalog lists it.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct CatalogEntry {
+    pub path: String,
+    pub title: String,
+    pub author: Option<String>,
+    pub asin: Option<String>,
+    pub size: u64,
+    /// When the file was put on the device
+    pub added: DateTime<Utc>,
+}
+
+impl From<Book> for CatalogEntry {
+    fn from(book: Book) -> Self {
+        Self {
+            path: book.path,
+            title: book.title,
+            author: book.author,
+            asin: book.asin,
+            size: book.size,
+            added: book.modified,
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct CatalogOutput {
+    pub path: String,
+    pub file: String,
+    pub format: CatalogFormat,
+    pub books: usize,
+}
+
+impl HumanReadable for CatalogOutput {
+    fn to_human(&self) -> String {
+        format!(
+            "Wrote {} book{} from {} to {}",
+            self.books,
+            if self.books == 1 { "" } else { "s" },
+            self.path,
+            self.file
+        )
+    }
+}
+
+/// Writes an inventory of the books under `path` to `file`, a row per book
+/// with its title, author, ASIN, size and the date it was added, for
+/// spreadsheets and reading trackers. Titles and authors come from the
+/// books' headers unless `from_names`.
+pub fn run_catalog(
+    output: &Output,
+    file: &str,
+    format: Option<CatalogFormat>,
+    path: &str,
+    from_names: bool,
+) -> Result<()> {
+    let format = match format {
+        Some(format) => format,
+        None => CatalogFormat::from_path(Path::new(file))?,
+    };
+    let session = Session::open(TransferOptions::default())?;
+    let entries = session.walk(path)?;
+    let mut books = books(&entries);
+    if !from_names {
+        match session.direct("reading book headers") {
+            Ok(kindle) => read_headers(output, kindle, &mut books),
+            Err(e) => output.warn(format!("{}; titles are taken from file names", e)),
+        }
+    }
+    books.sort_by_cached_key(|b| (b.title.to_lowercase(), b.path.clone()));
+    let entries: Vec<CatalogEntry> = books.into_iter().map(CatalogEntry::from).collect();
+
+    let data = match format {
+        CatalogFormat::Csv => to_csv(&entries),
+        CatalogFormat::Json => {
+            serde_json::to_vec_pretty(&entries).map_err(|e| Error::Io(std::io::Error::other(e)))?
+        }
+    };
+    std::fs::write(file, data)?;
+
+    output.print(&CatalogOutput {
+        path: path.to_string(),
+        file: file.to_string(),
+        format,
+        books: entries.len(),
+    });
+    Ok(())
+}
+
+/// One row per book with a header row.
+fn to_csv(entries: &[CatalogEntry]) -> Vec<u8> {
+    let mut out = String::from("path,title,author,asin,size,added\r\n");
+    for entry in entries {
+        let size = entry.size.to_string();
+        let added = entry.added.to_rfc3339();
+        let fields = [
+            entry.path.as_str(),
+            entry.title.as_str(),
+            entry.author.as_deref().unwrap_or_default(),
+            entry.asin.as_deref().unwrap_or_default(),
+            size.as_str(),
+            added.as_str(),
+        ];
+        let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        out.push_str(&row.join(","));
+        out.push_str("\r\n");
+    }
+    out.into_bytes()
+}
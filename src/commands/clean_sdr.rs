@@ -0,0 +1,150 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::books::{enclosing_sdr, is_sidecar, sidecar_groups, split_path};
+use crate::commands::ls::format_size;
+use crate::daemon::Session;
+use crate::device::{TransferOptions, WalkEntry};
+use crate::error::{Error, Result};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::io::{BufRead, IsTerminal, Write};
+
+#[derive(Serialize, JsonSchema)]
+pub struct CleanSdrOutput {
+    pub path: String,
+    pub orphans: Vec<Orphan>,
+    /// Bytes in all of `orphans`
+    pub bytes: u64,
+    /// Whether the orphans were deleted; false in a dry run
+    pub deleted: bool,
+    pub dry_run: bool,
+}
+
+/// Sidecars left behind by a book that is gone.
+#[derive(Serialize, JsonSchema)]
+pub struct Orphan {
+    /// The missing book's path without its extension
+    pub book: String,
+    /// The `.sdr` folder and sidecar files
+    pub paths: Vec<String>,
+    pub bytes: u64,
+}
+
+impl HumanReadable for CleanSdrOutput {
+    fn to_human(&self) -> String {
+        if self.orphans.is_empty() {
+            return format!("No orphaned sidecars in {}", self.path);
+        }
+        let verb = if self.deleted { "removed" } else { "orphaned" };
+        let mut lines: Vec<String> = self
+            .orphans
+            .iter()
+            .flat_map(|orphan| orphan.paths.iter().map(move |path| format!("{} {}", verb, path)))
+            .collect();
+        let count: usize = self.orphans.iter().map(|o| o.paths.len()).sum();
+        lines.push(format!(
+            "{} item{} of {} book{} that are gone, {}{}",
+            count,
+            if count == 1 { "" } else { "s" },
+            self.orphans.len(),
+            if self.orphans.len() == 1 { "" } else { "s" },
+            format_size(self.bytes),
+            if self.deleted { " freed" } else { "" }
+        ));
+        lines.join("\n")
+    }
+}
+
+/// Finds `.sdr` folders and sidecar files under `path` whose book no
+/// longer exists, and deletes them once confirmed. `yes` skips the
+/// question, which is required when stdin isn't a terminal.
+pub fn run_clean_sdr(output: &Output, path: &str, dry_run: bool, yes: bool) -> Result<()> {
+    let session = Session::open(TransferOptions::default())?;
+    let entries = session.walk(path)?;
+    let orphans = orphans(&entries);
+    let bytes = orphans.iter().map(|o| o.bytes).sum();
+
+    let delete = !orphans.is_empty() && !dry_run && (yes || confirm(&orphans, bytes)?);
+    if delete {
+        for orphan in &orphans {
+            for path in &orphan.paths {
+                delete_all(&session, &entries, path)?;
+            }
+        }
+    }
+    output.print(&CleanSdrOutput {
+        path: path.to_string(),
+        orphans,
+        bytes,
+        deleted: delete,
+        dry_run,
+    });
+    Ok(())
+}
+
+/// The sidecar groups in `entries` with no file of their name next to
+/// them. Any such file counts as the book, not only known book formats,
+/// so nothing that belongs to a book is reported.
+fn orphans(entries: &[WalkEntry]) -> Vec<Orphan> {
+    let stems: HashSet<(&str, &str)> = entries
+        .iter()
+        .filter(|item| !item.entry.is_folder && enclosing_sdr(&item.path).is_none())
+        .filter_map(|item| {
+            let (folder, name) = split_path(&item.path);
+            let (stem, extension) = name.rsplit_once('.')?;
+            (!is_sidecar(extension)).then_some((folder, stem))
+        })
+        .collect();
+    let mut orphans: Vec<Orphan> = sidecar_groups(entries)
+        .into_iter()
+        .filter(|(key, _)| !stems.contains(key))
+        .map(|((folder, stem), (bytes, mut paths))| {
+            paths.sort();
+            Orphan {
+                book: format!("{}/{}", folder, stem),
+                paths,
+                bytes,
+            }
+        })
+        .collect();
+    orphans.sort_by(|a, b| a.book.cmp(&b.book));
+    orphans
+}
+
+/// Asks on stderr whether to delete `orphans`; fails without a terminal to
+/// ask on.
+fn confirm(orphans: &[Orphan], bytes: u64) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Err(Error::InvalidPath(
+            "clean-sdr asks before deleting; pass --yes, or --dry-run to only list".to_string(),
+        ));
+    }
+    let count: usize = orphans.iter().map(|o| o.paths.len()).sum();
+    eprint!(
+        "Delete {} orphaned sidecar item{} ({})? [y/N] ",
+        count,
+        if count == 1 { "" } else { "s" },
+        format_size(bytes)
+    );
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Deletes `path`, and for a folder everything in it first: MTP only
+/// deletes empty folders reliably.
+fn delete_all(session: &Session, entries: &[WalkEntry], path: &str) -> Result<()> {
+    let prefix = format!("{}/", path);
+    let mut children: Vec<&str> = entries
+        .iter()
+        .map(|item| item.path.as_str())
+        .filter(|p| p.starts_with(&prefix))
+        .collect();
+    // Deepest first
+    children.sort_by_key(|p| std::cmp::Reverse(p.matches('/').count()));
+    for child in children {
+        session.delete(child)?;
+    }
+    session.delete(path)
+}
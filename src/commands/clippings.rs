@@ -0,0 +1,504 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::staging::StagingDir;
+use crate::daemon::Session;
+use crate::device::TransferOptions;
+use crate::error::{Error, Result};
+use chrono::NaiveDateTime;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::path::Path;
+
+/// The line between two entries of the clippings file.
+const SEPARATOR: &str = "==========";
+/// `Added on` timestamps as English firmware writes them, after the
+/// weekday: US, then UK, then the older form without seconds.
+const DATE_FORMATS: &[&str] = &[
+    "%B %d, %Y %I:%M:%S %p",
+    "%d %B %Y %H:%M:%S",
+    "%B %d, %Y, %I:%M %p",
+];
+
+/// What a clippings entry records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ClippingKind {
+    Highlight,
+    Note,
+    Bookmark,
+    /// Part of an article clipped from a periodical
+    Clip,
+}
+
+impl ClippingKind {
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            Self::Highlight => "Highlight",
+            Self::Note => "Note",
+            Self::Bookmark => "Bookmark",
+            Self::Clip => "Clip",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Clipping {
+    pub book: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    pub kind: ClippingKind,
+    /// Page as the book numbers it (may be roman), for books with page numbers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page: Option<String>,
+    /// Kindle location, or a range such as `171-172`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    /// Local time on the device; absent if the firmware language isn't English
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub added: Option<NaiveDateTime>,
+    /// Highlighted text or note; empty for bookmarks
+    pub text: String,
+}
+
+impl Clipping {
+    /// First and last location. Older firmware shortens the end of a
+    /// range to its last digits, as in `1234-36`.
+    pub(crate) fn location_range(&self) -> Option<(u32, u32)> {
+        let location = self.location.as_deref()?;
+        let (start, end) = location.split_once('-').unwrap_or((location, location));
+        let start: u32 = start.trim().parse().ok()?;
+        let end_digits = end.trim();
+        let mut end: u32 = end_digits.parse().ok()?;
+        if end < start {
+            let scale = 10u32.checked_pow(end_digits.len() as u32)?;
+            end += start - start % scale;
+            if end < start {
+                end += scale;
+            }
+        }
+        Some((start, end))
+    }
+
+    /// Where in the book and when, e.g. `page 12, location 171-172, 2025-03-03 10:20`.
+    pub(crate) fn position(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(page) = &self.page {
+            parts.push(format!("page {}", page));
+        }
+        if let Some(location) = &self.location {
+            parts.push(format!("location {}", location));
+        }
+        if let Some(added) = self.added {
+            parts.push(added.format("%Y-%m-%d %H:%M").to_string());
+        }
+        parts.join(", ")
+    }
+}
+
+/// A book's clippings, in the order they were made.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct BookClippings {
+    pub book: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    pub clippings: Vec<Clipping>,
+}
+
+/// File format of an export, chosen by extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ClippingsFormat {
+    Json,
+    Markdown,
+    Csv,
+}
+
+impl ClippingsFormat {
+    fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase).as_deref() {
+            Some("json") => Ok(Self::Json),
+            Some("md" | "markdown") => Ok(Self::Markdown),
+            Some("csv") => Ok(Self::Csv),
+            _ => Err(Error::InvalidPath(format!(
+                "'{}': use a .json, .md or .csv file",
+                path.display()
+            ))),
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ClippingsOutput {
+    pub source: String,
+    /// Export file; absent when the clippings are printed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<ClippingsFormat>,
+    /// Number of clippings, over all books
+    pub count: usize,
+    /// Number of books with clippings
+    pub books: usize,
+    /// Every clipping in file order, when printed without `--by-book`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clippings: Option<Vec<Clipping>>,
+    /// Clippings per book, when printed with `--by-book`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub by_book: Option<Vec<BookClippings>>,
+}
+
+impl HumanReadable for ClippingsOutput {
+    fn to_human(&self) -> String {
+        let summary = format!(
+            "{} clipping{} from {} book{}",
+            self.count,
+            if self.count == 1 { "" } else { "s" },
+            self.books,
+            if self.books == 1 { "" } else { "s" }
+        );
+        if let Some(file) = &self.file {
+            return format!("Exported {} to {}", summary, file);
+        }
+        let mut lines = Vec::new();
+        if let Some(books) = &self.by_book {
+            for book in books {
+                lines.push(book_heading(&book.book, book.author.as_deref()));
+                for clipping in &book.clippings {
+                    push_clipping(&mut lines, clipping);
+                }
+                lines.push(String::new());
+            }
+        }
+        for clipping in self.clippings.iter().flatten() {
+            lines.push(book_heading(&clipping.book, clipping.author.as_deref()));
+            push_clipping(&mut lines, clipping);
+            lines.push(String::new());
+        }
+        lines.push(summary);
+        lines.join("\n")
+    }
+}
+
+fn book_heading(book: &str, author: Option<&str>) -> String {
+    match author {
+        Some(author) => format!("{} — {}", book, author),
+        None => book.to_string(),
+    }
+}
+
+fn push_clipping(lines: &mut Vec<String>, clipping: &Clipping) {
+    lines.push(format!("  [{}] {}", clipping.kind.label(), clipping.position()));
+    lines.extend(clipping.text.lines().map(|line| format!("  {}", line)));
+}
+
+/// Reads `My Clippings.txt` from the device and prints its entries, or
+/// exports them to `file` as JSON, Markdown (one section per book) or CSV,
+/// by its extension.
+pub fn run_clippings(output: &Output, path: &str, file: Option<&str>, by_book: bool) -> Result<()> {
+    let format = file.map(|file| ClippingsFormat::from_path(Path::new(file))).transpose()?;
+    let session = Session::open(TransferOptions::default())?;
+    let clippings = fetch_clippings(&session, path)?;
+    let grouped = group_by_book(clippings.clone());
+
+    let mut clippings_output = ClippingsOutput {
+        source: path.to_string(),
+        file: file.map(str::to_string),
+        format,
+        count: clippings.len(),
+        books: grouped.len(),
+        clippings: None,
+        by_book: None,
+    };
+    match (file, format) {
+        (Some(file), Some(format)) => {
+            let data = match format {
+                ClippingsFormat::Json if by_book => to_json(&grouped)?,
+                ClippingsFormat::Json => to_json(&clippings)?,
+                ClippingsFormat::Markdown => to_markdown(&grouped),
+                ClippingsFormat::Csv => to_csv(&clippings),
+            };
+            std::fs::write(file, data)?;
+        }
+        _ if by_book => clippings_output.by_book = Some(grouped),
+        _ => clippings_output.clippings = Some(clippings),
+    }
+    output.print(&clippings_output);
+    Ok(())
+}
+
+/// Downloads the clippings file at `path` and parses it.
+pub(crate) fn fetch_clippings(session: &Session, path: &str) -> Result<Vec<Clipping>> {
+    let staging = StagingDir::new("clippings")?;
+    let staged = staging.join("clippings.txt");
+    session.download_file(path, &staged)?;
+    Ok(parse_clippings(&String::from_utf8_lossy(&std::fs::read(&staged)?)))
+}
+
+/// The entries of a clippings file. Each is a title line, a line saying
+/// what and where, a blank line and the text, ended by `==========`.
+pub(crate) fn parse_clippings(text: &str) -> Vec<Clipping> {
+    text.split(SEPARATOR).filter_map(parse_entry).collect()
+}
+
+fn parse_entry(entry: &str) -> Option<Clipping> {
+    // Some firmware starts every entry, not just the file, with a BOM
+    let mut lines = entry
+        .lines()
+        .map(|line| line.trim_start_matches('\u{feff}'))
+        .skip_while(|line| line.trim().is_empty());
+    let (book, author) = split_title(lines.next()?.trim());
+    let meta = lines.next()?;
+    let text = lines.collect::<Vec<_>>().join("\n").trim().to_string();
+
+    let meta = meta.trim().trim_start_matches('-').to_lowercase();
+    let description = meta.split('|').next().unwrap_or_default();
+    let kind = if description.contains("highlight") {
+        ClippingKind::Highlight
+    } else if description.contains("note") {
+        ClippingKind::Note
+    } else if description.contains("bookmark") {
+        ClippingKind::Bookmark
+    } else if description.contains("clip") {
+        ClippingKind::Clip
+    } else if text.is_empty() {
+        ClippingKind::Bookmark
+    } else {
+        ClippingKind::Highlight
+    };
+
+    let mut clipping = Clipping {
+        book,
+        author,
+        kind,
+        page: None,
+        location: None,
+        added: None,
+        text,
+    };
+    // Page and location share the first part ("on page 3 | location 40")
+    // or not ("at location 40"), so every part is searched
+    for part in meta.split('|').map(str::trim) {
+        if let Some(added) = part.strip_prefix("added on") {
+            clipping.added = parse_date(added.trim());
+            continue;
+        }
+        clipping.page = clipping.page.take().or_else(|| value_after(part, &["page "]));
+        clipping.location = clipping
+            .location
+            .take()
+            .or_else(|| value_after(part, &["location ", "loc. "]));
+    }
+    Some(clipping)
+}
+
+/// `Title (Author)` split at the last parenthesised group, which is the
+/// author; parentheses inside the title are left alone.
+fn split_title(line: &str) -> (String, Option<String>) {
+    let Some(inner) = line.strip_suffix(')') else {
+        return (line.to_string(), None);
+    };
+    let mut depth = 0;
+    for (i, c) in inner.char_indices().rev() {
+        match c {
+            ')' => depth += 1,
+            '(' if depth > 0 => depth -= 1,
+            '(' => {
+                let (title, author) = (inner[..i].trim(), inner[i + 1..].trim());
+                if title.is_empty() || author.is_empty() {
+                    break;
+                }
+                return (title.to_string(), Some(author.to_string()));
+            }
+            _ => {}
+        }
+    }
+    (line.to_string(), None)
+}
+
+/// The word following the first of `keys` in `part`.
+fn value_after(part: &str, keys: &[&str]) -> Option<String> {
+    keys.iter().find_map(|key| {
+        let start = part.find(key)? + key.len();
+        let value = part[start..].split_whitespace().next()?;
+        Some(value.trim_end_matches(',').to_string())
+    })
+}
+
+/// An English `Added on` timestamp, such as `Monday, March 3, 2025
+/// 10:20:30 AM`. `None` for other languages.
+fn parse_date(date: &str) -> Option<NaiveDateTime> {
+    let date = match date.split_once(", ") {
+        Some((weekday, rest)) if weekday.ends_with("day") && weekday.chars().all(char::is_alphabetic) => rest,
+        _ => date,
+    };
+    DATE_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(date, format).ok())
+}
+
+/// Groups clippings by book, books in the order of their first clipping.
+pub(crate) fn group_by_book(clippings: Vec<Clipping>) -> Vec<BookClippings> {
+    let mut books: Vec<BookClippings> = Vec::new();
+    for clipping in clippings {
+        match books
+            .iter_mut()
+            .find(|b| b.book == clipping.book && b.author == clipping.author)
+        {
+            Some(book) => book.clippings.push(clipping),
+            None => books.push(BookClippings {
+                book: clipping.book.clone(),
+                author: clipping.author.clone(),
+                clippings: vec![clipping],
+            }),
+        }
+    }
+    books
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    serde_json::to_vec_pretty(value).map_err(|e| Error::Io(std::io::Error::other(e)))
+}
+
+/// One `#` section per book, highlights as quotes followed by where they are.
+fn to_markdown(books: &[BookClippings]) -> Vec<u8> {
+    let mut out = String::new();
+    for book in books {
+        out.push_str(&format!("# {}\n\n", book.book));
+        if let Some(author) = &book.author {
+            out.push_str(&format!("*{}*\n\n", author));
+        }
+        for clipping in &book.clippings {
+            match clipping.kind {
+                ClippingKind::Bookmark => {
+                    out.push_str(&format!("- Bookmark: {}\n\n", clipping.position()));
+                    continue;
+                }
+                ClippingKind::Note => out.push_str(&format!("**Note:** {}\n\n", clipping.text)),
+                ClippingKind::Highlight | ClippingKind::Clip => {
+                    for line in clipping.text.lines() {
+                        out.push_str(&format!("> {}\n", line));
+                    }
+                    out.push('\n');
+                }
+            }
+            let position = clipping.position();
+            if !position.is_empty() {
+                out.push_str(&format!("— {}\n\n", position));
+            }
+        }
+    }
+    out.into_bytes()
+}
+
+/// One row per clipping with a header row, quoted as RFC 4180 asks.
+fn to_csv(clippings: &[Clipping]) -> Vec<u8> {
+    let mut out = String::from("book,author,kind,page,location,added,text\r\n");
+    for clipping in clippings {
+        let added = clipping.added.map(|added| added.format("%Y-%m-%d %H:%M:%S").to_string());
+        let fields = [
+            clipping.book.as_str(),
+            clipping.author.as_deref().unwrap_or_default(),
+            clipping.kind.label(),
+            clipping.page.as_deref().unwrap_or_default(),
+            clipping.location.as_deref().unwrap_or_default(),
+            added.as_deref().unwrap_or_default(),
+            clipping.text.as_str(),
+        ];
+        let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        out.push_str(&row.join(","));
+        out.push_str("\r\n");
+    }
+    out.into_bytes()
+}
+
+pub(crate) fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIPPINGS: &str = "\u{feff}The Hobbit (There and Back Again) (J.R.R. Tolkien)
+- Your Highlight on page 12 | location 171-172 | Added on Monday, March 3, 2025 10:20:30 AM
+
+In a hole in the ground there lived a hobbit.
+==========
+\u{feff}Dune (Frank Herbert)
+- Your Note at location 40 | Added on Tuesday, 4 March 2025 21:05:00
+
+Spice.
+==========
+Dune (Frank Herbert)
+- Your Bookmark on page xii | Added on Wednesday, March 5, 2025, 7:15 PM
+
+
+==========
+";
+
+    fn clipping(location: &str) -> Clipping {
+        Clipping {
+            book: "Book".to_string(),
+            author: None,
+            kind: ClippingKind::Highlight,
+            page: None,
+            location: Some(location.to_string()),
+            added: None,
+            text: String::new(),
+        }
+    }
+
+    #[test]
+    fn parses_every_entry() {
+        let clippings = parse_clippings(CLIPPINGS);
+        assert_eq!(clippings.len(), 3);
+
+        let hobbit = &clippings[0];
+        assert_eq!(hobbit.book, "The Hobbit (There and Back Again)");
+        assert_eq!(hobbit.author.as_deref(), Some("J.R.R. Tolkien"));
+        assert_eq!(hobbit.kind, ClippingKind::Highlight);
+        assert_eq!(hobbit.page.as_deref(), Some("12"));
+        assert_eq!(hobbit.location.as_deref(), Some("171-172"));
+        assert_eq!(hobbit.added.unwrap().to_string(), "2025-03-03 10:20:30");
+        assert_eq!(hobbit.text, "In a hole in the ground there lived a hobbit.");
+
+        let note = &clippings[1];
+        assert_eq!(note.kind, ClippingKind::Note);
+        assert_eq!(note.page, None);
+        assert_eq!(note.location.as_deref(), Some("40"));
+        assert_eq!(note.added.unwrap().to_string(), "2025-03-04 21:05:00");
+
+        let bookmark = &clippings[2];
+        assert_eq!(bookmark.kind, ClippingKind::Bookmark);
+        assert_eq!(bookmark.page.as_deref(), Some("xii"));
+        assert_eq!(bookmark.added.unwrap().to_string(), "2025-03-05 19:15:00");
+        assert!(bookmark.text.is_empty());
+    }
+
+    #[test]
+    fn dates_in_other_languages_are_left_out() {
+        let clippings = parse_clippings("Buch (Autor)\n- Ihre Markierung bei Position 5 | Hinzugefügt am Montag, 3. März 2025 10:20:30\n\nText\n==========\n");
+        assert_eq!(clippings.len(), 1);
+        assert_eq!(clippings[0].added, None);
+        assert_eq!(clippings[0].text, "Text");
+    }
+
+    #[test]
+    fn titles_without_an_author() {
+        assert_eq!(split_title("Notes"), ("Notes".to_string(), None));
+        assert_eq!(split_title("Notes ()"), ("Notes ()".to_string(), None));
+        assert_eq!(split_title("A (B (C))"), ("A".to_string(), Some("B (C)".to_string())));
+    }
+
+    #[test]
+    fn location_ranges_expand_shortened_ends() {
+        assert_eq!(clipping("40").location_range(), Some((40, 40)));
+        assert_eq!(clipping("171-172").location_range(), Some((171, 172)));
+        assert_eq!(clipping("1234-36").location_range(), Some((1234, 1236)));
+        assert_eq!(clipping("1298-02").location_range(), Some((1298, 1302)));
+        assert_eq!(clipping("xii").location_range(), None);
+    }
+}
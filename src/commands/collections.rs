@@ -0,0 +1,287 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::books::{books, Book};
+use crate::commands::hash::to_hex;
+use crate::commands::push::upload_atomically;
+use crate::commands::staging::StagingDir;
+use crate::daemon::Session;
+use crate::device::TransferOptions;
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+
+/// Where firmware before 5.x keeps collections; later firmware keeps them
+/// in a database on the system partition, which MTP doesn't expose.
+const COLLECTIONS_PATH: &str = "/system/collections.json";
+/// Where the device's own paths start; item hashes are of these.
+const MOUNT_PREFIX: &str = "/mnt/us";
+/// Locale for new collections when there are none to copy it from.
+const DEFAULT_LOCALE: &str = "en-US";
+const RESTART_NOTE: &str = "restart the Kindle to see the change";
+
+/// Collections by `Name@locale`, with their items and last access.
+type Collections = Map<String, Value>;
+
+#[derive(Serialize, JsonSchema)]
+pub struct CollectionsOutput {
+    pub collections: Vec<Collection>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct Collection {
+    pub name: String,
+    /// Paths of the books in it that are on the device
+    pub books: Vec<String>,
+    /// Items that match no book on the device, as the Kindle stores them:
+    /// `*` and a path hash, or `#` and an ASIN
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unresolved: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_access: Option<DateTime<Utc>>,
+}
+
+impl HumanReadable for CollectionsOutput {
+    fn to_human(&self) -> String {
+        if self.collections.is_empty() {
+            return "No collections".to_string();
+        }
+        let mut lines = Vec::new();
+        for collection in &self.collections {
+            let count = collection.books.len() + collection.unresolved.len();
+            lines.push(format!(
+                "{} ({} item{})",
+                collection.name,
+                count,
+                if count == 1 { "" } else { "s" }
+            ));
+            lines.extend(collection.books.iter().map(|book| format!("  {}", book)));
+            if !collection.unresolved.is_empty() {
+                lines.push(format!("  ... and {} not on the device", collection.unresolved.len()));
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct CollectionEditOutput {
+    pub collection: String,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// The whole collection was removed
+    pub deleted: bool,
+    /// Items left in the collection
+    pub items: usize,
+}
+
+impl HumanReadable for CollectionEditOutput {
+    fn to_human(&self) -> String {
+        let change = if self.deleted {
+            format!("Removed collection '{}'", self.collection)
+        } else if !self.added.is_empty() {
+            format!(
+                "Added {} book{} to '{}' ({} items)",
+                self.added.len(),
+                if self.added.len() == 1 { "" } else { "s" },
+                self.collection,
+                self.items
+            )
+        } else {
+            format!(
+                "Removed {} book{} from '{}' ({} items)",
+                self.removed.len(),
+                if self.removed.len() == 1 { "" } else { "s" },
+                self.collection,
+                self.items
+            )
+        };
+        format!("{}; {}", change, RESTART_NOTE)
+    }
+}
+
+/// Lists the collections in `collections.json` with the books in them.
+pub fn run_collections_list(output: &Output) -> Result<()> {
+    let session = Session::open(TransferOptions::default())?;
+    let collections = load(&session)?;
+    let by_item = book_items(&session)?;
+
+    let mut list: Vec<Collection> = collections
+        .iter()
+        .map(|(key, value)| {
+            let mut collection = Collection {
+                name: split_key(key).0.to_string(),
+                books: Vec::new(),
+                unresolved: Vec::new(),
+                last_access: value["lastAccess"].as_i64().and_then(DateTime::from_timestamp_millis),
+            };
+            for item in value["items"].as_array().into_iter().flatten().filter_map(Value::as_str) {
+                match by_item.get(item) {
+                    Some(path) => collection.books.push(path.clone()),
+                    None => collection.unresolved.push(item.to_string()),
+                }
+            }
+            collection.books.sort();
+            collection
+        })
+        .collect();
+    list.sort_by_cached_key(|c| c.name.to_lowercase());
+    output.print(&CollectionsOutput { collections: list });
+    Ok(())
+}
+
+/// Adds the books at `paths` to the collection `name`, creating it if need
+/// be. Books already in it are left alone.
+pub fn run_collections_add(output: &Output, name: &str, paths: &[String]) -> Result<()> {
+    let session = Session::open(TransferOptions::default())?;
+    let mut collections = load(&session)?;
+    for path in paths {
+        if session.stat(path)?.is_folder {
+            return Err(Error::InvalidPath(format!("'{}' is a folder", path)));
+        }
+    }
+
+    let key = find_key(&collections, name).unwrap_or_else(|| {
+        let locale = collections.keys().find_map(|k| split_key(k).1).unwrap_or(DEFAULT_LOCALE);
+        format!("{}@{}", name, locale)
+    });
+    let entry = collections
+        .entry(key)
+        .or_insert_with(|| json!({ "items": [], "lastAccess": 0 }));
+    let items = items_mut(entry);
+    let mut added = Vec::new();
+    for path in paths {
+        let item = path_item(path);
+        if !items.iter().any(|i| i.as_str() == Some(item.as_str())) {
+            items.push(Value::String(item));
+            added.push(path.clone());
+        }
+    }
+    let count = items.len();
+    entry["lastAccess"] = json!(Utc::now().timestamp_millis());
+    save(&session, &collections)?;
+
+    output.print(&CollectionEditOutput {
+        collection: name.to_string(),
+        added,
+        removed: Vec::new(),
+        deleted: false,
+        items: count,
+    });
+    Ok(())
+}
+
+/// Takes the books at `paths` out of the collection `name`, or removes the
+/// collection itself when no paths are given. The books stay on the device.
+pub fn run_collections_remove(output: &Output, name: &str, paths: &[String]) -> Result<()> {
+    let session = Session::open(TransferOptions::default())?;
+    let mut collections = load(&session)?;
+    let key = find_key(&collections, name)
+        .ok_or_else(|| Error::FileNotFound(format!("no collection named '{}'", name)))?;
+
+    let mut edit = CollectionEditOutput {
+        collection: name.to_string(),
+        added: Vec::new(),
+        removed: Vec::new(),
+        deleted: paths.is_empty(),
+        items: 0,
+    };
+    if paths.is_empty() {
+        collections.remove(&key);
+    } else {
+        let items = items_mut(collections.get_mut(&key).expect("key just found"));
+        for path in paths {
+            let item = path_item(path);
+            let before = items.len();
+            items.retain(|i| i.as_str() != Some(item.as_str()));
+            if items.len() < before {
+                edit.removed.push(path.clone());
+            }
+        }
+        edit.items = items.len();
+    }
+    save(&session, &collections)?;
+    output.print(&edit);
+    Ok(())
+}
+
+/// Reads `collections.json`. Its absence means firmware that keeps
+/// collections where they can't be reached over USB.
+fn load(session: &Session) -> Result<Collections> {
+    match session.stat(COLLECTIONS_PATH) {
+        Ok(_) => {}
+        Err(Error::FileNotFound(_)) => {
+            return Err(Error::Unsupported(format!(
+                "no {} on this Kindle; firmware 5.x and later keeps collections in a database MTP can't reach, so manage them on the device or with Amazon's apps",
+                COLLECTIONS_PATH
+            )));
+        }
+        Err(e) => return Err(e),
+    }
+    let staging = StagingDir::new("collections")?;
+    let staged = staging.join("collections.json");
+    session.download_file(COLLECTIONS_PATH, &staged)?;
+    let data = std::fs::read(&staged)?;
+    if data.iter().all(u8::is_ascii_whitespace) {
+        return Ok(Collections::new());
+    }
+    serde_json::from_slice(&data).map_err(|e| Error::InvalidPath(format!("{}: {}", COLLECTIONS_PATH, e)))
+}
+
+/// Writes `collections.json` back, replacing the old one only once the new
+/// one is complete.
+fn save(session: &Session, collections: &Collections) -> Result<()> {
+    let data = serde_json::to_vec(collections).map_err(|e| Error::Io(std::io::Error::other(e)))?;
+    let staging = StagingDir::new("collections")?;
+    let staged = staging.write("collections.json", &data)?;
+    upload_atomically(session, COLLECTIONS_PATH, true, |path| session.upload_file(&staged, path))
+}
+
+/// The stored key of collection `name`, matched without its locale and
+/// ignoring case.
+fn find_key(collections: &Collections, name: &str) -> Option<String> {
+    collections
+        .keys()
+        .find(|key| split_key(key).0.eq_ignore_ascii_case(name))
+        .cloned()
+}
+
+/// `Name@en-US` split into the name and its locale.
+fn split_key(key: &str) -> (&str, Option<&str>) {
+    match key.rsplit_once('@') {
+        Some((name, locale)) => (name, Some(locale)),
+        None => (key, None),
+    }
+}
+
+/// The `items` array of a collection, created if it is missing.
+fn items_mut(collection: &mut Value) -> &mut Vec<Value> {
+    if !collection["items"].is_array() {
+        collection["items"] = json!([]);
+    }
+    collection["items"].as_array_mut().expect("items is an array")
+}
+
+/// How a collection refers to a sideloaded book: `*` and the SHA-1 of its
+/// path as the Kindle sees it.
+fn path_item(path: &str) -> String {
+    format!("*{}", to_hex(&Sha1::digest(format!("{}{}", MOUNT_PREFIX, path).as_bytes())))
+}
+
+/// Book paths under `/documents` by every item that may refer to them:
+/// their path hash, and `#ASIN^EBOK` / `#ASIN^PDOC` for Amazon books.
+fn book_items(session: &Session) -> Result<HashMap<String, String>> {
+    let entries = session.walk("/documents")?;
+    let mut items = HashMap::new();
+    for Book { path, asin, .. } in books(&entries) {
+        if let Some(asin) = asin {
+            for kind in ["EBOK", "PDOC"] {
+                items.insert(format!("#{}^{}", asin, kind), path.clone());
+            }
+        }
+        items.insert(path_item(&path), path);
+    }
+    Ok(items)
+}
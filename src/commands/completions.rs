@@ -0,0 +1,241 @@
+use crate::cli::Args;
+use crate::config::Config;
+use crate::daemon::Session;
+use crate::device::TransferOptions;
+use crate::error::Result;
+use clap::CommandFactory;
+use clap_complete::Shell;
+
+const BIN_NAME: &str = "kindle-mtp";
+
+/// Device path arguments, as (subcommand, argument id). Only these get
+/// dynamic completion; everything else falls back to the static script.
+const REMOTE_ARGS: &[(&str, &str)] = &[
+    ("ls", "path"),
+    ("stat", "path"),
+    ("thumb", "remote"),
+    ("pull", "paths"),
+    ("push", "remote"),
+    ("rm", "paths"),
+    ("watch", "remote"),
+    ("diff", "remote"),
+    ("hash", "remote"),
+    ("bench", "dir"),
+    ("backup", "path"),
+    ("search", "path"),
+    ("restore", "path"),
+    ("sync", "source"),
+    ("sync", "destination"),
+    ("assert", "exists"),
+    ("assert", "missing"),
+    ("assert", "count_max"),
+    ("assert", "count_min"),
+];
+
+/// Wraps clap's bash completion: device path arguments are completed by
+/// `kindle-mtp __complete`, which exits non-zero for anything else.
+const BASH_DYNAMIC: &str = r#"
+_kindle__mtp_dynamic() {
+    local candidates
+    if candidates="$(kindle-mtp __complete --bash -- "${COMP_LINE:0:COMP_POINT}" 2>/dev/null)"; then
+        local IFS=$'\n'
+        COMPREPLY=( $candidates )
+        compopt -o nospace 2>/dev/null
+        return 0
+    fi
+    _kindle__mtp "$@"
+}
+
+complete -F _kindle__mtp_dynamic -o bashdefault -o default kindle-mtp
+"#;
+
+const ZSH_DYNAMIC: &str = r#"
+_kindle-mtp-dynamic() {
+    local -a candidates
+    local line="${(j: :)${(@q)words[1,CURRENT]}}"
+    if candidates=(${(f)"$(kindle-mtp __complete -- "$line" 2>/dev/null)"}); then
+        compadd -S '' -- $candidates
+        return 0
+    fi
+    _kindle-mtp "$@"
+}
+
+compdef _kindle-mtp-dynamic kindle-mtp
+"#;
+
+/// Prints the completion script for `shell`. Bash and zsh also complete
+/// device paths by asking the device (through the daemon, if running).
+pub fn run_completions(shell: Shell) -> Result<()> {
+    let mut stdout = std::io::stdout();
+    clap_complete::generate(shell, &mut Args::command(), BIN_NAME, &mut stdout);
+    match shell {
+        Shell::Bash => print!("{}", BASH_DYNAMIC),
+        Shell::Zsh => print!("{}", ZSH_DYNAMIC),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Prints candidates, one per line, for the last word of `line` (the command
+/// line up to the cursor). Returns false when that word isn't a device path,
+/// so the shell uses its static completion instead. With `bash`, candidates
+/// start after the word's last `:` (bash splits words there) and are escaped.
+pub fn run_complete(line: &str, bash: bool, config: &Config, transfer: TransferOptions) -> bool {
+    let mut words = shell_words::split(line)
+        .unwrap_or_else(|_| line.split_whitespace().map(str::to_string).collect());
+    if line.ends_with(char::is_whitespace) || words.is_empty() {
+        words.push(String::new());
+    }
+    let Some((current, before)) = words.split_last() else {
+        return false;
+    };
+    let Some((subcommand, arg)) = remote_argument(before) else {
+        return false;
+    };
+    if current.starts_with('-') || !REMOTE_ARGS.contains(&(subcommand.as_str(), arg.as_str())) {
+        return false;
+    }
+
+    let sync = subcommand == "sync";
+    let candidates = match split_word(current, config, sync) {
+        Some((typed_dir, folder, prefix)) => list_candidates(&typed_dir, &folder, prefix, transfer),
+        // A bare name: offer the roots it could start
+        None if !current.contains(':') && !current.starts_with('/') => {
+            let mut roots: Vec<String> = config.aliases.keys().map(|name| format!("{}:", name)).collect();
+            if sync {
+                roots.push("kindle:".to_string());
+            } else {
+                roots.push("/".to_string());
+            }
+            roots.retain(|root| root.starts_with(current.as_str()));
+            roots
+        }
+        // Local sync endpoints and unknown prefixes
+        None => return false,
+    };
+
+    let colon = current.rfind(':').map_or(0, |i| i + 1);
+    for candidate in candidates {
+        if bash {
+            println!("{}", escape(&candidate[colon.min(candidate.len())..]));
+        } else {
+            println!("{}", candidate);
+        }
+    }
+    true
+}
+
+/// Walks the words before the cursor and returns the subcommand and the id
+/// of the argument the cursor word belongs to.
+fn remote_argument(words: &[String]) -> Option<(String, String)> {
+    let mut cli = Args::command();
+    cli.build();
+    let mut subcommand: Option<&clap::Command> = None;
+    let mut positionals = 0;
+    let mut pending: Option<String> = None;
+    let mut stdout = false;
+
+    for word in words.iter().skip(1) {
+        if pending.take().is_some() {
+            continue;
+        }
+        let arg = if let Some(long) = word.strip_prefix("--") {
+            if long.is_empty() || long.contains('=') {
+                continue;
+            }
+            stdout |= long == "stdout";
+            find_arg(&cli, subcommand, |a| a.get_long() == Some(long))
+        } else if let Some(shorts) = word.strip_prefix('-').filter(|s| !s.is_empty()) {
+            let last = shorts.chars().last();
+            find_arg(&cli, subcommand, |a| a.get_short() == last)
+        } else {
+            match subcommand {
+                None => subcommand = Some(cli.find_subcommand(word)?),
+                Some(_) => positionals += 1,
+            }
+            continue;
+        };
+        if let Some(arg) = arg
+            && arg.get_action().takes_values()
+            && arg.get_num_args().is_none_or(|n| n.min_values() > 0)
+        {
+            pending = Some(arg.get_id().to_string());
+        }
+    }
+
+    let subcommand = subcommand?;
+    let name = subcommand.get_name().to_string();
+    if let Some(id) = pending {
+        return Some((name, id));
+    }
+    let args: Vec<&clap::Arg> = subcommand.get_positionals().collect();
+    let arg = match args.get(positionals) {
+        Some(arg) => arg,
+        None => args.last().filter(|a| a.get_num_args().is_some_and(|n| n.max_values() > 1))?,
+    };
+    // Only words up to the cursor are known, so a pull path after the first
+    // may be the local destination; without --stdout leave it to the shell
+    if name == "pull" && positionals > 0 && !stdout {
+        return None;
+    }
+    Some((name, arg.get_id().to_string()))
+}
+
+fn find_arg<'a>(
+    cli: &'a clap::Command,
+    subcommand: Option<&'a clap::Command>,
+    matches: impl Fn(&clap::Arg) -> bool,
+) -> Option<&'a clap::Arg> {
+    subcommand
+        .and_then(|s| s.get_arguments().find(|a| matches(a)))
+        .or_else(|| cli.get_arguments().find(|a| matches(a)))
+}
+
+/// Splits a device path word into what was typed up to the last `/` (or the
+/// alias prefix), the device folder that names, and the partial name after
+/// it. `None` if the word isn't a device path.
+fn split_word<'w>(word: &'w str, config: &Config, sync: bool) -> Option<(String, String, &'w str)> {
+    let (root, rest) = match word.split_once(':') {
+        Some((name, rest)) if config.aliases.contains_key(name) => (format!("{}:", name), rest),
+        Some(("kindle", rest)) if sync => ("kindle:".to_string(), rest),
+        None if word.starts_with('/') && !sync => (String::new(), word),
+        _ => return None,
+    };
+    let (dir, prefix) = match rest.rsplit_once('/') {
+        Some((dir, prefix)) => (format!("{}/", dir), prefix),
+        None => (String::new(), rest),
+    };
+    let typed_dir = format!("{}{}", root, dir);
+    let folder = match root.as_str() {
+        "" | "kindle:" => format!("/{}", dir.trim_start_matches('/')),
+        _ => config.expand_alias(&typed_dir),
+    };
+    Some((typed_dir, folder, prefix))
+}
+
+/// Lists `folder` and returns the entries starting with `prefix`, folders
+/// with a trailing `/`. Nothing if the device isn't reachable.
+fn list_candidates(typed_dir: &str, folder: &str, prefix: &str, transfer: TransferOptions) -> Vec<String> {
+    let entries = match Session::open(transfer).and_then(|session| session.list_files(folder)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut candidates: Vec<String> = entries
+        .into_iter()
+        .filter(|e| e.name.starts_with(prefix))
+        .map(|e| format!("{}{}{}", typed_dir, e.name, if e.is_folder { "/" } else { "" }))
+        .collect();
+    candidates.sort();
+    candidates
+}
+
+fn escape(word: &str) -> String {
+    let mut escaped = String::with_capacity(word.len());
+    for c in word.chars() {
+        if c.is_whitespace() || "\\'\"()[]{}&;|<>$`!*?#~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
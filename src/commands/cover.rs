@@ -0,0 +1,59 @@
+use crate::cli::{HumanReadable, Output};
+use crate::daemon::Session;
+use crate::device::TransferOptions;
+use crate::error::{Error, Result};
+use crate::metadata::read_cover;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Serialize, JsonSchema)]
+pub struct CoverOutput {
+    pub remote: String,
+    pub local: String,
+    pub bytes: u64,
+    /// `jpeg`, `png` or `gif`
+    pub format: String,
+}
+
+impl HumanReadable for CoverOutput {
+    fn to_human(&self) -> String {
+        format!(
+            "Saved cover of {} -> {} ({}, {} bytes)",
+            self.remote, self.local, self.format, self.bytes
+        )
+    }
+}
+
+/// Saves the cover image embedded in the book `remote` to `local`, or
+/// writes it to stdout when `local` is `-`. Only the cover is read from
+/// the device, at full size, unlike the device thumbnail `thumb` fetches.
+pub fn run_cover(output: &Output, remote: &str, local: &Path) -> Result<()> {
+    let session = Session::open(TransferOptions::default())?;
+    let kindle = session.direct("cover")?;
+    if kindle.stat(remote)?.is_folder {
+        return Err(Error::InvalidPath(format!("'{}' is a folder", remote)));
+    }
+    let cover = read_cover(kindle, remote)?.ok_or_else(|| {
+        Error::FileNotFound(format!(
+            "no embedded cover in {} (MOBI, AZW, AZW3 and EPUB are read; `thumb` may still work)",
+            remote
+        ))
+    })?;
+
+    if local == Path::new("-") {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(&cover.data)?;
+        stdout.flush()?;
+        return Ok(());
+    }
+    std::fs::write(local, &cover.data)?;
+    output.print(&CoverOutput {
+        remote: remote.to_string(),
+        local: local.display().to_string(),
+        bytes: cover.data.len() as u64,
+        format: cover.format.to_string(),
+    });
+    Ok(())
+}
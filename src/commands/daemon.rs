@@ -0,0 +1,17 @@
+use crate::cli::Output;
+use crate::device::TransferOptions;
+use crate::error::Result;
+
+/// Holds the device open and serves `ls`, `info`, `pull` and `push` from
+/// other invocations over a Unix socket until interrupted.
+#[cfg(unix)]
+pub fn run_daemon(output: &Output, transfer: TransferOptions) -> Result<()> {
+    crate::daemon::serve(output, transfer)
+}
+
+#[cfg(not(unix))]
+pub fn run_daemon(_output: &Output, _transfer: TransferOptions) -> Result<()> {
+    Err(crate::error::Error::InvalidPath(
+        "daemon mode needs Unix domain sockets".to_string(),
+    ))
+}
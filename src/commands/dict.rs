@@ -0,0 +1,188 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::ls::format_size;
+use crate::commands::overwrite::{Action, OverwritePolicy};
+use crate::commands::push::upload_atomically;
+use crate::commands::space::precheck;
+use crate::daemon::Session;
+use crate::device::TransferOptions;
+use crate::error::{Error, Result};
+use crate::metadata::{read_dictionary, read_local_dictionary, DictionaryInfo};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::path::Path;
+
+/// Where the Kindle looks for dictionaries.
+const DICTIONARY_FOLDER: &str = "/documents/dictionaries";
+/// Extensions a Kindle dictionary file can have.
+const DICTIONARY_EXTENSIONS: &[&str] = &["mobi", "azw", "prc"];
+
+#[derive(Serialize, JsonSchema)]
+pub struct DictInstallOutput {
+    pub local: String,
+    pub remote: String,
+    pub bytes: u64,
+    pub dictionary: DictionaryInfo,
+    pub replaced: bool,
+}
+
+impl HumanReadable for DictInstallOutput {
+    fn to_human(&self) -> String {
+        format!(
+            "Installed {} ({}) -> {}{}",
+            self.dictionary.title,
+            languages(&self.dictionary),
+            self.remote,
+            if self.replaced { ", replaced" } else { "" }
+        )
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct DictListOutput {
+    pub folder: String,
+    pub dictionaries: Vec<InstalledDictionary>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct InstalledDictionary {
+    pub path: String,
+    pub size: u64,
+    /// What its header says; absent for files that aren't MOBI
+    /// dictionaries, or when the header couldn't be read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dictionary: Option<DictionaryInfo>,
+}
+
+impl HumanReadable for DictListOutput {
+    fn to_human(&self) -> String {
+        if self.dictionaries.is_empty() {
+            return format!("No dictionaries in {}", self.folder);
+        }
+        self.dictionaries
+            .iter()
+            .map(|item| {
+                let name = item.path.rsplit('/').next().unwrap_or(&item.path);
+                match &item.dictionary {
+                    Some(info) => format!(
+                        "{:>8}  {:<9}  {} ({})",
+                        format_size(item.size),
+                        languages(info),
+                        info.title,
+                        name
+                    ),
+                    None => format!("{:>8}  {:<9}  {}", format_size(item.size), "?", name),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// `en → de`, or just the headword language for monolingual dictionaries.
+fn languages(info: &DictionaryInfo) -> String {
+    let input = info.input_language.as_deref().unwrap_or("?");
+    match info.output_language.as_deref() {
+        Some(output) if output != input => format!("{} → {}", input, output),
+        _ => input.to_string(),
+    }
+}
+
+/// Uploads the dictionary `local` to `documents/dictionaries` after
+/// checking its header says it is one; an e-book there would never show
+/// up in the library. `force` replaces a dictionary of the same name.
+pub fn run_dict_install(output: &Output, local: &str, force: bool, transfer: TransferOptions) -> Result<()> {
+    let local_path = Path::new(local);
+    if !local_path.is_file() {
+        return Err(Error::FileNotFound(local.to_string()));
+    }
+    let name = local_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .filter(|n| {
+            n.rsplit_once('.')
+                .is_some_and(|(_, extension)| DICTIONARY_EXTENSIONS.iter().any(|e| e.eq_ignore_ascii_case(extension)))
+        })
+        .ok_or_else(|| {
+            Error::InvalidPath(format!(
+                "'{}' isn't a .mobi, .azw or .prc file; Kindle dictionaries are MOBI files",
+                local
+            ))
+        })?;
+    let dictionary = read_local_dictionary(local_path)?.ok_or_else(|| {
+        Error::InvalidPath(format!(
+            "'{}' is a book, not a dictionary (its header has no headword index)",
+            local
+        ))
+    })?;
+    let size = std::fs::metadata(local_path)?.len();
+
+    let session = Session::open(transfer)?;
+    let kindle = session.direct("dict install")?;
+    kindle.create_folder_all(DICTIONARY_FOLDER)?;
+    let remote = format!("{}/{}", DICTIONARY_FOLDER, name);
+    let existing = match kindle.stat(&remote) {
+        Ok(entry) if entry.is_folder => {
+            return Err(Error::InvalidPath(format!("'{}' exists on the device and is a directory", remote)));
+        }
+        Ok(_) => true,
+        Err(Error::FileNotFound(_)) => false,
+        Err(e) => return Err(e),
+    };
+    let policy = if force { OverwritePolicy::Force } else { OverwritePolicy::NoClobber };
+    let action = policy.decide(existing, &remote)?;
+    precheck(output, kindle.storage_info()?.free_bytes, size, 1, false)?;
+    upload_atomically(&session, &remote, action == Action::Replace, |path| {
+        output.timed(&remote, || session.upload_file(local_path, path).map(|()| size))?;
+        Ok(())
+    })?;
+
+    output.print(&DictInstallOutput {
+        local: local.to_string(),
+        remote,
+        bytes: size,
+        dictionary,
+        replaced: action == Action::Replace,
+    });
+    Ok(())
+}
+
+/// Lists the files in `documents/dictionaries` with the languages their
+/// headers name. Through the daemon there are no partial reads, so only
+/// names and sizes.
+pub fn run_dict_list(output: &Output, transfer: TransferOptions) -> Result<()> {
+    let session = Session::open(transfer)?;
+    let entries = match session.walk(DICTIONARY_FOLDER) {
+        Ok(entries) => entries,
+        Err(Error::FileNotFound(_)) => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    let kindle = match session.direct("reading dictionary headers") {
+        Ok(kindle) => Some(kindle),
+        Err(e) => {
+            output.warn(format!("{}; languages aren't shown", e));
+            None
+        }
+    };
+
+    let mut dictionaries = Vec::new();
+    for item in entries.into_iter().filter(|item| !item.entry.is_folder) {
+        let dictionary = match kindle {
+            Some(kindle) => read_dictionary(kindle, &item.path).unwrap_or_else(|e| {
+                output.warn(format!("{}: {}", item.path, e));
+                None
+            }),
+            None => None,
+        };
+        dictionaries.push(InstalledDictionary {
+            path: item.path,
+            size: item.entry.size,
+            dictionary,
+        });
+    }
+    dictionaries.sort_by_cached_key(|d| d.path.to_lowercase());
+    output.print(&DictListOutput {
+        folder: DICTIONARY_FOLDER.to_string(),
+        dictionaries,
+    });
+    Ok(())
+}
@@ -0,0 +1,160 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::sync::normalize_remote_dir;
+use crate::commands::index;
+use crate::device::{Kindle, WalkEntry};
+use crate::error::{Error, Result};
+use crate::index::DeviceIndex;
+use serde::Serialize;
+use schemars::JsonSchema;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Serialize, JsonSchema)]
+pub struct DiffOutput {
+    pub local: String,
+    pub remote: String,
+    pub only_local: Vec<String>,
+    pub only_remote: Vec<String>,
+    pub differing: Vec<DiffEntry>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct DiffEntry {
+    pub path: String,
+    pub local_size: u64,
+    pub remote_size: u64,
+}
+
+impl HumanReadable for DiffOutput {
+    fn to_human(&self) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        lines.extend(self.only_local.iter().map(|p| format!("< {}", p)));
+        lines.extend(self.only_remote.iter().map(|p| format!("> {}", p)));
+        lines.extend(self.differing.iter().map(|d| {
+            format!("~ {} ({} local, {} on device)", d.path, d.local_size, d.remote_size)
+        }));
+        lines.push(format!(
+            "{} only local, {} only on device, {} differ in size",
+            self.only_local.len(),
+            self.only_remote.len(),
+            self.differing.len()
+        ));
+        lines.join("\n")
+    }
+}
+
+/// Compares the files under a local folder with those under a device folder,
+/// by relative path and size. Folders themselves are not reported; dotfiles
+/// are ignored, as `sync` would never upload them.
+///
+/// With `from_index` the device side comes from the index saved by
+/// `kindle-mtp index`, so no device is needed; `refresh` rebuilds that index
+/// from the device first.
+pub fn run_diff(
+    output: &Output,
+    local: &str,
+    remote: &str,
+    from_index: bool,
+    refresh: bool,
+) -> Result<()> {
+    let local_path = Path::new(local);
+    if !local_path.is_dir() {
+        return Err(Error::InvalidPath(format!("'{}' is not a directory", local)));
+    }
+
+    let mut local_files = BTreeMap::new();
+    collect_local(output, local_path, "", &mut local_files)?;
+
+    let remote = normalize_remote_dir(remote);
+    let relative = |item: &WalkEntry| {
+        let relative = item.path[remote.len()..].trim_start_matches('/').to_string();
+        (relative, item.entry.size)
+    };
+    let remote_files: BTreeMap<String, u64> = if from_index || refresh {
+        let index = if refresh {
+            index::refresh(&Kindle::detect()?)?
+        } else {
+            DeviceIndex::load(None)?
+        };
+        index
+            .walk(&remote)
+            .filter(|item| !item.entry.is_folder)
+            .map(relative)
+            .collect()
+    } else {
+        Kindle::detect()?
+            .walk(&remote)?
+            .iter()
+            .filter(|item| !item.entry.is_folder)
+            .map(relative)
+            .collect()
+    };
+
+    let mut diff_output = DiffOutput {
+        local: local.to_string(),
+        remote,
+        only_local: Vec::new(),
+        only_remote: Vec::new(),
+        differing: Vec::new(),
+    };
+
+    for (path, &local_size) in &local_files {
+        match remote_files.get(path) {
+            None => diff_output.only_local.push(path.clone()),
+            Some(&remote_size) if remote_size != local_size => {
+                diff_output.differing.push(DiffEntry {
+                    path: path.clone(),
+                    local_size,
+                    remote_size,
+                });
+            }
+            Some(_) => {}
+        }
+    }
+    diff_output.only_remote = remote_files
+        .into_keys()
+        .filter(|p| !local_files.contains_key(p))
+        .collect();
+
+    output.print(&diff_output);
+    Ok(())
+}
+
+/// Records every file under `dir` as a `/`-separated path relative to the
+/// diff root, so it lines up with device paths on every platform.
+fn collect_local(
+    output: &Output,
+    dir: &Path,
+    prefix: &str,
+    files: &mut BTreeMap<String, u64>,
+) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => {
+                output.warn(format!(
+                    "skipped '{}': file name is not valid UTF-8",
+                    entry.path().display()
+                ));
+                continue;
+            }
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+
+        let relative = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        let metadata = std::fs::metadata(entry.path())?;
+        if metadata.is_dir() {
+            collect_local(output, &entry.path(), &relative, files)?;
+        } else {
+            files.insert(relative, metadata.len());
+        }
+    }
+    Ok(())
+}
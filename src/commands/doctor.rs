@@ -0,0 +1,351 @@
+use crate::cli::{HumanReadable, Output};
+use crate::config::{Config, PerformanceConfig};
+use crate::device::host::{self, Desktop, Grabber};
+use crate::device::{Kindle, TransferOptions};
+use crate::error::{Error, Result};
+use serde::Serialize;
+use schemars::JsonSchema;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Where `--tune` looks for a file to read back.
+const SAMPLE_DIR: &str = "/documents";
+/// Ideal benchmark file size: large enough to hide per-file setup cost, small
+/// enough that the full trial matrix finishes in well under a minute.
+const SAMPLE_TARGET: u64 = 8 * 1024 * 1024;
+const CHUNK_CANDIDATES: [u64; 3] = [256 * 1024, 1024 * 1024, 4 * 1024 * 1024];
+const QUEUE_CANDIDATES: [usize; 2] = [1, 2];
+/// Where udev looks for rules, local overrides first.
+const UDEV_RULE_DIRS: [&str; 3] = ["/etc/udev/rules.d", "/usr/lib/udev/rules.d", "/lib/udev/rules.d"];
+const UDEV_FIX: &str = "create /etc/udev/rules.d/69-kindle.rules containing \
+SUBSYSTEM==\"usb\", ATTR{idVendor}==\"1949\", MODE=\"0660\", TAG+=\"uaccess\" \
+then run `sudo udevadm control --reload && sudo udevadm trigger` and replug the Kindle";
+
+#[derive(Serialize, JsonSchema)]
+pub struct DoctorOutput {
+    pub config_path: Option<String>,
+    pub config_found: bool,
+    pub performance: PerformanceConfig,
+    /// Host problems that commonly keep the device from opening
+    pub checks: Vec<Check>,
+    pub tune: Option<TuneReport>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+    /// What to do about a warning or failure
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fix: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    Warn,
+    Fail,
+    /// Not applicable here, e.g. Linux-only checks on macOS
+    Skip,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct TuneReport {
+    pub sample: String,
+    pub sample_bytes: u64,
+    pub trials: Vec<Trial>,
+    pub suggested: PerformanceConfig,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct Trial {
+    pub chunk_size: u64,
+    pub queue_depth: usize,
+    pub bytes_per_sec: u64,
+}
+
+impl HumanReadable for DoctorOutput {
+    fn to_human(&self) -> String {
+        let mut lines = vec![match (&self.config_path, self.config_found) {
+            (Some(path), true) => format!("Config: {}", path),
+            (Some(path), false) => format!("Config: {} (not found, using defaults)", path),
+            (None, _) => "Config: no home directory, using defaults".to_string(),
+        }];
+        lines.push(format!(
+            "Performance: chunk_size={} queue_depth={} hashing_threads={} io_threads={} device_parallelism={} cached_open={} retries={} retry_delay_ms={}",
+            self.performance.chunk_size,
+            self.performance.queue_depth,
+            self.performance.hashing_threads,
+            self.performance.io_threads,
+            self.performance.device_parallelism,
+            self.performance.cached_open,
+            self.performance.retries,
+            self.performance.retry_delay_ms
+        ));
+        if let Some(timeout) = self.performance.timeout_secs {
+            lines.push(format!("Timeout: {}s per transfer or walk", timeout));
+        }
+        if let Some(rate) = self.performance.limit_rate {
+            lines.push(format!("Rate limit: {} bytes/s", rate));
+        }
+
+        lines.push("\nChecks:".to_string());
+        for check in &self.checks {
+            let status = match check.status {
+                CheckStatus::Ok => "ok",
+                CheckStatus::Warn => "WARN",
+                CheckStatus::Fail => "FAIL",
+                CheckStatus::Skip => "skip",
+            };
+            lines.push(format!("  {:<4}  {}: {}", status, check.name, check.detail));
+            if let Some(fix) = &check.fix {
+                lines.push(format!("        fix: {}", fix));
+            }
+        }
+
+        if let Some(tune) = &self.tune {
+            lines.push(format!("\nRead benchmark: {} ({} bytes)", tune.sample, tune.sample_bytes));
+            for trial in &tune.trials {
+                lines.push(format!(
+                    "  chunk {:>8}  queue {}  {:>6.2} MB/s",
+                    trial.chunk_size,
+                    trial.queue_depth,
+                    trial.bytes_per_sec as f64 / 1_000_000.0
+                ));
+            }
+            lines.push("\nSuggested config:\n".to_string());
+            lines.push(suggested_toml(&tune.suggested));
+        }
+        lines.join("\n")
+    }
+}
+
+fn suggested_toml(performance: &PerformanceConfig) -> String {
+    #[derive(Serialize)]
+    struct Section<'a> {
+        performance: &'a PerformanceConfig,
+    }
+    toml::to_string(&Section { performance }).unwrap_or_default()
+}
+
+/// Reports the effective configuration and checks the host for the usual
+/// reasons a Kindle won't open: USB permissions, desktop services holding
+/// the device, and USB autosuspend. With `tune`, reads one file from the
+/// device under each candidate chunk size and queue depth and suggests the
+/// fastest combination.
+pub fn run_doctor(output: &Output, config: &Config, tune: bool, transfer: TransferOptions) -> Result<()> {
+    let path = Config::path();
+    let mut doctor_output = DoctorOutput {
+        config_found: path.as_ref().is_some_and(|p| p.is_file()),
+        config_path: path.map(|p| p.display().to_string()),
+        performance: PerformanceConfig {
+            chunk_size: transfer.chunk_size as u64,
+            timeout_secs: transfer.timeout.map(|t| t.as_secs()),
+            limit_rate: transfer.rate_limit,
+            ..config.performance.clone()
+        },
+        checks: run_checks(),
+        tune: None,
+    };
+
+    if tune {
+        doctor_output.tune = Some(run_tune(&config.performance, transfer)?);
+    }
+
+    output.print(&doctor_output);
+    Ok(())
+}
+
+fn run_tune(current: &PerformanceConfig, transfer: TransferOptions) -> Result<TuneReport> {
+    let mut kindle = Kindle::detect()?;
+    // The sample comes from the Kindle's own folder layout
+    kindle.info().require_kindle("doctor --tune")?;
+
+    let sample = kindle
+        .walk(SAMPLE_DIR)?
+        .into_iter()
+        .filter(|item| !item.entry.is_folder && item.entry.size > 0)
+        .min_by_key(|item| item.entry.size.abs_diff(SAMPLE_TARGET))
+        .ok_or_else(|| {
+            Error::FileNotFound(format!("no file under {} to benchmark with", SAMPLE_DIR))
+        })?;
+
+    // Warm-up read so the first trial doesn't pay for device-side caching
+    kindle.read_file(&sample.path, |_| Ok(()))?;
+
+    let mut trials = Vec::new();
+    for chunk_size in CHUNK_CANDIDATES {
+        for queue_depth in QUEUE_CANDIDATES {
+            kindle.set_transfer_options(TransferOptions {
+                chunk_size: chunk_size as usize,
+                queue_depth,
+                // Measure what the cable can do, not the configured cap
+                rate_limit: None,
+                ..transfer
+            });
+            let start = Instant::now();
+            let bytes = kindle.read_file(&sample.path, |_| Ok(()))?;
+            let elapsed = start.elapsed().as_secs_f64().max(f64::EPSILON);
+            trials.push(Trial {
+                chunk_size,
+                queue_depth,
+                bytes_per_sec: (bytes as f64 / elapsed) as u64,
+            });
+        }
+    }
+
+    let best = trials
+        .iter()
+        .max_by_key(|t| t.bytes_per_sec)
+        .expect("at least one trial");
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+
+    Ok(TuneReport {
+        suggested: PerformanceConfig {
+            chunk_size: best.chunk_size,
+            queue_depth: best.queue_depth,
+            // One thread reads the device; hashing beyond a few cores can't keep up
+            hashing_threads: cores.clamp(1, 4),
+            io_threads: current.io_threads,
+            device_parallelism: 1,
+            cached_open: current.cached_open,
+            retries: current.retries,
+            retry_delay_ms: current.retry_delay_ms,
+            timeout_secs: current.timeout_secs,
+            limit_rate: current.limit_rate,
+        },
+        sample: sample.path,
+        sample_bytes: sample.entry.size,
+        trials,
+    })
+}
+
+fn run_checks() -> Vec<Check> {
+    let kindles = host::kindle_sysfs_devices(&Kindle::device_filter());
+    vec![
+        check_udev_rule(),
+        check_usb_access(&kindles),
+        check_mtp_grabbers(&host::mtp_grabbers()),
+        check_autosuspend(&kindles),
+    ]
+}
+
+fn check(name: &'static str, status: CheckStatus, detail: impl Into<String>, fix: Option<String>) -> Check {
+    Check {
+        name,
+        status,
+        detail: detail.into(),
+        fix,
+    }
+}
+
+fn skip_off_linux(name: &'static str) -> Option<Check> {
+    (!cfg!(target_os = "linux")).then(|| check(name, CheckStatus::Skip, "only needed on Linux", None))
+}
+
+/// Without a rule only root can open the device node.
+fn check_udev_rule() -> Check {
+    const NAME: &str = "udev_rule";
+    if let Some(skip) = skip_off_linux(NAME) {
+        return skip;
+    }
+    let rule = UDEV_RULE_DIRS
+        .iter()
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .find(|path| {
+            std::fs::read_to_string(path).is_ok_and(|rules| {
+                rules.lines().any(|line| !line.trim_start().starts_with('#') && line.contains("1949"))
+            })
+        });
+    match rule {
+        Some(path) => check(NAME, CheckStatus::Ok, format!("{} covers Amazon devices", path.display()), None),
+        None => check(
+            NAME,
+            CheckStatus::Warn,
+            "no udev rule mentions the Amazon vendor ID 1949",
+            Some(UDEV_FIX.to_string()),
+        ),
+    }
+}
+
+/// Whether this user can open the connected Kindle's USB device node, which
+/// is what libusb needs.
+fn check_usb_access(kindles: &[PathBuf]) -> Check {
+    const NAME: &str = "usb_access";
+    if let Some(skip) = skip_off_linux(NAME) {
+        return skip;
+    }
+    let Some(node) = kindles.iter().find_map(|dir| host::usb_device_node(dir)) else {
+        return check(NAME, CheckStatus::Skip, "no Kindle on the USB bus", None);
+    };
+    match OpenOptions::new().read(true).write(true).open(&node) {
+        Ok(_) => check(NAME, CheckStatus::Ok, format!("{} is readable and writable", node.display()), None),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => check(
+            NAME,
+            CheckStatus::Fail,
+            format!("no permission to open {}", node.display()),
+            Some(UDEV_FIX.to_string()),
+        ),
+        Err(e) => check(NAME, CheckStatus::Warn, format!("could not open {}: {}", node.display(), e), None),
+    }
+}
+
+/// MTP allows one session per device; a file manager that grabbed it first
+/// makes every open here fail or hang.
+fn check_mtp_grabbers(grabbers: &[Grabber]) -> Check {
+    const NAME: &str = "mtp_conflicts";
+    let Some(first) = grabbers.first() else {
+        return check(NAME, CheckStatus::Ok, "no other MTP client is running", None);
+    };
+    let running: Vec<String> = grabbers
+        .iter()
+        .map(|g| format!("{} (pid {})", g.name, g.pid))
+        .collect();
+    let fix = match first.desktop {
+        Desktop::Gvfs => "eject the Kindle in Files, or run `gio mount -u mtp://*` (see `gio mount -l`); \
+            `systemctl --user mask --now gvfs-mtp-volume-monitor` stops the automount for good",
+        Desktop::Kio => "close Dolphin windows showing the Kindle, then run `killall kiod5` (or kiod6)",
+        Desktop::AndroidFileTransfer => "quit Android File Transfer and run `killall \"Android File Transfer Agent\"`",
+        Desktop::ImageCapture => "quit Image Capture and Photos, or run `killall PTPCamera`",
+    };
+    check(
+        NAME,
+        CheckStatus::Warn,
+        format!("{} may hold the device: {}", first.desktop.label(), running.join(", ")),
+        Some(fix.to_string()),
+    )
+}
+
+/// Autosuspend can power the Kindle's port down between commands, which
+/// shows up as timeouts on the next open.
+fn check_autosuspend(kindles: &[PathBuf]) -> Check {
+    const NAME: &str = "usb_autosuspend";
+    if let Some(skip) = skip_off_linux(NAME) {
+        return skip;
+    }
+    let globally_off = host::read_attribute(Path::new("/sys/module/usbcore/parameters"), "autosuspend")
+        .is_some_and(|delay| delay == "-1");
+    if globally_off {
+        return check(NAME, CheckStatus::Ok, "disabled (usbcore.autosuspend=-1)", None);
+    }
+    let Some(kindle) = kindles.first() else {
+        return check(NAME, CheckStatus::Skip, "no Kindle on the USB bus", None);
+    };
+    match host::read_attribute(kindle, "power/control").as_deref() {
+        Some("auto") => check(
+            NAME,
+            CheckStatus::Warn,
+            format!("enabled for {}", kindle.display()),
+            Some(format!(
+                "run `echo on | sudo tee {}/power/control`, or boot with usbcore.autosuspend=-1",
+                kindle.display()
+            )),
+        ),
+        _ => check(NAME, CheckStatus::Ok, format!("off for {}", kindle.display()), None),
+    }
+}
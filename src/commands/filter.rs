@@ -0,0 +1,288 @@
+//! `--include`/`--exclude` patterns for the recursive commands.
+//!
+//! Patterns are written like `.gitignore` lines and matched against paths
+//! relative to the folder the command starts from: `*` and `?` stay within
+//! one path component, `**` crosses them, `[abc]` is a character class. A
+//! pattern without a slash matches a name at any depth (`*.sdr`); one with
+//! a slash matches from the starting folder (`fonts/*.ttf`). A trailing
+//! slash matches only folders. The Kindle's storage is FAT32, so matching
+//! ignores case.
+
+use crate::device::WalkEntry;
+use regex::{Regex, RegexBuilder};
+use std::collections::HashSet;
+
+/// One `--include` or `--exclude` pattern.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    regex: Regex,
+    /// Matches the whole relative path rather than the last name in it
+    anchored: bool,
+    /// Matches folders only
+    folders_only: bool,
+}
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        let folders_only = pattern.ends_with('/');
+        let trimmed = pattern.trim_end_matches('/');
+        let anchored = trimmed.contains('/');
+        let trimmed = trimmed.trim_start_matches('/');
+        if trimmed.is_empty() {
+            return Err(format!("empty pattern '{}'", pattern));
+        }
+        let regex = RegexBuilder::new(&format!("^{}$", glob_to_regex(trimmed)))
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| format!("invalid pattern '{}': {}", pattern, e))?;
+        Ok(Self {
+            regex,
+            anchored,
+            folders_only,
+        })
+    }
+
+    fn matches(&self, relative: &str, is_folder: bool) -> bool {
+        if self.folders_only && !is_folder {
+            return false;
+        }
+        let subject = if self.anchored {
+            relative
+        } else {
+            relative.rsplit('/').next().unwrap_or(relative)
+        };
+        self.regex.is_match(subject)
+    }
+}
+
+/// Which entries of a recursive walk a command takes. An excluded folder
+/// leaves out everything in it. With includes, only files that match one,
+/// or lie in a folder that does, are taken.
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl PathFilter {
+    pub fn new(include: Vec<Pattern>, exclude: Vec<Pattern>) -> Self {
+        Self { include, exclude }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether the folder at `relative` is walked into.
+    pub(crate) fn enters(&self, relative: &str) -> bool {
+        !ancestors(relative).any(|folder| self.exclude.iter().any(|p| p.matches(folder, true)))
+    }
+
+    /// Whether the file at `relative` is taken.
+    pub(crate) fn keeps(&self, relative: &str) -> bool {
+        let (folder, _) = relative.rsplit_once('/').unwrap_or(("", relative));
+        if (!folder.is_empty() && !self.enters(folder)) || self.exclude.iter().any(|p| p.matches(relative, false)) {
+            return false;
+        }
+        self.include.is_empty()
+            || self.include.iter().any(|p| p.matches(relative, false))
+            || ancestors(folder).any(|folder| self.include.iter().any(|p| p.matches(folder, true)))
+    }
+
+    /// Whether an empty folder at `relative` is kept: with includes, only
+    /// folders that match one, since the others were only there to hold
+    /// files.
+    pub(crate) fn keeps_folder(&self, relative: &str) -> bool {
+        self.enters(relative)
+            && (self.include.is_empty()
+                || ancestors(relative).any(|folder| self.include.iter().any(|p| p.matches(folder, true))))
+    }
+
+    /// Drops the entries of a walk of `root` the filter doesn't take. A
+    /// folder stays if it is kept on its own or holds a file that is.
+    pub(crate) fn retain(&self, root: &str, entries: &mut Vec<WalkEntry>) {
+        if self.is_empty() {
+            return;
+        }
+        let root = root.trim_end_matches('/');
+        let relative = |path: &'_ str| -> String { path.strip_prefix(root).unwrap_or(path).trim_start_matches('/').to_string() };
+        let mut needed: HashSet<String> = HashSet::new();
+        for item in entries.iter().filter(|item| !item.entry.is_folder) {
+            let relative = relative(&item.path);
+            if self.keeps(&relative) {
+                needed.extend(ancestors(&relative).skip(1).map(str::to_string));
+            }
+        }
+        entries.retain(|item| {
+            let relative = relative(&item.path);
+            if item.entry.is_folder {
+                needed.contains(&relative) || self.keeps_folder(&relative)
+            } else {
+                self.keeps(&relative)
+            }
+        });
+    }
+}
+
+/// `path` and each folder above it, longest first: `a/b/c`, `a/b`, `a`.
+fn ancestors(path: &str) -> impl Iterator<Item = &str> {
+    let mut next = (!path.is_empty()).then_some(path);
+    std::iter::from_fn(move || {
+        let current = next?;
+        next = current.rsplit_once('/').map(|(parent, _)| parent);
+        Some(current)
+    })
+}
+
+/// The regular expression for a glob, without anchors.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::new();
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    // `**/` also matches no folder at all
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                let mut class = String::new();
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == ']' && !class.is_empty() && class != "!" {
+                        closed = true;
+                        break;
+                    }
+                    class.push(c);
+                }
+                if closed {
+                    let class = match class.strip_prefix('!') {
+                        Some(rest) => format!("^{}", rest),
+                        None => class,
+                    };
+                    regex.push('[');
+                    regex.push_str(&class.replace('\\', "\\\\").replace('[', "\\["));
+                    regex.push(']');
+                } else {
+                    regex.push_str(&regex::escape(&format!("[{}", class)));
+                }
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> PathFilter {
+        let parse = |patterns: &[&str]| patterns.iter().map(|p| Pattern::parse(p).unwrap()).collect();
+        PathFilter::new(parse(include), parse(exclude))
+    }
+
+    #[test]
+    fn unanchored_patterns_match_names_at_any_depth() {
+        let pattern = Pattern::parse("*.sdr").unwrap();
+        assert!(pattern.matches("Book.sdr", true));
+        assert!(pattern.matches("Books/Book.sdr", true));
+        assert!(!pattern.matches("Books/Book.sdr/x.azw3", false));
+    }
+
+    #[test]
+    fn patterns_with_a_slash_match_from_the_start() {
+        let pattern = Pattern::parse("fonts/*.ttf").unwrap();
+        assert!(pattern.matches("fonts/a.ttf", false));
+        assert!(!pattern.matches("x/fonts/a.ttf", false));
+        assert!(!pattern.matches("fonts/sub/a.ttf", false));
+        assert!(Pattern::parse("/a.txt").unwrap().matches("a.txt", false));
+        assert!(!Pattern::parse("/a.txt").unwrap().matches("b/a.txt", false));
+    }
+
+    #[test]
+    fn double_star_crosses_folders() {
+        let pattern = Pattern::parse("docs/**/*.pdf").unwrap();
+        assert!(pattern.matches("docs/a.pdf", false));
+        assert!(pattern.matches("docs/x/y/a.pdf", false));
+        assert!(Pattern::parse("docs/**").unwrap().matches("docs/x/y", false));
+    }
+
+    #[test]
+    fn question_marks_and_classes() {
+        assert!(Pattern::parse("?.txt").unwrap().matches("a.txt", false));
+        assert!(!Pattern::parse("?.txt").unwrap().matches("ab.txt", false));
+        let class = Pattern::parse("[ab]*.txt").unwrap();
+        assert!(class.matches("a1.txt", false));
+        assert!(!class.matches("c1.txt", false));
+        let negated = Pattern::parse("[!ab]*.txt").unwrap();
+        assert!(negated.matches("c1.txt", false));
+        assert!(!negated.matches("a1.txt", false));
+        // An unclosed class is literal
+        assert!(Pattern::parse("[ab").unwrap().matches("[ab", false));
+    }
+
+    #[test]
+    fn regex_characters_are_literal() {
+        let pattern = Pattern::parse("a+b (1).txt").unwrap();
+        assert!(pattern.matches("a+b (1).txt", false));
+        assert!(!pattern.matches("aab (1).txt", false));
+    }
+
+    #[test]
+    fn matching_ignores_case() {
+        assert!(Pattern::parse("*.AZW3").unwrap().matches("book.azw3", false));
+    }
+
+    #[test]
+    fn trailing_slash_matches_folders_only() {
+        let pattern = Pattern::parse("Book.sdr/").unwrap();
+        assert!(pattern.matches("Book.sdr", true));
+        assert!(!pattern.matches("Book.sdr", false));
+    }
+
+    #[test]
+    fn empty_patterns_are_rejected() {
+        assert!(Pattern::parse("").is_err());
+        assert!(Pattern::parse("/").is_err());
+    }
+
+    #[test]
+    fn excluded_folders_leave_out_their_contents() {
+        let filter = filter(&[], &["*.sdr/"]);
+        assert!(!filter.enters("Book.sdr"));
+        assert!(!filter.keeps("Book.sdr/notes.pds"));
+        assert!(!filter.keeps("a/Book.sdr/b/notes.pds"));
+        assert!(filter.keeps("Book.azw3"));
+    }
+
+    #[test]
+    fn includes_take_matching_files_and_folders() {
+        let filter = filter(&["*.azw3", "fonts/"], &[]);
+        assert!(filter.keeps("a/Book.azw3"));
+        assert!(!filter.keeps("a/Book.pdf"));
+        assert!(filter.keeps("fonts/a.ttf"));
+        assert!(filter.keeps_folder("fonts"));
+        assert!(!filter.keeps_folder("a"));
+    }
+
+    #[test]
+    fn excludes_win_over_includes() {
+        let filter = filter(&["*.azw3"], &["old/"]);
+        assert!(filter.keeps("new/Book.azw3"));
+        assert!(!filter.keeps("old/Book.azw3"));
+    }
+
+    #[test]
+    fn ancestors_run_longest_first() {
+        assert_eq!(ancestors("a/b/c").collect::<Vec<_>>(), ["a/b/c", "a/b", "a"]);
+        assert_eq!(ancestors("").count(), 0);
+    }
+}
@@ -0,0 +1,274 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::push::upload_atomically;
+use crate::commands::space::precheck;
+use crate::daemon::Session;
+use crate::device::{Kindle, TransferOptions};
+use crate::error::{Error, Result};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::io::Read;
+use std::path::Path;
+
+/// Digits of the device code in newer serial numbers: base 32 without
+/// I, O, Y and Z.
+const SERIAL_BASE32: &str = "0123456789ABCDEFGHJKLMNPQRSTUVWX";
+
+/// What to do on the Kindle once the update file is on it.
+const NEXT_STEPS: &[&str] = &[
+    "Eject the Kindle and unplug it",
+    "Make sure the battery is above 50%",
+    "On the Kindle, open Settings, then the menu, then \"Update Your Kindle\"",
+    "Leave it alone while it installs; it restarts by itself, sometimes twice",
+];
+
+#[derive(Serialize, JsonSchema)]
+pub struct FwUpdateOutput {
+    pub local: String,
+    pub remote: String,
+    pub bytes: u64,
+    /// Package type from the file's magic, e.g. `FC04`
+    pub package: String,
+    /// Device codes the package is built for, if its header lists them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub devices: Option<Vec<u16>>,
+    /// The connected Kindle's device code, from its serial number
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_code: Option<u16>,
+    /// Whether the package was checked against the connected Kindle;
+    /// false only with `--force`
+    pub model_checked: bool,
+    pub next_steps: Vec<String>,
+}
+
+impl HumanReadable for FwUpdateOutput {
+    fn to_human(&self) -> String {
+        let mut lines = vec![format!(
+            "Uploaded {} -> {} ({} bytes, verified)",
+            self.local, self.remote, self.bytes
+        )];
+        if !self.model_checked {
+            lines.push("Not checked against the connected model (--force)".to_string());
+        }
+        lines.push(String::new());
+        lines.push("Next steps:".to_string());
+        for (n, step) in self.next_steps.iter().enumerate() {
+            lines.push(format!("  {}. {}", n + 1, step));
+        }
+        lines.join("\n")
+    }
+}
+
+/// The header fields of a Kindle update package that matter here.
+struct Package {
+    magic: String,
+    /// Device codes it installs on; `None` for package types whose header
+    /// doesn't say in a readable way
+    devices: Option<Vec<u16>>,
+}
+
+/// Uploads the firmware update `local` to the device root, where the
+/// Kindle looks for it, after checking it is an update package for the
+/// connected model. `force` skips the model check, not the format check.
+pub fn run_fw_update(output: &Output, local: &str, force: bool, transfer: TransferOptions) -> Result<()> {
+    let local_path = Path::new(local);
+    if !local_path.is_file() {
+        return Err(Error::FileNotFound(local.to_string()));
+    }
+    // The Kindle only installs update*.bin from the root folder
+    let name = local_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .filter(|n| n.starts_with("update") && n.ends_with(".bin"))
+        .ok_or_else(|| {
+            Error::InvalidPath(format!(
+                "'{}' isn't named update*.bin, so the Kindle wouldn't find it",
+                local
+            ))
+        })?;
+    let package = read_package(local_path)?;
+    let size = std::fs::metadata(local_path)?.len();
+
+    // Reading the serial and stat-ing the upload need the device directly
+    let session = Session::Direct(Kindle::open(transfer)?);
+    let kindle = session.direct("fw-update")?;
+    let info = kindle.info();
+    info.require_kindle("fw-update")?;
+    let device_code = device_code(&info.serial);
+    let model_checked = match (&package.devices, device_code) {
+        (Some(devices), Some(code)) if devices.contains(&code) => true,
+        _ if force => false,
+        (Some(devices), Some(code)) => {
+            return Err(Error::InvalidPath(format!(
+                "{} is for device codes {}, not this {} ({:#x}); --force uploads it anyway",
+                name,
+                format_codes(devices),
+                info.model,
+                code
+            )));
+        }
+        (None, _) => {
+            return Err(Error::InvalidPath(format!(
+                "can't tell which models a {} package is for; --force uploads it anyway",
+                package.magic
+            )));
+        }
+        (_, None) => {
+            return Err(Error::InvalidPath(format!(
+                "can't tell the model from serial number '{}'; --force uploads it anyway",
+                info.serial
+            )));
+        }
+    };
+
+    let remote = format!("/{}", name);
+    let existing = match kindle.stat(&remote) {
+        Ok(entry) if entry.is_folder => {
+            return Err(Error::InvalidPath(format!("'{}' exists on the device and is a directory", remote)));
+        }
+        Ok(_) => true,
+        Err(Error::FileNotFound(_)) => false,
+        Err(e) => return Err(e),
+    };
+    precheck(output, kindle.storage_info()?.free_bytes, size, 1, false)?;
+    upload_atomically(&session, &remote, existing, |path| {
+        output.timed(&remote, || session.upload_file(local_path, path).map(|()| size))?;
+        let uploaded = kindle.stat(path)?.size;
+        if uploaded != size {
+            return Err(Error::VerificationFailed(format!(
+                "{} is {} bytes on the device, {} locally",
+                remote, uploaded, size
+            )));
+        }
+        Ok(())
+    })?;
+
+    output.print(&FwUpdateOutput {
+        local: local.to_string(),
+        remote,
+        bytes: size,
+        package: package.magic,
+        devices: package.devices,
+        device_code,
+        model_checked,
+        next_steps: NEXT_STEPS.iter().map(|s| s.to_string()).collect(),
+    });
+    Ok(())
+}
+
+/// Reads the header of the update package at `path`. OTA packages list
+/// the devices they are for in clear; recovery and signed packages don't.
+fn read_package(path: &Path) -> Result<Package> {
+    let mut header = Vec::new();
+    std::fs::File::open(path)?.take(4096).read_to_end(&mut header)?;
+    let not_update = || Error::InvalidPath(format!("'{}' is not a Kindle update package", path.display()));
+    let magic = header.get(..4).and_then(|m| std::str::from_utf8(m).ok()).ok_or_else(not_update)?;
+    let u16_at = |offset: usize| header.get(offset..offset + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+    let devices = match magic {
+        // OTA v1: 32-bit source and target revisions, then one device
+        "FC02" | "FD03" => Some(vec![u16_at(12).ok_or_else(not_update)?]),
+        // OTA v2: 64-bit revisions, then a counted list of devices
+        "FC04" | "FD04" | "FL01" => {
+            let count = u16_at(20).ok_or_else(not_update)? as usize;
+            let devices = (0..count)
+                .map(|n| u16_at(22 + 2 * n))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(not_update)?;
+            Some(devices)
+        }
+        "FB01" | "FB02" | "FB03" | "SP01" => None,
+        _ => return Err(not_update()),
+    };
+    Ok(Package {
+        magic: magic.to_string(),
+        devices,
+    })
+}
+
+/// The device code in a Kindle serial number, as update headers list it:
+/// two hex digits after a `B0` or `90` prefix on older models, three base
+/// 32 digits after `G0x` on newer ones.
+pub(crate) fn device_code(serial: &str) -> Option<u16> {
+    let serial = serial.to_ascii_uppercase();
+    if serial.starts_with("B0") || serial.starts_with("90") {
+        return u16::from_str_radix(serial.get(2..4)?, 16).ok();
+    }
+    if serial.starts_with("G0") {
+        return serial
+            .get(3..6)?
+            .chars()
+            .try_fold(0u16, |code, c| Some(code * 32 + SERIAL_BASE32.find(c)? as u16));
+    }
+    None
+}
+
+fn format_codes(codes: &[u16]) -> String {
+    codes.iter().map(|c| format!("{:#x}", c)).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::staging::StagingDir;
+
+    fn package(header: &[u8]) -> Result<Package> {
+        let staging = StagingDir::new("test").unwrap();
+        read_package(&staging.write("update.bin", header).unwrap())
+    }
+
+    #[test]
+    fn device_codes_from_old_serials_are_hex() {
+        assert_eq!(device_code("B0240123456789AB"), Some(0x24));
+        assert_eq!(device_code("9023012345678901"), Some(0x23));
+    }
+
+    #[test]
+    fn device_codes_from_new_serials_are_base32() {
+        // A Paperwhite 3
+        assert_eq!(device_code("G090G10000000000"), Some(0x201));
+        assert_eq!(device_code("g090g10000000000"), Some(0x201));
+        // I and O aren't base 32 digits
+        assert_eq!(device_code("G090I10000000000"), None);
+    }
+
+    #[test]
+    fn device_codes_need_a_known_prefix() {
+        assert_eq!(device_code(""), None);
+        assert_eq!(device_code("G09"), None);
+        assert_eq!(device_code("A1B2C3D4"), None);
+    }
+
+    #[test]
+    fn ota_v1_packages_name_one_device() {
+        let mut header = b"FC02".to_vec();
+        header.resize(12, 0);
+        header.extend_from_slice(&0x24u16.to_le_bytes());
+        let package = package(&header).unwrap();
+        assert_eq!(package.magic, "FC02");
+        assert_eq!(package.devices, Some(vec![0x24]));
+    }
+
+    #[test]
+    fn ota_v2_packages_list_devices() {
+        let mut header = b"FC04".to_vec();
+        header.resize(20, 0);
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&0x201u16.to_le_bytes());
+        header.extend_from_slice(&0x202u16.to_le_bytes());
+        assert_eq!(package(&header).unwrap().devices, Some(vec![0x201, 0x202]));
+
+        // The count promises more devices than the header holds
+        header.truncate(24);
+        assert!(package(&header).is_err());
+    }
+
+    #[test]
+    fn signed_packages_name_no_devices() {
+        assert_eq!(package(b"SP01").unwrap().devices, None);
+    }
+
+    #[test]
+    fn other_files_are_refused() {
+        assert!(package(b"PK\x03\x04").is_err());
+        assert!(package(b"FC").is_err());
+    }
+}
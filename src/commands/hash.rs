@@ -0,0 +1,85 @@
+use crate::cli::{HumanReadable, Output};
+use crate::device::{Kindle, TransferOptions};
+use crate::error::Result;
+use md5::Md5;
+use serde::Serialize;
+use schemars::JsonSchema;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha256,
+    Md5,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct HashOutput {
+    pub remote: String,
+    pub algorithm: HashAlgorithm,
+    pub digest: String,
+    pub bytes: u64,
+}
+
+impl HumanReadable for HashOutput {
+    fn to_human(&self) -> String {
+        // Same layout as sha256sum/md5sum so output can be diffed against local checksums
+        format!("{}  {}", self.digest, self.remote)
+    }
+}
+
+pub fn run_hash(
+    output: &Output,
+    remote: &str,
+    algorithm: HashAlgorithm,
+    transfer: TransferOptions,
+) -> Result<()> {
+    let kindle = Kindle::open(transfer)?;
+
+    let (digest, bytes) = match algorithm {
+        HashAlgorithm::Sha256 => hash_remote::<Sha256>(&kindle, remote)?,
+        HashAlgorithm::Md5 => hash_remote::<Md5>(&kindle, remote)?,
+    };
+
+    let hash_output = HashOutput {
+        remote: remote.to_string(),
+        algorithm,
+        digest,
+        bytes,
+    };
+
+    output.print(&hash_output);
+    Ok(())
+}
+
+pub(crate) fn hash_remote<D: Digest + Send>(kindle: &Kindle, remote: &str) -> Result<(String, u64)> {
+    let mut hasher = D::new();
+    let bytes = kindle.read_file(remote, |chunk| {
+        hasher.update(chunk);
+        Ok(())
+    })?;
+    Ok((to_hex(&hasher.finalize()), bytes))
+}
+
+pub(crate) fn hash_local<D: Digest>(path: &Path) -> Result<(String, u64)> {
+    let mut hasher = D::new();
+    let mut file = File::open(path)?;
+    let mut buf = [0u8; 64 * 1024];
+    let mut bytes = 0u64;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        bytes += n as u64;
+    }
+    Ok((to_hex(&hasher.finalize()), bytes))
+}
+
+pub(crate) fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
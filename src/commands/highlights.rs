@@ -0,0 +1,223 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::clippings::{fetch_clippings, group_by_book, BookClippings, Clipping, ClippingKind};
+use crate::commands::hash::to_hex;
+use crate::commands::sanitize::sanitize_name;
+use crate::daemon::Session;
+use crate::device::TransferOptions;
+use crate::error::Result;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::path::Path;
+
+/// Hex digits of a highlight's ID kept in its marker.
+const ID_LEN: usize = 12;
+
+#[derive(Serialize, JsonSchema)]
+pub struct HighlightsExportOutput {
+    pub dir: String,
+    pub notes: Vec<NoteExport>,
+    /// Highlights and notes written this run, over all books
+    pub added: usize,
+}
+
+/// One book's Markdown note.
+#[derive(Serialize, JsonSchema)]
+pub struct NoteExport {
+    pub book: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    pub file: String,
+    /// Highlights and notes written this run
+    pub added: usize,
+    /// Highlights and notes the book has, duplicates left out
+    pub total: usize,
+    /// The note file didn't exist before, or `--full` rewrote it
+    pub created: bool,
+}
+
+impl HumanReadable for HighlightsExportOutput {
+    fn to_human(&self) -> String {
+        let mut lines: Vec<String> = self
+            .notes
+            .iter()
+            .filter(|note| note.added > 0)
+            .map(|note| {
+                format!(
+                    "  +{:<4} {}{}",
+                    note.added,
+                    note.file,
+                    if note.created { " (new)" } else { "" }
+                )
+            })
+            .collect();
+        lines.push(format!(
+            "{} new highlight{} in {} ({} books)",
+            self.added,
+            if self.added == 1 { "" } else { "s" },
+            self.dir,
+            self.notes.len()
+        ));
+        lines.join("\n")
+    }
+}
+
+/// Writes one Markdown note per book with highlights into `dir`, with
+/// front matter for Obsidian and similar tools. A highlight the reader
+/// extended shows up in the clippings file twice; only the longer one is
+/// kept. Existing notes only get the highlights they don't have yet, found
+/// by the ID marker after each one, so edits made to them survive; `full`
+/// rewrites them instead.
+pub fn run_highlights_export(output: &Output, dir: &str, path: &str, full: bool) -> Result<()> {
+    let session = Session::open(TransferOptions::default())?;
+    let clippings = fetch_clippings(&session, path)?;
+    let dir_path = Path::new(dir);
+    std::fs::create_dir_all(dir_path)?;
+
+    let marker = Regex::new(r"<!-- kindle-mtp:([0-9a-f]+) -->").expect("valid marker pattern");
+    let mut export_output = HighlightsExportOutput {
+        dir: dir.to_string(),
+        notes: Vec::new(),
+        added: 0,
+    };
+    let mut used_names = HashSet::new();
+    for book in group_by_book(clippings) {
+        let highlights = dedupe(book.clippings.iter().filter(|c| c.kind != ClippingKind::Bookmark));
+        if highlights.is_empty() {
+            continue;
+        }
+        let file = note_name(&book, &mut used_names);
+        let note_path = dir_path.join(&file);
+
+        let existing = match std::fs::read_to_string(&note_path) {
+            Ok(text) if !full => Some(text),
+            Ok(_) => None,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let known: HashSet<String> = existing
+            .iter()
+            .flat_map(|text| marker.captures_iter(text).map(|c| c[1].to_string()))
+            .collect();
+        let new: Vec<&Clipping> = highlights.iter().filter(|h| !known.contains(&id(h))).copied().collect();
+
+        if !new.is_empty() {
+            let mut text = match existing.clone() {
+                Some(text) => text,
+                None => front_matter(&book),
+            };
+            if !text.ends_with("\n\n") {
+                text.push_str(if text.ends_with('\n') { "\n" } else { "\n\n" });
+            }
+            for highlight in &new {
+                text.push_str(&render(highlight));
+            }
+            std::fs::write(&note_path, text)?;
+        }
+        export_output.added += new.len();
+        export_output.notes.push(NoteExport {
+            book: book.book,
+            author: book.author,
+            file,
+            added: new.len(),
+            total: highlights.len(),
+            created: existing.is_none(),
+        });
+    }
+
+    output.print(&export_output);
+    Ok(())
+}
+
+/// Highlights and notes in reading order, with a highlight dropped when a
+/// later or longer one at overlapping locations contains its text.
+fn dedupe<'a>(clippings: impl Iterator<Item = &'a Clipping>) -> Vec<&'a Clipping> {
+    let mut kept: Vec<&Clipping> = Vec::new();
+    for clipping in clippings {
+        let same = kept.iter_mut().find(|k| {
+            k.kind == clipping.kind
+                && overlaps(k, clipping)
+                && (k.text.contains(&clipping.text) || clipping.text.contains(&k.text))
+        });
+        match same {
+            Some(k) if clipping.text.len() >= k.text.len() => *k = clipping,
+            Some(_) => {}
+            None => kept.push(clipping),
+        }
+    }
+    kept.sort_by_key(|c| c.location_range().map_or(u32::MAX, |(start, _)| start));
+    kept
+}
+
+/// Whether two clippings are at overlapping locations, or either has none.
+fn overlaps(a: &Clipping, b: &Clipping) -> bool {
+    match (a.location_range(), b.location_range()) {
+        (Some((a_start, a_end)), Some((b_start, b_end))) => a_start <= b_end && b_start <= a_end,
+        _ => true,
+    }
+}
+
+/// Stable ID of a highlight, for finding it again in an existing note.
+fn id(clipping: &Clipping) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(clipping.kind.label().as_bytes());
+    hasher.update([0]);
+    hasher.update(clipping.location.as_deref().unwrap_or_default().as_bytes());
+    hasher.update([0]);
+    hasher.update(clipping.text.as_bytes());
+    to_hex(&hasher.finalize())[..ID_LEN].to_string()
+}
+
+/// The note's file name: the title made safe for any file system, with the
+/// author added when two books share a title.
+fn note_name(book: &BookClippings, used: &mut HashSet<String>) -> String {
+    let mut name = sanitize_name(&format!("{}.md", book.book));
+    if !used.insert(name.to_lowercase())
+        && let Some(author) = &book.author
+    {
+        name = sanitize_name(&format!("{} ({}).md", book.book, author));
+        used.insert(name.to_lowercase());
+    }
+    name
+}
+
+/// YAML front matter and the title heading. Values are JSON strings, which
+/// YAML reads as double-quoted scalars.
+fn front_matter(book: &BookClippings) -> String {
+    let quote = |s: &str| serde_json::to_string(s).unwrap_or_default();
+    let mut text = String::from("---\n");
+    let _ = writeln!(text, "title: {}", quote(&book.book));
+    if let Some(author) = &book.author {
+        let _ = writeln!(text, "author: {}", quote(author));
+    }
+    text.push_str("source: kindle\ntags:\n  - kindle\n  - highlights\n---\n\n");
+    let _ = writeln!(text, "# {}", book.book);
+    text
+}
+
+/// A highlight as a quote, a note as bold text, each followed by where it
+/// is and its ID marker.
+fn render(clipping: &Clipping) -> String {
+    let mut text = String::new();
+    match clipping.kind {
+        ClippingKind::Note => {
+            let _ = writeln!(text, "**Note:** {}", clipping.text);
+        }
+        _ => {
+            for line in clipping.text.lines() {
+                let _ = writeln!(text, "> {}", line);
+            }
+        }
+    }
+    text.push('\n');
+    let position = clipping.position();
+    if !position.is_empty() {
+        let _ = write!(text, "— {} ", position);
+    }
+    let _ = writeln!(text, "<!-- kindle-mtp:{} -->", id(clipping));
+    text.push('\n');
+    text
+}
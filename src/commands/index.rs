@@ -0,0 +1,55 @@
+use crate::cli::{HumanReadable, Output};
+use crate::device::Kindle;
+use crate::error::Result;
+use crate::index::DeviceIndex;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use schemars::JsonSchema;
+
+#[derive(Serialize, JsonSchema)]
+pub struct IndexOutput {
+    pub file: String,
+    pub serial: String,
+    pub model: String,
+    pub created: DateTime<Utc>,
+    pub files: usize,
+    pub folders: usize,
+    pub bytes: u64,
+}
+
+impl HumanReadable for IndexOutput {
+    fn to_human(&self) -> String {
+        format!(
+            "Indexed {} {} into {} ({} files, {} folders, {} bytes)",
+            self.model, self.serial, self.file, self.files, self.folders, self.bytes
+        )
+    }
+}
+
+/// Walks the whole device and stores the result as its offline index.
+pub fn run_index(output: &Output) -> Result<()> {
+    let kindle = Kindle::detect()?;
+    let index = refresh(&kindle)?;
+    output.print(&summary(&index)?);
+    Ok(())
+}
+
+/// Rebuilds and saves the index of `kindle`.
+pub(crate) fn refresh(kindle: &Kindle) -> Result<DeviceIndex> {
+    let index = DeviceIndex::build(kindle)?;
+    index.save()?;
+    Ok(index)
+}
+
+fn summary(index: &DeviceIndex) -> Result<IndexOutput> {
+    let files = index.entries.iter().filter(|item| !item.entry.is_folder);
+    Ok(IndexOutput {
+        file: crate::index::index_path(&index.serial)?.display().to_string(),
+        serial: index.serial.clone(),
+        model: index.model.clone(),
+        created: index.created,
+        files: files.clone().count(),
+        folders: index.entries.iter().filter(|item| item.entry.is_folder).count(),
+        bytes: files.map(|item| item.entry.size).sum(),
+    })
+}
@@ -1,14 +1,18 @@
 use crate::cli::{HumanReadable, Output};
-use crate::device::Kindle;
+use crate::daemon::Session;
+use crate::device::TransferOptions;
 use crate::error::Result;
 use serde::Serialize;
+use schemars::JsonSchema;
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 pub struct InfoOutput {
     pub device: String,
     pub manufacturer: String,
     pub model: String,
     pub serial: String,
+    /// USB vendor and product ID, e.g. "1949:0004"
+    pub usb_id: String,
     pub storage_description: String,
     pub total_bytes: u64,
     pub free_bytes: u64,
@@ -23,6 +27,7 @@ impl HumanReadable for InfoOutput {
              Manufacturer: {}\n\
              Model: {}\n\
              Serial: {}\n\
+             USB ID: {}\n\
              Storage: {} ({:.2}GB)\n\
              Free: {:.2}GB",
             self.device,
@@ -33,6 +38,7 @@ impl HumanReadable for InfoOutput {
             } else {
                 &self.serial
             },
+            self.usb_id,
             self.storage_description,
             total_gb,
             free_gb
@@ -41,15 +47,15 @@ impl HumanReadable for InfoOutput {
 }
 
 pub fn run_info(output: &Output) -> Result<()> {
-    let kindle = Kindle::detect()?;
-    let info = kindle.info();
-    let storage = kindle.storage_info()?;
+    let (info, storage) = Session::open(TransferOptions::default())?.info()?;
 
+    let usb_id = format!("{:04x}:{:04x}", info.vendor_id, info.product_id);
     let info_output = InfoOutput {
         device: info.friendly_name,
         manufacturer: info.manufacturer,
         model: info.model,
         serial: info.serial,
+        usb_id,
         storage_description: storage.description,
         total_bytes: storage.total_bytes,
         free_bytes: storage.free_bytes,
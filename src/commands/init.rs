@@ -0,0 +1,202 @@
+use crate::cli::{HumanReadable, Output};
+use crate::config::{Config, SyncPair};
+use crate::device::Kindle;
+use crate::error::{Error, Result};
+use serde::Serialize;
+use schemars::JsonSchema;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+/// udev rule granting the logged-in user access to Amazon MTP devices.
+#[cfg(target_os = "linux")]
+const UDEV_RULES_PATH: &str = "/etc/udev/rules.d/69-kindle-mtp.rules";
+#[cfg(target_os = "linux")]
+const UDEV_RULE: &str =
+    "SUBSYSTEM==\"usb\", ATTR{idVendor}==\"1949\", MODE=\"0660\", TAG+=\"uaccess\"\n";
+
+#[derive(Serialize, JsonSchema)]
+pub struct InitOutput {
+    pub config_path: String,
+    pub device: Option<String>,
+    pub download_dir: Option<String>,
+    pub sync: Vec<String>,
+    pub udev_rules: Option<String>,
+}
+
+impl HumanReadable for InitOutput {
+    fn to_human(&self) -> String {
+        let mut lines = vec![format!("Wrote {}", self.config_path)];
+        if let Some(dir) = &self.download_dir {
+            lines.push(format!("  downloads: {}", dir));
+        }
+        for pair in &self.sync {
+            lines.push(format!("  sync: {}", pair));
+        }
+        if let Some(rules) = &self.udev_rules {
+            lines.push(format!("  udev rules: {}", rules));
+        }
+        lines.push("Run `kindle-mtp doctor` to check the setup.".to_string());
+        lines.join("\n")
+    }
+}
+
+/// Interactive first-run setup: finds the device, then asks for a download
+/// folder, an optional sync pair and (on Linux) udev rules, and writes the
+/// answers to the config file. Prompts go to stderr so `--json` stays clean.
+pub fn run_init(output: &Output, config: Config) -> Result<()> {
+    if !std::io::stdin().is_terminal() {
+        return Err(Error::InvalidPath(
+            "init is interactive; edit the config file directly instead".to_string(),
+        ));
+    }
+    let config_path = Config::path()
+        .ok_or_else(|| Error::InvalidPath("No home directory for the config file".to_string()))?;
+    let mut config = config;
+    let mut prompt = Prompt::new();
+
+    if config_path.is_file()
+        && !prompt.confirm(
+            &format!("{} exists; update it? (comments will be lost)", config_path.display()),
+            false,
+        )?
+    {
+        return Err(Error::InvalidPath("Setup cancelled".to_string()));
+    }
+
+    // 1. Device
+    eprintln!("Looking for a Kindle...");
+    let device = match Kindle::detect() {
+        Ok(kindle) => {
+            let info = kindle.info();
+            eprintln!("Found {} (serial {})", info.model, info.serial);
+            Some(format!("{} ({})", info.model, info.serial))
+        }
+        Err(e) => {
+            eprintln!("No Kindle found ({}). Setup continues; plug it in later.", e);
+            None
+        }
+    };
+
+    // 2. Download folder
+    let default_dir = config
+        .defaults
+        .download_dir
+        .clone()
+        .or_else(|| dirs::download_dir().map(|d| d.join("Kindle")))
+        .unwrap_or_else(|| PathBuf::from("."));
+    let download_dir = PathBuf::from(prompt.ask(
+        "Download folder for `pull`",
+        &default_dir.display().to_string(),
+    )?);
+    std::fs::create_dir_all(&download_dir)?;
+    config.defaults.download_dir = Some(download_dir);
+
+    // 3. Sync pair
+    if prompt.confirm("Set up a folder to keep in sync?", config.sync.is_empty())? {
+        let local = prompt.ask("Local folder", "./books")?;
+        let remote = prompt.ask("Folder on the Kindle", "/documents")?;
+        let from_device = prompt.ask("Direction: 1) computer -> Kindle  2) Kindle -> computer", "1")? == "2";
+        config.sync.push(if from_device {
+            SyncPair {
+                source: format!("kindle:{}", remote),
+                destination: local,
+            }
+        } else {
+            SyncPair {
+                source: local,
+                destination: format!("kindle:{}", remote),
+            }
+        });
+    }
+
+    // 4. Device permissions
+    let udev_rules = install_udev_rules(&mut prompt, device.is_some())?;
+
+    // 5. Write
+    config.save(&config_path)?;
+
+    let init_output = InitOutput {
+        config_path: config_path.display().to_string(),
+        device,
+        download_dir: config
+            .defaults
+            .download_dir
+            .as_ref()
+            .map(|d| d.display().to_string()),
+        sync: config
+            .sync
+            .iter()
+            .map(|p| format!("{} -> {}", p.source, p.destination))
+            .collect(),
+        udev_rules,
+    };
+    output.print(&init_output);
+    Ok(())
+}
+
+/// Offers to install the udev rule when the device wasn't usable. Writing to
+/// /etc needs root, so on failure the equivalent command is printed instead.
+#[cfg(target_os = "linux")]
+fn install_udev_rules(prompt: &mut Prompt, device_found: bool) -> Result<Option<String>> {
+    if Path::new(UDEV_RULES_PATH).exists() {
+        return Ok(Some(UDEV_RULES_PATH.to_string()));
+    }
+    if device_found || !prompt.confirm("Install udev rules so non-root users can access the Kindle?", true)? {
+        return Ok(None);
+    }
+    match std::fs::write(UDEV_RULES_PATH, UDEV_RULE) {
+        Ok(()) => {
+            eprintln!("Installed {}; replug the Kindle.", UDEV_RULES_PATH);
+            Ok(Some(UDEV_RULES_PATH.to_string()))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            eprintln!("Needs root. Run:");
+            eprintln!(
+                "  echo '{}' | sudo tee {} && sudo udevadm control --reload-rules",
+                UDEV_RULE.trim_end(),
+                UDEV_RULES_PATH
+            );
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn install_udev_rules(_prompt: &mut Prompt, _device_found: bool) -> Result<Option<String>> {
+    // macOS and Windows need no extra permissions for MTP
+    Ok(None)
+}
+
+struct Prompt {
+    stdin: std::io::StdinLock<'static>,
+}
+
+impl Prompt {
+    fn new() -> Self {
+        Self {
+            stdin: std::io::stdin().lock(),
+        }
+    }
+
+    /// Asks a question; an empty answer takes `default`.
+    fn ask(&mut self, question: &str, default: &str) -> Result<String> {
+        eprint!("{} [{}]: ", question, default);
+        std::io::stderr().flush()?;
+        let mut line = String::new();
+        if self.stdin.read_line(&mut line)? == 0 {
+            return Err(Error::InvalidPath("Setup cancelled".to_string()));
+        }
+        let answer = line.trim();
+        Ok(if answer.is_empty() { default } else { answer }.to_string())
+    }
+
+    fn confirm(&mut self, question: &str, default: bool) -> Result<bool> {
+        let answer = self.ask(question, if default { "Y/n" } else { "y/N" })?;
+        Ok(match answer.to_ascii_lowercase().as_str() {
+            "y" | "yes" => true,
+            "n" | "no" => false,
+            _ => default,
+        })
+    }
+}
@@ -0,0 +1,159 @@
+use crate::cli::{Args, HumanReadable, Output};
+use crate::error::Result;
+use clap::{ArgAction, CommandFactory};
+use serde::Serialize;
+use schemars::JsonSchema;
+
+/// The CLI surface as data, so wrappers can generate bindings against the
+/// installed version instead of scraping `--help`.
+#[derive(Serialize, JsonSchema)]
+pub struct CommandInfo {
+    pub name: String,
+    pub about: Option<String>,
+    pub version: Option<String>,
+    pub args: Vec<ArgInfo>,
+    pub subcommands: Vec<CommandInfo>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ArgInfo {
+    pub id: String,
+    /// "flag", "option" or "positional"
+    pub kind: &'static str,
+    /// "bool", "count", "enum" or "string"
+    pub value_type: &'static str,
+    pub long: Option<String>,
+    pub short: Option<char>,
+    pub value_name: Option<String>,
+    pub help: Option<String>,
+    pub required: bool,
+    pub multiple: bool,
+    pub global: bool,
+    pub default: Vec<String>,
+    pub possible_values: Vec<String>,
+}
+
+impl CommandInfo {
+    fn from_clap(cmd: &clap::Command) -> Self {
+        Self {
+            name: cmd.get_name().to_string(),
+            about: cmd.get_about().map(|s| s.to_string()),
+            version: cmd.get_version().map(|s| s.to_string()),
+            args: cmd
+                .get_arguments()
+                .filter(|a| !a.is_hide_set())
+                .map(ArgInfo::from_clap)
+                .collect(),
+            subcommands: cmd
+                .get_subcommands()
+                .filter(|c| !c.is_hide_set())
+                .map(CommandInfo::from_clap)
+                .collect(),
+        }
+    }
+
+    fn write_human(&self, depth: usize, lines: &mut Vec<String>) {
+        let indent = "  ".repeat(depth);
+        lines.push(format!(
+            "{}{}{}",
+            indent,
+            self.name,
+            self.about
+                .as_ref()
+                .map(|a| format!(" - {}", a))
+                .unwrap_or_default()
+        ));
+        for arg in &self.args {
+            lines.push(format!("{}    {}", indent, arg.to_human()));
+        }
+        for sub in &self.subcommands {
+            sub.write_human(depth + 1, lines);
+        }
+    }
+}
+
+impl ArgInfo {
+    fn from_clap(arg: &clap::Arg) -> Self {
+        let action = arg.get_action();
+        let possible_values: Vec<String> = arg
+            .get_possible_values()
+            .iter()
+            .filter(|v| !v.is_hide_set())
+            .map(|v| v.get_name().to_string())
+            .collect();
+
+        let value_type = match action {
+            ArgAction::SetTrue | ArgAction::SetFalse => "bool",
+            ArgAction::Count => "count",
+            _ if !possible_values.is_empty() => "enum",
+            _ => "string",
+        };
+        let kind = if arg.is_positional() {
+            "positional"
+        } else if action.takes_values() {
+            "option"
+        } else {
+            "flag"
+        };
+
+        Self {
+            id: arg.get_id().to_string(),
+            kind,
+            value_type,
+            long: arg.get_long().map(str::to_string),
+            short: arg.get_short(),
+            value_name: arg
+                .get_value_names()
+                .and_then(|names| names.first())
+                .map(|n| n.to_string()),
+            help: arg.get_help().map(|h| h.to_string()),
+            required: arg.is_required_set(),
+            multiple: matches!(action, ArgAction::Append)
+                || arg.get_num_args().is_some_and(|n| n.max_values() > 1),
+            global: arg.is_global_set(),
+            default: arg
+                .get_default_values()
+                .iter()
+                .map(|v| v.to_string_lossy().into_owned())
+                .collect(),
+            // Flags always "accept" true/false; only enums are interesting
+            possible_values: if value_type == "enum" {
+                possible_values
+            } else {
+                Vec::new()
+            },
+        }
+    }
+
+    fn to_human(&self) -> String {
+        let mut name = match (&self.long, self.short) {
+            (Some(long), Some(short)) => format!("-{}, --{}", short, long),
+            (Some(long), None) => format!("--{}", long),
+            (None, Some(short)) => format!("-{}", short),
+            (None, None) => format!("<{}>", self.id),
+        };
+        if !self.possible_values.is_empty() {
+            name.push_str(&format!(" [{}]", self.possible_values.join("|")));
+        }
+        if !self.default.is_empty() {
+            name.push_str(&format!(" (default: {})", self.default.join(",")));
+        }
+        name
+    }
+}
+
+impl HumanReadable for CommandInfo {
+    fn to_human(&self) -> String {
+        let mut lines = Vec::new();
+        self.write_human(0, &mut lines);
+        lines.join("\n")
+    }
+}
+
+pub fn run_introspect(output: &Output) -> Result<()> {
+    let mut cmd = Args::command();
+    // Propagates global args and fills in generated ones like --help
+    cmd.build();
+    output.print(&CommandInfo::from_clap(&cmd));
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::ls::format_size;
+use crate::daemon::Session;
+use crate::device::{TransferOptions, WalkEntry};
+use crate::error::Result;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+#[derive(Serialize, JsonSchema)]
+pub struct LargestOutput {
+    pub path: String,
+    /// The largest files, largest first
+    pub files: Vec<WalkEntry>,
+    /// Files below `path` in all
+    pub total_files: usize,
+    pub total_bytes: u64,
+}
+
+impl HumanReadable for LargestOutput {
+    fn to_human(&self) -> String {
+        if self.files.is_empty() {
+            return format!("No files in {}", self.path);
+        }
+        let mut lines: Vec<String> = self
+            .files
+            .iter()
+            .map(|item| format!("{:>8}  {}", format_size(item.entry.size), item.path))
+            .collect();
+        let shown: u64 = self.files.iter().map(|item| item.entry.size).sum();
+        lines.push(format!(
+            "{} of {} files, {} of {} in {}",
+            self.files.len(),
+            self.total_files,
+            format_size(shown),
+            format_size(self.total_bytes),
+            self.path
+        ));
+        lines.join("\n")
+    }
+}
+
+/// Lists the `count` largest files below `path`.
+pub fn run_largest(output: &Output, path: &str, count: usize) -> Result<()> {
+    let session = Session::open(TransferOptions::default())?;
+    let mut files: Vec<WalkEntry> = session
+        .walk(path)?
+        .into_iter()
+        .filter(|item| !item.entry.is_folder)
+        .collect();
+    let total_files = files.len();
+    let total_bytes = files.iter().map(|item| item.entry.size).sum();
+    files.sort_by(|a, b| b.entry.size.cmp(&a.entry.size).then_with(|| a.path.cmp(&b.path)));
+    files.truncate(count);
+
+    output.print(&LargestOutput {
+        path: path.to_string(),
+        files,
+        total_files,
+        total_bytes,
+    });
+    Ok(())
+}
@@ -1,19 +1,31 @@
+use crate::cli::style::{self, BOLD_BLUE, GREEN};
 use crate::cli::{HumanReadable, Output};
-use crate::device::{Kindle, FileEntry};
+use crate::daemon::Session;
+use crate::device::{FileEntry, TransferOptions};
 use crate::error::Result;
+use schemars::JsonSchema;
+use chrono::{DateTime, Local, Utc};
 use serde::Serialize;
+use std::cmp::Ordering;
 
-#[derive(Serialize)]
+/// Extensions colored as books in human listings.
+const BOOK_EXTENSIONS: &[&str] = &["azw", "azw3", "azw4", "kfx", "mobi", "prc", "epub", "pdf"];
+/// Spaces between columns.
+const COLUMN_GAP: usize = 2;
+
+#[derive(Serialize, JsonSchema)]
 pub struct LsOutput {
     pub path: String,
     pub entries: Vec<LsEntry>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 pub struct LsEntry {
+    /// File name; the full device path in recursive listings
     pub name: String,
     pub size: u64,
     pub is_folder: bool,
+    pub modified: DateTime<Utc>,
 }
 
 impl From<FileEntry> for LsEntry {
@@ -22,6 +34,32 @@ impl From<FileEntry> for LsEntry {
             name: f.name,
             size: f.size,
             is_folder: f.is_folder,
+            modified: f.modified,
+        }
+    }
+}
+
+impl LsEntry {
+    /// The name with folders and books colored, when color is on.
+    fn painted_name(&self) -> String {
+        let is_book = self.name.rsplit_once('.').is_some_and(|(_, ext)| {
+            BOOK_EXTENSIONS.iter().any(|b| b.eq_ignore_ascii_case(ext))
+        });
+        match (self.is_folder, is_book) {
+            (true, _) => style::paint(&self.name, BOLD_BLUE),
+            (false, true) => style::paint(&self.name, GREEN),
+            (false, false) => self.name.clone(),
+        }
+    }
+
+    /// A short-listing cell: the painted name, with `/` after folders, and
+    /// its width on screen.
+    fn cell(&self) -> (String, usize) {
+        let width = self.name.chars().count();
+        if self.is_folder {
+            (format!("{}/", self.painted_name()), width + 1)
+        } else {
+            (self.painted_name(), width)
         }
     }
 }
@@ -31,21 +69,54 @@ impl HumanReadable for LsOutput {
         if self.entries.is_empty() {
             return "(empty)".to_string();
         }
-        self.entries
+        let cells: Vec<(String, usize)> = self.entries.iter().map(LsEntry::cell).collect();
+        match style::terminal_width() {
+            Some(width) => columns(&cells, width),
+            None => cells.into_iter().map(|(text, _)| text).collect::<Vec<_>>().join("\n"),
+        }
+    }
+}
+
+/// Lays cells out top-to-bottom, then left-to-right, in as few rows as fit
+/// `width`, like GNU ls.
+fn columns(cells: &[(String, usize)], width: usize) -> String {
+    let layout = (1..=cells.len())
+        .map(|rows| {
+            let widths: Vec<usize> = cells
+                .chunks(rows)
+                .map(|column| column.iter().map(|(_, w)| *w).max().unwrap_or(0))
+                .collect();
+            (rows, widths)
+        })
+        .find(|(_, widths)| {
+            widths.iter().sum::<usize>() + COLUMN_GAP * widths.len().saturating_sub(1) <= width
+        });
+    let Some((rows, widths)) = layout else {
+        return cells.iter().map(|(text, _)| text.as_str()).collect::<Vec<_>>().join("\n");
+    };
+
+    let mut lines = Vec::with_capacity(rows);
+    for row in 0..rows {
+        let mut line = String::new();
+        let row_cells: Vec<(usize, &(String, usize))> = cells
             .iter()
-            .map(|e| {
-                if e.is_folder {
-                    format!("{}/", e.name)
-                } else {
-                    e.name.clone()
-                }
-            })
-            .collect::<Vec<_>>()
-            .join("\n")
+            .enumerate()
+            .skip(row)
+            .step_by(rows)
+            .collect();
+        for (i, (index, (text, cell_width))) in row_cells.iter().enumerate() {
+            line.push_str(text);
+            if i + 1 < row_cells.len() {
+                let column = index / rows;
+                line.push_str(&" ".repeat(widths[column] - cell_width + COLUMN_GAP));
+            }
+        }
+        lines.push(line);
     }
+    lines.join("\n")
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 pub struct LsOutputLong(pub LsOutput);
 
 impl HumanReadable for LsOutputLong {
@@ -63,14 +134,15 @@ impl HumanReadable for LsOutputLong {
                 } else {
                     format_size(e.size)
                 };
-                format!("{} {:>10}  {}", type_char, size_str, e.name)
+                let modified = e.modified.with_timezone(&Local).format("%Y-%m-%d %H:%M");
+                format!("{} {:>10}  {}  {}", type_char, size_str, modified, e.painted_name())
             })
             .collect::<Vec<_>>()
             .join("\n")
     }
 }
 
-fn format_size(bytes: u64) -> String {
+pub(crate) fn format_size(bytes: u64) -> String {
     if bytes >= 1_000_000_000 {
         format!("{:.1}G", bytes as f64 / 1_000_000_000.0)
     } else if bytes >= 1_000_000 {
@@ -82,14 +154,77 @@ fn format_size(bytes: u64) -> String {
     }
 }
 
-pub fn run_ls(output: &Output, path: &str, long: bool) -> Result<()> {
-    let kindle = Kindle::detect()?;
-    let files = kindle.list_files(path)?;
+/// Listing order. Names sort folders first and ignore case, like the TUI;
+/// size and time put the largest and newest first, like `ls -S` and `ls -t`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LsSort {
+    #[default]
+    Name,
+    Size,
+    Time,
+}
+
+/// How `ls` presents a listing.
+#[derive(Debug, Clone, Default)]
+pub struct LsOptions {
+    pub long: bool,
+    /// List the whole subtree; entries are named by their full path
+    pub recursive: bool,
+    pub sort: LsSort,
+    pub reverse: bool,
+    /// Only files with one of these extensions (case-insensitive, no dot)
+    pub extensions: Vec<String>,
+    pub only_dirs: bool,
+    pub only_files: bool,
+}
+
+impl LsOptions {
+    fn keep(&self, entry: &FileEntry) -> bool {
+        if entry.is_folder {
+            return !self.only_files && self.extensions.is_empty();
+        }
+        if self.only_dirs {
+            return false;
+        }
+        self.extensions.is_empty()
+            || entry.name.rsplit_once('.').is_some_and(|(_, ext)| {
+                self.extensions.iter().any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(ext))
+            })
+    }
+}
+
+pub fn run_ls(output: &Output, path: &str, options: LsOptions) -> Result<()> {
+    let session = Session::open(TransferOptions::default())?;
+    ls(output, &session, path, options)
+}
 
-    let ls_output = LsOutput {
+/// `ls` over an already open session, shared with the shell.
+pub(crate) fn ls(output: &Output, session: &Session, path: &str, options: LsOptions) -> Result<()> {
+    let mut files = if options.recursive {
+        session
+            .walk(path)?
+            .into_iter()
+            .map(|item| FileEntry {
+                name: item.path,
+                ..item.entry
+            })
+            .collect()
+    } else {
+        session.list_files(path)?
+    };
+    files.retain(|f| options.keep(f));
+    sort_entries(&mut files, options.sort, options.reverse, options.recursive);
+    let long = options.long;
+
+    let mut ls_output = LsOutput {
         path: path.to_string(),
         entries: files.into_iter().map(LsEntry::from).collect(),
     };
+    if output.is_ndjson() {
+        for entry in ls_output.entries.drain(..) {
+            output.event("entry", &entry);
+        }
+    }
 
     if long {
         if output.is_json() {
@@ -103,3 +238,24 @@ pub fn run_ls(output: &Output, path: &str, long: bool) -> Result<()> {
 
     Ok(())
 }
+
+/// Recursive listings sort by name path segment by segment, so a folder's
+/// contents directly follow it.
+fn sort_entries(entries: &mut [FileEntry], sort: LsSort, reverse: bool, recursive: bool) {
+    let by_name = |a: &FileEntry, b: &FileEntry| {
+        a.name.to_lowercase().split('/').cmp(b.name.to_lowercase().split('/'))
+    };
+    entries.sort_by(|a, b| match sort {
+        LsSort::Name if recursive => by_name(a, b),
+        LsSort::Name => match (a.is_folder, b.is_folder) {
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            _ => by_name(a, b),
+        },
+        LsSort::Size => b.size.cmp(&a.size).then_with(|| by_name(a, b)),
+        LsSort::Time => b.modified.cmp(&a.modified).then_with(|| by_name(a, b)),
+    });
+    if reverse {
+        entries.reverse();
+    }
+}
@@ -1,9 +1,116 @@
+mod archive;
+mod assert;
+mod audio;
+mod backup;
+mod bench;
+mod books;
+mod batch;
+mod calibre;
+mod catalog;
+mod clean_sdr;
+mod clippings;
+mod collections;
+mod completions;
+mod cover;
+mod daemon;
+mod diff;
+mod dict;
+mod doctor;
+mod filter;
+mod fw_update;
 mod status;
 mod info;
+mod init;
 mod ls;
+mod monitor;
+mod mount;
+mod mtp_debug;
+pub(crate) mod opds;
+mod overwrite;
 mod pull;
+mod hash;
+mod highlights;
+mod index;
+mod introspect;
+mod largest;
+pub(crate) mod push;
+mod restore;
+mod rm;
+mod safe_path;
+mod sanitize;
+mod schema;
+mod screensaver;
+mod search;
+mod send;
+mod serve;
+mod shell;
+mod snapshot;
+mod space;
+pub(crate) mod staging;
+mod stat;
+mod stats;
+mod sync;
+mod thumb;
+mod trash;
+mod tui;
+mod usage;
+mod verify;
+mod vocab;
+mod watch;
+mod writers;
 
+pub use assert::{run_assert, Assertions};
 pub use status::run_status;
 pub use info::run_info;
-pub use ls::run_ls;
-pub use pull::run_pull;
+pub use init::run_init;
+pub use ls::{run_ls, LsOptions, LsSort};
+pub use monitor::run_monitor;
+pub use mount::run_mount;
+pub use mtp_debug::run_mtp_debug;
+pub use overwrite::OverwritePolicy;
+pub use pull::{run_pull, PullOptions};
+pub use hash::{run_hash, HashAlgorithm};
+pub use highlights::run_highlights_export;
+pub use index::run_index;
+pub use introspect::run_introspect;
+pub use largest::run_largest;
+pub use backup::run_backup;
+pub use bench::run_bench;
+pub use books::run_books;
+pub use batch::run_batch;
+pub use calibre::{run_calibre_import, LibraryFilter};
+pub use catalog::{run_catalog, CatalogFormat};
+pub use clean_sdr::run_clean_sdr;
+pub use clippings::run_clippings;
+pub use collections::{run_collections_add, run_collections_list, run_collections_remove};
+pub use completions::{run_complete, run_completions};
+pub use cover::run_cover;
+pub use opds::{run_opds_export, run_opds_serve};
+pub use push::{run_push, PushOptions};
+pub use send::{run_send, SendFormat, SendOptions};
+pub use restore::run_restore;
+pub use rm::run_rm;
+pub use schema::run_schema;
+pub use screensaver::{run_screensaver_list, run_screensaver_push, run_screensaver_rm};
+pub use search::run_search;
+pub use serve::run_serve;
+pub use shell::run_shell;
+pub use snapshot::run_snapshot_export;
+pub use space::{set_free_space_margin, DEFAULT_FREE_SPACE_MARGIN};
+pub use stat::run_stat;
+pub use stats::run_stats;
+pub use sync::{run_sync, run_sync_pairs, SyncOptions};
+pub use thumb::run_thumb;
+pub use trash::{run_trash_list, run_trash_restore};
+pub use tui::run_tui;
+pub use usage::run_usage;
+pub use daemon::run_daemon;
+pub use diff::run_diff;
+pub use audio::{run_audio_ls, run_audio_pull, run_audio_push};
+pub use dict::{run_dict_install, run_dict_list};
+pub use doctor::run_doctor;
+pub use filter::{PathFilter, Pattern};
+pub use fw_update::run_fw_update;
+pub use verify::VerifyMode;
+pub use vocab::run_vocab_export;
+pub use watch::run_watch;
@@ -0,0 +1,128 @@
+use crate::cli::{HumanReadable, Output};
+use crate::device::Kindle;
+use crate::error::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use schemars::JsonSchema;
+use std::process::Command;
+use std::time::Duration;
+
+/// How often the USB bus is rescanned. libmtp exposes no hotplug callback.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, JsonSchema)]
+pub struct MonitorEvent {
+    pub event: &'static str,
+    pub time: DateTime<Utc>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    /// Exit status of the hook, if one ran
+    pub hook_status: Option<i32>,
+}
+
+impl HumanReadable for MonitorEvent {
+    fn to_human(&self) -> String {
+        let mut line = format!(
+            "{} {} {}",
+            self.time.format("%Y-%m-%d %H:%M:%S"),
+            self.event,
+            self.model.as_deref().unwrap_or("Kindle")
+        );
+        if let Some(serial) = &self.serial {
+            line.push_str(&format!(" ({})", serial));
+        }
+        if let Some(status) = self.hook_status {
+            line.push_str(&format!(", hook exited with {}", status));
+        }
+        line
+    }
+}
+
+/// Device identity handed to hooks; kept from connect so the disconnect hook
+/// knows which device left.
+#[derive(Default, Clone)]
+struct DeviceInfo {
+    manufacturer: Option<String>,
+    model: Option<String>,
+    serial: Option<String>,
+}
+
+/// Watches for a Kindle being plugged in or removed and runs the matching
+/// hook through the shell, until interrupted. Hooks get the device in
+/// `KINDLE_MTP_*` environment variables and run to completion before
+/// monitoring resumes, so an `--on-connect 'kindle-mtp backup ...'` has the
+/// device to itself.
+pub fn run_monitor(output: &Output, on_connect: Option<&str>, on_disconnect: Option<&str>) -> Result<()> {
+    output.info("Monitoring for Kindle connections (Ctrl-C to stop)");
+
+    let mut connected = false;
+    let mut device = DeviceInfo::default();
+    loop {
+        let present = Kindle::is_present();
+        if present != connected {
+            connected = present;
+            let (event, hook) = if present {
+                device = read_device_info();
+                ("connect", on_connect)
+            } else {
+                ("disconnect", on_disconnect)
+            };
+
+            let hook_status = hook.map(|cmd| run_hook(output, cmd, event, &device));
+            output.print(&MonitorEvent {
+                event,
+                time: Utc::now(),
+                model: device.model.clone(),
+                serial: device.serial.clone(),
+                hook_status,
+            });
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Opens a session just long enough to read the device identity. A device
+/// that's still starting up may refuse; hooks then get only the event.
+fn read_device_info() -> DeviceInfo {
+    match Kindle::detect() {
+        Ok(kindle) => {
+            let info = kindle.info();
+            DeviceInfo {
+                manufacturer: Some(info.manufacturer),
+                model: Some(info.model),
+                serial: Some(info.serial),
+            }
+        }
+        Err(_) => DeviceInfo::default(),
+    }
+}
+
+fn run_hook(output: &Output, cmd: &str, event: &str, device: &DeviceInfo) -> i32 {
+    let mut command = if cfg!(windows) {
+        let mut c = Command::new("cmd");
+        c.args(["/C", cmd]);
+        c
+    } else {
+        let mut c = Command::new("sh");
+        c.args(["-c", cmd]);
+        c
+    };
+    command.env("KINDLE_MTP_EVENT", event);
+    for (key, value) in [
+        ("KINDLE_MTP_MANUFACTURER", &device.manufacturer),
+        ("KINDLE_MTP_MODEL", &device.model),
+        ("KINDLE_MTP_SERIAL", &device.serial),
+    ] {
+        if let Some(value) = value {
+            command.env(key, value);
+        }
+    }
+
+    match command.status() {
+        Ok(status) => status.code().unwrap_or(-1),
+        Err(e) => {
+            output.info(format!("Warning: could not run hook '{}': {}", cmd, e));
+            -1
+        }
+    }
+}
@@ -0,0 +1,16 @@
+use crate::cli::Output;
+use crate::error::Result;
+
+/// Serves the device as a filesystem at `mountpoint` until it is unmounted.
+#[cfg(feature = "mount")]
+pub fn run_mount(output: &Output, mountpoint: &str) -> Result<()> {
+    let kindle = crate::device::Kindle::detect()?;
+    crate::mount::mount(output, kindle, std::path::Path::new(mountpoint))
+}
+
+#[cfg(not(feature = "mount"))]
+pub fn run_mount(_output: &Output, _mountpoint: &str) -> Result<()> {
+    Err(crate::error::Error::InvalidPath(
+        "this build has no FUSE support; rebuild with `--features mount`".to_string(),
+    ))
+}
@@ -0,0 +1,362 @@
+use crate::cli::{HumanReadable, Output};
+use crate::daemon::Session;
+use crate::device::{MtpCapabilities, RawResponse, TransferOptions};
+use crate::error::Result;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::path::Path;
+
+/// Bytes of a data phase shown as a hex dump; the rest is only counted.
+const HUMAN_DUMP_LIMIT: usize = 512;
+
+/// Names of the standard PTP and MTP operations.
+const OPERATIONS: &[(u16, &str)] = &[
+    (0x1001, "GetDeviceInfo"),
+    (0x1002, "OpenSession"),
+    (0x1003, "CloseSession"),
+    (0x1004, "GetStorageIDs"),
+    (0x1005, "GetStorageInfo"),
+    (0x1006, "GetNumObjects"),
+    (0x1007, "GetObjectHandles"),
+    (0x1008, "GetObjectInfo"),
+    (0x1009, "GetObject"),
+    (0x100A, "GetThumb"),
+    (0x100B, "DeleteObject"),
+    (0x100C, "SendObjectInfo"),
+    (0x100D, "SendObject"),
+    (0x100E, "InitiateCapture"),
+    (0x100F, "FormatStore"),
+    (0x1010, "ResetDevice"),
+    (0x1011, "SelfTest"),
+    (0x1012, "SetObjectProtection"),
+    (0x1013, "PowerDown"),
+    (0x1014, "GetDevicePropDesc"),
+    (0x1015, "GetDevicePropValue"),
+    (0x1016, "SetDevicePropValue"),
+    (0x1017, "ResetDevicePropValue"),
+    (0x1018, "TerminateOpenCapture"),
+    (0x1019, "MoveObject"),
+    (0x101A, "CopyObject"),
+    (0x101B, "GetPartialObject"),
+    (0x101C, "InitiateOpenCapture"),
+    (0x9801, "GetObjectPropsSupported"),
+    (0x9802, "GetObjectPropDesc"),
+    (0x9803, "GetObjectPropValue"),
+    (0x9804, "SetObjectPropValue"),
+    (0x9805, "GetObjectPropList"),
+    (0x9806, "SetObjectPropList"),
+    (0x9807, "GetInterdependentPropDesc"),
+    (0x9808, "SendObjectPropList"),
+    (0x9810, "GetObjectReferences"),
+    (0x9811, "SetObjectReferences"),
+    (0x9820, "Skip"),
+    (0x95C1, "GetPartialObject64"),
+    (0x95C2, "SendPartialObject"),
+    (0x95C3, "TruncateObject"),
+    (0x95C4, "BeginEditObject"),
+    (0x95C5, "EndEditObject"),
+];
+
+/// Names of the standard PTP and MTP response codes.
+const RESPONSES: &[(u16, &str)] = &[
+    (0x2001, "OK"),
+    (0x2002, "GeneralError"),
+    (0x2003, "SessionNotOpen"),
+    (0x2004, "InvalidTransactionID"),
+    (0x2005, "OperationNotSupported"),
+    (0x2006, "ParameterNotSupported"),
+    (0x2007, "IncompleteTransfer"),
+    (0x2008, "InvalidStorageID"),
+    (0x2009, "InvalidObjectHandle"),
+    (0x200A, "DevicePropNotSupported"),
+    (0x200B, "InvalidObjectFormatCode"),
+    (0x200C, "StoreFull"),
+    (0x200D, "ObjectWriteProtected"),
+    (0x200E, "StoreReadOnly"),
+    (0x200F, "AccessDenied"),
+    (0x2010, "NoThumbnailPresent"),
+    (0x2011, "SelfTestFailed"),
+    (0x2012, "PartialDeletion"),
+    (0x2013, "StoreNotAvailable"),
+    (0x2014, "SpecificationByFormatUnsupported"),
+    (0x2015, "NoValidObjectInfo"),
+    (0x2016, "InvalidCodeFormat"),
+    (0x2017, "UnknownVendorCode"),
+    (0x2018, "CaptureAlreadyTerminated"),
+    (0x2019, "DeviceBusy"),
+    (0x201A, "InvalidParentObject"),
+    (0x201B, "InvalidDevicePropFormat"),
+    (0x201C, "InvalidDevicePropValue"),
+    (0x201D, "InvalidParameter"),
+    (0x201E, "SessionAlreadyOpen"),
+    (0x201F, "TransactionCancelled"),
+    (0x2020, "SpecificationOfDestinationUnsupported"),
+    (0xA801, "InvalidObjectPropCode"),
+    (0xA802, "InvalidObjectPropFormat"),
+    (0xA803, "InvalidObjectPropValue"),
+    (0xA804, "InvalidObjectReference"),
+    (0xA806, "InvalidDataset"),
+    (0xA808, "ObjectTooLarge"),
+];
+
+/// Names of the standard PTP and MTP events.
+const EVENTS: &[(u16, &str)] = &[
+    (0x4001, "CancelTransaction"),
+    (0x4002, "ObjectAdded"),
+    (0x4003, "ObjectRemoved"),
+    (0x4004, "StoreAdded"),
+    (0x4005, "StoreRemoved"),
+    (0x4006, "DevicePropChanged"),
+    (0x4007, "ObjectInfoChanged"),
+    (0x4008, "DeviceInfoChanged"),
+    (0x4009, "RequestObjectTransfer"),
+    (0x400A, "StoreFull"),
+    (0x400B, "DeviceReset"),
+    (0x400C, "StorageInfoChanged"),
+    (0x400D, "CaptureComplete"),
+    (0x400E, "UnreportedStatus"),
+    (0xC801, "ObjectPropChanged"),
+    (0xC802, "ObjectPropDescChanged"),
+    (0xC803, "ObjectReferencesChanged"),
+];
+
+/// Names of the standard PTP and MTP device properties.
+const DEVICE_PROPERTIES: &[(u16, &str)] = &[
+    (0x5001, "BatteryLevel"),
+    (0x5002, "FunctionalMode"),
+    (0x5003, "ImageSize"),
+    (0x5011, "DateTime"),
+    (0x5015, "Artist"),
+    (0x5016, "CopyrightInfo"),
+    (0xD401, "SynchronizationPartner"),
+    (0xD402, "DeviceFriendlyName"),
+    (0xD403, "Volume"),
+    (0xD404, "SupportedFormatsOrdered"),
+    (0xD405, "DeviceIcon"),
+    (0xD406, "SessionInitiatorVersionInfo"),
+    (0xD407, "PerceivedDeviceType"),
+    (0xD410, "PlaybackRate"),
+    (0xD411, "PlaybackObject"),
+    (0xD412, "PlaybackContainerIndex"),
+];
+
+/// Names of the object formats a Kindle or a phone is likely to list.
+const FORMATS: &[(u16, &str)] = &[
+    (0x3000, "Undefined"),
+    (0x3001, "Association"),
+    (0x3002, "Script"),
+    (0x3003, "Executable"),
+    (0x3004, "Text"),
+    (0x3005, "HTML"),
+    (0x3006, "DPOF"),
+    (0x3007, "AIFF"),
+    (0x3008, "WAV"),
+    (0x3009, "MP3"),
+    (0x300A, "AVI"),
+    (0x300B, "MPEG"),
+    (0x300C, "ASF"),
+    (0x3801, "EXIF/JPEG"),
+    (0x3804, "BMP"),
+    (0x3807, "GIF"),
+    (0x3808, "JFIF"),
+    (0x380B, "PNG"),
+    (0x380D, "TIFF"),
+    (0xB901, "WMA"),
+    (0xB902, "OGG"),
+    (0xB903, "AAC"),
+    (0xB904, "Audible"),
+    (0xB906, "FLAC"),
+    (0xB982, "MP4"),
+    (0xBA03, "AbstractAudioAlbum"),
+    (0xBA05, "AbstractAVPlaylist"),
+    (0xBA83, "XMLDocument"),
+    (0xBA85, "MSWordDocument"),
+    (0xBA87, "MSExcelSpreadsheet"),
+    (0xBA88, "MSPowerpointPresentation"),
+];
+
+/// An MTP code with its standard name, if it has one.
+#[derive(Serialize, JsonSchema)]
+pub struct MtpCode {
+    pub code: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl MtpCode {
+    fn new(code: u16, names: &[(u16, &str)]) -> Self {
+        Self {
+            code,
+            name: names
+                .iter()
+                .find(|(known, _)| *known == code)
+                .map(|(_, name)| name.to_string()),
+        }
+    }
+
+    fn list(codes: &[u16], names: &[(u16, &str)]) -> Vec<Self> {
+        codes.iter().map(|&code| Self::new(code, names)).collect()
+    }
+}
+
+impl std::fmt::Display for MtpCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{:#06x} {}", self.code, name),
+            None => write!(f, "{:#06x}", self.code),
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct CapabilitiesOutput {
+    pub vendor_extension: String,
+    pub operations: Vec<MtpCode>,
+    pub events: Vec<MtpCode>,
+    pub device_properties: Vec<MtpCode>,
+    pub capture_formats: Vec<MtpCode>,
+    pub playback_formats: Vec<MtpCode>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct RawOperationOutput {
+    pub operation: MtpCode,
+    pub params: Vec<u32>,
+    pub response: MtpCode,
+    pub response_params: Vec<u32>,
+    pub data_bytes: usize,
+    /// The data phase in hex; omitted when it was written to `--out`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_file: Option<String>,
+}
+
+/// `mtp-debug` prints the capabilities, or with `--op` the one operation.
+#[derive(Serialize, JsonSchema)]
+pub struct MtpDebugOutput {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<CapabilitiesOutput>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<RawOperationOutput>,
+}
+
+impl HumanReadable for MtpDebugOutput {
+    fn to_human(&self) -> String {
+        let mut text = String::new();
+        if let Some(capabilities) = &self.capabilities {
+            let _ = writeln!(text, "Vendor extension: {}", capabilities.vendor_extension);
+            for (title, codes) in [
+                ("Operations", &capabilities.operations),
+                ("Events", &capabilities.events),
+                ("Device properties", &capabilities.device_properties),
+                ("Capture formats", &capabilities.capture_formats),
+                ("Playback formats", &capabilities.playback_formats),
+            ] {
+                let _ = writeln!(text, "{} ({}):", title, codes.len());
+                for code in codes {
+                    let _ = writeln!(text, "  {}", code);
+                }
+            }
+        }
+        if let Some(raw) = &self.raw {
+            let _ = writeln!(text, "Operation: {} {}", raw.operation, hex_params(&raw.params));
+            let _ = writeln!(text, "Response: {} {}", raw.response, hex_params(&raw.response_params));
+            match (&raw.data_file, &raw.data) {
+                (Some(file), _) => {
+                    let _ = writeln!(text, "Data: {} bytes written to {}", raw.data_bytes, file);
+                }
+                (None, Some(data)) if raw.data_bytes > 0 => {
+                    let _ = writeln!(text, "Data ({} bytes):", raw.data_bytes);
+                    text.push_str(&hex_dump(data));
+                }
+                _ => {
+                    let _ = writeln!(text, "Data: none");
+                }
+            }
+        }
+        text.trim_end().to_string()
+    }
+}
+
+/// Prints what the device's DeviceInfo dataset lists, or issues operation
+/// `op` with `params` and prints the response. The data phase goes to
+/// `out` when given, else into the output as hex.
+pub fn run_mtp_debug(output: &Output, op: Option<u16>, params: &[u32], out: Option<&Path>) -> Result<()> {
+    let session = Session::open(TransferOptions::default())?;
+    let kindle = session.direct("mtp-debug")?;
+    let Some(op) = op else {
+        let capabilities = kindle.capabilities()?;
+        output.print(&MtpDebugOutput {
+            capabilities: Some(capabilities_output(capabilities)),
+            raw: None,
+        });
+        return Ok(());
+    };
+
+    let RawResponse {
+        code,
+        params: response_params,
+        data,
+    } = kindle.raw_operation(op, params)?;
+    let data_file = match out {
+        Some(path) => {
+            std::fs::write(path, &data)?;
+            Some(path.display().to_string())
+        }
+        None => None,
+    };
+    output.print(&MtpDebugOutput {
+        capabilities: None,
+        raw: Some(RawOperationOutput {
+            operation: MtpCode::new(op, OPERATIONS),
+            params: params.to_vec(),
+            response: MtpCode::new(code, RESPONSES),
+            response_params,
+            data_bytes: data.len(),
+            data: data_file.is_none().then(|| data.iter().map(|b| format!("{:02x}", b)).collect()),
+            data_file,
+        }),
+    });
+    Ok(())
+}
+
+fn capabilities_output(capabilities: MtpCapabilities) -> CapabilitiesOutput {
+    CapabilitiesOutput {
+        vendor_extension: capabilities.vendor_extension,
+        operations: MtpCode::list(&capabilities.operations, OPERATIONS),
+        events: MtpCode::list(&capabilities.events, EVENTS),
+        device_properties: MtpCode::list(&capabilities.device_properties, DEVICE_PROPERTIES),
+        capture_formats: MtpCode::list(&capabilities.capture_formats, FORMATS),
+        playback_formats: MtpCode::list(&capabilities.playback_formats, FORMATS),
+    }
+}
+
+fn hex_params(params: &[u32]) -> String {
+    let params: Vec<String> = params.iter().map(|p| format!("{:#x}", p)).collect();
+    format!("[{}]", params.join(", "))
+}
+
+/// `hexdump -C` style lines for the first `HUMAN_DUMP_LIMIT` bytes of
+/// `hex`, the data phase as two hex digits per byte.
+fn hex_dump(hex: &str) -> String {
+    let bytes: Vec<u8> = hex
+        .as_bytes()
+        .chunks(2)
+        .filter_map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect();
+    let mut text = String::new();
+    for (line, chunk) in bytes.chunks(16).take(HUMAN_DUMP_LIMIT / 16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+            .collect();
+        let _ = writeln!(text, "  {:08x}  {:<47}  |{}|", line * 16, hex.join(" "), ascii);
+    }
+    if bytes.len() > HUMAN_DUMP_LIMIT {
+        let _ = writeln!(text, "  ... {} more bytes (use --out to save them)", bytes.len() - HUMAN_DUMP_LIMIT);
+    }
+    text
+}
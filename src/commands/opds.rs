@@ -0,0 +1,284 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::books::{books, read_headers, Book, BookFormat};
+use crate::commands::hash::to_hex;
+use crate::commands::safe_path::{join_under, prepare_under};
+use crate::daemon::Session;
+use crate::device::{Kindle, TransferOptions, WalkEntry};
+use crate::error::Result;
+use crate::metadata::read_cover;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::Path;
+
+/// File name of the feed `opds --out` writes.
+const FEED_FILE: &str = "catalog.xml";
+/// Media type of the feed.
+pub(crate) const ACQUISITION_FEED_TYPE: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+
+#[derive(Serialize, JsonSchema)]
+pub struct OpdsExportOutput {
+    pub dir: String,
+    /// The feed to point a reading app at
+    pub feed: String,
+    pub books: usize,
+    /// Books downloaded this run; the rest were already there at the same size
+    pub downloaded: usize,
+    pub covers: usize,
+}
+
+impl HumanReadable for OpdsExportOutput {
+    fn to_human(&self) -> String {
+        format!(
+            "Wrote {} with {} book{} ({} downloaded, {} covers)",
+            self.feed,
+            self.books,
+            if self.books == 1 { "" } else { "s" },
+            self.downloaded,
+            self.covers
+        )
+    }
+}
+
+/// Where a feed entry links to.
+struct BookLinks {
+    acquisition: String,
+    /// The cover image and its media type, if known
+    cover: Option<(String, Option<&'static str>)>,
+}
+
+/// The books in a device folder with what their headers say, kept between
+/// updates so a served feed only reads the headers of new or changed books.
+#[derive(Default)]
+pub(crate) struct Catalog {
+    books: HashMap<String, Book>,
+}
+
+impl Catalog {
+    /// The books among `entries`, sorted by title. Headers are read with
+    /// `kindle`; without it titles come from the file names.
+    pub(crate) fn update(&mut self, output: &Output, entries: &[WalkEntry], kindle: Option<&Kindle>) -> Vec<Book> {
+        let mut current = Vec::new();
+        let mut fresh = Vec::new();
+        for book in books(entries) {
+            match self.books.get(&book.path) {
+                Some(known) if known.size == book.size && known.modified == book.modified => {
+                    current.push(known.clone());
+                }
+                _ => fresh.push(book),
+            }
+        }
+        if let Some(kindle) = kindle {
+            read_headers(output, kindle, &mut fresh);
+        }
+        current.extend(fresh);
+        current.sort_by_cached_key(|b| (b.title.to_lowercase(), b.path.clone()));
+        self.books = current.iter().map(|b| (b.path.clone(), b.clone())).collect();
+        current
+    }
+}
+
+/// Writes an OPDS catalog of the books under `path` into `dir`: the feed,
+/// the books themselves under `books/` and their covers under `covers/`,
+/// ready for any static web server. Books already there at the same size
+/// aren't downloaded again.
+pub fn run_opds_export(output: &Output, dir: &str, path: &str) -> Result<()> {
+    let session = Session::open(TransferOptions::default())?;
+    let (info, _) = session.info()?;
+    let entries = session.walk(path)?;
+    let kindle = match session.direct("reading book headers and covers") {
+        Ok(kindle) => Some(kindle),
+        Err(e) => {
+            output.warn(format!("{}; titles are taken from file names and there are no covers", e));
+            None
+        }
+    };
+    let books = Catalog::default().update(output, &entries, kindle);
+
+    let root = Path::new(dir);
+    std::fs::create_dir_all(root.join("covers"))?;
+    let mut export_output = OpdsExportOutput {
+        dir: dir.to_string(),
+        feed: root.join(FEED_FILE).display().to_string(),
+        books: books.len(),
+        downloaded: 0,
+        covers: 0,
+    };
+    let mut links = HashMap::new();
+    for book in &books {
+        let relative = format!("books/{}", relative_path(path, &book.path));
+        let local = join_under(root, &relative)?;
+        if !std::fs::metadata(&local).is_ok_and(|meta| meta.len() == book.size) {
+            prepare_under(root, &local)?;
+            output.timed(&book.path, || session.download_file(&book.path, &local).map(|()| book.size))?;
+            export_output.downloaded += 1;
+        }
+
+        let cover = match kindle.map(|kindle| read_cover(kindle, &book.path)).transpose() {
+            Ok(cover) => cover.flatten(),
+            Err(e) => {
+                output.warn(format!("{}: {}", book.path, e));
+                None
+            }
+        };
+        let cover = match cover {
+            Some(cover) => {
+                let name = format!("covers/{}.{}", entry_id(&book.path), cover.format);
+                std::fs::write(root.join(&name), &cover.data)?;
+                export_output.covers += 1;
+                Some((name, Some(image_type(cover.format))))
+            }
+            None => None,
+        };
+        let acquisition = relative.split('/').map(percent_encode).collect::<Vec<_>>().join("/");
+        links.insert(book.path.clone(), BookLinks { acquisition, cover });
+    }
+
+    let xml = feed(&info.friendly_name, &info.serial, FEED_FILE, &books, |book| {
+        links.remove(&book.path).expect("every book has links")
+    });
+    std::fs::write(root.join(FEED_FILE), xml)?;
+    output.print(&export_output);
+    Ok(())
+}
+
+/// Serves an OPDS feed of the books under `path` on `addr`, with downloads
+/// and covers read from the device on request.
+pub fn run_opds_serve(output: &Output, addr: &str, path: &str, transfer: TransferOptions) -> Result<()> {
+    crate::http::serve_opds(output, addr, path, transfer)
+}
+
+/// The feed `opds --serve` hands out at `/opds`, linking to its download
+/// and cover routes.
+pub(crate) fn served_feed(output: &Output, kindle: &Kindle, catalog: &mut Catalog, root: &str) -> Result<String> {
+    let info = kindle.info();
+    let entries = kindle.walk(if root.is_empty() { "/" } else { root })?;
+    let books = catalog.update(output, &entries, Some(kindle));
+    Ok(feed(&info.friendly_name, &info.serial, "/opds", &books, |book| {
+        let path: String = form_urlencoded::byte_serialize(book.path.as_bytes()).collect();
+        BookLinks {
+            acquisition: format!("/opds/download?path={}", path),
+            // Only MOBI family files and EPUBs store covers `read_cover` finds
+            cover: matches!(
+                book.format,
+                BookFormat::Azw3 | BookFormat::Azw | BookFormat::Mobi | BookFormat::Epub
+            )
+            .then(|| (format!("/opds/cover?path={}", path), None)),
+        }
+    }))
+}
+
+/// The media type a book at `path` is served with.
+pub(crate) fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit_once('.').map_or("", |(_, extension)| extension);
+    BookFormat::from_extension(extension).map_or("application/octet-stream", BookFormat::mime_type)
+}
+
+/// An OPDS 1.2 acquisition feed listing `books`, titled after the device.
+fn feed(
+    title: &str,
+    serial: &str,
+    self_href: &str,
+    books: &[Book],
+    mut links: impl FnMut(&Book) -> BookLinks,
+) -> String {
+    let updated = books.iter().map(|b| b.modified).max().unwrap_or_else(Utc::now);
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(
+        "<feed xmlns=\"http://www.w3.org/2005/Atom\" xmlns:dc=\"http://purl.org/dc/terms/\" xmlns:opds=\"http://opds-spec.org/2010/catalog\">\n",
+    );
+    let _ = writeln!(xml, "  <id>urn:kindle-mtp:{}</id>", escape(serial));
+    let _ = writeln!(xml, "  <title>{}</title>", escape(if title.is_empty() { "Kindle" } else { title }));
+    let _ = writeln!(xml, "  <updated>{}</updated>", timestamp(updated));
+    xml.push_str("  <author><name>kindle-mtp</name></author>\n");
+    for rel in ["self", "start"] {
+        let _ = writeln!(
+            xml,
+            "  <link rel=\"{}\" href=\"{}\" type=\"{}\"/>",
+            rel,
+            escape(self_href),
+            ACQUISITION_FEED_TYPE
+        );
+    }
+    for book in books {
+        let BookLinks { acquisition, cover } = links(book);
+        xml.push_str("  <entry>\n");
+        let _ = writeln!(xml, "    <title>{}</title>", escape(&book.title));
+        let _ = writeln!(xml, "    <id>urn:kindle-mtp:{}:{}</id>", escape(serial), entry_id(&book.path));
+        let _ = writeln!(xml, "    <updated>{}</updated>", timestamp(book.modified));
+        if let Some(author) = &book.author {
+            for name in author.split('&').map(str::trim).filter(|n| !n.is_empty()) {
+                let _ = writeln!(xml, "    <author><name>{}</name></author>", escape(name));
+            }
+        }
+        if let Some(language) = &book.language {
+            let _ = writeln!(xml, "    <dc:language>{}</dc:language>", escape(language));
+        }
+        if let Some(asin) = &book.asin {
+            let _ = writeln!(xml, "    <dc:identifier>urn:asin:{}</dc:identifier>", escape(asin));
+        }
+        if let Some((href, image_type)) = &cover {
+            let type_attr = image_type.map(|t| format!(" type=\"{}\"", t)).unwrap_or_default();
+            for rel in ["http://opds-spec.org/image", "http://opds-spec.org/image/thumbnail"] {
+                let _ = writeln!(xml, "    <link rel=\"{}\" href=\"{}\"{}/>", rel, escape(href), type_attr);
+            }
+        }
+        let _ = writeln!(
+            xml,
+            "    <link rel=\"http://opds-spec.org/acquisition\" href=\"{}\" type=\"{}\" length=\"{}\"/>",
+            escape(&acquisition),
+            escape(book.format.mime_type()),
+            book.size
+        );
+        xml.push_str("  </entry>\n");
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+/// A stable ID for the book at `path`, for entry IDs and cover names.
+fn entry_id(path: &str) -> String {
+    to_hex(&Sha256::digest(path.as_bytes()))[..16].to_string()
+}
+
+/// `path` below the folder `root`, without a leading slash.
+fn relative_path<'a>(root: &str, path: &'a str) -> &'a str {
+    path.strip_prefix(root.trim_end_matches('/')).unwrap_or(path).trim_start_matches('/')
+}
+
+/// The media type of a cover in `format` as `metadata::Cover` names it.
+pub(crate) fn image_type(format: &str) -> &'static str {
+    match format {
+        "png" => "image/png",
+        "gif" => "image/gif",
+        _ => "image/jpeg",
+    }
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// `segment` with everything but unreserved URL characters percent-encoded.
+fn percent_encode(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            let _ = write!(encoded, "%{:02X}", byte);
+        }
+    }
+    encoded
+}
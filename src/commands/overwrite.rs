@@ -0,0 +1,99 @@
+use crate::device::FileEntry;
+use crate::error::{Error, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::Metadata;
+
+/// Devices store timestamps at coarse resolution (FAT32 to two seconds), so
+/// smaller differences don't make either side newer.
+const MODIFIED_SLACK_SECS: i64 = 2;
+
+/// What `pull` and `push` do when the destination file already exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverwritePolicy {
+    /// Fail before transferring anything
+    #[default]
+    NoClobber,
+    /// Replace the existing file
+    Force,
+    /// Leave the existing file alone and carry on
+    SkipExisting,
+    /// Replace the existing file unless it is already up to date
+    Newer,
+}
+
+/// Outcome of applying a policy to one destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Action {
+    Create,
+    Replace,
+    Skip,
+}
+
+impl OverwritePolicy {
+    pub(crate) fn decide(self, exists: bool, path: &str) -> Result<Action> {
+        match (exists, self) {
+            (false, _) => Ok(Action::Create),
+            (true, Self::Force | Self::Newer) => Ok(Action::Replace),
+            (true, Self::SkipExisting) => Ok(Action::Skip),
+            (true, Self::NoClobber) => Err(Error::InvalidPath(format!(
+                "'{}' already exists (use --force to replace it or --skip-existing to keep it)",
+                path
+            ))),
+        }
+    }
+
+    /// [`decide`](Self::decide) for a destination with `existing` on it.
+    /// Under `Newer` one that is up to date with the source is skipped;
+    /// `source` is only asked for then.
+    pub(crate) fn decide_for(
+        self,
+        existing: Option<Stamp>,
+        source: impl FnOnce() -> Result<Stamp>,
+        path: &str,
+    ) -> Result<Action> {
+        match existing {
+            Some(existing) if self == Self::Newer && existing.is_up_to_date_with(source()?) => Ok(Action::Skip),
+            existing => self.decide(existing.is_some(), path),
+        }
+    }
+}
+
+/// Size and modification time of one side of a transfer.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Stamp {
+    pub size: u64,
+    /// Unknown on filesystems that don't record it
+    pub modified: Option<DateTime<Utc>>,
+}
+
+impl Stamp {
+    pub(crate) fn local(metadata: &Metadata) -> Self {
+        Self {
+            size: metadata.len(),
+            modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+        }
+    }
+
+    pub(crate) fn remote(entry: &FileEntry) -> Self {
+        Self {
+            size: entry.size,
+            modified: Some(entry.modified),
+        }
+    }
+
+    /// Whether this destination needs no copy of `source`: it was modified
+    /// after it, or has its size and is no older. A destination changed
+    /// since the last transfer is kept even when the sizes differ.
+    fn is_up_to_date_with(self, source: Stamp) -> bool {
+        let newer = |a: Stamp, b: Stamp| matches!((a.modified, b.modified), (Some(a), Some(b)) if is_newer(a, b));
+        newer(self, source) || (self.size == source.size && !newer(source, self))
+    }
+}
+
+/// Whether `a` is later than `b` by more than the device's timestamps can
+/// tell apart.
+pub(crate) fn is_newer(a: DateTime<Utc>, b: DateTime<Utc>) -> bool {
+    a > b + Duration::seconds(MODIFIED_SLACK_SECS)
+}
@@ -1,31 +1,237 @@
 use crate::cli::{HumanReadable, Output};
-use crate::device::Kindle;
+use crate::commands::archive::TarStream;
+use crate::commands::books::is_sidecar_path;
+use crate::commands::filter::PathFilter;
+use crate::commands::overwrite::{Action, OverwritePolicy, Stamp};
+use crate::commands::safe_path::{join_under, prepare_under};
+use crate::commands::sync::normalize_remote_dir;
+use crate::commands::verify::{verify_transfer, VerifyMode};
+use crate::commands::writers::{with_writers, FileJob, POOLED_FILE_MAX};
+use crate::daemon::Session;
+use crate::device::TransferOptions;
 use crate::error::{Error, Result};
 use serde::Serialize;
-use std::path::Path;
+use schemars::JsonSchema;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 pub struct PullOutput {
     pub remote: String,
     pub local: String,
     pub bytes: u64,
+    pub replaced: bool,
+    pub skipped: bool,
+    pub verified: bool,
 }
 
 impl HumanReadable for PullOutput {
     fn to_human(&self) -> String {
-        format!("Downloaded {} -> {} ({} bytes)", self.remote, self.local, self.bytes)
+        if self.skipped {
+            return format!("Skipped {} ({} already exists)", self.remote, self.local);
+        }
+        format!(
+            "{} {} -> {} ({} bytes){}",
+            if self.replaced { "Replaced" } else { "Downloaded" },
+            self.remote,
+            self.local,
+            self.bytes,
+            if self.verified { ", verified" } else { "" }
+        )
     }
 }
 
-pub fn run_pull(output: &Output, remote: &str, local: &str, recursive: bool) -> Result<()> {
-    if recursive {
-        return Err(Error::Mtp("Recursive download not yet implemented".to_string()));
+#[derive(Serialize, JsonSchema)]
+pub struct PullTreeOutput {
+    pub remote: String,
+    pub local: String,
+    pub files: usize,
+    pub folders: usize,
+    pub bytes: u64,
+    pub skipped: Vec<String>,
+    pub verified: bool,
+}
+
+impl HumanReadable for PullTreeOutput {
+    fn to_human(&self) -> String {
+        let mut line = format!(
+            "Downloaded {} -> {} ({} files, {} folders, {} bytes){}",
+            self.remote,
+            self.local,
+            self.files,
+            self.folders,
+            self.bytes,
+            if self.verified { ", verified" } else { "" }
+        );
+        if !self.skipped.is_empty() {
+            line.push_str(&format!(", {} skipped", self.skipped.len()));
+        }
+        line
     }
+}
 
-    let kindle = Kindle::detect()?;
+/// What one of several remote paths given to `pull` became.
+#[derive(Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum PulledPath {
+    File(PullOutput),
+    Folder(PullTreeOutput),
+}
+
+impl HumanReadable for PulledPath {
+    fn to_human(&self) -> String {
+        match self {
+            Self::File(file) => file.to_human(),
+            Self::Folder(folder) => folder.to_human(),
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct PullManyOutput {
+    pub local: String,
+    /// A result per remote path, in the order given. With NDJSON, single
+    /// files are streamed as `file` events instead.
+    pub pulled: Vec<PulledPath>,
+    pub bytes: u64,
+}
+
+impl HumanReadable for PullManyOutput {
+    fn to_human(&self) -> String {
+        let mut lines: Vec<String> = self.pulled.iter().map(PulledPath::to_human).collect();
+        lines.push(format!("{} paths -> {} ({} bytes)", self.pulled.len(), self.local, self.bytes));
+        lines.join("\n")
+    }
+}
+
+/// How `pull` treats what it downloads.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PullOptions {
+    pub recursive: bool,
+    pub verify: Option<VerifyMode>,
+    pub to_stdout: bool,
+    pub keep_partial: bool,
+    pub overwrite: OverwritePolicy,
+    /// Leave out `.sdr` folders and sidecar files of a recursive pull
+    pub skip_sdr: bool,
+}
+
+/// Downloads files, or folders with `recursive`; `filter` picks what a
+/// recursive download takes.
+pub fn run_pull(
+    output: &Output,
+    paths: &[String],
+    options: PullOptions,
+    filter: &PathFilter,
+    download_dir: &Path,
+    transfer: TransferOptions,
+) -> Result<()> {
+    if options.to_stdout {
+        return pull_to_stdout(paths, options.recursive, options.skip_sdr, filter, transfer);
+    }
+
+    let session = Session::open(transfer)?;
+    pull(output, &session, paths, options, filter, download_dir)
+}
+
+/// `pull` over an already open session, shared with the shell. The last
+/// of several paths is the local destination, a directory when more than
+/// one remote path is given.
+pub(crate) fn pull(
+    output: &Output,
+    session: &Session,
+    paths: &[String],
+    options: PullOptions,
+    filter: &PathFilter,
+    download_dir: &Path,
+) -> Result<()> {
+    let (remotes, local) = match paths {
+        [] => return Err(Error::InvalidPath("Expected <remote>... [local]".to_string())),
+        [remote] => {
+            std::fs::create_dir_all(download_dir)?;
+            (std::slice::from_ref(remote), download_dir)
+        }
+        [remotes @ .., local] => (remotes, Path::new(local.as_str())),
+    };
+
+    if let [remote] = remotes {
+        if options.recursive && session.stat(remote)?.is_folder {
+            let tree_output = pull_tree(output, session, remote, local, options, filter)?;
+            output.print(&tree_output);
+        } else {
+            let pull_output = pull_file(output, session, remote, local, options)?;
+            output.print(&pull_output);
+        }
+        return Ok(());
+    }
+    pull_many(output, session, remotes, local, options, filter)
+}
+
+/// Downloads several remote paths into the directory `local`, creating it.
+/// Every path is looked up before the first download, so a typo in the
+/// last one doesn't leave the others half-done.
+fn pull_many(
+    output: &Output,
+    session: &Session,
+    remotes: &[String],
+    local: &Path,
+    options: PullOptions,
+    filter: &PathFilter,
+) -> Result<()> {
+    if local.exists() && !local.is_dir() {
+        return Err(Error::InvalidPath(format!(
+            "'{}' is not a directory (several paths are pulled into one)",
+            local.display()
+        )));
+    }
+    let mut folders = Vec::with_capacity(remotes.len());
+    for remote in remotes {
+        let is_folder = session.stat(remote)?.is_folder;
+        if is_folder && !options.recursive {
+            return Err(Error::InvalidPath(format!("'{}' is a directory (use -r)", remote)));
+        }
+        folders.push(is_folder);
+    }
+    std::fs::create_dir_all(local)?;
+
+    let mut many_output = PullManyOutput {
+        local: local.display().to_string(),
+        pulled: Vec::new(),
+        bytes: 0,
+    };
+    for (remote, is_folder) in remotes.iter().zip(folders) {
+        if is_folder {
+            let tree_output = pull_tree(output, session, remote, local, options, filter)?;
+            many_output.bytes += tree_output.bytes;
+            many_output.pulled.push(PulledPath::Folder(tree_output));
+            continue;
+        }
+        let pull_output = pull_file(output, session, remote, local, options)?;
+        if !pull_output.skipped {
+            many_output.bytes += pull_output.bytes;
+        }
+        if output.is_ndjson() {
+            output.event("file", &pull_output);
+            continue;
+        }
+        many_output.pulled.push(PulledPath::File(pull_output));
+    }
+    output.print(&many_output);
+    Ok(())
+}
+
+/// Downloads the file `remote` to `local`, or into it when it is a
+/// directory.
+fn pull_file(output: &Output, session: &Session, remote: &str, local: &Path, options: PullOptions) -> Result<PullOutput> {
+    let PullOptions {
+        verify,
+        keep_partial,
+        overwrite,
+        ..
+    } = options;
 
     // Determine the local file path
-    let local_path = Path::new(local);
+    let local_path = local;
     let dest_path = if local_path.is_dir() {
         // Extract filename from remote path
         let filename = remote
@@ -37,19 +243,300 @@ pub fn run_pull(output: &Output, remote: &str, local: &str, recursive: bool) ->
         local_path.to_path_buf()
     };
 
-    kindle.download_file(remote, &dest_path)?;
+    let action = overwrite.decide_for(
+        local_file(&dest_path)?,
+        || Ok(Stamp::remote(&session.stat(remote)?)),
+        &dest_path.display().to_string(),
+    )?;
+    if action != Action::Skip {
+        download(output, session, remote, &dest_path, keep_partial)?;
+
+        if let Some(mode) = verify {
+            verify_transfer(session, remote, &dest_path, mode)?;
+        }
+    }
 
     // Get file size for output
     let bytes = std::fs::metadata(&dest_path)
         .map(|m| m.len())
         .unwrap_or(0);
 
-    let pull_output = PullOutput {
+    Ok(PullOutput {
         remote: remote.to_string(),
         local: dest_path.display().to_string(),
         bytes,
+        replaced: action == Action::Replace,
+        skipped: action == Action::Skip,
+        verified: verify.is_some() && action != Action::Skip,
+    })
+}
+
+/// Copies a device folder like `cp -r`: into `local/<name>` when `local` is an
+/// existing directory, otherwise to `local` itself. Every path derived from a
+/// device name is checked to stay under that root; offending entries are
+/// skipped with a warning rather than written. With the no-clobber policy
+/// every destination is checked before the first download starts.
+fn pull_tree(
+    output: &Output,
+    session: &Session,
+    remote: &str,
+    local: &Path,
+    options: PullOptions,
+    filter: &PathFilter,
+) -> Result<PullTreeOutput> {
+    let PullOptions {
+        verify,
+        keep_partial,
+        overwrite,
+        skip_sdr,
+        ..
+    } = options;
+    // Walked paths are absolute, so the argument is too before it is cut off
+    let base = normalize_remote_dir(remote);
+    let root: PathBuf = if local.is_dir() {
+        let name = base.rsplit('/').next().unwrap_or_default();
+        join_under(local, name)?
+    } else {
+        local.to_path_buf()
     };
+    std::fs::create_dir_all(&root)?;
 
-    output.print(&pull_output);
-    Ok(())
+    let mut tree_output = PullTreeOutput {
+        remote: remote.to_string(),
+        local: root.display().to_string(),
+        files: 0,
+        folders: 0,
+        bytes: 0,
+        skipped: Vec::new(),
+        verified: verify.is_some(),
+    };
+
+    let mut walked = session.walk(&base)?;
+    filter.retain(&base, &mut walked);
+    let mut plan = Vec::new();
+    for item in walked {
+        if skip_sdr && is_sidecar_path(&item.path, item.entry.is_folder) {
+            continue;
+        }
+        let relative = relative_to(&base, &item.path);
+        let local_path = match join_under(&root, relative)
+            .and_then(|p| prepare_under(&root, &p).map(|_| p))
+        {
+            Ok(p) => p,
+            Err(e) => {
+                output.warn(format!("skipped: {}", e.display_chain()));
+                tree_output.skipped.push(item.path);
+                continue;
+            }
+        };
+        let action = if item.entry.is_folder {
+            Action::Create
+        } else {
+            overwrite.decide_for(
+                local_file(&local_path)?,
+                || Ok(Stamp::remote(&item.entry)),
+                &local_path.display().to_string(),
+            )?
+        };
+        plan.push((item, local_path, action));
+    }
+
+    // Small files go through writer threads when this process reads the
+    // device itself; verifying needs each file on disk before moving on
+    let pooled = match session {
+        Session::Direct(kindle) if verify.is_none() => Some(kindle),
+        _ => None,
+    };
+    let threads = pooled.map_or(1, |kindle| kindle.transfer_options().io_threads);
+    with_writers(threads, |writers| {
+        for (item, local_path, action) in plan {
+            if item.entry.is_folder {
+                std::fs::create_dir_all(&local_path)?;
+                tree_output.folders += 1;
+                continue;
+            }
+            let mut event = PullOutput {
+                remote: item.path.clone(),
+                local: local_path.display().to_string(),
+                bytes: item.entry.size,
+                replaced: action == Action::Replace,
+                skipped: action == Action::Skip,
+                verified: verify.is_some(),
+            };
+            if action == Action::Skip {
+                event.bytes = 0;
+                output.event("file", &event);
+                if !output.is_ndjson() {
+                    tree_output.skipped.push(item.path);
+                }
+                continue;
+            }
+
+            match pooled {
+                Some(kindle) if item.entry.size <= POOLED_FILE_MAX => {
+                    let mut data = Vec::with_capacity(item.entry.size as usize);
+                    output.timed(&item.path, || kindle.download_to_writer(&item.path, &mut data))?;
+                    writers.write(FileJob {
+                        path: local_path.clone(),
+                        data,
+                        modified: None,
+                    })?;
+                }
+                _ => download(output, session, &item.path, &local_path, keep_partial)?,
+            }
+            if let Some(mode) = verify {
+                verify_transfer(session, &item.path, &local_path, mode)?;
+            }
+            output.event("file", &event);
+            tree_output.files += 1;
+            tree_output.bytes += item.entry.size;
+        }
+        Ok(())
+    })?;
+
+    Ok(tree_output)
+}
+
+/// Downloads one file. If that fails or is interrupted, the truncated local
+/// file is removed unless `keep_partial`.
+/// Where `path`, found walking the normalized folder `base`, goes below the
+/// local root.
+fn relative_to<'p>(base: &str, path: &'p str) -> &'p str {
+    path.strip_prefix(base).unwrap_or(path).trim_start_matches('/')
+}
+
+fn download(output: &Output, session: &Session, remote: &str, local: &Path, keep_partial: bool) -> Result<()> {
+    let result = output.timed(remote, || {
+        session.download_file(remote, local)?;
+        Ok(std::fs::metadata(local)?.len())
+    });
+    if result.is_err() && !keep_partial {
+        let _ = std::fs::remove_file(local);
+    }
+    result.map(|_| ())
+}
+
+/// The file at a local download destination, if one is already there.
+fn local_file(path: &Path) -> Result<Option<Stamp>> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_dir() => Err(Error::InvalidPath(format!(
+            "'{}' is a directory",
+            path.display()
+        ))),
+        Ok(metadata) => Ok(Some(Stamp::local(&metadata))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes a single file as raw bytes, or anything more (several paths or a
+/// folder) as a tar stream, e.g. `pull --stdout -r /documents | tar -x`.
+fn pull_to_stdout(
+    remotes: &[String],
+    recursive: bool,
+    skip_sdr: bool,
+    filter: &PathFilter,
+    transfer: TransferOptions,
+) -> Result<()> {
+    let session = Session::open(transfer)?;
+    let kindle = session.direct("pull --stdout")?;
+
+    let mut entries = Vec::with_capacity(remotes.len());
+    for remote in remotes {
+        let entry = kindle.stat(remote)?;
+        if entry.is_folder && !recursive {
+            return Err(Error::InvalidPath(format!(
+                "'{}' is a directory (use -r)",
+                remote
+            )));
+        }
+        entries.push((remote.as_str(), entry));
+    }
+
+    let stdout = BufWriter::new(std::io::stdout());
+
+    if let [(remote, entry)] = entries.as_slice()
+        && !entry.is_folder
+    {
+        let mut stdout = stdout;
+        kindle.download_to_writer(remote, &mut stdout)?;
+        return Ok(());
+    }
+
+    let mut archive = TarStream::new(stdout);
+    for (remote, entry) in entries {
+        // Members are named relative to the requested path's parent, so
+        // `/documents` unpacks as `documents/...`
+        let base = remote.trim_end_matches('/');
+        let prefix_len = base.rfind('/').map(|i| i + 1).unwrap_or(0);
+
+        if !entry.is_folder {
+            archive.append_remote_file(kindle, remote, &base[prefix_len..], &entry)?;
+            continue;
+        }
+
+        archive.append_dir(&base[prefix_len..], &entry)?;
+        let mut walked = kindle.walk(remote)?;
+        filter.retain(remote, &mut walked);
+        for item in walked {
+            if skip_sdr && is_sidecar_path(&item.path, item.entry.is_folder) {
+                continue;
+            }
+            let archive_path = &item.path[prefix_len.min(item.path.len())..];
+            let archive_path = archive_path.trim_start_matches('/');
+            if item.entry.is_folder {
+                archive.append_dir(archive_path, &item.entry)?;
+            } else {
+                archive.append_remote_file(kindle, &item.path, archive_path, &item.entry)?;
+            }
+        }
+    }
+    archive.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::filter::Pattern;
+    use crate::device::{FileEntry, WalkEntry};
+    use chrono::Utc;
+
+    fn walk_entry(path: &str, is_folder: bool) -> WalkEntry {
+        WalkEntry {
+            path: path.to_string(),
+            entry: FileEntry {
+                name: path.rsplit('/').next().unwrap_or_default().to_string(),
+                size: 0,
+                is_folder,
+                id: 0,
+                modified: Utc::now(),
+            },
+        }
+    }
+
+    #[test]
+    fn relative_paths_ignore_how_the_folder_was_written() {
+        for remote in ["documents", "/documents", "/documents/", "documents/"] {
+            let base = normalize_remote_dir(remote);
+            assert_eq!(relative_to(&base, "/documents/foo.azw3"), "foo.azw3", "{}", remote);
+            assert_eq!(relative_to(&base, "/documents/Books/bar.azw3"), "Books/bar.azw3", "{}", remote);
+        }
+        assert_eq!(relative_to(&normalize_remote_dir("/"), "/documents/foo.azw3"), "documents/foo.azw3");
+    }
+
+    #[test]
+    fn filters_match_paths_below_the_normalized_folder() {
+        let filter = PathFilter::new(vec![Pattern::parse("Books/*.azw3").unwrap()], Vec::new());
+        for remote in ["documents", "/documents/"] {
+            let base = normalize_remote_dir(remote);
+            let mut walked = vec![
+                walk_entry("/documents/Books", true),
+                walk_entry("/documents/Books/bar.azw3", false),
+                walk_entry("/documents/foo.azw3", false),
+            ];
+            filter.retain(&base, &mut walked);
+            let kept: Vec<&str> = walked.iter().map(|item| item.path.as_str()).collect();
+            assert_eq!(kept, ["/documents/Books", "/documents/Books/bar.azw3"], "{}", remote);
+        }
+    }
 }
@@ -0,0 +1,570 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::calibre::update_device_metadata;
+use crate::commands::filter::PathFilter;
+use crate::commands::overwrite::{Action, OverwritePolicy, Stamp};
+use crate::commands::sanitize::sanitize_name;
+use crate::commands::space::precheck;
+use crate::commands::verify::{verify_transfer, VerifyMode};
+use crate::daemon::Session;
+use crate::device::{FileEntry, TransferOptions};
+use crate::error::{Error, Result};
+use serde::Serialize;
+use schemars::JsonSchema;
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::Path;
+use tracing::debug;
+
+#[derive(Serialize, JsonSchema)]
+pub struct PushOutput {
+    pub local: String,
+    pub remote: String,
+    pub bytes: u64,
+    pub replaced: bool,
+    pub skipped: bool,
+    pub verified: bool,
+    pub dry_run: bool,
+    /// The name before `--sanitize` or `--rename-on-conflict` changed it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub renamed_from: Option<String>,
+}
+
+impl HumanReadable for PushOutput {
+    fn to_human(&self) -> String {
+        let line = self.summary();
+        match &self.renamed_from {
+            Some(name) => format!("{} (renamed from '{}')", line, name),
+            None => line,
+        }
+    }
+}
+
+impl PushOutput {
+    fn summary(&self) -> String {
+        if self.skipped {
+            return format!("Skipped {} ({} already exists)", self.local, self.remote);
+        }
+        if self.dry_run {
+            return format!(
+                "Would {} {} -> {} ({} bytes)",
+                if self.replaced { "replace" } else { "upload" },
+                self.local,
+                self.remote,
+                self.bytes
+            );
+        }
+        format!(
+            "{} {} -> {} ({} bytes){}",
+            if self.replaced { "Replaced" } else { "Uploaded" },
+            self.local,
+            self.remote,
+            self.bytes,
+            if self.verified { ", verified" } else { "" }
+        )
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct PushTreeOutput {
+    pub local: String,
+    pub remote: String,
+    pub files: usize,
+    /// Folders created on the device
+    pub folders: usize,
+    pub bytes: u64,
+    /// Files left alone because they were already there
+    pub skipped: Vec<String>,
+    /// Local entries that can't go onto the device: hidden files, names
+    /// that aren't UTF-8, and anything that isn't a file or folder
+    pub unsupported: Vec<String>,
+    pub dry_run: bool,
+}
+
+impl HumanReadable for PushTreeOutput {
+    fn to_human(&self) -> String {
+        let mut line = format!(
+            "{} {} -> {} ({} files, {} folders, {} bytes)",
+            if self.dry_run { "Would upload" } else { "Uploaded" },
+            self.local,
+            self.remote,
+            self.files,
+            self.folders,
+            self.bytes
+        );
+        if !self.skipped.is_empty() {
+            line.push_str(&format!(", {} skipped", self.skipped.len()));
+        }
+        if !self.unsupported.is_empty() {
+            line.push_str(&format!(", {} unsupported", self.unsupported.len()));
+        }
+        line
+    }
+}
+
+/// How `push` treats what it uploads.
+#[derive(Debug, Clone, Copy)]
+pub struct PushOptions {
+    /// Upload a local folder with everything in it
+    pub recursive: bool,
+    pub verify: Option<VerifyMode>,
+    pub overwrite: OverwritePolicy,
+    pub dry_run: bool,
+    /// Make the device file name safe, see `sanitize_name`
+    pub sanitize: bool,
+    /// Upload next to an existing file as `Name (1).ext` instead of
+    /// applying `overwrite`
+    pub rename_on_conflict: bool,
+    /// Upload under a temporary name and rename once complete, see
+    /// `upload_atomically`
+    pub atomic: bool,
+}
+
+impl Default for PushOptions {
+    fn default() -> Self {
+        Self {
+            recursive: false,
+            verify: None,
+            overwrite: OverwritePolicy::default(),
+            dry_run: false,
+            sanitize: false,
+            rename_on_conflict: false,
+            atomic: true,
+        }
+    }
+}
+
+/// Uploads one file, or a folder tree with `recursive` taking what `filter`
+/// allows. An existing file at the destination is handled by `overwrite`;
+/// it's never left in place next to the upload, since MTP would happily
+/// store a second object of the same name.
+pub fn run_push(
+    output: &Output,
+    local: &str,
+    remote: &str,
+    options: PushOptions,
+    filter: &PathFilter,
+    transfer: TransferOptions,
+) -> Result<()> {
+    if local == "-" {
+        if options.verify.is_some() {
+            return Err(Error::InvalidPath("--verify needs a local file, not stdin".to_string()));
+        }
+        let session = Session::open(transfer)?;
+        return push_stdin(output, &session, remote, options);
+    }
+    let local_path = Path::new(local);
+    if local_path.is_dir() {
+        if !options.recursive {
+            return Err(Error::InvalidPath(format!("'{}' is a directory (use -r)", local)));
+        }
+        let session = Session::open(transfer)?;
+        return push_tree(output, &session, local_path, remote, options, filter);
+    }
+    if !local_path.is_file() {
+        return Err(Error::FileNotFound(local.to_string()));
+    }
+    let session = Session::open(transfer)?;
+    push(output, &session, local, remote, options)
+}
+
+/// Copies a local folder onto the device like `cp -r`: into `remote/<name>`
+/// when `remote` is an existing folder, otherwise to `remote` itself,
+/// creating folders as needed. Every file is found and the space checked
+/// before the first upload; each file then goes up as `push` would upload
+/// it on its own.
+fn push_tree(
+    output: &Output,
+    session: &Session,
+    local: &Path,
+    remote: &str,
+    options: PushOptions,
+    filter: &PathFilter,
+) -> Result<()> {
+    let name = local
+        .canonicalize()?
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map(str::to_string)
+        .ok_or_else(|| Error::InvalidPath(format!("'{}' has no usable folder name", local.display())))?;
+    let remote = remote.trim_end_matches('/');
+    let root = match session.stat(if remote.is_empty() { "/" } else { remote }) {
+        Ok(entry) if entry.is_folder => format!("{}/{}", remote, folder_name(&name, options.sanitize)),
+        Ok(_) => {
+            return Err(Error::InvalidPath(format!(
+                "'{}' exists on the device and is not a directory",
+                remote
+            )))
+        }
+        Err(Error::FileNotFound(_)) => remote.to_string(),
+        Err(e) => return Err(e),
+    };
+
+    let mut tree_output = PushTreeOutput {
+        local: local.display().to_string(),
+        remote: root.clone(),
+        files: 0,
+        folders: 0,
+        bytes: 0,
+        skipped: Vec::new(),
+        unsupported: Vec::new(),
+        dry_run: options.dry_run,
+    };
+    let mut folders = vec![(root.clone(), String::new())];
+    let mut files = Vec::new();
+    let mut pending = vec![(local.to_path_buf(), root, String::new())];
+    while let Some((local_dir, remote_dir, relative_dir)) = pending.pop() {
+        let mut entries = std::fs::read_dir(&local_dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            let file_type = std::fs::metadata(&path).map(|meta| meta.file_type());
+            let name = match entry.file_name().into_string() {
+                Ok(name) if !name.starts_with('.') => name,
+                _ => {
+                    tree_output.unsupported.push(path.display().to_string());
+                    continue;
+                }
+            };
+            let relative = if relative_dir.is_empty() { name.clone() } else { format!("{}/{}", relative_dir, name) };
+            match file_type {
+                Ok(file_type) if file_type.is_dir() => {
+                    if !filter.enters(&relative) {
+                        continue;
+                    }
+                    let remote_path = format!("{}/{}", remote_dir, folder_name(&name, options.sanitize));
+                    folders.push((remote_path.clone(), relative.clone()));
+                    pending.push((path, remote_path, relative));
+                }
+                Ok(file_type) if file_type.is_file() => {
+                    if filter.keeps(&relative) {
+                        files.push((path, format!("{}/{}", remote_dir, name)));
+                    }
+                }
+                _ => tree_output.unsupported.push(path.display().to_string()),
+            }
+        }
+    }
+    // With --include, folders are only there to hold the files it picks
+    folders.retain(|(remote_folder, relative)| {
+        relative.is_empty()
+            || filter.keeps_folder(relative)
+            || files.iter().any(|(_, remote_file)| remote_file.starts_with(&format!("{}/", remote_folder)))
+    });
+    for path in &tree_output.unsupported {
+        output.warn(format!("skipped '{}': not a file that can go onto the device", path));
+    }
+
+    let mut needed = 0;
+    for (path, _) in &files {
+        needed += std::fs::metadata(path)?.len();
+    }
+    let (_, storage) = session.info()?;
+    precheck(output, storage.free_bytes, needed, files.len(), options.dry_run)?;
+
+    let kindle = session.direct("push -r")?;
+    for (folder, _) in &folders {
+        if session.stat(folder).is_err() {
+            if !options.dry_run {
+                kindle.create_folder_all(folder)?;
+            }
+            tree_output.folders += 1;
+        }
+    }
+    let mut uploads = Vec::new();
+    for (path, remote_path) in files {
+        let local = path.to_string_lossy();
+        let file_output = push_file(output, session, &local, &remote_path, options)?;
+        if file_output.skipped {
+            if !output.is_ndjson() {
+                tree_output.skipped.push(file_output.local.clone());
+            }
+        } else {
+            tree_output.files += 1;
+            tree_output.bytes += file_output.bytes;
+            if !options.dry_run {
+                uploads.push((file_output.remote.clone(), path));
+            }
+        }
+        output.event("file", &file_output);
+    }
+    if !uploads.is_empty() {
+        update_device_metadata(output, session, |metadata| {
+            for (remote, local) in &uploads {
+                metadata.record_upload(remote, local, None);
+            }
+        });
+    }
+    output.print(&tree_output);
+    Ok(())
+}
+
+/// A local folder's name on the device, sanitized like file names with
+/// `--sanitize`.
+fn folder_name(name: &str, sanitize: bool) -> String {
+    if sanitize { sanitize_name(name) } else { name.to_string() }
+}
+
+/// `push - <remote>`: uploads stdin to a file path. MTP needs the size
+/// before the first byte, so the input is read into memory first.
+fn push_stdin(output: &Output, session: &Session, remote: &str, options: PushOptions) -> Result<()> {
+    let PushOptions {
+        overwrite, dry_run, ..
+    } = options;
+    let kindle = session.direct("push from stdin")?;
+    if remote == "/" || session.stat(remote).is_ok_and(|e| e.is_folder) {
+        return Err(Error::InvalidPath(format!(
+            "'{}' is a folder; give the file name to create",
+            remote
+        )));
+    }
+    let mut data = Vec::new();
+    std::io::stdin().lock().read_to_end(&mut data)?;
+
+    let (remote, renamed_from) = destination(session, remote, options)?;
+    let remote = remote.as_str();
+    let existing = existing_file(session, remote)?;
+    // What arrives on stdin is as new as it gets
+    let source = Stamp {
+        size: data.len() as u64,
+        modified: Some(chrono::Utc::now()),
+    };
+    let action = overwrite.decide_for(existing.as_ref().map(Stamp::remote), || Ok(source), remote)?;
+    let replaced = action == Action::Replace;
+    if action != Action::Skip {
+        let freed = existing.as_ref().filter(|_| !options.atomic);
+        check_space(output, session, data.len() as u64, freed, dry_run)?;
+    }
+    if !dry_run && action != Action::Skip {
+        upload(session, remote, replaced, options.atomic, |path| {
+            let size = data.len() as u64;
+            output.timed(remote, || kindle.upload_from_reader(path, &mut data.as_slice(), size).map(|()| size))?;
+            Ok(())
+        })?;
+    }
+
+    output.print(&PushOutput {
+        local: "-".to_string(),
+        remote: remote.to_string(),
+        bytes: data.len() as u64,
+        replaced,
+        skipped: action == Action::Skip,
+        verified: false,
+        dry_run,
+        renamed_from,
+    });
+    Ok(())
+}
+
+/// `push` over an already open session, shared with the shell.
+pub(crate) fn push(
+    output: &Output,
+    session: &Session,
+    local: &str,
+    remote: &str,
+    options: PushOptions,
+) -> Result<()> {
+    let push_output = push_file(output, session, local, remote, options)?;
+    if !push_output.skipped && !push_output.dry_run {
+        update_device_metadata(output, session, |metadata| {
+            metadata.record_upload(&push_output.remote, Path::new(local), None);
+        });
+    }
+    output.print(&push_output);
+    Ok(())
+}
+
+/// Uploads one file as `push` does and returns what happened instead of
+/// printing it, for commands that report more (`send`).
+pub(crate) fn push_file(
+    output: &Output,
+    session: &Session,
+    local: &str,
+    remote: &str,
+    options: PushOptions,
+) -> Result<PushOutput> {
+    let PushOptions {
+        verify,
+        overwrite,
+        dry_run,
+        ..
+    } = options;
+    let local_path = Path::new(local);
+    if !local_path.is_file() {
+        return Err(Error::FileNotFound(local.to_string()));
+    }
+
+    // Pushing into an existing folder keeps the local file name
+    let is_folder = remote == "/" || session.stat(remote).map(|e| e.is_folder).unwrap_or(false);
+    let dest_path = if is_folder {
+        let filename = local_path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| Error::InvalidPath("Invalid local path".to_string()))?;
+        format!("{}/{}", remote.trim_end_matches('/'), filename)
+    } else {
+        remote.to_string()
+    };
+    let (dest_path, renamed_from) = destination(session, &dest_path, options)?;
+
+    let existing = existing_file(session, &dest_path)?;
+    let metadata = std::fs::metadata(local_path)?;
+    let action = overwrite.decide_for(existing.as_ref().map(Stamp::remote), || Ok(Stamp::local(&metadata)), &dest_path)?;
+    let replaced = action == Action::Replace;
+    let size = metadata.len();
+    if action != Action::Skip {
+        let freed = existing.as_ref().filter(|_| !options.atomic);
+        check_space(output, session, size, freed, dry_run)?;
+    }
+
+    if !dry_run && action != Action::Skip {
+        upload(session, &dest_path, replaced, options.atomic, |path| {
+            output.timed(&dest_path, || session.upload_file(local_path, path).map(|()| size))?;
+            match verify {
+                Some(mode) => verify_transfer(session, path, local_path, mode),
+                None => Ok(()),
+            }
+        })?;
+    }
+
+    Ok(PushOutput {
+        local: local.to_string(),
+        remote: dest_path,
+        bytes: size,
+        replaced,
+        skipped: action == Action::Skip,
+        verified: verify.is_some() && !dry_run && action != Action::Skip,
+        dry_run,
+        renamed_from,
+    })
+}
+
+/// Runs `send` to create the file at `dest_path`, replacing the file there
+/// if `replace`. Without `atomic` the old file goes first and a failed
+/// upload can leave a partial one in its place.
+fn upload(
+    session: &Session,
+    dest_path: &str,
+    replace: bool,
+    atomic: bool,
+    send: impl FnOnce(&str) -> Result<()>,
+) -> Result<()> {
+    if atomic {
+        return upload_atomically(session, dest_path, replace, send);
+    }
+    if replace {
+        session.delete(dest_path)?;
+    }
+    send(dest_path)
+}
+
+/// Uploads (and verifies) under a hidden `.part` name next to `dest_path`,
+/// then renames it into place, so the Kindle's indexer never picks up a
+/// half-written book. A replaced file stays until the new one is complete.
+pub(crate) fn upload_atomically(
+    session: &Session,
+    dest_path: &str,
+    replace: bool,
+    send: impl FnOnce(&str) -> Result<()>,
+) -> Result<()> {
+    let temp = match dest_path.rsplit_once('/') {
+        Some((dir, name)) => format!("{}/.{}.part", dir, name),
+        None => format!(".{}.part", dest_path),
+    };
+    // Left behind by a push that was killed; MTP would add a second object
+    if session.stat(&temp).is_ok() {
+        session.delete(&temp)?;
+    }
+    if let Err(e) = send(&temp) {
+        // The upload may have failed before creating anything
+        if let Err(cleanup) = session.delete(&temp) {
+            debug!(temp, error = %cleanup, "could not remove partial upload");
+        }
+        return Err(e);
+    }
+    if replace {
+        session.delete(dest_path)?;
+    }
+    session.rename(&temp, dest_path)
+}
+
+/// Where an upload to `path` goes once `--sanitize` and
+/// `--rename-on-conflict` are applied, and the original name if either
+/// changed it.
+fn destination(session: &Session, path: &str, options: PushOptions) -> Result<(String, Option<String>)> {
+    let (path, renamed_from) = sanitize_path(path, options.sanitize);
+    if !options.rename_on_conflict || session.stat(&path).is_err() {
+        return Ok((path, renamed_from));
+    }
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (Some(dir), name),
+        None => (None, path.as_str()),
+    };
+    let free = free_name(session, dir.filter(|d| !d.is_empty()).unwrap_or("/"), name)?;
+    let renamed_from = renamed_from.unwrap_or_else(|| name.to_string());
+    Ok(match dir {
+        Some(dir) => (format!("{}/{}", dir, free), Some(renamed_from)),
+        None => (free, Some(renamed_from)),
+    })
+}
+
+/// The first of `Name (1).ext`, `Name (2).ext`, ... not taken in `dir`,
+/// as desktop file managers name copies.
+fn free_name(session: &Session, dir: &str, name: &str) -> Result<String> {
+    let taken: HashSet<String> = session.list_files(dir)?.into_iter().map(|e| e.name).collect();
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    let mut n = 1;
+    loop {
+        let candidate = format!("{} ({}){}", stem, n, extension);
+        if !taken.contains(&candidate) {
+            return Ok(candidate);
+        }
+        n += 1;
+    }
+}
+
+/// `path` with its last component sanitized if `sanitize` is set, and the
+/// original name if that changed it.
+fn sanitize_path(path: &str, sanitize: bool) -> (String, Option<String>) {
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (Some(dir), name),
+        None => (None, path),
+    };
+    let clean = if sanitize { sanitize_name(name) } else { name.to_string() };
+    if clean == name {
+        return (path.to_string(), None);
+    }
+    match dir {
+        Some(dir) => (format!("{}/{}", dir, clean), Some(name.to_string())),
+        None => (clean, Some(name.to_string())),
+    }
+}
+
+/// The file already at the upload destination, if any; a folder there is
+/// an error.
+fn existing_file(session: &Session, dest_path: &str) -> Result<Option<FileEntry>> {
+    match session.stat(dest_path) {
+        Ok(existing) if existing.is_folder => Err(Error::InvalidPath(format!(
+            "'{}' exists on the device and is a directory",
+            dest_path
+        ))),
+        Ok(existing) => Ok(Some(existing)),
+        Err(Error::FileNotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Checks the upload fits, counting the space a replaced file frees.
+fn check_space(
+    output: &Output,
+    session: &Session,
+    size: u64,
+    replaced: Option<&FileEntry>,
+    dry_run: bool,
+) -> Result<()> {
+    let (_, storage) = session.info()?;
+    let needed = size.saturating_sub(replaced.map_or(0, |e| e.size));
+    precheck(output, storage.free_bytes, needed, 1, dry_run)
+}
@@ -0,0 +1,146 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::backup::{local_file_path, resolve_snapshot, Manifest};
+use crate::commands::push::upload_atomically;
+use crate::commands::space::precheck;
+use crate::commands::sync::normalize_remote_dir;
+use crate::daemon::Session;
+use crate::device::{Kindle, TransferOptions};
+use crate::error::{Error, Result};
+use serde::Serialize;
+use schemars::JsonSchema;
+use std::path::Path;
+
+#[derive(Serialize, JsonSchema)]
+pub struct RestoreOutput {
+    pub backup: String,
+    pub path: String,
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    pub skipped: Vec<String>,
+    pub bytes: u64,
+    pub dry_run: bool,
+}
+
+impl HumanReadable for RestoreOutput {
+    fn to_human(&self) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        lines.extend(self.created.iter().map(|p| format!("+ {}", p)));
+        lines.extend(self.updated.iter().map(|p| format!("~ {}", p)));
+        lines.push(format!(
+            "{} created, {} updated, {} skipped ({} bytes {})",
+            self.created.len(),
+            self.updated.len(),
+            self.skipped.len(),
+            self.bytes,
+            if self.dry_run { "to transfer, dry run" } else { "transferred" }
+        ));
+        lines.join("\n")
+    }
+}
+
+/// Pushes a backup snapshot (or one subtree of it) back onto the device.
+/// Given a backup directory rather than a snapshot, the newest snapshot is
+/// used. Files already on the device with the backed-up size are left alone.
+/// With `dry_run` the device is only read.
+pub fn run_restore(
+    output: &Output,
+    backup_dir: &str,
+    path: Option<&str>,
+    dry_run: bool,
+    transfer: TransferOptions,
+) -> Result<()> {
+    let snapshot = resolve_snapshot(Path::new(backup_dir))?;
+    let manifest = Manifest::load(&snapshot)?;
+
+    let subtree = normalize_remote_dir(path.unwrap_or(&manifest.root));
+    let in_subtree = |p: &str| {
+        subtree == "/" || p == subtree || p.starts_with(&format!("{}/", subtree))
+    };
+    let entries: Vec<_> = manifest.entries.iter().filter(|e| in_subtree(&e.path)).collect();
+    if entries.is_empty() {
+        return Err(Error::FileNotFound(format!(
+            "'{}' is not in the backup",
+            subtree
+        )));
+    }
+
+    let session = Session::Direct(Kindle::open(transfer)?);
+    let kindle = session.direct("restore")?;
+
+    let mut restore_output = RestoreOutput {
+        backup: snapshot.display().to_string(),
+        path: subtree,
+        created: Vec::new(),
+        updated: Vec::new(),
+        skipped: Vec::new(),
+        bytes: 0,
+        dry_run,
+    };
+
+    // Manifest entries are in walk order, so folders precede their contents.
+    // Decide every file first, so free space is checked before the first
+    // upload; a replaced file counts in full, since the old copy is only
+    // deleted once the new one is on the device.
+    let mut uploads = Vec::new();
+    for entry in entries {
+        if entry.is_folder {
+            uploads.push((entry, None));
+            continue;
+        }
+        match kindle.stat(&entry.path) {
+            Ok(existing) if !existing.is_folder && existing.size == entry.size => {
+                restore_output.skipped.push(entry.path.clone());
+            }
+            Ok(existing) if existing.is_folder => {
+                return Err(Error::InvalidPath(format!(
+                    "'{}' exists on the device and is a directory",
+                    entry.path
+                )));
+            }
+            Ok(_) => uploads.push((entry, Some(true))),
+            Err(Error::FileNotFound(_)) => uploads.push((entry, Some(false))),
+            Err(e) => return Err(e),
+        }
+    }
+    let (needed, files) = uploads
+        .iter()
+        .filter(|(_, replace)| replace.is_some())
+        .fold((0, 0), |(needed, files), (entry, _)| (needed + entry.size, files + 1));
+    if files > 0 {
+        precheck(output, kindle.storage_info()?.free_bytes, needed, files, dry_run)?;
+    }
+
+    for (entry, replace) in uploads {
+        let Some(replace) = replace else {
+            if !dry_run {
+                kindle.create_folder_all(&entry.path)?;
+            }
+            continue;
+        };
+
+        if let Some((parent, _)) = entry.path.rsplit_once('/')
+            && !parent.is_empty()
+            && !dry_run
+        {
+            kindle.create_folder_all(parent)?;
+        }
+
+        let local_path = local_file_path(&snapshot, &entry.path)?;
+        if !dry_run {
+            if replace {
+                upload_atomically(&session, &entry.path, true, |path| kindle.upload_file(&local_path, path))?;
+            } else {
+                kindle.upload_file(&local_path, &entry.path)?;
+            }
+        }
+        restore_output.bytes += entry.size;
+        if replace {
+            restore_output.updated.push(entry.path.clone());
+        } else {
+            restore_output.created.push(entry.path.clone());
+        }
+    }
+
+    output.print(&restore_output);
+    Ok(())
+}
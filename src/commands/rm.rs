@@ -0,0 +1,114 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::calibre::update_device_metadata;
+use crate::commands::trash::move_to_trash;
+use crate::daemon::Session;
+use crate::device::TransferOptions;
+use crate::error::{Error, Result};
+use serde::Serialize;
+use schemars::JsonSchema;
+
+/// NDJSON event for one deleted (or, in a dry run, planned) path.
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct RmEvent<'a> {
+    pub path: &'a str,
+    pub dry_run: bool,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct RmOutput {
+    pub removed: Vec<String>,
+    /// IDs of the copies kept with `--trash`, for `trash restore`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub trashed: Vec<String>,
+    pub dry_run: bool,
+}
+
+impl HumanReadable for RmOutput {
+    fn to_human(&self) -> String {
+        let verb = if self.dry_run { "would remove" } else { "removed" };
+        let mut lines: Vec<String> = self.removed.iter().map(|p| format!("{} {}", verb, p)).collect();
+        if !self.trashed.is_empty() {
+            lines.push(format!(
+                "Kept in the trash as {} (kindle-mtp trash restore <ID> puts it back)",
+                self.trashed.join(", ")
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Deletes files, or whole folders with `recursive`. Every path is resolved
+/// before anything is deleted, so a typo in the last argument doesn't leave
+/// the first ones half-done. With `trash` each path is copied into the
+/// local trash first, and nothing is deleted unless every copy succeeded.
+pub fn run_rm(output: &Output, paths: &[String], recursive: bool, trash: bool, dry_run: bool) -> Result<()> {
+    let session = Session::open(TransferOptions::default())?;
+    rm(output, &session, paths, recursive, trash, dry_run)
+}
+
+/// `rm` over an already open session, shared with the shell.
+pub(crate) fn rm(
+    output: &Output,
+    session: &Session,
+    paths: &[String],
+    recursive: bool,
+    trash: bool,
+    dry_run: bool,
+) -> Result<()> {
+
+    let mut planned = Vec::new();
+    let mut requested = Vec::new();
+    for path in paths {
+        let path = format!("/{}", path.trim_matches('/'));
+        if path == "/" {
+            return Err(Error::InvalidPath("Refusing to remove the device root".to_string()));
+        }
+        let entry = session.stat(&path)?;
+        if entry.is_folder {
+            if !recursive {
+                return Err(Error::InvalidPath(format!(
+                    "'{}' is a directory (use -r)",
+                    path
+                )));
+            }
+            // Children first: MTP only deletes empty folders reliably
+            let mut children: Vec<String> =
+                session.walk(&path)?.into_iter().map(|item| item.path).collect();
+            children.reverse();
+            planned.extend(children);
+        }
+        requested.push((path.clone(), entry.is_folder));
+        planned.push(path);
+    }
+
+    let mut rm_output = RmOutput {
+        removed: Vec::new(),
+        trashed: Vec::new(),
+        dry_run,
+    };
+    if trash && !dry_run {
+        let (info, _) = session.info()?;
+        for (path, is_folder) in &requested {
+            let item = move_to_trash(output, session, &info.serial, path, *is_folder)?;
+            rm_output.trashed.push(item.id);
+        }
+    }
+    for path in planned {
+        if !dry_run {
+            session.delete(&path)?;
+        }
+        if output.is_ndjson() {
+            output.event("removed", &RmEvent { path: &path, dry_run });
+            continue;
+        }
+        rm_output.removed.push(path);
+    }
+
+    if !dry_run {
+        update_device_metadata(output, session, |metadata| {
+            requested.iter().for_each(|(path, _)| metadata.record_removal(path));
+        });
+    }
+    output.print(&rm_output);
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use crate::error::{Error, Result};
+use std::path::{Component, Path, PathBuf};
+
+/// Joins a device-relative path onto `root`, refusing anything that could
+/// escape it. Names come from the device, so `..`, absolute components,
+/// backslashes and NULs are treated as hostile (zip-slip for MTP trees).
+pub(crate) fn join_under(root: &Path, relative: &str) -> Result<PathBuf> {
+    let mut path = root.to_path_buf();
+    for part in relative.split('/').filter(|p| !p.is_empty()) {
+        let unsafe_part = part == "."
+            || part == ".."
+            || part.contains('\\')
+            || part.contains('\0')
+            || !matches!(
+                Path::new(part).components().next(),
+                Some(Component::Normal(_))
+            )
+            || Path::new(part).components().count() != 1;
+        if unsafe_part {
+            return Err(Error::InvalidPath(format!(
+                "refusing unsafe device path '{}'",
+                relative
+            )));
+        }
+        path.push(part);
+    }
+    Ok(path)
+}
+
+/// Creates the parent folders of `path` and checks that, once symlinks are
+/// resolved, it still lands under `root`.
+pub(crate) fn prepare_under(root: &Path, path: &Path) -> Result<()> {
+    let parent = path.parent().unwrap_or(root);
+    std::fs::create_dir_all(parent)?;
+
+    let canonical_root = root.canonicalize()?;
+    let canonical_parent = parent.canonicalize()?;
+    if !canonical_parent.starts_with(&canonical_root) {
+        return Err(Error::InvalidPath(format!(
+            "'{}' resolves outside '{}'",
+            path.display(),
+            root.display()
+        )));
+    }
+
+    // The final component may itself be a planted symlink
+    if let Ok(meta) = std::fs::symlink_metadata(path)
+        && meta.file_type().is_symlink()
+    {
+        return Err(Error::InvalidPath(format!(
+            "'{}' is a symlink; refusing to write through it",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::staging::StagingDir;
+
+    #[test]
+    fn join_under_keeps_plain_names() {
+        let root = Path::new("/out");
+        assert_eq!(join_under(root, "a/b.azw3").unwrap(), Path::new("/out/a/b.azw3"));
+        // Repeated and outer slashes add nothing
+        assert_eq!(join_under(root, "/a//b/").unwrap(), Path::new("/out/a/b"));
+        assert_eq!(join_under(root, "").unwrap(), Path::new("/out"));
+    }
+
+    #[test]
+    fn join_under_refuses_escapes() {
+        let root = Path::new("/out");
+        for hostile in ["..", "a/../../etc", "./a", "a\\..\\b", "a\0b"] {
+            assert!(
+                matches!(join_under(root, hostile), Err(Error::InvalidPath(_))),
+                "{:?}",
+                hostile
+            );
+        }
+    }
+
+    #[test]
+    fn prepare_under_creates_parents() {
+        let staging = StagingDir::new("test").unwrap();
+        let root = staging.path();
+        let path = join_under(root, "a/b/c.txt").unwrap();
+        prepare_under(root, &path).unwrap();
+        assert!(root.join("a/b").is_dir());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn prepare_under_refuses_symlinks_out_of_root() {
+        let staging = StagingDir::new("test").unwrap();
+        let root = staging.join("root");
+        let outside = staging.join("outside");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+        let through = join_under(&root, "link/book.azw3").unwrap();
+        assert!(matches!(prepare_under(&root, &through), Err(Error::InvalidPath(_))));
+
+        std::os::unix::fs::symlink(outside.join("book.azw3"), root.join("book.azw3")).unwrap();
+        let planted = join_under(&root, "book.azw3").unwrap();
+        assert!(matches!(prepare_under(&root, &planted), Err(Error::InvalidPath(_))));
+    }
+}
@@ -0,0 +1,99 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+
+/// Longest name `--sanitize` leaves, in bytes. The Kindle's FAT32 storage
+/// allows 255, but the indexer gives up on much shorter names in practice.
+const MAX_NAME_LEN: usize = 128;
+
+/// A file or folder name `--sanitize` changed on the way to the device.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Renamed {
+    pub from: String,
+    pub to: String,
+}
+
+/// `name` made safe for the device: transliterated to ASCII, with the
+/// characters FAT32 rejects replaced or dropped, whitespace collapsed and
+/// the stem cut so the whole name fits `MAX_NAME_LEN`. The extension is
+/// kept, so the Kindle still recognises the format.
+pub(crate) fn sanitize_name(name: &str) -> String {
+    let mut clean = String::with_capacity(name.len());
+    for c in deunicode::deunicode(name).chars() {
+        match c {
+            // "Title: Subtitle" reads better as "Title - Subtitle"
+            ':' => clean.push_str(" -"),
+            '/' | '\\' | '|' => clean.push('-'),
+            '<' | '>' | '"' | '?' | '*' => {}
+            c if c.is_control() || c.is_whitespace() => {
+                if !clean.ends_with(' ') {
+                    clean.push(' ');
+                }
+            }
+            c => clean.push(c),
+        }
+    }
+    let clean = clean.split(' ').filter(|word| !word.is_empty()).collect::<Vec<_>>().join(" ");
+    let clean = clean.trim_start_matches('.').trim_end_matches(['.', ' ']);
+
+    let (stem, extension) = match clean.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && extension.len() <= 8 => (stem, Some(extension)),
+        _ => (clean, None),
+    };
+    let room = MAX_NAME_LEN - extension.map_or(0, |e| e.len() + 1);
+    // deunicode only emits ASCII, so any byte index is a char boundary
+    let stem = stem[..stem.len().min(room)].trim_end_matches(['.', ' ']);
+    let stem = if stem.is_empty() { "file" } else { stem };
+    match extension {
+        Some(extension) => format!("{}.{}", stem, extension),
+        None => stem.to_string(),
+    }
+}
+
+/// `name` sanitized if `sanitize` is set, else unchanged.
+pub(crate) fn device_name(name: &str, sanitize: bool) -> String {
+    if sanitize { sanitize_name(name) } else { name.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_characters_fat32_rejects() {
+        assert_eq!(sanitize_name("Title: Subtitle.epub"), "Title - Subtitle.epub");
+        assert_eq!(sanitize_name("AC/DC|Live.pdf"), "AC-DC-Live.pdf");
+        assert_eq!(sanitize_name("What? <Really> \"yes\"*.txt"), "What Really yes.txt");
+    }
+
+    #[test]
+    fn transliterates_to_ascii() {
+        assert_eq!(sanitize_name("Les Misérables.azw3"), "Les Miserables.azw3");
+    }
+
+    #[test]
+    fn collapses_whitespace_and_trims_dots() {
+        assert_eq!(sanitize_name("  a \t\n b  .txt"), "a b.txt");
+        assert_eq!(sanitize_name("..hidden"), "hidden");
+        assert_eq!(sanitize_name("name. . "), "name");
+    }
+
+    #[test]
+    fn keeps_the_extension_when_cutting_long_names() {
+        let long = format!("{}.azw3", "a".repeat(300));
+        let clean = sanitize_name(&long);
+        assert_eq!(clean.len(), MAX_NAME_LEN);
+        assert!(clean.ends_with(".azw3"));
+    }
+
+    #[test]
+    fn names_left_empty_become_file() {
+        assert_eq!(sanitize_name("???"), "file");
+        assert_eq!(sanitize_name("..."), "file");
+    }
+
+    #[test]
+    fn device_name_only_sanitizes_when_asked() {
+        assert_eq!(device_name("a:b", false), "a:b");
+        assert_eq!(device_name("a:b", true), "a -b");
+    }
+}
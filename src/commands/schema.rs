@@ -0,0 +1,192 @@
+use super::assert::AssertOutput;
+use super::backup::{BackupEvent, BackupOutput};
+use super::bench::BenchOutput;
+use super::books::BooksOutput;
+use super::catalog::CatalogOutput;
+use super::batch::BatchOutput;
+use super::clean_sdr::CleanSdrOutput;
+use super::clippings::ClippingsOutput;
+use super::collections::{CollectionEditOutput, CollectionsOutput};
+use super::cover::CoverOutput;
+use super::diff::DiffOutput;
+use super::audio::{AudioLsOutput, AudioPullOutput, AudioPushOutput};
+use super::dict::{DictInstallOutput, DictListOutput};
+use super::doctor::DoctorOutput;
+use super::fw_update::FwUpdateOutput;
+use super::hash::HashOutput;
+use super::highlights::HighlightsExportOutput;
+use super::index::IndexOutput;
+use super::info::InfoOutput;
+use super::init::InitOutput;
+use super::introspect::CommandInfo;
+use super::largest::LargestOutput;
+use super::ls::{LsEntry, LsOutput};
+use super::monitor::MonitorEvent;
+use super::mtp_debug::MtpDebugOutput;
+use super::pull::{PullManyOutput, PullOutput, PullTreeOutput};
+use super::opds::OpdsExportOutput;
+use super::push::{PushOutput, PushTreeOutput};
+use super::send::SendOutput;
+use super::restore::RestoreOutput;
+use super::rm::{RmEvent, RmOutput};
+use super::trash::{TrashListOutput, TrashRestoreOutput};
+use super::screensaver::{ScreensaverListOutput, ScreensaverPushOutput};
+use super::search::SearchOutput;
+use super::snapshot::SnapshotOutput;
+use super::stat::StatOutput;
+use super::stats::StatsOutput;
+use super::status::StatusOutput;
+use super::sync::{SyncEvent, SyncOutput};
+use super::thumb::ThumbOutput;
+use super::usage::UsageOutput;
+use super::vocab::VocabExportOutput;
+use super::watch::WatchEvent;
+use crate::cli::{TransferStats, SCHEMA_VERSION};
+use crate::error::{Error, ErrorReport, Result};
+use schemars::generate::SchemaSettings;
+use schemars::{schema_for, JsonSchema, Schema};
+use serde_json::{json, Value};
+
+/// Every JSON document the CLI prints, by name: command results, NDJSON
+/// events as `<command>.<event>`, and the error object.
+fn schemas() -> Vec<(&'static str, Schema)> {
+    vec![
+        ("status", result::<StatusOutput>()),
+        ("info", result::<InfoOutput>()),
+        ("init", result::<InitOutput>()),
+        ("ls", result::<LsOutput>()),
+        ("ls.entry", event::<LsEntry>("entry")),
+        ("pull", with_stats(result::<PullOutput>())),
+        ("pull.recursive", with_stats(result::<PullTreeOutput>())),
+        ("pull.multiple", with_stats(result::<PullManyOutput>())),
+        ("pull.file", event::<PullOutput>("file")),
+        ("stat", result::<StatOutput>()),
+        ("thumb", result::<ThumbOutput>()),
+        ("cover", result::<CoverOutput>()),
+        ("push", with_stats(result::<PushOutput>())),
+        ("push.recursive", with_stats(result::<PushTreeOutput>())),
+        ("push.file", event::<PushOutput>("file")),
+        ("send", with_stats(result::<SendOutput>())),
+        ("calibre.import", with_stats(result::<SendOutput>())),
+        ("rm", result::<RmOutput>()),
+        ("trash.list", result::<TrashListOutput>()),
+        ("trash.restore", with_stats(result::<TrashRestoreOutput>())),
+        ("rm.removed", event::<RmEvent>("removed")),
+        ("sync", with_stats(result::<SyncOutput>())),
+        ("sync.file", event::<SyncEvent>("file")),
+        ("watch", result::<WatchEvent>()),
+        ("monitor", result::<MonitorEvent>()),
+        ("batch", result::<BatchOutput>()),
+        ("diff", result::<DiffOutput>()),
+        ("backup", result::<BackupOutput>()),
+        ("backup.file", event::<BackupEvent>("file")),
+        ("restore", result::<RestoreOutput>()),
+        ("snapshot", result::<SnapshotOutput>()),
+        ("index", result::<IndexOutput>()),
+        ("search", result::<SearchOutput>()),
+        ("books", result::<BooksOutput>()),
+        ("catalog", result::<CatalogOutput>()),
+        ("usage", result::<UsageOutput>()),
+        ("largest", result::<LargestOutput>()),
+        ("clean-sdr", result::<CleanSdrOutput>()),
+        ("clippings", result::<ClippingsOutput>()),
+        ("collections", result::<CollectionsOutput>()),
+        ("collections.edit", result::<CollectionEditOutput>()),
+        ("highlights", result::<HighlightsExportOutput>()),
+        ("vocab", result::<VocabExportOutput>()),
+        ("audio.ls", result::<AudioLsOutput>()),
+        ("audio.pull", with_stats(result::<AudioPullOutput>())),
+        ("audio.push", with_stats(result::<AudioPushOutput>())),
+        ("opds", with_stats(result::<OpdsExportOutput>())),
+        ("dict.install", result::<DictInstallOutput>()),
+        ("dict.list", result::<DictListOutput>()),
+        ("screensaver.push", result::<ScreensaverPushOutput>()),
+        ("screensaver.list", result::<ScreensaverListOutput>()),
+        ("hash", result::<HashOutput>()),
+        ("bench", result::<BenchOutput>()),
+        ("assert", result::<AssertOutput>()),
+        ("doctor", result::<DoctorOutput>()),
+        ("stats", result::<StatsOutput>()),
+        ("fw-update", result::<FwUpdateOutput>()),
+        ("mtp-debug", result::<MtpDebugOutput>()),
+        ("introspect", result::<CommandInfo>()),
+        ("error", schema_for!(ErrorReport)),
+    ]
+}
+
+/// A command result: the struct plus what `Output::print` adds to it.
+fn result<T: JsonSchema>() -> Schema {
+    let mut schema = schema_for!(T);
+    add_property(&mut schema, "schema_version", json!({ "const": SCHEMA_VERSION }), true);
+    add_property(
+        &mut schema,
+        "warnings",
+        json!({ "type": "array", "items": { "type": "string" } }),
+        false,
+    );
+    schema
+}
+
+/// A transfer result, which `--stats` extends with `stats`.
+fn with_stats(mut schema: Schema) -> Schema {
+    let mut stats = SchemaSettings::default()
+        .with(|s| s.inline_subschemas = true)
+        .into_generator()
+        .into_root_schema_for::<TransferStats>()
+        .to_value();
+    if let Value::Object(stats) = &mut stats {
+        stats.remove("$schema");
+    }
+    add_property(&mut schema, "stats", stats, false);
+    schema
+}
+
+/// An NDJSON event line: the struct plus its `event` tag.
+fn event<T: JsonSchema>(name: &str) -> Schema {
+    let mut schema = schema_for!(T);
+    add_property(&mut schema, "event", json!({ "const": name }), true);
+    add_property(&mut schema, "schema_version", json!({ "const": SCHEMA_VERSION }), true);
+    schema
+}
+
+fn add_property(schema: &mut Schema, name: &str, property: Value, required: bool) {
+    let Some(object) = schema.as_object_mut() else {
+        return;
+    };
+    if let Some(Value::Object(properties)) = object.get_mut("properties") {
+        properties.insert(name.to_string(), property);
+    }
+    if required
+        && let Value::Array(names) = object.entry("required").or_insert_with(|| json!([]))
+    {
+        names.push(name.into());
+    }
+}
+
+/// Prints the JSON Schema of one output (`name`), or of all of them keyed
+/// by name. Always JSON: the schemas are for tools, not people.
+pub fn run_schema(name: Option<&str>) -> Result<()> {
+    let schemas = schemas();
+    let document = match name {
+        None => {
+            let schemas: serde_json::Map<String, Value> = schemas
+                .into_iter()
+                .map(|(name, schema)| (name.to_string(), schema.to_value()))
+                .collect();
+            json!({ "schema_version": SCHEMA_VERSION, "schemas": schemas })
+        }
+        Some(name) => {
+            let names: Vec<&str> = schemas.iter().map(|(n, _)| *n).collect();
+            let Some((_, schema)) = schemas.iter().find(|(n, _)| *n == name) else {
+                return Err(Error::InvalidPath(format!(
+                    "no schema named '{}' (available: {})",
+                    name,
+                    names.join(", ")
+                )));
+            };
+            schema.clone().to_value()
+        }
+    };
+    println!("{}", serde_json::to_string_pretty(&document).unwrap_or_default());
+    Ok(())
+}
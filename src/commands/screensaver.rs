@@ -0,0 +1,273 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::fw_update::device_code;
+use crate::commands::push::upload_atomically;
+use crate::commands::rm::rm;
+use crate::commands::staging::StagingDir;
+use crate::daemon::Session;
+use crate::device::{FileEntry, TransferOptions};
+use crate::error::{Error, Result};
+use image::imageops::{self, FilterType};
+use image::{GrayImage, ImageFormat, Luma};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::io::Cursor;
+use std::path::Path;
+
+/// Images smaller than this fraction of the screen are upscaled visibly.
+const MIN_SCALE_WARNING: f32 = 0.5;
+
+/// Portrait screen sizes by the device codes in serial numbers (see
+/// `fw_update::device_code`).
+const SCREENS: &[(&[u16], (u32, u32))] = &[
+    // Kindle 4, Touch, Basic (7th generation), Kindle 10
+    (&[0x0E, 0x23, 0x0F, 0x10, 0x11, 0x12, 0xC6, 0xDD, 0x3CF, 0x3D0, 0x3D1, 0x3D2, 0x414], (600, 800)),
+    // Paperwhite 1 and 2
+    (
+        &[
+            0x24, 0x1B, 0x1C, 0x1D, 0x1F, 0x20, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xF2, 0x17, 0x5A, 0x5F,
+            0x60, 0x61, 0x62, 0xF4, 0xF9,
+        ],
+        (758, 1024),
+    ),
+    // Voyage, Paperwhite 3 and 4, Oasis 1, Kindle 11
+    (
+        &[
+            0x13, 0x54, 0x2A, 0x4F, 0x52, 0x53, 0x201, 0x202, 0x204, 0x205, 0x206, 0x207, 0x26B,
+            0x26C, 0x26D, 0x26E, 0x26F, 0x270, 0x293, 0x294, 0x20C, 0x20D, 0x219, 0x21A, 0x21B,
+            0x21C, 0x2F7, 0x361, 0x362, 0x363, 0x364, 0x365, 0x366, 0x367, 0x372, 0x373, 0x374,
+            0x375, 0x376, 0x402, 0x4D8, 0x4D9, 0x4DA, 0x4DB, 0x4DC, 0x4DD, 0x7F1, 0x84C, 0x84D,
+            0x86A, 0x8BB, 0x957, 0x958,
+        ],
+        (1072, 1448),
+    ),
+    // Oasis 2 and 3
+    (
+        &[
+            0x295, 0x296, 0x297, 0x298, 0x2E1, 0x2E2, 0x2E6, 0x2E7, 0x2E8, 0x341, 0x342, 0x343,
+            0x344, 0x347, 0x34A, 0x3D4, 0x3D5, 0x3D6, 0x3D7, 0x3D8, 0x434,
+        ],
+        (1264, 1680),
+    ),
+    // Paperwhite 5
+    (&[0x690, 0x6FF, 0x700, 0x7AD, 0x829, 0x82A, 0x971, 0x972, 0x9B3], (1236, 1648)),
+    // Scribe
+    (&[0x847, 0x874, 0x875, 0x8C3, 0x8E0, 0x8F2, 0x974], (1860, 2480)),
+];
+
+#[derive(Serialize, JsonSchema)]
+pub struct ScreensaverPushOutput {
+    pub local: String,
+    pub remote: String,
+    pub width: u32,
+    pub height: u32,
+    /// Size of the image before it was scaled
+    pub source_width: u32,
+    pub source_height: u32,
+    pub bytes: u64,
+}
+
+impl HumanReadable for ScreensaverPushOutput {
+    fn to_human(&self) -> String {
+        format!(
+            "Pushed {} -> {} ({}x{} from {}x{}, {} bytes)",
+            self.local,
+            self.remote,
+            self.width,
+            self.height,
+            self.source_width,
+            self.source_height,
+            self.bytes
+        )
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ScreensaverListOutput {
+    pub folder: String,
+    pub images: Vec<FileEntry>,
+}
+
+impl HumanReadable for ScreensaverListOutput {
+    fn to_human(&self) -> String {
+        if self.images.is_empty() {
+            return format!("No screensavers in {}", self.folder);
+        }
+        self.images
+            .iter()
+            .map(|image| format!("{:>10}  {}", image.size, image.name))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Scales `local` to fill the screen (or fit inside it, with `fit`),
+/// converts it to a grayscale PNG and uploads it to `folder`. Landscape
+/// images are turned to portrait. The screen size comes from the model
+/// unless `size` is given.
+pub fn run_screensaver_push(
+    output: &Output,
+    local: &str,
+    folder: &str,
+    size: Option<(u32, u32)>,
+    fit: bool,
+    transfer: TransferOptions,
+) -> Result<()> {
+    let source = image::open(local).map_err(|e| Error::InvalidPath(format!("{}: {}", local, e)))?;
+    let session = Session::open(transfer)?;
+    check_folder(&session, folder)?;
+    let (width, height) = match size {
+        Some(size) => size,
+        None => {
+            let (info, _) = session.info()?;
+            screen_size(&info.serial).ok_or_else(|| {
+                Error::Unsupported(format!(
+                    "unknown screen size for serial number '{}'; give it with --size, e.g. --size 1072x1448",
+                    info.serial
+                ))
+            })?
+        }
+    };
+
+    let mut gray = source.to_luma8();
+    if gray.width() > gray.height() {
+        gray = imageops::rotate90(&gray);
+    }
+    let (source_width, source_height) = gray.dimensions();
+    let scale = (width as f32 / source_width as f32).min(height as f32 / source_height as f32);
+    if scale > 1.0 / MIN_SCALE_WARNING {
+        output.warn(format!(
+            "{} is {}x{}, far smaller than the {}x{} screen; it will look blurry",
+            local, source_width, source_height, width, height
+        ));
+    }
+    let screen = if fit {
+        fit_into(&gray, width, height)
+    } else {
+        fill(&gray, width, height)
+    };
+
+    let mut data = Vec::new();
+    screen
+        .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+        .map_err(|e| Error::Io(std::io::Error::other(e)))?;
+    let stem = Path::new(local).file_stem().and_then(|s| s.to_str()).unwrap_or("screensaver");
+    let remote = format!("{}/{}.png", folder.trim_end_matches('/'), stem);
+    let existing = match session.stat(&remote) {
+        Ok(entry) if entry.is_folder => {
+            return Err(Error::InvalidPath(format!("'{}' is a directory", remote)));
+        }
+        Ok(_) => true,
+        Err(Error::FileNotFound(_)) => false,
+        Err(e) => return Err(e),
+    };
+
+    let staging = StagingDir::new("screensaver")?;
+    let staged = staging.write("screensaver.png", &data)?;
+    upload_atomically(&session, &remote, existing, |path| session.upload_file(&staged, path))?;
+
+    output.print(&ScreensaverPushOutput {
+        local: local.to_string(),
+        remote,
+        width,
+        height,
+        source_width,
+        source_height,
+        bytes: data.len() as u64,
+    });
+    Ok(())
+}
+
+/// Lists the images in the screensaver folder.
+pub fn run_screensaver_list(output: &Output, folder: &str, transfer: TransferOptions) -> Result<()> {
+    let session = Session::open(transfer)?;
+    check_folder(&session, folder)?;
+    let mut images: Vec<FileEntry> = session
+        .list_files(folder)?
+        .into_iter()
+        .filter(|entry| !entry.is_folder)
+        .collect();
+    images.sort_by_cached_key(|entry| entry.name.to_lowercase());
+    output.print(&ScreensaverListOutput {
+        folder: folder.to_string(),
+        images,
+    });
+    Ok(())
+}
+
+/// Deletes screensaver images by name (or path) from the folder.
+pub fn run_screensaver_rm(
+    output: &Output,
+    folder: &str,
+    names: &[String],
+    dry_run: bool,
+    transfer: TransferOptions,
+) -> Result<()> {
+    let session = Session::open(transfer)?;
+    let paths: Vec<String> = names
+        .iter()
+        .map(|name| {
+            if name.starts_with('/') {
+                name.clone()
+            } else {
+                format!("{}/{}", folder.trim_end_matches('/'), name)
+            }
+        })
+        .collect();
+    rm(output, &session, &paths, false, false, dry_run)
+}
+
+/// Fails unless `folder` exists, which it only does with the hack
+/// installed.
+fn check_folder(session: &Session, folder: &str) -> Result<()> {
+    match session.stat(folder) {
+        Ok(entry) if entry.is_folder => Ok(()),
+        Ok(_) => Err(Error::InvalidPath(format!("'{}' is not a folder", folder))),
+        Err(Error::FileNotFound(_)) => Err(Error::Unsupported(format!(
+            "no {} on the device; custom screensavers need a jailbroken Kindle with the ScreenSavers hack",
+            folder
+        ))),
+        Err(e) => Err(e),
+    }
+}
+
+/// The portrait screen size of the model with serial number `serial`.
+fn screen_size(serial: &str) -> Option<(u32, u32)> {
+    let code = device_code(serial)?;
+    SCREENS
+        .iter()
+        .find(|(codes, _)| codes.contains(&code))
+        .map(|(_, size)| *size)
+}
+
+/// Scales `image` to cover `width` x `height` and crops the overflow
+/// evenly from both sides.
+fn fill(image: &GrayImage, width: u32, height: u32) -> GrayImage {
+    let scale = (width as f32 / image.width() as f32).max(height as f32 / image.height() as f32);
+    let scaled_width = ((image.width() as f32 * scale).round() as u32).max(width);
+    let scaled_height = ((image.height() as f32 * scale).round() as u32).max(height);
+    let scaled = imageops::resize(image, scaled_width, scaled_height, FilterType::Lanczos3);
+    imageops::crop_imm(
+        &scaled,
+        (scaled_width - width) / 2,
+        (scaled_height - height) / 2,
+        width,
+        height,
+    )
+    .to_image()
+}
+
+/// Scales `image` to fit inside `width` x `height`, centred on white.
+fn fit_into(image: &GrayImage, width: u32, height: u32) -> GrayImage {
+    let scale = (width as f32 / image.width() as f32).min(height as f32 / image.height() as f32);
+    let scaled_width = ((image.width() as f32 * scale).round() as u32).clamp(1, width);
+    let scaled_height = ((image.height() as f32 * scale).round() as u32).clamp(1, height);
+    let scaled = imageops::resize(image, scaled_width, scaled_height, FilterType::Lanczos3);
+    let mut screen = GrayImage::from_pixel(width, height, Luma([255]));
+    imageops::overlay(
+        &mut screen,
+        &scaled,
+        i64::from((width - scaled_width) / 2),
+        i64::from((height - scaled_height) / 2),
+    );
+    screen
+}
@@ -0,0 +1,59 @@
+use crate::cli::{HumanReadable, Output};
+use crate::device::WalkEntry;
+use crate::error::{Error, Result};
+use crate::index::DeviceIndex;
+use chrono::{DateTime, Utc};
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+use schemars::JsonSchema;
+
+#[derive(Serialize, JsonSchema)]
+pub struct SearchOutput {
+    pub query: String,
+    pub serial: String,
+    /// When the searched index was built
+    pub indexed: DateTime<Utc>,
+    pub matches: Vec<WalkEntry>,
+}
+
+impl HumanReadable for SearchOutput {
+    fn to_human(&self) -> String {
+        let mut lines: Vec<String> = self.matches.iter().map(|m| m.path.clone()).collect();
+        lines.push(format!(
+            "{} matches (index of {} from {})",
+            self.matches.len(),
+            self.serial,
+            self.indexed.format("%Y-%m-%d %H:%M")
+        ));
+        lines.join("\n")
+    }
+}
+
+/// Finds entries under `root` in the saved index whose name contains `query`,
+/// ignoring case, or matches it as a regular expression with `regex`. Never
+/// opens the device, so the results are as fresh as the last `index` run.
+pub fn run_search(output: &Output, query: &str, regex: bool, root: &str) -> Result<()> {
+    let pattern = if regex {
+        Regex::new(query)
+    } else {
+        RegexBuilder::new(&regex::escape(query))
+            .case_insensitive(true)
+            .build()
+    }
+    .map_err(|e| Error::InvalidPath(format!("invalid pattern '{}': {}", query, e)))?;
+
+    let index = DeviceIndex::load(None)?;
+    let matches = index
+        .walk(root)
+        .filter(|item| pattern.is_match(&item.entry.name))
+        .cloned()
+        .collect();
+
+    output.print(&SearchOutput {
+        query: query.to_string(),
+        serial: index.serial,
+        indexed: index.created,
+        matches,
+    });
+    Ok(())
+}
@@ -0,0 +1,215 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::books::BookFormat;
+use crate::commands::calibre::{update_device_metadata, DeviceBook};
+use crate::commands::push::{push_file, PushOptions, PushOutput};
+use crate::commands::staging::StagingDir;
+use crate::daemon::Session;
+use crate::device::TransferOptions;
+use crate::error::{Error, Result};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Calibre's converter, looked up on `PATH`.
+const EBOOK_CONVERT: &str = "ebook-convert";
+/// Where the macOS Calibre app keeps it, off `PATH` unless its command
+/// line tools were installed.
+const EBOOK_CONVERT_MACOS: &str = "/Applications/calibre.app/Contents/MacOS/ebook-convert";
+/// Lines of the converter's output kept in the error when it fails.
+const CONVERTER_ERROR_LINES: usize = 5;
+
+/// What `send` converts books the Kindle can't open into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SendFormat {
+    /// KF8, with the formatting of modern e-books
+    Azw3,
+    /// Mobipocket, for Kindles from before 2012
+    Mobi,
+}
+
+impl SendFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Azw3 => "azw3",
+            Self::Mobi => "mobi",
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct SendOutput {
+    /// The format the book was converted to; absent when it was sent as is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub converted_to: Option<SendFormat>,
+    #[serde(flatten)]
+    pub upload: PushOutput,
+}
+
+impl HumanReadable for SendOutput {
+    fn to_human(&self) -> String {
+        match self.converted_to {
+            Some(format) => format!("Converted to {}; {}", format.extension().to_uppercase(), self.upload.to_human()),
+            None => self.upload.to_human(),
+        }
+    }
+}
+
+/// How `send` gets books onto the device.
+#[derive(Debug, Clone)]
+pub struct SendOptions {
+    pub format: SendFormat,
+    /// Converter to run instead of Calibre's `ebook-convert`; it is called
+    /// the same way, with the input and output files
+    pub converter: Option<PathBuf>,
+    /// Upload books the Kindle can't open as they are
+    pub no_convert: bool,
+    pub push: PushOptions,
+}
+
+/// Sends e-books to `remote`, converting the ones in formats the Kindle
+/// can't open (EPUB, DOCX, FB2, ...) with Calibre first: an offline
+/// Send-to-Kindle. Native formats go up untouched.
+pub fn run_send(
+    output: &Output,
+    files: &[String],
+    remote: &str,
+    options: SendOptions,
+    transfer: TransferOptions,
+) -> Result<()> {
+    let files: Vec<(String, Option<DeviceBook>)> = files.iter().map(|file| (file.clone(), None)).collect();
+    let session = Session::open(transfer)?;
+    send(output, &session, &files, remote, &options)
+}
+
+/// `send` over an open session, shared with `calibre import`, which knows
+/// more about each book than its file says.
+pub(crate) fn send(
+    output: &Output,
+    session: &Session,
+    files: &[(String, Option<DeviceBook>)],
+    remote: &str,
+    options: &SendOptions,
+) -> Result<()> {
+    for (file, _) in files {
+        if !Path::new(file).is_file() {
+            return Err(Error::FileNotFound(file.clone()));
+        }
+    }
+    let converter = files
+        .iter()
+        .any(|(file, _)| needs_conversion(file) && !options.no_convert)
+        .then(|| find_converter(options.converter.as_deref()))
+        .transpose()?;
+
+    let staging = StagingDir::new("send")?;
+    let mut uploaded = Vec::new();
+    let result = files.iter().try_for_each(|(file, book)| {
+        let (send_output, sent) = match &converter {
+            Some(converter) if needs_conversion(file) => {
+                send_converted(output, session, file, remote, converter, staging.path(), options)?
+            }
+            _ => {
+                let upload = push_file(output, session, file, remote, options.push)?;
+                let send_output = SendOutput {
+                    converted_to: None,
+                    upload,
+                };
+                (send_output, PathBuf::from(file))
+            }
+        };
+        if !send_output.upload.skipped && !send_output.upload.dry_run {
+            uploaded.push((send_output.upload.remote.clone(), sent, book.as_ref()));
+        }
+        output.print(&send_output);
+        Ok(())
+    });
+    if !uploaded.is_empty() {
+        update_device_metadata(output, session, |metadata| {
+            for (remote, local, book) in &uploaded {
+                metadata.record_upload(remote, local, *book);
+            }
+        });
+    }
+    result
+}
+
+/// Converts `file` into `staging` and uploads the result under the book's
+/// own name with the new extension. Returns the converted file with the
+/// output.
+fn send_converted(
+    output: &Output,
+    session: &Session,
+    file: &str,
+    remote: &str,
+    converter: &Path,
+    staging: &Path,
+    options: &SendOptions,
+) -> Result<(SendOutput, PathBuf)> {
+    let stem = Path::new(file).file_stem().and_then(|s| s.to_str()).unwrap_or("book");
+    let converted = staging.join(format!("{}.{}", stem, options.format.extension()));
+    if options.push.dry_run {
+        // Nothing is uploaded, so the real size doesn't matter
+        std::fs::File::create(&converted)?;
+    } else {
+        output.info(format!("Converting {} to {}", file, options.format.extension().to_uppercase()));
+        convert(converter, Path::new(file), &converted)?;
+    }
+    let mut upload = push_file(output, session, &converted.to_string_lossy(), remote, options.push)?;
+    upload.local = file.to_string();
+    let send_output = SendOutput {
+        converted_to: Some(options.format),
+        upload,
+    };
+    Ok((send_output, converted))
+}
+
+/// Whether the Kindle can't open `file` as it is.
+fn needs_conversion(file: &str) -> bool {
+    let extension = Path::new(file).extension().and_then(|e| e.to_str()).unwrap_or_default();
+    !matches!(BookFormat::from_extension(extension), Some(format) if format != BookFormat::Epub)
+}
+
+/// `ebook-convert` from `PATH` or the Calibre app, unless another
+/// converter was named.
+fn find_converter(named: Option<&Path>) -> Result<PathBuf> {
+    if let Some(path) = named {
+        return Ok(path.to_path_buf());
+    }
+    let on_path = std::env::var_os("PATH")
+        .into_iter()
+        .flat_map(|paths| std::env::split_paths(&paths).collect::<Vec<_>>())
+        .flat_map(|dir| [dir.join(EBOOK_CONVERT), dir.join(format!("{}.exe", EBOOK_CONVERT))])
+        .find(|path| path.is_file());
+    on_path
+        .or_else(|| Some(PathBuf::from(EBOOK_CONVERT_MACOS)).filter(|path| path.is_file()))
+        .ok_or_else(|| {
+            Error::Unsupported(format!(
+                "{} not found; install Calibre, name another converter with --converter, or send the book as is with --no-convert",
+                EBOOK_CONVERT
+            ))
+        })
+}
+
+/// Runs `converter input output`, as `ebook-convert` takes them.
+fn convert(converter: &Path, input: &Path, converted: &Path) -> Result<()> {
+    let result = Command::new(converter).arg(input).arg(converted).output().map_err(|e| {
+        Error::Unsupported(format!("could not run {}: {}", converter.display(), e))
+    })?;
+    if result.status.success() && converted.is_file() {
+        return Ok(());
+    }
+    // ebook-convert reports most of its errors on stdout
+    let log = if result.stderr.is_empty() { &result.stdout } else { &result.stderr };
+    let log = String::from_utf8_lossy(log);
+    let lines: Vec<&str> = log.lines().filter(|line| !line.trim().is_empty()).collect();
+    let tail = lines[lines.len().saturating_sub(CONVERTER_ERROR_LINES)..].join("\n");
+    Err(Error::Io(std::io::Error::other(format!(
+        "{} could not convert {} ({}){}",
+        converter.display(),
+        input.display(),
+        result.status,
+        if tail.is_empty() { String::new() } else { format!(":\n{}", tail) }
+    ))))
+}
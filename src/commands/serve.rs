@@ -0,0 +1,8 @@
+use crate::cli::Output;
+use crate::device::TransferOptions;
+use crate::error::Result;
+
+/// Holds the device open and serves the REST API on `addr` until interrupted.
+pub fn run_serve(output: &Output, addr: &str, transfer: TransferOptions) -> Result<()> {
+    crate::http::serve(output, addr, transfer)
+}
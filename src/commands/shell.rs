@@ -0,0 +1,333 @@
+use crate::cli::{OverwriteArgs, Output};
+use crate::commands::filter::PathFilter;
+use crate::commands::ls::LsOptions;
+use crate::commands::pull::PullOptions;
+use crate::commands::push::PushOptions;
+use crate::commands::{ls, pull, push, rm};
+use crate::config::Config;
+use crate::daemon::Session;
+use crate::device::TransferOptions;
+use crate::error::{Error, Result};
+use clap::{Parser, Subcommand};
+use rustyline::completion::{extract_word, Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// One line typed at the `kindle-mtp shell` prompt. Remote paths are
+/// relative to the current device folder unless they start with `/` or an
+/// alias (`docs:`).
+#[derive(Parser)]
+#[command(no_binary_name = true, disable_version_flag = true)]
+pub(crate) struct ShellLine {
+    #[command(subcommand)]
+    pub command: ShellCommand,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum ShellCommand {
+    /// List a device folder (default: the current one)
+    Ls {
+        path: Option<String>,
+
+        /// Show sizes
+        #[arg(short, long)]
+        long: bool,
+    },
+
+    /// Change the current device folder (default: /)
+    Cd { path: Option<String> },
+
+    /// Print the current device folder
+    Pwd,
+
+    /// Download a file, or a folder with -r
+    Pull {
+        remote: String,
+
+        /// Local destination (default: the download folder)
+        local: Option<String>,
+
+        #[arg(short, long)]
+        recursive: bool,
+
+        #[command(flatten)]
+        overwrite: OverwriteArgs,
+    },
+
+    /// Upload a file (default destination: the current folder)
+    Push {
+        local: String,
+
+        remote: Option<String>,
+
+        #[command(flatten)]
+        overwrite: OverwriteArgs,
+    },
+
+    /// Delete files, or folders with -r
+    Rm {
+        #[arg(required = true)]
+        paths: Vec<String>,
+
+        #[arg(short, long)]
+        recursive: bool,
+    },
+
+    /// Leave the shell
+    #[command(alias = "quit")]
+    Exit,
+}
+
+/// Device folders listed while completing, so repeated <Tab> presses don't
+/// each go back to the device. Cleared after every command.
+type ListingCache = RefCell<HashMap<String, Vec<(String, bool)>>>;
+
+/// Tab completion: command names for the first word, local paths for
+/// `push`'s source, device paths everywhere else.
+struct ShellHelper {
+    session: Rc<Session>,
+    cwd: Rc<RefCell<String>>,
+    listings: ListingCache,
+    files: FilenameCompleter,
+}
+
+const COMMANDS: &[&str] = &["ls", "cd", "pwd", "pull", "push", "rm", "help", "exit"];
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, word) = extract_word(line, pos, Some('\\'), is_break_char);
+        let before: Vec<&str> = line[..start].split_whitespace().collect();
+
+        let Some(&command) = before.first() else {
+            let candidates = COMMANDS
+                .iter()
+                .filter(|c| c.starts_with(word))
+                .map(|c| Pair {
+                    display: c.to_string(),
+                    replacement: format!("{} ", c),
+                })
+                .collect();
+            return Ok((start, candidates));
+        };
+        let positional = before.iter().skip(1).filter(|w| !w.starts_with('-')).count();
+        if command == "push" && positional == 0 {
+            return self.files.complete_path(line, pos);
+        }
+
+        let word = word.replace('\\', "");
+        let (dir, prefix) = match word.rsplit_once('/') {
+            Some((dir, prefix)) => (format!("{}/", dir), prefix),
+            None => (String::new(), word.as_str()),
+        };
+        let folder = resolve(&self.cwd.borrow(), &dir);
+        let mut listings = self.listings.borrow_mut();
+        if !listings.contains_key(&folder) {
+            let entries = match self.session.list_files(&folder) {
+                Ok(entries) => entries.into_iter().map(|e| (e.name, e.is_folder)).collect(),
+                Err(_) => return Ok((start, Vec::new())),
+            };
+            listings.insert(folder.clone(), entries);
+        }
+
+        let candidates = listings[&folder]
+            .iter()
+            .filter(|(name, _)| name.starts_with(prefix))
+            .map(|(name, is_folder)| {
+                let suffix = if *is_folder { "/" } else { "" };
+                Pair {
+                    display: format!("{}{}", name, suffix),
+                    replacement: format!("{}{}{}", dir, escape(name), suffix),
+                }
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+fn is_break_char(c: char) -> bool {
+    c == ' ' || c == '\t'
+}
+
+fn escape(name: &str) -> String {
+    name.chars()
+        .flat_map(|c| match c {
+            ' ' | '\\' | '\'' | '"' => vec!['\\', c],
+            _ => vec![c],
+        })
+        .collect()
+}
+
+/// Runs commands against one device session until `exit` or Ctrl-D.
+/// Starts in the config's `remote_root`, if set.
+pub fn run_shell(output: &Output, config: &Config, transfer: TransferOptions) -> Result<()> {
+    let session = Rc::new(Session::open(transfer)?);
+    let cwd = Rc::new(RefCell::new(resolve("/", config.remote_root().unwrap_or("/"))));
+
+    let mut editor: Editor<ShellHelper, DefaultHistory> =
+        Editor::new().map_err(|e| Error::Io(std::io::Error::other(e)))?;
+    editor.set_helper(Some(ShellHelper {
+        session: Rc::clone(&session),
+        cwd: Rc::clone(&cwd),
+        listings: RefCell::new(HashMap::new()),
+        files: FilenameCompleter::new(),
+    }));
+    let history = history_path();
+    if let Some(path) = &history {
+        let _ = editor.load_history(path);
+    }
+
+    output.info("Connected. Type `help` for commands, Ctrl-D to leave.");
+    loop {
+        let prompt = format!("kindle:{}> ", cwd.borrow());
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(Error::Io(std::io::Error::other(e))),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+
+        let words = match shell_words::split(&line) {
+            Ok(words) => words,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                continue;
+            }
+        };
+        let command = match ShellLine::try_parse_from(words) {
+            Ok(parsed) => parsed.command,
+            // Also covers `help` and `--help`, which clap reports as errors
+            Err(e) => {
+                let _ = e.print();
+                continue;
+            }
+        };
+        if matches!(command, ShellCommand::Exit) {
+            break;
+        }
+
+        let mut current = cwd.borrow().clone();
+        match execute(output, &session, &mut current, command, config) {
+            Ok(()) => *cwd.borrow_mut() = current,
+            Err(e) => eprintln!("Error: {}", e.display_chain()),
+        }
+        output.finish();
+        if let Some(helper) = editor.helper() {
+            helper.listings.borrow_mut().clear();
+        }
+    }
+
+    if let Some(path) = &history {
+        if let Some(parent) = path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        let _ = editor.save_history(path);
+    }
+    Ok(())
+}
+
+/// Runs one command; `cd` updates `cwd`. Shared with `batch`.
+pub(crate) fn execute(
+    output: &Output,
+    session: &Session,
+    cwd: &mut String,
+    command: ShellCommand,
+    config: &Config,
+) -> Result<()> {
+    match command {
+        ShellCommand::Ls { path, long } => {
+            let path = resolve(cwd, &config.expand_alias(path.as_deref().unwrap_or(".")));
+            ls::ls(output, session, &path, LsOptions { long, ..LsOptions::default() })
+        }
+        ShellCommand::Cd { path } => {
+            let path = resolve(cwd, &config.expand_alias(path.as_deref().unwrap_or("/")));
+            if path != "/" && !session.stat(&path)?.is_folder {
+                return Err(Error::InvalidPath(format!("'{}' is not a directory", path)));
+            }
+            *cwd = path;
+            Ok(())
+        }
+        ShellCommand::Pwd => {
+            println!("{}", cwd);
+            Ok(())
+        }
+        ShellCommand::Pull {
+            remote,
+            local,
+            recursive,
+            overwrite,
+        } => {
+            let mut paths = vec![resolve(cwd, &config.expand_alias(&remote))];
+            paths.extend(local);
+            let options = PullOptions {
+                recursive,
+                overwrite: overwrite.policy(config.overwrite()),
+                ..PullOptions::default()
+            };
+            pull::pull(output, session, &paths, options, &PathFilter::default(), config.download_dir())
+        }
+        ShellCommand::Push {
+            local,
+            remote,
+            overwrite,
+        } => {
+            let remote = resolve(cwd, &config.expand_alias(remote.as_deref().unwrap_or(".")));
+            let options = PushOptions {
+                overwrite: overwrite.policy(config.overwrite()),
+                ..PushOptions::default()
+            };
+            push::push(output, session, &local, &remote, options)
+        }
+        ShellCommand::Rm { paths, recursive } => {
+            let paths: Vec<String> = paths.iter().map(|p| resolve(cwd, &config.expand_alias(p))).collect();
+            rm::rm(output, session, &paths, recursive, config.trash(), false)
+        }
+        ShellCommand::Exit => Ok(()),
+    }
+}
+
+/// Resolves `path` against the device folder `cwd`, folding `.` and `..`.
+pub(crate) fn resolve(cwd: &str, path: &str) -> String {
+    let mut parts: Vec<&str> = if path.starts_with('/') {
+        Vec::new()
+    } else {
+        cwd.split('/').filter(|s| !s.is_empty()).collect()
+    };
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+fn history_path() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|dir| dir.join("kindle-mtp").join("shell_history"))
+}
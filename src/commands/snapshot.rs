@@ -0,0 +1,235 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::sync::normalize_remote_dir;
+use crate::device::Kindle;
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use schemars::JsonSchema;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// File format of an export, chosen by extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotFormat {
+    Json,
+    Sqlite,
+}
+
+impl SnapshotFormat {
+    fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase).as_deref() {
+            Some("json") => Ok(Self::Json),
+            Some("sqlite" | "sqlite3" | "db") => Ok(Self::Sqlite),
+            _ => Err(Error::InvalidPath(format!(
+                "'{}': use a .json or .sqlite file",
+                path.display()
+            ))),
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct SnapshotOutput {
+    pub file: String,
+    pub format: SnapshotFormat,
+    pub root: String,
+    pub files: usize,
+    pub folders: usize,
+    pub bytes: u64,
+}
+
+impl HumanReadable for SnapshotOutput {
+    fn to_human(&self) -> String {
+        format!(
+            "Exported {} to {} ({} files, {} folders, {} bytes)",
+            self.root, self.file, self.files, self.folders, self.bytes
+        )
+    }
+}
+
+/// A node of the nested JSON export.
+#[derive(Serialize, JsonSchema)]
+struct TreeNode {
+    name: String,
+    path: String,
+    size: u64,
+    is_folder: bool,
+    modified: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    children: Vec<TreeNode>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct JsonSnapshot {
+    created: DateTime<Utc>,
+    serial: String,
+    model: String,
+    root: TreeNode,
+}
+
+/// One flattened entry, as stored in SQLite.
+struct Row {
+    path: String,
+    parent: String,
+    name: String,
+    size: u64,
+    is_folder: bool,
+    modified: DateTime<Utc>,
+}
+
+const SQLITE_SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS snapshots (
+    id       INTEGER PRIMARY KEY,
+    created  TEXT NOT NULL,
+    serial   TEXT NOT NULL,
+    model    TEXT NOT NULL,
+    root     TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS entries (
+    snapshot_id INTEGER NOT NULL REFERENCES snapshots(id),
+    path        TEXT NOT NULL,
+    parent      TEXT NOT NULL,
+    name        TEXT NOT NULL,
+    size        INTEGER NOT NULL,
+    is_folder   INTEGER NOT NULL,
+    modified    TEXT NOT NULL,
+    PRIMARY KEY (snapshot_id, path)
+);
+CREATE INDEX IF NOT EXISTS entries_parent ON entries(snapshot_id, parent);
+";
+
+/// Walks the device tree under `root` and writes it to `file`. A JSON export
+/// is a single nested tree; a SQLite export adds one snapshot to the
+/// database, so the same file accumulates a device's history and can be
+/// queried (or diffed) without the device attached.
+pub fn run_snapshot_export(output: &Output, file: &str, root: &str) -> Result<()> {
+    let path = Path::new(file);
+    let format = SnapshotFormat::from_path(path)?;
+
+    let kindle = Kindle::detect()?;
+    let info = kindle.info();
+    let root = normalize_remote_dir(root);
+    let created = Utc::now();
+
+    let rows: Vec<Row> = kindle
+        .walk(&root)?
+        .into_iter()
+        .map(|item| {
+            let (parent, name) = item.path.rsplit_once('/').unwrap_or(("", &item.path));
+            Row {
+                parent: if parent.is_empty() { "/" } else { parent }.to_string(),
+                name: name.to_string(),
+                size: item.entry.size,
+                is_folder: item.entry.is_folder,
+                modified: item.entry.modified,
+                path: item.path,
+            }
+        })
+        .collect();
+
+    let snapshot_output = SnapshotOutput {
+        file: file.to_string(),
+        format,
+        root: root.clone(),
+        files: rows.iter().filter(|r| !r.is_folder).count(),
+        folders: rows.iter().filter(|r| r.is_folder).count(),
+        bytes: rows.iter().filter(|r| !r.is_folder).map(|r| r.size).sum(),
+    };
+
+    match format {
+        SnapshotFormat::Json => {
+            let snapshot = JsonSnapshot {
+                created,
+                serial: info.serial,
+                model: info.model,
+                root: build_tree(&root, &rows, created),
+            };
+            let data = serde_json::to_vec_pretty(&snapshot)
+                .map_err(|e| Error::Io(std::io::Error::other(e)))?;
+            std::fs::write(path, data)?;
+        }
+        SnapshotFormat::Sqlite => {
+            write_sqlite(path, created, &info.serial, &info.model, &root, &rows)
+                .map_err(|e| Error::Io(std::io::Error::other(e)))?;
+        }
+    }
+
+    output.print(&snapshot_output);
+    Ok(())
+}
+
+/// Nests the flat walk under `root`.
+fn build_tree(root: &str, rows: &[Row], created: DateTime<Utc>) -> TreeNode {
+    let mut by_parent: HashMap<&str, Vec<&Row>> = HashMap::new();
+    for row in rows {
+        by_parent.entry(row.parent.as_str()).or_default().push(row);
+    }
+
+    fn children_of(parent: &str, by_parent: &HashMap<&str, Vec<&Row>>) -> Vec<TreeNode> {
+        by_parent
+            .get(parent)
+            .into_iter()
+            .flatten()
+            .map(|r| TreeNode {
+                name: r.name.clone(),
+                path: r.path.clone(),
+                size: r.size,
+                is_folder: r.is_folder,
+                modified: r.modified,
+                children: if r.is_folder {
+                    children_of(&r.path, by_parent)
+                } else {
+                    Vec::new()
+                },
+            })
+            .collect()
+    }
+
+    TreeNode {
+        name: root.rsplit('/').next().unwrap_or_default().to_string(),
+        path: root.to_string(),
+        size: 0,
+        is_folder: true,
+        modified: created,
+        children: children_of(root, &by_parent),
+    }
+}
+
+fn write_sqlite(
+    path: &Path,
+    created: DateTime<Utc>,
+    serial: &str,
+    model: &str,
+    root: &str,
+    rows: &[Row],
+) -> rusqlite::Result<()> {
+    let mut conn = Connection::open(path)?;
+    conn.execute_batch(SQLITE_SCHEMA)?;
+
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT INTO snapshots (created, serial, model, root) VALUES (?1, ?2, ?3, ?4)",
+        params![created.to_rfc3339(), serial, model, root],
+    )?;
+    let snapshot_id = tx.last_insert_rowid();
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO entries (snapshot_id, path, parent, name, size, is_folder, modified)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?;
+        for row in rows {
+            insert.execute(params![
+                snapshot_id,
+                row.path,
+                row.parent,
+                row.name,
+                row.size as i64,
+                row.is_folder,
+                row.modified.to_rfc3339(),
+            ])?;
+        }
+    }
+    tx.commit()
+}
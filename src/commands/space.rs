@@ -0,0 +1,48 @@
+use crate::cli::Output;
+use crate::commands::ls::format_size;
+use crate::error::{Error, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Space left free by default: the Kindle needs some for its own indexes
+/// and `.sdr` reading state.
+pub const DEFAULT_FREE_SPACE_MARGIN: u64 = 10 * 1024 * 1024;
+
+/// Free space uploads must leave on the device; see `set_free_space_margin`.
+static MARGIN: AtomicU64 = AtomicU64::new(DEFAULT_FREE_SPACE_MARGIN);
+
+/// Sets the free space `push` and `sync` leave on the device. Set once at
+/// startup, from `free_space_margin` in the config file.
+pub fn set_free_space_margin(bytes: u64) {
+    MARGIN.store(bytes, Ordering::Relaxed);
+}
+
+/// `Error::StorageFull` saying how much is missing if uploading `needed`
+/// bytes in `files` files would eat into the margin.
+fn ensure_free_space(free: u64, needed: u64, files: usize) -> Result<()> {
+    let margin = MARGIN.load(Ordering::Relaxed);
+    let required = needed.saturating_add(margin);
+    if required <= free {
+        return Ok(());
+    }
+    Err(Error::StorageFull(format!(
+        "{} file{} need {}, the device has {} free ({} kept spare): {} short",
+        files,
+        if files == 1 { "" } else { "s" },
+        format_size(needed),
+        format_size(free),
+        format_size(margin),
+        format_size(required - free)
+    )))
+}
+
+/// `ensure_free_space` before uploading; a dry run only warns, since
+/// nothing is written.
+pub(crate) fn precheck(output: &Output, free: u64, needed: u64, files: usize, dry_run: bool) -> Result<()> {
+    match ensure_free_space(free, needed, files) {
+        Err(e) if dry_run => {
+            output.warn(e.to_string());
+            Ok(())
+        }
+        result => result,
+    }
+}
@@ -0,0 +1,121 @@
+//! Local copies of device files, staged on their way to or from the Kindle.
+//!
+//! The temp directory is shared, so staging files there under predictable
+//! names would let another user read them, or plant a symlink where one is
+//! about to be written. Each [`StagingDir`] is a fresh directory only this
+//! user can enter, and files written into it must not exist yet.
+
+use crate::error::{Error, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// How many taken names `StagingDir::new` skips before giving up.
+const ATTEMPTS: u32 = 100;
+
+/// A private directory in the temp directory, removed with everything in it
+/// when dropped.
+pub(crate) struct StagingDir {
+    path: PathBuf,
+}
+
+impl StagingDir {
+    /// Creates `kindle-mtp-<purpose>-<pid>-<n>` in the temp directory. A
+    /// name that is taken, by a leftover or by someone else, is skipped
+    /// rather than reused.
+    pub(crate) fn new(purpose: &str) -> Result<Self> {
+        let temp = std::env::temp_dir();
+        for n in 0..ATTEMPTS {
+            let path = temp.join(format!("kindle-mtp-{}-{}-{}", purpose, std::process::id(), n));
+            match create_private_dir(&path) {
+                Ok(()) => return Ok(Self { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(Error::Io(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("no free staging directory name in {}", temp.display()),
+        )))
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Where a file called `name` goes; nothing is created. For downloads,
+    /// which create the file themselves.
+    pub(crate) fn join(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+
+    /// Creates the file `name`, failing if it exists.
+    pub(crate) fn create(&self, name: &str) -> Result<(PathBuf, File)> {
+        let path = self.join(name);
+        let file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        Ok((path, file))
+    }
+
+    /// Writes `data` to the new file `name`.
+    pub(crate) fn write(&self, name: &str, data: &[u8]) -> Result<PathBuf> {
+        let (path, mut file) = self.create(name)?;
+        file.write_all(data)?;
+        Ok(path)
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            debug!(path = %self.path.display(), error = %e, "could not remove staging directory");
+        }
+    }
+}
+
+#[cfg(unix)]
+fn create_private_dir(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    std::fs::DirBuilder::new().mode(0o700).create(path)
+}
+
+/// The temp directory is per user on Windows.
+#[cfg(not(unix))]
+fn create_private_dir(path: &Path) -> io::Result<()> {
+    std::fs::create_dir(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_private_and_removed_on_drop() {
+        let staging = StagingDir::new("test").unwrap();
+        let dir = staging.path().to_path_buf();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+        }
+        let path = staging.write("book.json", b"{}").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"{}");
+        drop(staging);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn existing_files_are_not_overwritten() {
+        let staging = StagingDir::new("test").unwrap();
+        staging.write("a", b"first").unwrap();
+        assert!(staging.write("a", b"second").is_err());
+        assert_eq!(std::fs::read(staging.join("a")).unwrap(), b"first");
+    }
+
+    #[test]
+    fn taken_names_are_skipped() {
+        let first = StagingDir::new("test-taken").unwrap();
+        let second = StagingDir::new("test-taken").unwrap();
+        assert_ne!(first.path(), second.path());
+    }
+}
@@ -0,0 +1,94 @@
+use crate::cli::{HumanReadable, Output};
+use crate::daemon::Session;
+use crate::device::{ObjectProperty, PropertyValue, TransferOptions};
+use crate::error::Result;
+use crate::metadata::{read_metadata, BookMetadata};
+use chrono::{DateTime, Local, Utc};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+#[derive(Serialize, JsonSchema)]
+pub struct StatOutput {
+    pub path: String,
+    pub size: u64,
+    pub is_folder: bool,
+    /// MTP object handle; only stable for the current session
+    pub id: u32,
+    pub modified: DateTime<Utc>,
+    /// Raw MTP object properties, with `--props`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub properties: Option<Vec<ObjectProperty>>,
+    /// What the book's header says, with `--meta`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<BookMetadata>,
+}
+
+impl HumanReadable for StatOutput {
+    fn to_human(&self) -> String {
+        let mut out = format!(
+            "    Path: {}\n    Type: {}\n    Size: {} bytes\nModified: {}\n  Object: {}",
+            self.path,
+            if self.is_folder { "folder" } else { "file" },
+            self.size,
+            self.modified.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S %z"),
+            self.id
+        );
+        if let Some(metadata) = &self.metadata {
+            out.push_str(&format!("\n\n   Title: {}", metadata.title));
+            for (label, value) in [
+                ("Author", &metadata.author),
+                ("ASIN", &metadata.asin),
+                ("Language", &metadata.language),
+                ("Publisher", &metadata.publisher),
+            ] {
+                if let Some(value) = value {
+                    out.push_str(&format!("\n{:>8}: {}", label, value));
+                }
+            }
+        }
+        if let Some(properties) = &self.properties {
+            let width = properties.iter().map(|p| p.name.len()).max().unwrap_or(0);
+            out.push_str("\n\nProperties:");
+            for property in properties {
+                let value = match &property.value {
+                    PropertyValue::Integer(n) => n.to_string(),
+                    PropertyValue::Text(text) => text.clone(),
+                };
+                out.push_str(&format!("\n  {:<width$}  {}", property.name, value, width = width));
+            }
+        }
+        out
+    }
+}
+
+/// Shows one file or folder on the device. `props` also reads its raw MTP
+/// properties and `meta` a book's header; both need the device itself
+/// rather than the daemon.
+pub fn run_stat(output: &Output, path: &str, props: bool, meta: bool) -> Result<()> {
+    let session = Session::open(TransferOptions::default())?;
+    let entry = session.stat(path)?;
+    let properties = if props {
+        Some(session.direct("stat --props")?.object_properties(path)?)
+    } else {
+        None
+    };
+    let metadata = if meta && !entry.is_folder {
+        let metadata = read_metadata(session.direct("stat --meta")?, path)?;
+        if metadata.is_none() {
+            output.warn(format!("{} has no header kindle-mtp can read (MOBI, AZW or AZW3)", path));
+        }
+        metadata
+    } else {
+        None
+    };
+    output.print(&StatOutput {
+        path: path.to_string(),
+        size: entry.size,
+        is_folder: entry.is_folder,
+        id: entry.id,
+        modified: entry.modified,
+        properties,
+        metadata,
+    });
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use crate::cli::{HumanReadable, Output};
+use crate::error::Result;
+use crate::history;
+use chrono::{Duration, NaiveDate, Utc};
+use serde::Serialize;
+use schemars::JsonSchema;
+use std::collections::BTreeMap;
+
+#[derive(Serialize, JsonSchema)]
+pub struct StatsOutput {
+    pub since: String,
+    pub runs: usize,
+    pub failed: usize,
+    pub warnings: usize,
+    pub bytes: u64,
+    pub days: Vec<DayStats>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct DayStats {
+    pub date: NaiveDate,
+    pub runs: usize,
+    pub failed: usize,
+    pub warnings: usize,
+    pub bytes: u64,
+    /// Bytes per second over runs that moved data; `None` if none did
+    pub throughput: Option<u64>,
+}
+
+impl HumanReadable for StatsOutput {
+    fn to_human(&self) -> String {
+        if self.runs == 0 {
+            return format!("No runs recorded since {}", self.since);
+        }
+        let mut lines = vec![format!(
+            "{:<10}  {:>5}  {:>6}  {:>8}  {:>10}  {:>10}",
+            "DATE", "RUNS", "FAILED", "WARNINGS", "BYTES", "RATE"
+        )];
+        for day in &self.days {
+            lines.push(format!(
+                "{:<10}  {:>5}  {:>6}  {:>8}  {:>10}  {:>10}",
+                day.date,
+                day.runs,
+                day.failed,
+                day.warnings,
+                day.bytes,
+                day.throughput
+                    .map(|r| format!("{}/s", format_rate(r)))
+                    .unwrap_or_else(|| "-".to_string())
+            ));
+        }
+        lines.push(format!(
+            "{} runs since {}, {} failed, {} warnings, {} bytes transferred",
+            self.runs, self.since, self.failed, self.warnings, self.bytes
+        ));
+        lines.join("\n")
+    }
+}
+
+fn format_rate(bytes_per_sec: u64) -> String {
+    if bytes_per_sec >= 1_000_000 {
+        format!("{:.1}M", bytes_per_sec as f64 / 1_000_000.0)
+    } else if bytes_per_sec >= 1_000 {
+        format!("{:.1}K", bytes_per_sec as f64 / 1_000.0)
+    } else {
+        format!("{}B", bytes_per_sec)
+    }
+}
+
+/// Summarises the run history day by day. A falling transfer rate or a
+/// rising failure count across days usually points at a cable or a device
+/// on its way out.
+pub fn run_stats(output: &Output, last: Duration) -> Result<()> {
+    let since = Utc::now() - last;
+    let runs = history::load_since(since)?;
+
+    // (runs, failed, warnings, bytes, transfer bytes, transfer ms)
+    let mut by_day: BTreeMap<NaiveDate, (usize, usize, usize, u64, u64, u64)> = BTreeMap::new();
+    for run in &runs {
+        let day = by_day.entry(run.started.date_naive()).or_default();
+        day.0 += 1;
+        day.1 += usize::from(run.error.is_some());
+        day.2 += run.warnings;
+        day.3 += run.bytes;
+        if run.bytes > 0 {
+            day.4 += run.bytes;
+            day.5 += run.duration_ms;
+        }
+    }
+
+    let days: Vec<DayStats> = by_day
+        .into_iter()
+        .map(|(date, (runs, failed, warnings, bytes, moved, ms))| DayStats {
+            date,
+            runs,
+            failed,
+            warnings,
+            bytes,
+            throughput: (moved > 0 && ms > 0).then(|| moved * 1000 / ms),
+        })
+        .collect();
+
+    let stats_output = StatsOutput {
+        since: since.format("%Y-%m-%d %H:%M UTC").to_string(),
+        runs: runs.len(),
+        failed: days.iter().map(|d| d.failed).sum(),
+        warnings: days.iter().map(|d| d.warnings).sum(),
+        bytes: days.iter().map(|d| d.bytes).sum(),
+        days,
+    };
+
+    output.print(&stats_output);
+    Ok(())
+}
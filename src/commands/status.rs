@@ -1,9 +1,11 @@
 use crate::cli::{HumanReadable, Output};
-use crate::device::Kindle;
+use crate::daemon::Session;
+use crate::device::TransferOptions;
 use crate::error::Result;
 use serde::Serialize;
+use schemars::JsonSchema;
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 pub struct StatusOutput {
     pub connected: bool,
     pub model: String,
@@ -26,9 +28,7 @@ impl HumanReadable for StatusOutput {
 }
 
 pub fn run_status(output: &Output) -> Result<()> {
-    let kindle = Kindle::detect()?;
-    let info = kindle.info();
-    let storage = kindle.storage_info()?;
+    let (info, storage) = Session::open(TransferOptions::default())?.info()?;
 
     let status = StatusOutput {
         connected: true,
@@ -0,0 +1,752 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::books::{enclosing_sdr, is_sidecar_path, split_path};
+use crate::commands::safe_path::{join_under, prepare_under};
+use crate::commands::sanitize::{device_name, Renamed};
+use crate::commands::space::precheck;
+use crate::commands::calibre::update_device_metadata;
+use crate::commands::filter::PathFilter;
+use crate::commands::overwrite::is_newer;
+use crate::commands::push::upload_atomically;
+use crate::config::SyncPair;
+use crate::daemon::Session;
+use crate::device::{FileEntry, Kindle, TransferOptions};
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use schemars::JsonSchema;
+use std::collections::HashSet;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, JsonSchema)]
+pub struct SyncOutput {
+    pub source: String,
+    pub destination: String,
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    pub skipped: Vec<String>,
+    /// Destination paths `--delete` removed because the source has no
+    /// counterpart
+    pub deleted: Vec<String>,
+    /// Local names `--sanitize` changed, with the device path they got
+    pub renamed: Vec<Renamed>,
+    pub bytes: u64,
+    pub dry_run: bool,
+    /// Device paths uploaded to and the local files they came from, for
+    /// calibre's device metadata
+    #[serde(skip)]
+    uploads: Vec<(String, PathBuf)>,
+    /// Device paths deleted, for the same
+    #[serde(skip)]
+    removals: Vec<String>,
+}
+
+impl HumanReadable for SyncOutput {
+    fn to_human(&self) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        lines.extend(self.created.iter().map(|p| format!("+ {}", p)));
+        lines.extend(self.updated.iter().map(|p| format!("~ {}", p)));
+        lines.extend(self.deleted.iter().map(|p| format!("- {}", p)));
+        lines.extend(self.renamed.iter().map(|r| format!("  {} -> {}", r.from, r.to)));
+        let deleted = if self.deleted.is_empty() {
+            String::new()
+        } else {
+            format!(", {} deleted", self.deleted.len())
+        };
+        lines.push(format!(
+            "{} created, {} updated{}, {} skipped ({} bytes {})",
+            self.created.len(),
+            self.updated.len(),
+            deleted,
+            self.skipped.len(),
+            self.bytes,
+            if self.dry_run { "to transfer, dry run" } else { "transferred" }
+        ));
+        lines.join("\n")
+    }
+}
+
+#[derive(Clone, Copy, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Change {
+    Created,
+    Updated,
+    Skipped,
+    Deleted,
+}
+
+/// NDJSON event for one file the sync copied, would copy, deleted, or left
+/// alone.
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct SyncEvent<'a> {
+    pub change: Change,
+    pub path: &'a str,
+    pub bytes: u64,
+    pub dry_run: bool,
+}
+
+impl SyncOutput {
+    /// Counts one file; NDJSON streams it instead of collecting the path.
+    fn record(&mut self, output: &Output, change: Change, path: String, bytes: u64) {
+        self.bytes += bytes;
+        if output.is_ndjson() {
+            let dry_run = self.dry_run;
+            output.event("file", &SyncEvent { change, path: &path, bytes, dry_run });
+            return;
+        }
+        match change {
+            Change::Created => self.created.push(path),
+            Change::Updated => self.updated.push(path),
+            Change::Skipped => self.skipped.push(path),
+            Change::Deleted => self.deleted.push(path),
+        }
+    }
+
+    /// Notes a name `--sanitize` changed; NDJSON streams it.
+    fn record_rename(&mut self, output: &Output, from: &Path, to: &str) {
+        let renamed = Renamed {
+            from: from.display().to_string(),
+            to: to.to_string(),
+        };
+        if output.is_ndjson() {
+            output.event("renamed", &renamed);
+            return;
+        }
+        self.renamed.push(renamed);
+    }
+}
+
+/// How a sync treats what it finds on either side.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SyncOptions {
+    /// Compute and print the plan without changing either side
+    pub dry_run: bool,
+    /// Make uploaded file and folder names safe for the device first
+    pub sanitize: bool,
+    /// Keep destination files modified after their source even when the
+    /// sizes differ, rather than replacing them
+    pub newer: bool,
+    /// Remove destination files and folders the source has no counterpart
+    /// for
+    pub delete: bool,
+}
+
+/// Prefix marking the device side of a sync, e.g. `kindle:/documents`.
+const DEVICE_PREFIX: &str = "kindle:";
+
+/// Which way a sync copies, with the device path and the local path.
+enum Direction<'a> {
+    ToDevice { local: &'a str, remote: &'a str },
+    FromDevice { remote: &'a str, local: &'a str },
+}
+
+/// Works out the device side of a sync and checks what can be checked
+/// without the device.
+fn direction<'a>(source: &'a str, destination: &'a str) -> Result<Direction<'a>> {
+    let direction = match (source.strip_prefix(DEVICE_PREFIX), destination.strip_prefix(DEVICE_PREFIX)) {
+        (Some(_), Some(_)) => {
+            return Err(Error::InvalidPath(
+                "Only one side of a sync can be on the device".to_string(),
+            ));
+        }
+        (Some(remote), None) => Direction::FromDevice { remote, local: destination },
+        (None, Some(remote)) => Direction::ToDevice { local: source, remote },
+        (None, None) => Direction::ToDevice { local: source, remote: destination },
+    };
+    if let Direction::ToDevice { local, .. } = direction
+        && !Path::new(local).is_dir()
+    {
+        return Err(Error::InvalidPath(format!("'{}' is not a directory", local)));
+    }
+    Ok(direction)
+}
+
+/// One-way mirror between a local folder and a device folder. The side
+/// prefixed with `kindle:` is the device; an unprefixed destination is also
+/// treated as the device, so `sync ./books /documents` pushes. Only what
+/// `filter` takes is synced.
+pub fn run_sync(
+    output: &Output,
+    source: &str,
+    destination: &str,
+    options: SyncOptions,
+    filter: &PathFilter,
+    transfer: TransferOptions,
+) -> Result<()> {
+    let direction = direction(source, destination)?;
+    let session = Session::Direct(Kindle::open(transfer)?);
+    sync(output, &session, direction, options, filter)
+}
+
+/// Runs every `[[sync]]` pair from the config in order over one device
+/// session, stopping at the first failure. Every pair is checked first.
+pub fn run_sync_pairs(
+    output: &Output,
+    pairs: &[SyncPair],
+    options: SyncOptions,
+    filter: &PathFilter,
+    transfer: TransferOptions,
+) -> Result<()> {
+    if pairs.is_empty() {
+        return Err(Error::InvalidPath(
+            "No sync pairs configured (pass SOURCE DESTINATION or run `kindle-mtp init`)".to_string(),
+        ));
+    }
+    let directions = pairs
+        .iter()
+        .map(|pair| direction(&pair.source, &pair.destination))
+        .collect::<Result<Vec<_>>>()?;
+    let session = Session::Direct(Kindle::open(transfer)?);
+    for direction in directions {
+        sync(output, &session, direction, options, filter)?;
+    }
+    Ok(())
+}
+
+fn sync(
+    output: &Output,
+    session: &Session,
+    direction: Direction,
+    options: SyncOptions,
+    filter: &PathFilter,
+) -> Result<()> {
+    let kindle = session.direct("sync")?;
+    match direction {
+        Direction::ToDevice { local, remote } => sync_to_device(output, session, local, remote, options, filter),
+        Direction::FromDevice { remote, local } => sync_from_device(output, kindle, remote, local, options, filter),
+    }
+}
+
+/// Uploads files that are missing on the device, or whose size differs or
+/// whose local copy is newer. With `delete`, removes what the local folder
+/// doesn't have.
+fn sync_to_device(
+    output: &Output,
+    session: &Session,
+    source: &str,
+    destination: &str,
+    options: SyncOptions,
+    filter: &PathFilter,
+) -> Result<()> {
+    let SyncOptions { dry_run, .. } = options;
+    let kindle = session.direct("sync")?;
+    let source_path = Path::new(source);
+    let destination = normalize_remote_dir(destination);
+    let (needed, files) = upload_size(kindle, source_path, &destination, "", options, filter)?;
+    if files > 0 {
+        precheck(output, kindle.storage_info()?.free_bytes, needed, files, dry_run)?;
+    }
+    if !dry_run {
+        kindle.create_folder_all(&destination)?;
+    }
+
+    let mut sync_output = SyncOutput {
+        source: source.to_string(),
+        destination: destination.clone(),
+        created: Vec::new(),
+        updated: Vec::new(),
+        skipped: Vec::new(),
+        deleted: Vec::new(),
+        renamed: Vec::new(),
+        bytes: 0,
+        dry_run,
+        uploads: Vec::new(),
+        removals: Vec::new(),
+    };
+
+    sync_dir(output, session, source_path, &destination, "", options, filter, &mut sync_output)?;
+    if !sync_output.uploads.is_empty() || !sync_output.removals.is_empty() {
+        update_device_metadata(output, session, |metadata| {
+            for path in &sync_output.removals {
+                metadata.record_removal(path);
+            }
+            for (remote, local) in &sync_output.uploads {
+                metadata.record_upload(remote, local, None);
+            }
+        });
+    }
+
+    output.print(&sync_output);
+    Ok(())
+}
+
+/// Downloads files that are missing locally, or whose size differs or whose
+/// device copy is newer. Local timestamps are set from the device so the next
+/// run can compare them. With `delete`, removes local files the device
+/// doesn't have.
+fn sync_from_device(
+    output: &Output,
+    kindle: &Kindle,
+    source: &str,
+    destination: &str,
+    options: SyncOptions,
+    filter: &PathFilter,
+) -> Result<()> {
+    let SyncOptions {
+        dry_run, newer, delete, ..
+    } = options;
+    let source = normalize_remote_dir(source);
+    let destination_path = Path::new(destination);
+    if !dry_run {
+        std::fs::create_dir_all(destination_path)?;
+    }
+
+    let mut sync_output = SyncOutput {
+        source: format!("{}{}", DEVICE_PREFIX, source),
+        destination: destination.to_string(),
+        created: Vec::new(),
+        updated: Vec::new(),
+        skipped: Vec::new(),
+        deleted: Vec::new(),
+        renamed: Vec::new(),
+        bytes: 0,
+        dry_run,
+        uploads: Vec::new(),
+        removals: Vec::new(),
+    };
+
+    let mut walked = kindle.walk(&source)?;
+    filter.retain(&source, &mut walked);
+    let on_device: HashSet<String> = walked
+        .iter()
+        .map(|item| item.path[source.len()..].trim_start_matches('/').to_string())
+        .collect();
+    for item in walked {
+        let relative = item.path[source.len()..].trim_start_matches('/');
+        let local_path = match join_under(destination_path, relative).and_then(|p| {
+            if !dry_run {
+                prepare_under(destination_path, &p)?;
+            }
+            Ok(p)
+        }) {
+            Ok(p) => p,
+            Err(e) => {
+                output.warn(format!("skipped: {}", e.display_chain()));
+                continue;
+            }
+        };
+
+        if item.entry.is_folder {
+            if !dry_run {
+                std::fs::create_dir_all(&local_path)?;
+            }
+            continue;
+        }
+
+        let label = local_path.display().to_string();
+        match std::fs::metadata(&local_path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                if !dry_run {
+                    output.timed(&item.path, || {
+                        download_preserving_mtime(kindle, &item.path, &local_path, &item.entry).map(|()| item.entry.size)
+                    })?;
+                }
+                sync_output.record(output, Change::Created, label, item.entry.size);
+            }
+            Err(e) => return Err(e.into()),
+            Ok(metadata) if metadata.is_dir() => {
+                return Err(Error::InvalidPath(format!("'{}' is a directory", label)));
+            }
+            Ok(metadata) if is_remote_changed(&item.entry, &metadata, newer) => {
+                if !dry_run {
+                    output.timed(&item.path, || {
+                        download_preserving_mtime(kindle, &item.path, &local_path, &item.entry).map(|()| item.entry.size)
+                    })?;
+                }
+                sync_output.record(output, Change::Updated, label, item.entry.size);
+            }
+            Ok(_) => sync_output.record(output, Change::Skipped, label, 0),
+        }
+    }
+    if delete && destination_path.is_dir() {
+        remove_local_extras(output, destination_path, "", &on_device, filter, &mut sync_output)?;
+    }
+
+    output.print(&sync_output);
+    Ok(())
+}
+
+/// Downloads a file and copies the device timestamp onto the local copy.
+pub(crate) fn download_preserving_mtime(kindle: &Kindle, remote: &str, local: &Path, entry: &FileEntry) -> Result<()> {
+    kindle.download_file(remote, local)?;
+    let file = std::fs::File::options().write(true).open(local)?;
+    file.set_modified(entry.modified.into())?;
+    Ok(())
+}
+
+/// Syncs `local_dir` into `remote_dir`; `relative_dir` is where `local_dir`
+/// is below the sync's source, for `filter`.
+#[allow(clippy::too_many_arguments)]
+fn sync_dir(
+    output: &Output,
+    session: &Session,
+    local_dir: &Path,
+    remote_dir: &str,
+    relative_dir: &str,
+    options: SyncOptions,
+    filter: &PathFilter,
+    out: &mut SyncOutput,
+) -> Result<()> {
+    let SyncOptions { sanitize, newer, .. } = options;
+    let kindle = session.direct("sync")?;
+    let remote_entries = match kindle.list_files(remote_dir) {
+        // In a dry run, folders the sync would create don't exist yet
+        Err(Error::FileNotFound(_)) if out.dry_run => Vec::new(),
+        result => result?,
+    };
+
+    let mut local_entries: Vec<_> = std::fs::read_dir(local_dir)?.collect::<std::io::Result<_>>()?;
+    local_entries.sort_by_key(|e| e.file_name());
+
+    // Sanitizing can map two local names to one device name
+    let mut taken = HashSet::new();
+    for entry in local_entries {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => {
+                output.warn(format!(
+                    "skipped '{}': file name is not valid UTF-8",
+                    entry.path().display()
+                ));
+                continue;
+            }
+        };
+        // Dotfiles (.DS_Store, editor swap files) are never useful on the device
+        if name.starts_with('.') {
+            continue;
+        }
+        let relative = join_relative(relative_dir, &name);
+        if !filter_takes(filter, &entry.path(), &relative)? {
+            continue;
+        }
+
+        let local_path = entry.path();
+        let device_name = device_name(&name, sanitize);
+        let remote_path = join_remote(remote_dir, &device_name);
+        if !taken.insert(device_name.clone()) {
+            output.warn(format!(
+                "skipped '{}': another file is already synced as '{}'",
+                local_path.display(),
+                remote_path
+            ));
+            continue;
+        }
+        if device_name != name {
+            out.record_rename(output, &local_path, &remote_path);
+        }
+        let metadata = std::fs::metadata(&local_path)?;
+        let existing = remote_entries.iter().find(|e| e.name == device_name);
+
+        if metadata.is_dir() {
+            match existing {
+                Some(e) if e.is_folder => {}
+                Some(_) => {
+                    return Err(Error::InvalidPath(format!(
+                        "'{}' exists on the device and is not a directory",
+                        remote_path
+                    )))
+                }
+                None if out.dry_run => {}
+                None => {
+                    kindle.create_folder(&remote_path)?;
+                }
+            }
+            sync_dir(output, session, &local_path, &remote_path, &relative, options, filter, out)?;
+            continue;
+        }
+
+        match existing {
+            None => {
+                if !out.dry_run {
+                    output.timed(&remote_path, || {
+                        kindle.upload_file(&local_path, &remote_path).map(|()| metadata.len())
+                    })?;
+                    out.uploads.push((remote_path.clone(), local_path.clone()));
+                }
+                out.record(output, Change::Created, remote_path, metadata.len());
+            }
+            Some(remote) if remote.is_folder => {
+                return Err(Error::InvalidPath(format!(
+                    "'{}' exists on the device and is a directory",
+                    remote_path
+                )));
+            }
+            Some(remote) if is_changed(&metadata, remote, newer) => {
+                // MTP has no in-place overwrite, so replace the object once
+                // the new copy is on the device
+                if !out.dry_run {
+                    output.timed(&remote_path, || {
+                        upload_atomically(session, &remote_path, true, |path| kindle.upload_file(&local_path, path))
+                            .map(|()| metadata.len())
+                    })?;
+                    out.uploads.push((remote_path.clone(), local_path.clone()));
+                }
+                out.record(output, Change::Updated, remote_path, metadata.len());
+            }
+            Some(_) => out.record(output, Change::Skipped, remote_path, 0),
+        }
+    }
+
+    if options.delete {
+        for path in device_removals(kindle, remote_dir, relative_dir, &remote_entries, &taken, filter)? {
+            if !out.dry_run {
+                kindle.delete(&path)?;
+                out.removals.push(path.clone());
+            }
+            out.record(output, Change::Deleted, path, 0);
+        }
+    }
+    Ok(())
+}
+
+/// What `--delete` removes from `remote_dir`: the entries the local folder
+/// has nothing in `synced` for, with everything in them, contents before
+/// their folder. Dotfiles, whatever `filter` doesn't take and the `.sdr`
+/// folders and sidecar files of books that stay are left alone, along with
+/// the folders holding them.
+fn device_removals(
+    kindle: &Kindle,
+    remote_dir: &str,
+    relative_dir: &str,
+    remote_entries: &[FileEntry],
+    synced: &HashSet<String>,
+    filter: &PathFilter,
+) -> Result<Vec<String>> {
+    // Path, path below the sync's destination and whether it is a folder,
+    // each folder before what it holds
+    let mut extras: Vec<(String, String, bool)> = Vec::new();
+    for entry in remote_entries {
+        if synced.contains(&entry.name) || entry.name.starts_with('.') {
+            continue;
+        }
+        let path = join_remote(remote_dir, &entry.name);
+        let relative = join_relative(relative_dir, &entry.name);
+        if !entry.is_folder {
+            extras.push((path, relative, false));
+            continue;
+        }
+        if !filter.enters(&relative) {
+            continue;
+        }
+        let walked = kindle.walk(&path)?;
+        extras.push((path.clone(), relative.clone(), true));
+        for item in walked {
+            let below = item.path[path.len()..].trim_start_matches('/');
+            let item_relative = join_relative(&relative, below);
+            extras.push((item.path, item_relative, item.entry.is_folder));
+        }
+    }
+
+    let removes_file = |path: &str, relative: &str| !split_path(path).1.starts_with('.') && filter.keeps(relative);
+    // A book by its path without the extension, which its sidecars share
+    let book_key = |path: &str| {
+        let (folder, name) = split_path(path);
+        format!("{}/{}", folder, name.rsplit_once('.').map_or(name, |(stem, _)| stem))
+    };
+    let staying_books: HashSet<String> = remote_entries
+        .iter()
+        .filter(|entry| synced.contains(&entry.name) && !entry.is_folder)
+        .map(|entry| join_remote(remote_dir, &entry.name))
+        .chain(
+            extras
+                .iter()
+                .filter(|(path, relative, is_folder)| !is_folder && !removes_file(path, relative))
+                .map(|(path, ..)| path.clone()),
+        )
+        .filter(|path| !is_sidecar_path(path, false))
+        .map(|path| book_key(&path))
+        .collect();
+    let belongs_to_staying_book = |path: &str, is_folder: bool| {
+        is_sidecar_path(path, is_folder) && staying_books.contains(&book_key(enclosing_sdr(path).unwrap_or(path)))
+    };
+
+    let mut holding = HashSet::new();
+    let mut emptied = HashSet::new();
+    let mut removals = Vec::new();
+    for (path, relative, is_folder) in extras.into_iter().rev() {
+        let removed = !belongs_to_staying_book(&path, is_folder)
+            && if is_folder {
+                !holding.contains(&path) && (emptied.contains(&path) || filter.keeps_folder(&relative))
+            } else {
+                removes_file(&path, &relative)
+            };
+        let parent = split_path(&path).0.to_string();
+        if removed {
+            emptied.insert(parent);
+            removals.push(path);
+        } else {
+            holding.insert(parent);
+        }
+    }
+    Ok(removals)
+}
+
+/// Removes what is in the local `dir`, at `relative_dir` below the sync's
+/// destination, but not `on_device`, by the rules of [`device_removals`].
+/// Returns whether `dir` is left (or in a dry run, would be left) empty.
+fn remove_local_extras(
+    output: &Output,
+    dir: &Path,
+    relative_dir: &str,
+    on_device: &HashSet<String>,
+    filter: &PathFilter,
+    out: &mut SyncOutput,
+) -> Result<bool> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir)?.collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    let mut emptied = true;
+    for entry in entries {
+        let name = match entry.file_name().into_string() {
+            Ok(name) if !name.starts_with('.') => name,
+            _ => {
+                emptied = false;
+                continue;
+            }
+        };
+        let relative = join_relative(relative_dir, &name);
+        let path = entry.path();
+        // Links are removed, never followed
+        if entry.file_type()?.is_dir() {
+            let on_device_too = on_device.contains(&relative);
+            if !on_device_too && !filter.enters(&relative) {
+                emptied = false;
+                continue;
+            }
+            // Like on the device, a folder that was empty already only goes
+            // if the filter takes it
+            let was_empty = std::fs::read_dir(&path)?.next().is_none();
+            let holds_nothing = remove_local_extras(output, &path, &relative, on_device, filter, out)?;
+            if on_device_too || !holds_nothing || (was_empty && !filter.keeps_folder(&relative)) {
+                emptied = false;
+                continue;
+            }
+            if !out.dry_run {
+                std::fs::remove_dir(&path)?;
+            }
+        } else if on_device.contains(&relative) || !filter.keeps(&relative) {
+            emptied = false;
+            continue;
+        } else if !out.dry_run {
+            std::fs::remove_file(&path)?;
+        }
+        out.record(output, Change::Deleted, path.display().to_string(), 0);
+    }
+    Ok(emptied)
+}
+
+/// Bytes the device will need for a sync to `remote_dir`, and how many files
+/// it uploads. Replaced files count in full like new ones: the old copy is
+/// only deleted once the new one is on the device. Follows the same rules
+/// as `sync_dir`, without writing anything.
+fn upload_size(
+    kindle: &Kindle,
+    local_dir: &Path,
+    remote_dir: &str,
+    relative_dir: &str,
+    options: SyncOptions,
+    filter: &PathFilter,
+) -> Result<(u64, usize)> {
+    let remote_entries = match kindle.list_files(remote_dir) {
+        Err(Error::FileNotFound(_)) => Vec::new(),
+        result => result?,
+    };
+
+    let (mut needed, mut files) = (0, 0);
+    for entry in std::fs::read_dir(local_dir)? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        let relative = join_relative(relative_dir, &name);
+        if !filter_takes(filter, &entry.path(), &relative)? {
+            continue;
+        }
+        let name = device_name(&name, options.sanitize);
+        let metadata = std::fs::metadata(entry.path())?;
+        let existing = remote_entries.iter().find(|e| e.name == name);
+        if metadata.is_dir() {
+            let remote = join_remote(remote_dir, &name);
+            let (sub_needed, sub_files) = upload_size(kindle, &entry.path(), &remote, &relative, options, filter)?;
+            needed += sub_needed;
+            files += sub_files;
+            continue;
+        }
+        match existing {
+            None => {
+                needed += metadata.len();
+                files += 1;
+            }
+            Some(remote) if !remote.is_folder && is_changed(&metadata, remote, options.newer) => {
+                needed += metadata.len();
+                files += 1;
+            }
+            Some(_) => {}
+        }
+    }
+    Ok((needed, files))
+}
+
+/// Whether `filter` takes the local file or folder at `path`, `relative`
+/// below the sync's source. With `--include` a folder is only taken if it
+/// holds a file that is, so the sync doesn't create empty folders.
+fn filter_takes(filter: &PathFilter, path: &Path, relative: &str) -> Result<bool> {
+    if !std::fs::metadata(path)?.is_dir() {
+        return Ok(filter.keeps(relative));
+    }
+    if !filter.enters(relative) {
+        return Ok(false);
+    }
+    if filter.keeps_folder(relative) {
+        return Ok(true);
+    }
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if !name.starts_with('.') && filter_takes(filter, &entry.path(), &join_relative(relative, &name))? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn join_relative(dir: &str, name: &str) -> String {
+    if dir.is_empty() { name.to_string() } else { format!("{}/{}", dir, name) }
+}
+
+/// A file is changed when its size differs or the local copy is newer. Devices
+/// store timestamps at coarse resolution, so small differences are ignored.
+/// With `newer`, a device copy modified after the local one is never changed.
+fn is_changed(local: &Metadata, remote: &FileEntry, newer: bool) -> bool {
+    let Ok(modified) = local.modified().map(DateTime::<Utc>::from) else {
+        return local.len() != remote.size;
+    };
+    if newer && is_newer(remote.modified, modified) {
+        return false;
+    }
+    local.len() != remote.size || is_newer(modified, remote.modified)
+}
+
+/// Mirror of [`is_changed`] for the device → local direction.
+fn is_remote_changed(remote: &FileEntry, local: &Metadata, newer: bool) -> bool {
+    let Ok(modified) = local.modified().map(DateTime::<Utc>::from) else {
+        return true;
+    };
+    if newer && is_newer(modified, remote.modified) {
+        return false;
+    }
+    local.len() != remote.size || is_newer(remote.modified, modified)
+}
+
+pub(crate) fn normalize_remote_dir(path: &str) -> String {
+    let trimmed = path.trim_matches('/');
+    format!("/{}", trimmed)
+}
+
+pub(crate) fn join_remote(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+}
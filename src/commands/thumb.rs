@@ -0,0 +1,46 @@
+use crate::cli::{HumanReadable, Output};
+use crate::daemon::Session;
+use crate::device::TransferOptions;
+use crate::error::{Error, Result};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Serialize, JsonSchema)]
+pub struct ThumbOutput {
+    pub remote: String,
+    pub local: String,
+    pub bytes: u64,
+}
+
+impl HumanReadable for ThumbOutput {
+    fn to_human(&self) -> String {
+        format!("Saved thumbnail of {} -> {} ({} bytes)", self.remote, self.local, self.bytes)
+    }
+}
+
+/// Saves the device's thumbnail of `remote` (for books, the cover) to
+/// `local`, or writes it to stdout when `local` is `-`.
+pub fn run_thumb(output: &Output, remote: &str, local: &Path) -> Result<()> {
+    let session = Session::open(TransferOptions::default())?;
+    let kindle = session.direct("thumb")?;
+    if kindle.stat(remote)?.is_folder {
+        return Err(Error::InvalidPath(format!("'{}' is a folder", remote)));
+    }
+    let thumbnail = kindle.thumbnail(remote)?;
+
+    if local == Path::new("-") {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(&thumbnail)?;
+        stdout.flush()?;
+        return Ok(());
+    }
+    std::fs::write(local, &thumbnail)?;
+    output.print(&ThumbOutput {
+        remote: remote.to_string(),
+        local: local.display().to_string(),
+        bytes: thumbnail.len() as u64,
+    });
+    Ok(())
+}
@@ -0,0 +1,286 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::calibre::update_device_metadata;
+use crate::commands::ls::format_size;
+use crate::commands::safe_path::join_under;
+use crate::daemon::Session;
+use crate::device::lock::serial_file_name;
+use crate::device::TransferOptions;
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+const TRASH_DIR: &str = "trash";
+/// What was deleted and from where, written once the copy is complete.
+const ITEM_FILE: &str = "item.json";
+/// Holds the copy itself, under the deleted file or folder's own name.
+const FILES_DIR: &str = "files";
+
+/// A file or folder `rm --trash` kept a copy of before deleting it.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TrashItem {
+    /// Name to give `trash restore`
+    pub id: String,
+    /// The Kindle it was deleted from
+    pub serial: String,
+    pub path: String,
+    pub is_folder: bool,
+    /// Bytes in the copy, every file of a folder included
+    pub size: u64,
+    pub deleted: DateTime<Utc>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct TrashListOutput {
+    pub items: Vec<TrashItem>,
+}
+
+impl HumanReadable for TrashListOutput {
+    fn to_human(&self) -> String {
+        if self.items.is_empty() {
+            return "The trash is empty".to_string();
+        }
+        let id_width = self.items.iter().map(|item| item.id.len()).max().unwrap_or(0).max(2);
+        let several_devices = self.items.iter().any(|item| item.serial != self.items[0].serial);
+        let mut lines = vec![format!("{:<id_width$}  {:<16}  {:>9}  PATH", "ID", "DELETED", "SIZE")];
+        for item in &self.items {
+            let mut path = item.path.clone();
+            if item.is_folder {
+                path.push('/');
+            }
+            if several_devices {
+                path = format!("{} ({})", path, item.serial);
+            }
+            lines.push(format!(
+                "{:<id_width$}  {:<16}  {:>9}  {}",
+                item.id,
+                item.deleted.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M"),
+                format_size(item.size),
+                path
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct TrashRestoreOutput {
+    pub restored: Vec<TrashItem>,
+}
+
+impl HumanReadable for TrashRestoreOutput {
+    fn to_human(&self) -> String {
+        self.restored
+            .iter()
+            .map(|item| format!("restored {}", item.path))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Copies `path` (a folder with everything in it when `is_folder`) from the
+/// device into the trash of the Kindle with `serial`, before `rm` deletes
+/// it. A copy that fails part way is removed again.
+pub(crate) fn move_to_trash(
+    output: &Output,
+    session: &Session,
+    serial: &str,
+    path: &str,
+    is_folder: bool,
+) -> Result<TrashItem> {
+    let deleted = Utc::now();
+    let device_dir = device_dir(serial)?;
+    let base = deleted.format("%Y%m%d-%H%M%S").to_string();
+    let mut id = base.clone();
+    let mut n = 1;
+    while device_dir.join(&id).exists() {
+        n += 1;
+        id = format!("{}-{}", base, n);
+    }
+    let dir = device_dir.join(&id);
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let copy = dir.join(FILES_DIR).join(name);
+
+    let result = (|| -> Result<u64> {
+        std::fs::create_dir_all(dir.join(FILES_DIR))?;
+        if !is_folder {
+            return output.timed(path, || {
+                session.download_file(path, &copy)?;
+                Ok(std::fs::metadata(&copy)?.len())
+            });
+        }
+        std::fs::create_dir_all(&copy)?;
+        let mut size = 0;
+        for item in session.walk(path)? {
+            let relative = item.path.strip_prefix(path).unwrap_or(&item.path).trim_start_matches('/');
+            let local = join_under(&copy, relative)?;
+            if item.entry.is_folder {
+                std::fs::create_dir_all(&local)?;
+            } else {
+                output.timed(&item.path, || session.download_file(&item.path, &local).map(|()| item.entry.size))?;
+                size += item.entry.size;
+            }
+        }
+        Ok(size)
+    })();
+    let item = result.and_then(|size| {
+        let item = TrashItem {
+            id,
+            serial: serial.to_string(),
+            path: path.to_string(),
+            is_folder,
+            size,
+            deleted,
+        };
+        let json = serde_json::to_vec_pretty(&item).map_err(|e| Error::Io(std::io::Error::other(e)))?;
+        std::fs::write(dir.join(ITEM_FILE), json)?;
+        Ok(item)
+    });
+    if item.is_err() {
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    item
+}
+
+/// Lists what `rm --trash` kept, for every Kindle, newest first. The
+/// device doesn't need to be connected.
+pub fn run_trash_list(output: &Output) -> Result<()> {
+    let mut items = Vec::new();
+    let root = trash_root()?;
+    let devices = match std::fs::read_dir(&root) {
+        Ok(devices) => devices,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            output.print(&TrashListOutput { items });
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    for device in devices {
+        let device = device?.path();
+        if device.is_dir() {
+            items.extend(items_in(&device)?.into_iter().map(|(item, _)| item));
+        }
+    }
+    items.sort_by(|a, b| b.deleted.cmp(&a.deleted).then_with(|| b.id.cmp(&a.id)));
+    output.print(&TrashListOutput { items });
+    Ok(())
+}
+
+/// Puts trashed files and folders back where they were deleted from on the
+/// connected Kindle, and drops them from the trash. Nothing on the device
+/// is replaced: an item whose path is taken again is refused.
+pub fn run_trash_restore(output: &Output, ids: &[String], transfer: TransferOptions) -> Result<()> {
+    let session = Session::open(transfer)?;
+    let (info, _) = session.info()?;
+    let dir = device_dir(&info.serial)?;
+    let items = items_in(&dir)?;
+
+    let mut picked = Vec::new();
+    for id in ids {
+        let Some((item, item_dir)) = items.iter().find(|(item, _)| &item.id == id) else {
+            return Err(Error::FileNotFound(format!(
+                "'{}' is not in the trash of this Kindle ({})",
+                id, info.serial
+            )));
+        };
+        match session.stat(&item.path) {
+            Ok(_) => {
+                return Err(Error::InvalidPath(format!(
+                    "'{}' exists on the device; move it away to restore {}",
+                    item.path, item.id
+                )));
+            }
+            Err(Error::FileNotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+        picked.push((item.clone(), item_dir.clone()));
+    }
+
+    let kindle = session.direct("restoring from the trash")?;
+    let mut restore_output = TrashRestoreOutput { restored: Vec::new() };
+    let mut uploads = Vec::new();
+    for (item, item_dir) in picked {
+        let name = item.path.rsplit('/').next().unwrap_or(&item.path);
+        let copy = item_dir.join(FILES_DIR).join(name);
+        if let Some((parent, _)) = item.path.rsplit_once('/')
+            && !parent.is_empty()
+        {
+            kindle.create_folder_all(parent)?;
+        }
+        if item.is_folder {
+            kindle.create_folder_all(&item.path)?;
+            let mut folders = vec![(copy, item.path.clone())];
+            while let Some((local_dir, remote_dir)) = folders.pop() {
+                for entry in std::fs::read_dir(&local_dir)? {
+                    let entry = entry?;
+                    let remote = format!("{}/{}", remote_dir, entry.file_name().to_string_lossy());
+                    if entry.file_type()?.is_dir() {
+                        kindle.create_folder_all(&remote)?;
+                        folders.push((entry.path(), remote));
+                    } else {
+                        upload(output, &session, &entry.path(), &remote)?;
+                        uploads.push((remote, entry.path()));
+                    }
+                }
+            }
+        } else {
+            upload(output, &session, &copy, &item.path)?;
+            uploads.push((item.path.clone(), copy));
+        }
+        // The copies are read again for calibre's metadata below
+        restore_output.restored.push(item);
+    }
+    if !uploads.is_empty() {
+        update_device_metadata(output, &session, |metadata| {
+            for (remote, local) in &uploads {
+                metadata.record_upload(remote, local, None);
+            }
+        });
+    }
+    for item in &restore_output.restored {
+        let _ = std::fs::remove_dir_all(dir.join(&item.id));
+    }
+    output.print(&restore_output);
+    Ok(())
+}
+
+fn upload(output: &Output, session: &Session, local: &Path, remote: &str) -> Result<()> {
+    let size = std::fs::metadata(local)?.len();
+    output.timed(remote, || session.upload_file(local, remote).map(|()| size))?;
+    Ok(())
+}
+
+/// The complete items in a device's trash folder with the folder of each.
+/// Copies interrupted before their `item.json` was written are left out.
+fn items_in(device_dir: &Path) -> Result<Vec<(TrashItem, PathBuf)>> {
+    let entries = match std::fs::read_dir(device_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut items = Vec::new();
+    for entry in entries {
+        let dir = entry?.path();
+        let Ok(json) = std::fs::read(dir.join(ITEM_FILE)) else {
+            continue;
+        };
+        if let Ok(item) = serde_json::from_slice::<TrashItem>(&json) {
+            items.push((item, dir));
+        }
+    }
+    Ok(items)
+}
+
+/// `trash/` in the platform data directory, beside the run history, e.g.
+/// `~/.local/share/kindle-mtp/trash` on Linux.
+fn trash_root() -> Result<PathBuf> {
+    dirs::data_local_dir()
+        .map(|dir| dir.join("kindle-mtp").join(TRASH_DIR))
+        .ok_or_else(|| Error::InvalidPath("No local data directory for the trash".to_string()))
+}
+
+/// `trash/<serial>` in the platform data directory.
+fn device_dir(serial: &str) -> Result<PathBuf> {
+    Ok(trash_root()?.join(serial_file_name(serial)))
+}
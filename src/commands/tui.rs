@@ -0,0 +1,408 @@
+use crate::commands::ls::format_size;
+use crate::commands::overwrite::{Action, OverwritePolicy, Stamp};
+use crate::config::Config;
+use crate::device::{CancelToken, FileEntry, Kindle, TransferOptions};
+use crate::error::Result;
+use std::io::{stdout, Stdout};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind},
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    ExecutableCommand,
+};
+use ratatui::{
+    prelude::*,
+    widgets::{Block, Borders, Gauge, List, ListItem, ListState, Paragraph},
+};
+
+/// How often a running download redraws its gauge and looks for Esc.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+struct App {
+    kindle: Option<Kindle>,
+    transfer: TransferOptions,
+    /// Folder to start in and return to on reconnecting
+    root: Vec<String>,
+    /// Where `g`/F5 saves the selected file
+    download_dir: PathBuf,
+    /// What to do when the download folder has the file already
+    overwrite: OverwritePolicy,
+    view: View,
+    should_quit: bool,
+}
+
+/// What the screen shows, kept apart from the device so a download can
+/// redraw it from the transfer's worker thread.
+struct View {
+    connected: bool,
+    current_path: Vec<String>,
+    entries: Vec<FileEntry>,
+    list_state: ListState,
+    status_message: String,
+    /// The running download: file name, bytes written and file size
+    progress: Option<(String, u64, u64)>,
+}
+
+impl View {
+    fn current_path_string(&self) -> String {
+        if self.current_path.is_empty() {
+            "/".to_string()
+        } else {
+            format!("/{}", self.current_path.join("/"))
+        }
+    }
+
+    fn selected(&self) -> Option<&FileEntry> {
+        self.list_state.selected().and_then(|i| self.entries.get(i))
+    }
+
+    fn select_next(&mut self) {
+        if self.entries.is_empty() {
+            return;
+        }
+        let i = match self.list_state.selected() {
+            Some(i) => {
+                if i >= self.entries.len() - 1 {
+                    0
+                } else {
+                    i + 1
+                }
+            }
+            None => 0,
+        };
+        self.list_state.select(Some(i));
+    }
+
+    fn select_previous(&mut self) {
+        if self.entries.is_empty() {
+            return;
+        }
+        let i = match self.list_state.selected() {
+            Some(i) => {
+                if i == 0 {
+                    self.entries.len() - 1
+                } else {
+                    i - 1
+                }
+            }
+            None => 0,
+        };
+        self.list_state.select(Some(i));
+    }
+}
+
+impl App {
+    fn new(root: &str, download_dir: PathBuf, overwrite: OverwritePolicy, transfer: TransferOptions) -> Self {
+        let root: Vec<String> = root.split('/').filter(|s| !s.is_empty()).map(str::to_string).collect();
+        Self {
+            kindle: None,
+            transfer,
+            download_dir,
+            overwrite,
+            view: View {
+                connected: false,
+                current_path: root.clone(),
+                entries: vec![],
+                list_state: ListState::default(),
+                status_message: "Press 'c' to connect to Kindle".to_string(),
+                progress: None,
+            },
+            root,
+            should_quit: false,
+        }
+    }
+
+    fn connect(&mut self) {
+        self.view.status_message = "Connecting to Kindle...".to_string();
+        match Kindle::open(self.transfer) {
+            Ok(kindle) => {
+                self.kindle = Some(kindle);
+                self.view.connected = true;
+                self.view.status_message = "Connected! Loading files...".to_string();
+                self.refresh_listing();
+            }
+            Err(e) => {
+                self.view.status_message = format!("Connection failed: {}", e.display_chain());
+                self.kindle = None;
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        self.kindle = None;
+        self.view.connected = false;
+        self.view.entries.clear();
+        self.view.current_path = self.root.clone();
+        self.view.list_state.select(None);
+        self.view.status_message = "Disconnected. Press 'c' to reconnect.".to_string();
+    }
+
+    fn refresh_listing(&mut self) {
+        if let Some(kindle) = &self.kindle {
+            let path = self.view.current_path_string();
+            match kindle.list_files(&path) {
+                Ok(mut entries) => {
+                    // Sort: folders first, then files, alphabetically
+                    entries.sort_by(|a, b| {
+                        match (a.is_folder, b.is_folder) {
+                            (true, false) => std::cmp::Ordering::Less,
+                            (false, true) => std::cmp::Ordering::Greater,
+                            _ => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+                        }
+                    });
+                    self.view.entries = entries;
+                    if !self.view.entries.is_empty() {
+                        self.view.list_state.select(Some(0));
+                    } else {
+                        self.view.list_state.select(None);
+                    }
+                    self.view.status_message = format!("Path: {} ({} items)", path, self.view.entries.len());
+                }
+                Err(e) => {
+                    self.view.status_message = format!("Error listing files: {}", e.display_chain());
+                }
+            }
+        }
+    }
+
+    fn enter_directory(&mut self) {
+        if let Some(entry) = self.view.selected()
+            && entry.is_folder
+        {
+            let name = entry.name.clone();
+            self.view.current_path.push(name);
+            self.refresh_listing();
+        }
+    }
+
+    fn go_up(&mut self) {
+        if !self.view.current_path.is_empty() {
+            self.view.current_path.pop();
+            self.refresh_listing();
+        }
+    }
+
+    /// Downloads the selected file into the download folder, redrawing the
+    /// gauge as chunks arrive. An existing copy there is handled by the
+    /// config's `overwrite`, as `pull` does. Esc cancels the download and
+    /// removes what was written.
+    fn download_selected(&mut self, terminal: &mut Terminal<CrosstermBackend<Stdout>>) {
+        let Some(kindle) = self.kindle.as_mut() else {
+            return;
+        };
+        let Some(entry) = self.view.selected().cloned() else {
+            return;
+        };
+        if entry.is_folder {
+            self.view.status_message = format!("'{}' is a folder; only files can be downloaded", entry.name);
+            return;
+        }
+        let remote = format!("{}/{}", self.view.current_path_string().trim_end_matches('/'), entry.name);
+        let local = self.download_dir.join(&entry.name);
+        let label = local.display().to_string();
+        let existing = std::fs::metadata(&local).ok().map(|metadata| Stamp::local(&metadata));
+        match self.overwrite.decide_for(existing, || Ok(Stamp::remote(&entry)), &label) {
+            Ok(Action::Skip) => {
+                self.view.status_message = format!("Kept the copy of {} in {}", entry.name, self.download_dir.display());
+                return;
+            }
+            Ok(_) => {}
+            // The policy's own message names flags the browser doesn't have
+            Err(_) => {
+                self.view.status_message = format!(
+                    "{} exists; set `overwrite` in the config to replace it",
+                    label
+                );
+                return;
+            }
+        }
+        if let Err(e) = std::fs::create_dir_all(&self.download_dir) {
+            self.view.status_message = format!("Cannot create {}: {}", self.download_dir.display(), e);
+            return;
+        }
+
+        let cancel = CancelToken::new();
+        kindle.set_cancel_token(cancel.clone());
+        let view = &mut self.view;
+        view.progress = Some((entry.name.clone(), 0, entry.size));
+        let _ = terminal.draw(|frame| ui(frame, view));
+        let mut drawn = Some(Instant::now());
+        let result = kindle.download_file_with_progress(&remote, &local, |done, total| {
+            if let Some(progress) = &mut view.progress {
+                (progress.1, progress.2) = (done, total);
+            }
+            if drawn.is_some_and(|at| at.elapsed() < PROGRESS_INTERVAL) && done < total {
+                return;
+            }
+            drawn = Some(Instant::now());
+            let _ = terminal.draw(|frame| ui(frame, view));
+            if esc_pressed() {
+                cancel.cancel();
+            }
+        });
+        // A cancelled token stays cancelled, so later calls get a fresh one
+        kindle.set_cancel_token(CancelToken::new());
+        self.view.progress = None;
+        self.view.status_message = match result {
+            Ok(()) => format!("Downloaded {} to {}", entry.name, label),
+            Err(e) => {
+                let _ = std::fs::remove_file(&local);
+                if e.kind() == "cancelled" {
+                    format!("Download of {} cancelled", entry.name)
+                } else {
+                    format!("Download failed: {}", e.display_chain())
+                }
+            }
+        };
+    }
+
+    fn handle_key(&mut self, key: KeyCode, terminal: &mut Terminal<CrosstermBackend<Stdout>>) {
+        match key {
+            KeyCode::Char('q') => self.should_quit = true,
+            KeyCode::Char('c') if self.kindle.is_none() => self.connect(),
+            KeyCode::Char('d') if self.kindle.is_some() => self.disconnect(),
+            KeyCode::Char('r') if self.kindle.is_some() => self.refresh_listing(),
+            KeyCode::Char('g') | KeyCode::F(5) if self.kindle.is_some() => self.download_selected(terminal),
+            KeyCode::Up | KeyCode::Char('k') => self.view.select_previous(),
+            KeyCode::Down | KeyCode::Char('j') => self.view.select_next(),
+            KeyCode::Enter | KeyCode::Right | KeyCode::Char('l') => self.enter_directory(),
+            KeyCode::Backspace | KeyCode::Left | KeyCode::Char('h') => self.go_up(),
+            _ => {}
+        }
+    }
+}
+
+/// Whether Esc was pressed since the last look, dropping other keys: the
+/// browser can't act on them while a download runs.
+fn esc_pressed() -> bool {
+    let mut pressed = false;
+    while event::poll(Duration::ZERO).unwrap_or(false) {
+        if let Ok(Event::Key(key)) = event::read()
+            && key.kind == KeyEventKind::Press
+            && key.code == KeyCode::Esc
+        {
+            pressed = true;
+        }
+    }
+    pressed
+}
+
+/// Full-screen file browser over one device connection, opened with the
+/// same device selection and transfer options as the other commands. It
+/// starts in the config's `remote_root`, else at the top of the device.
+/// Files are downloaded into `download_dir`.
+pub fn run_tui(config: &Config, download_dir: PathBuf, transfer: TransferOptions) -> Result<()> {
+    // Setup terminal
+    enable_raw_mode()?;
+    stdout().execute(EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
+
+    // Create app and connect right away, as `shell` does
+    let mut app = App::new(config.remote_root().unwrap_or("/"), download_dir, config.overwrite(), transfer);
+    app.connect();
+
+    // Main loop
+    loop {
+        terminal.draw(|frame| ui(frame, &mut app.view))?;
+
+        if event::poll(std::time::Duration::from_millis(100))?
+            && let Event::Key(key) = event::read()?
+            && key.kind == KeyEventKind::Press
+        {
+            app.handle_key(key.code, &mut terminal);
+        }
+
+        if app.should_quit {
+            break;
+        }
+    }
+
+    // Cleanup
+    disable_raw_mode()?;
+    stdout().execute(LeaveAlternateScreen)?;
+    Ok(())
+}
+
+fn ui(frame: &mut Frame, view: &mut View) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(3),  // Title
+            Constraint::Min(10),    // File list
+            Constraint::Length(3),  // Status
+            Constraint::Length(3),  // Help
+        ])
+        .split(frame.area());
+
+    // Title bar
+    let connected = if view.connected { "CONNECTED" } else { "DISCONNECTED" };
+    let title = format!(" Kindle File Browser [{}] ", connected);
+    let title_block = Paragraph::new(title)
+        .style(Style::default().fg(if view.connected { Color::Green } else { Color::Red }))
+        .block(Block::default().borders(Borders::ALL));
+    frame.render_widget(title_block, chunks[0]);
+
+    // File list
+    let path_display = view.current_path_string();
+    let items: Vec<ListItem> = view
+        .entries
+        .iter()
+        .map(|entry| {
+            let icon = if entry.is_folder { "📁" } else { "📄" };
+            let size = if entry.is_folder {
+                String::new()
+            } else {
+                format_size(entry.size)
+            };
+            let modified = entry.modified.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M");
+            let line = format!("{} {:<40} {:>10}  {}", icon, entry.name, size, modified);
+            ListItem::new(line)
+        })
+        .collect();
+
+    let list = List::new(items)
+        .block(Block::default()
+            .title(format!(" {} ", path_display))
+            .borders(Borders::ALL))
+        .highlight_style(Style::default().bg(Color::DarkGray).fg(Color::White))
+        .highlight_symbol("▶ ");
+
+    frame.render_stateful_widget(list, chunks[1], &mut view.list_state);
+
+    // Status bar, or the gauge while a download runs
+    match &view.progress {
+        Some((name, done, total)) => {
+            let ratio = if *total == 0 { 1.0 } else { (*done as f64 / *total as f64).min(1.0) };
+            let gauge = Gauge::default()
+                .block(Block::default().borders(Borders::ALL).title(format!(" Downloading {} ", name)))
+                .gauge_style(Style::default().fg(Color::Green).bg(Color::DarkGray))
+                .ratio(ratio)
+                .label(format!(
+                    "{} / {} ({:.0}%)",
+                    format_size(*done),
+                    format_size(*total),
+                    ratio * 100.0
+                ));
+            frame.render_widget(gauge, chunks[2]);
+        }
+        None => {
+            let status = Paragraph::new(format!(" {} ", view.status_message))
+                .block(Block::default().borders(Borders::ALL).title(" Status "));
+            frame.render_widget(status, chunks[2]);
+        }
+    }
+
+    // Help bar
+    let help_text = if view.progress.is_some() {
+        " Esc:Cancel download "
+    } else if view.connected {
+        " q:Quit | d:Disconnect | r:Refresh | ↑↓/jk:Navigate | Enter/→:Open | Backspace/←:Back | g/F5:Download "
+    } else {
+        " q:Quit | c:Connect "
+    };
+    let help = Paragraph::new(help_text)
+        .style(Style::default().fg(Color::Cyan))
+        .block(Block::default().borders(Borders::ALL).title(" Help "));
+    frame.render_widget(help, chunks[3]);
+}
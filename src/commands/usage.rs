@@ -0,0 +1,207 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::books::{is_sidecar, BookFormat};
+use crate::commands::filter::PathFilter;
+use crate::commands::ls::format_size;
+use crate::daemon::Session;
+use crate::device::{TransferOptions, WalkEntry};
+use crate::error::Result;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Audiobook and audio file extensions.
+pub(crate) const AUDIO_EXTENSIONS: &[&str] = &["aax", "aa", "mp3", "m4a", "m4b"];
+/// Folder names screensaver images live in, on stock and jailbroken
+/// firmware.
+const SCREENSAVER_FOLDERS: &[&str] = &["screensaver", "screensavers", "screen_saver"];
+/// Name for files directly in the folder looked at.
+const TOP_LEVEL_FILES: &str = "(files)";
+
+/// What a file on the device is, for `usage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    Books,
+    Audiobooks,
+    /// `.sdr` folders and the files the Kindle keeps next to books
+    Sidecars,
+    Screensavers,
+    Dictionaries,
+    Fonts,
+    /// The firmware's own files under `/system`
+    System,
+    Other,
+}
+
+impl FileKind {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Books => "books",
+            Self::Audiobooks => "audiobooks",
+            Self::Sidecars => "sidecars",
+            Self::Screensavers => "screensavers",
+            Self::Dictionaries => "dictionaries",
+            Self::Fonts => "fonts",
+            Self::System => "system",
+            Self::Other => "other",
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct UsageOutput {
+    pub path: String,
+    /// Bytes in the files below `path`
+    pub used_bytes: u64,
+    /// The storage's capacity and free space
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+    pub folders: Vec<FolderUsage>,
+    pub kinds: Vec<KindUsage>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct FolderUsage {
+    /// Folder below `path`; `(files)` for the files directly in it
+    pub name: String,
+    pub files: usize,
+    pub bytes: u64,
+    /// Share of `used_bytes`
+    pub percent: f64,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct KindUsage {
+    pub kind: FileKind,
+    pub files: usize,
+    pub bytes: u64,
+    /// Share of `used_bytes`
+    pub percent: f64,
+}
+
+impl HumanReadable for UsageOutput {
+    fn to_human(&self) -> String {
+        let width = self.folders.iter().map(|f| f.name.chars().count()).max().unwrap_or(0).max(6);
+        let mut lines = vec![format!(
+            "{:<width$}  {:>8}  {:>6}  {:>6}",
+            "FOLDER",
+            "SIZE",
+            "FILES",
+            "USED",
+            width = width
+        )];
+        for folder in &self.folders {
+            lines.push(format!(
+                "{:<width$}  {:>8}  {:>6}  {:>5.1}%",
+                folder.name,
+                format_size(folder.bytes),
+                folder.files,
+                folder.percent,
+                width = width
+            ));
+        }
+        lines.push(String::new());
+        lines.push(format!("{:<12}  {:>8}  {:>6}  {:>6}", "TYPE", "SIZE", "FILES", "USED"));
+        for kind in &self.kinds {
+            lines.push(format!(
+                "{:<12}  {:>8}  {:>6}  {:>5.1}%",
+                kind.kind.label(),
+                format_size(kind.bytes),
+                kind.files,
+                kind.percent
+            ));
+        }
+        lines.push(String::new());
+        lines.push(format!(
+            "{} in {}; {} of {} free on the device",
+            format_size(self.used_bytes),
+            self.path,
+            format_size(self.free_bytes),
+            format_size(self.total_bytes)
+        ));
+        lines.join("\n")
+    }
+}
+
+/// Adds up the files below `path` that `filter` takes by the folder right
+/// below it and by kind, largest first, to show where the space goes.
+pub fn run_usage(output: &Output, path: &str, filter: &PathFilter) -> Result<()> {
+    let session = Session::open(TransferOptions::default())?;
+    let (_, storage) = session.info()?;
+    let mut entries = session.walk(path)?;
+    filter.retain(path, &mut entries);
+    let root = path.trim_end_matches('/');
+
+    let mut folders: BTreeMap<String, (usize, u64)> = BTreeMap::new();
+    let mut kinds: BTreeMap<FileKind, (usize, u64)> = BTreeMap::new();
+    for item in entries.iter().filter(|item| !item.entry.is_folder) {
+        let relative = item.path.strip_prefix(root).unwrap_or(&item.path).trim_start_matches('/');
+        let folder = match relative.split_once('/') {
+            Some((folder, _)) => folder.to_string(),
+            None => TOP_LEVEL_FILES.to_string(),
+        };
+        for (files, bytes) in [folders.entry(folder).or_default(), kinds.entry(classify(item)).or_default()] {
+            *files += 1;
+            *bytes += item.entry.size;
+        }
+    }
+
+    let used_bytes: u64 = folders.values().map(|(_, bytes)| bytes).sum();
+    let percent = |bytes: u64| if used_bytes == 0 { 0.0 } else { bytes as f64 * 100.0 / used_bytes as f64 };
+    let mut folders: Vec<FolderUsage> = folders
+        .into_iter()
+        .map(|(name, (files, bytes))| FolderUsage {
+            name,
+            files,
+            bytes,
+            percent: percent(bytes),
+        })
+        .collect();
+    folders.sort_by_key(|e| std::cmp::Reverse(e.bytes));
+    let mut kinds: Vec<KindUsage> = kinds
+        .into_iter()
+        .map(|(kind, (files, bytes))| KindUsage {
+            kind,
+            files,
+            bytes,
+            percent: percent(bytes),
+        })
+        .collect();
+    kinds.sort_by_key(|e| std::cmp::Reverse(e.bytes));
+
+    output.print(&UsageOutput {
+        path: path.to_string(),
+        used_bytes,
+        total_bytes: storage.total_bytes,
+        free_bytes: storage.free_bytes,
+        folders,
+        kinds,
+    });
+    Ok(())
+}
+
+/// The kind of file `item` is, by where it is and its extension.
+fn classify(item: &WalkEntry) -> FileKind {
+    let lower = item.path.to_lowercase();
+    let folders: Vec<&str> = lower.split('/').filter(|p| !p.is_empty()).collect();
+    let folders = &folders[..folders.len().saturating_sub(1)];
+    let extension = item.entry.name.rsplit_once('.').map_or("", |(_, extension)| extension);
+
+    if folders.iter().any(|f| f.ends_with(".sdr")) || is_sidecar(extension) {
+        FileKind::Sidecars
+    } else if folders.iter().any(|f| SCREENSAVER_FOLDERS.contains(f)) {
+        FileKind::Screensavers
+    } else if folders.contains(&"dictionaries") {
+        FileKind::Dictionaries
+    } else if folders.contains(&"audible") || AUDIO_EXTENSIONS.iter().any(|a| a.eq_ignore_ascii_case(extension)) {
+        FileKind::Audiobooks
+    } else if folders.first() == Some(&"fonts") {
+        FileKind::Fonts
+    } else if folders.first() == Some(&"system") {
+        FileKind::System
+    } else if BookFormat::from_extension(extension).is_some() {
+        FileKind::Books
+    } else {
+        FileKind::Other
+    }
+}
@@ -0,0 +1,52 @@
+use crate::commands::hash::{hash_local, hash_remote};
+use crate::daemon::Session;
+use crate::error::{Error, Result};
+use sha2::Sha256;
+use std::path::Path;
+
+/// How thoroughly to check a file after transferring it.
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum VerifyMode {
+    /// Compare local and remote sizes
+    Size,
+    /// Compare sizes, then re-read both copies and compare SHA-256 digests
+    Hash,
+}
+
+/// Checks that the local and remote copies of a transferred file match.
+pub fn verify_transfer(session: &Session, remote: &str, local: &Path, mode: VerifyMode) -> Result<()> {
+    let remote_size = session.stat(remote)?.size;
+    let local_size = std::fs::metadata(local)?.len();
+    if remote_size != local_size {
+        return Err(Error::VerificationFailed(format!(
+            "{}: size mismatch (local {} bytes, device {} bytes)",
+            remote, local_size, remote_size
+        )));
+    }
+
+    if let VerifyMode::Hash = mode {
+        let kindle = session.direct("--verify=hash")?;
+        // The local hash needs no device access, so it can run alongside
+        // the remote read when a spare thread is allowed
+        let (local_digest, remote_digest) = if kindle.transfer_options().hashing_threads > 1 {
+            std::thread::scope(|scope| {
+                let local_hash = scope.spawn(|| hash_local::<Sha256>(local));
+                let remote_hash = hash_remote::<Sha256>(kindle, remote);
+                let local_hash = local_hash.join().map_err(|_| {
+                    Error::TransferFailed("Hashing worker panicked".to_string())
+                })?;
+                Ok::<_, Error>((local_hash?.0, remote_hash?.0))
+            })?
+        } else {
+            (hash_local::<Sha256>(local)?.0, hash_remote::<Sha256>(kindle, remote)?.0)
+        };
+        if local_digest != remote_digest {
+            return Err(Error::VerificationFailed(format!(
+                "{}: checksum mismatch (local {}, device {})",
+                remote, local_digest, remote_digest
+            )));
+        }
+    }
+
+    Ok(())
+}
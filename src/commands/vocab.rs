@@ -0,0 +1,225 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::clippings::csv_field;
+use crate::commands::staging::StagingDir;
+use crate::daemon::Session;
+use crate::device::TransferOptions;
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OpenFlags};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// `category` of a word the reader marked as learned in Vocabulary Builder.
+const CATEGORY_MASTERED: i64 = 100;
+
+/// Every lookup with its word and book, oldest first.
+const LOOKUPS_QUERY: &str = "
+SELECT w.id, w.word, w.stem, w.lang, w.category, l.usage, l.timestamp, b.title, b.authors
+FROM LOOKUPS l
+JOIN WORDS w ON l.word_key = w.id
+LEFT JOIN BOOK_INFO b ON l.book_key = b.id
+ORDER BY l.timestamp
+";
+
+/// File format of an export, chosen by extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum VocabFormat {
+    Json,
+    Csv,
+    /// Tab-separated notes for Anki's File > Import, one per word
+    Anki,
+}
+
+impl VocabFormat {
+    fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase).as_deref() {
+            Some("json") => Ok(Self::Json),
+            Some("csv") => Ok(Self::Csv),
+            Some("txt" | "tsv") => Ok(Self::Anki),
+            _ => Err(Error::InvalidPath(format!(
+                "'{}': use a .json, .csv, or .txt file (Anki)",
+                path.display()
+            ))),
+        }
+    }
+}
+
+/// A word looked up in the dictionary, with every time it was.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct VocabWord {
+    pub word: String,
+    /// Dictionary form, e.g. `run` for `running`
+    pub stem: String,
+    pub language: String,
+    /// Marked as learned in Vocabulary Builder
+    pub mastered: bool,
+    pub lookups: Vec<Lookup>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Lookup {
+    /// The sentence the word was looked up in
+    pub usage: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub book: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authors: Option<String>,
+    pub looked_up: DateTime<Utc>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct VocabExportOutput {
+    pub source: String,
+    pub file: String,
+    pub format: VocabFormat,
+    pub words: usize,
+    pub lookups: usize,
+    pub mastered: usize,
+}
+
+impl HumanReadable for VocabExportOutput {
+    fn to_human(&self) -> String {
+        format!(
+            "Exported {} words ({} lookups, {} mastered) to {}",
+            self.words, self.lookups, self.mastered, self.file
+        )
+    }
+}
+
+/// Copies Vocabulary Builder's database off the device and exports its
+/// words with the sentences they were looked up in and the books those
+/// came from: JSON, CSV (a row per lookup) or Anki notes (a note per
+/// word), by the extension of `file`.
+pub fn run_vocab_export(output: &Output, file: &str, path: &str) -> Result<()> {
+    let format = VocabFormat::from_path(Path::new(file))?;
+    let session = Session::open(TransferOptions::default())?;
+    let staging = StagingDir::new("vocab")?;
+    let staged = staging.join("vocab.db");
+    session.download_file(path, &staged)?;
+    let words = read_words(&staged).map_err(|e| Error::Io(std::io::Error::other(e)))?;
+
+    let data = match format {
+        VocabFormat::Json => serde_json::to_vec_pretty(&words).map_err(|e| Error::Io(std::io::Error::other(e)))?,
+        VocabFormat::Csv => to_csv(&words),
+        VocabFormat::Anki => to_anki(&words),
+    };
+    std::fs::write(file, data)?;
+
+    output.print(&VocabExportOutput {
+        source: path.to_string(),
+        file: file.to_string(),
+        format,
+        words: words.len(),
+        lookups: words.iter().map(|w| w.lookups.len()).sum(),
+        mastered: words.iter().filter(|w| w.mastered).count(),
+    });
+    Ok(())
+}
+
+/// The words in a copy of `vocab.db`, in the order they were first looked up.
+fn read_words(db: &Path) -> rusqlite::Result<Vec<VocabWord>> {
+    let conn = Connection::open_with_flags(db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut statement = conn.prepare(LOOKUPS_QUERY)?;
+    let mut rows = statement.query([])?;
+
+    let mut words: Vec<VocabWord> = Vec::new();
+    let mut index_by_id = HashMap::new();
+    while let Some(row) = rows.next()? {
+        let id: String = row.get(0)?;
+        let lookup = Lookup {
+            usage: row.get::<_, Option<String>>(5)?.unwrap_or_default().trim().to_string(),
+            book: row.get(7)?,
+            authors: row.get(8)?,
+            looked_up: DateTime::from_timestamp_millis(row.get::<_, Option<i64>>(6)?.unwrap_or(0)).unwrap_or_default(),
+        };
+        let index = *index_by_id.entry(id).or_insert_with(|| {
+            words.push(VocabWord {
+                word: String::new(),
+                stem: String::new(),
+                language: String::new(),
+                mastered: false,
+                lookups: Vec::new(),
+            });
+            words.len() - 1
+        });
+        let word = &mut words[index];
+        if word.lookups.is_empty() {
+            word.word = row.get::<_, Option<String>>(1)?.unwrap_or_default();
+            word.stem = row.get::<_, Option<String>>(2)?.unwrap_or_default();
+            word.language = row.get::<_, Option<String>>(3)?.unwrap_or_default();
+            word.mastered = row.get::<_, Option<i64>>(4)? == Some(CATEGORY_MASTERED);
+        }
+        word.lookups.push(lookup);
+    }
+    Ok(words)
+}
+
+/// One row per lookup with a header row.
+fn to_csv(words: &[VocabWord]) -> Vec<u8> {
+    let mut out = String::from("word,stem,language,mastered,book,authors,usage,looked_up\r\n");
+    for word in words {
+        for lookup in &word.lookups {
+            let looked_up = lookup.looked_up.to_rfc3339();
+            let fields = [
+                word.word.as_str(),
+                word.stem.as_str(),
+                word.language.as_str(),
+                if word.mastered { "true" } else { "false" },
+                lookup.book.as_deref().unwrap_or_default(),
+                lookup.authors.as_deref().unwrap_or_default(),
+                lookup.usage.as_str(),
+                looked_up.as_str(),
+            ];
+            let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+            out.push_str(&row.join(","));
+            out.push_str("\r\n");
+        }
+    }
+    out.into_bytes()
+}
+
+/// Anki's plain text import: a header naming the separator, then one note
+/// per word with the word on the front and its usages, the word in bold,
+/// and their books on the back. The stem is a third field for cards that
+/// want it; `kindle` and the language are tags.
+fn to_anki(words: &[VocabWord]) -> Vec<u8> {
+    let mut out = String::from("#separator:tab\n#html:true\n#tags column:4\n");
+    for word in words {
+        let back: Vec<String> = word
+            .lookups
+            .iter()
+            .map(|lookup| {
+                let word_html = html_escape(&word.word);
+                let mut usage = html_escape(&lookup.usage);
+                if !word_html.is_empty() {
+                    usage = usage.replace(&word_html, &format!("<b>{}</b>", word_html));
+                }
+                match &lookup.book {
+                    Some(book) => format!("{}<br><i>{}</i>", usage, html_escape(book)),
+                    None => usage,
+                }
+            })
+            .collect();
+        let fields = [
+            html_escape(&word.word),
+            back.join("<br><br>"),
+            html_escape(&word.stem),
+            if word.language.is_empty() {
+                "kindle".to_string()
+            } else {
+                format!("kindle {}", word.language.replace(' ', "_"))
+            },
+        ];
+        let fields: Vec<String> = fields.iter().map(|f| f.replace(['\t', '\n', '\r'], " ")).collect();
+        out.push_str(&fields.join("\t"));
+        out.push('\n');
+    }
+    out.into_bytes()
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
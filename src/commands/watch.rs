@@ -0,0 +1,193 @@
+use crate::cli::{HumanReadable, Output};
+use crate::commands::sync::{join_remote, normalize_remote_dir};
+use crate::device::{Kindle, TransferOptions};
+use crate::error::{Error, Result};
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde::Serialize;
+use schemars::JsonSchema;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// File types worth sending to the reader; everything else is ignored.
+const WATCH_EXTENSIONS: [&str; 3] = ["epub", "pdf", "azw3"];
+/// A file must keep the same size this long before it's considered fully
+/// written (browsers and Calibre write in several steps).
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+/// Pause between attempts while the device is unplugged or busy.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, JsonSchema)]
+pub struct WatchEvent {
+    pub local: String,
+    pub remote: String,
+    pub bytes: u64,
+    pub skipped: bool,
+}
+
+impl HumanReadable for WatchEvent {
+    fn to_human(&self) -> String {
+        if self.skipped {
+            format!("Skipped {} ({} already on the device)", self.local, self.remote)
+        } else {
+            format!("Uploaded {} -> {} ({} bytes)", self.local, self.remote, self.bytes)
+        }
+    }
+}
+
+/// A file seen in the watched folder, waiting to be uploaded.
+struct Pending {
+    size: u64,
+    /// When `size` was last seen to change
+    changed: Instant,
+    /// Earliest time for the next upload attempt
+    not_before: Instant,
+}
+
+/// Watches `local` and uploads every new e-book dropped into it to `remote`,
+/// until interrupted. Files already on the device are left alone. When the
+/// device is missing or a transfer fails, the file stays queued and is
+/// retried, so the Kindle can be plugged in after the file was dropped.
+pub fn run_watch(output: &Output, local: &str, remote: &str, transfer: TransferOptions) -> Result<()> {
+    let local_dir = Path::new(local);
+    if !local_dir.is_dir() {
+        return Err(Error::InvalidPath(format!("'{}' is not a directory", local)));
+    }
+    let remote = normalize_remote_dir(remote);
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)
+        .map_err(|e| Error::Io(std::io::Error::other(e)))?;
+    watcher
+        .watch(local_dir, RecursiveMode::NonRecursive)
+        .map_err(|e| Error::Io(std::io::Error::other(e)))?;
+    output.info(format!("Watching {} -> {} (Ctrl-C to stop)", local, remote));
+
+    let mut pending: BTreeMap<PathBuf, Pending> = BTreeMap::new();
+    let mut kindle: Option<Kindle> = None;
+    let mut waiting_for_device = false;
+
+    loop {
+        match rx.recv_timeout(Duration::from_millis(500)) {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in event.paths.into_iter().filter(|p| is_watched(p)) {
+                        let now = Instant::now();
+                        pending.entry(path).or_insert(Pending {
+                            size: u64::MAX,
+                            changed: now,
+                            not_before: now,
+                        });
+                    }
+                }
+            }
+            Ok(Err(e)) => output.info(format!("Warning: watch error: {}", e)),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        let now = Instant::now();
+        let mut ready = Vec::new();
+        pending.retain(|path, state| {
+            let Ok(metadata) = std::fs::metadata(path) else {
+                // Deleted or renamed away before it was uploaded
+                return false;
+            };
+            if metadata.len() != state.size {
+                state.size = metadata.len();
+                state.changed = now;
+            } else if now.duration_since(state.changed) >= SETTLE_TIME && now >= state.not_before {
+                ready.push(path.clone());
+            }
+            true
+        });
+
+        for path in ready {
+            if kindle.is_none() {
+                match Kindle::detect() {
+                    Ok(mut k) => {
+                        k.set_transfer_options(transfer);
+                        if waiting_for_device {
+                            output.info("Kindle connected");
+                            waiting_for_device = false;
+                        }
+                        kindle = Some(k);
+                    }
+                    Err(e) => {
+                        if !waiting_for_device {
+                            output.info(format!("Waiting for the Kindle ({})", e.display_chain()));
+                            waiting_for_device = true;
+                        }
+                        delay_all(&mut pending, now + RETRY_DELAY);
+                        break;
+                    }
+                }
+            }
+            let Some(device) = &kindle else { break };
+
+            match upload_one(device, &path, &remote) {
+                Ok(event) => {
+                    pending.remove(&path);
+                    output.print(&event);
+                }
+                Err(e) => {
+                    output.info(format!(
+                        "Warning: {}: {} (will retry)",
+                        path.display(),
+                        e.display_chain()
+                    ));
+                    // The session is likely gone (unplugged, screen locked);
+                    // reconnect on the next attempt
+                    kindle = None;
+                    delay_all(&mut pending, now + RETRY_DELAY);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn upload_one(kindle: &Kindle, local: &Path, remote_dir: &str) -> Result<WatchEvent> {
+    let name = local
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| Error::InvalidPath(format!("'{}' has no UTF-8 file name", local.display())))?;
+    let remote = join_remote(remote_dir, name);
+    let bytes = std::fs::metadata(local)?.len();
+
+    let skipped = match kindle.stat(&remote) {
+        Ok(_) => true,
+        Err(Error::FileNotFound(_)) => {
+            kindle.create_folder_all(remote_dir)?;
+            kindle.upload_file(local, &remote)?;
+            false
+        }
+        Err(e) => return Err(e),
+    };
+
+    Ok(WatchEvent {
+        local: local.display().to_string(),
+        remote,
+        bytes,
+        skipped,
+    })
+}
+
+fn is_watched(path: &Path) -> bool {
+    let hidden = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .is_none_or(|n| n.starts_with('.'));
+    !hidden
+        && path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| WATCH_EXTENSIONS.iter().any(|w| e.eq_ignore_ascii_case(w)))
+}
+
+fn delay_all(pending: &mut BTreeMap<PathBuf, Pending>, until: Instant) {
+    for state in pending.values_mut() {
+        state.not_before = until;
+    }
+}
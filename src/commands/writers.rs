@@ -0,0 +1,108 @@
+//! Writing the small files of a recursive download on worker threads. MTP
+//! runs one operation at a time, so the device can't be read in parallel;
+//! what can overlap is the local side. For thousands of sidecar files the
+//! create/write/close of each one costs about as much as reading it, so the
+//! device thread only reads them into memory and moves on.
+
+use crate::error::{Context, Error, Result};
+use chrono::{DateTime, Utc};
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+
+/// Files up to this size are read into memory and written by a worker;
+/// bigger ones stream to disk on the device thread as usual.
+pub(crate) const POOLED_FILE_MAX: u64 = 1024 * 1024;
+
+/// A downloaded file waiting to be written.
+pub(crate) struct FileJob {
+    pub path: PathBuf,
+    pub data: Vec<u8>,
+    /// Timestamp to give the local copy
+    pub modified: Option<DateTime<Utc>>,
+}
+
+/// Hands `FileJob`s to the writer threads of `with_writers`.
+pub(crate) struct Writers<'a> {
+    tx: Option<SyncSender<FileJob>>,
+    failure: &'a Mutex<Option<Error>>,
+}
+
+impl Writers<'_> {
+    /// Queues `job`, waiting while every writer is busy and the queue is
+    /// full. Fails with the error of an earlier job that couldn't be
+    /// written, so a full disk stops the download.
+    pub fn write(&self, job: FileJob) -> Result<()> {
+        if let Some(e) = lock(self.failure).take() {
+            return Err(e);
+        }
+        match &self.tx {
+            None => write_file(job),
+            Some(tx) => tx
+                .send(job)
+                .map_err(|_| Error::TransferFailed("local writer threads stopped".to_string())),
+        }
+    }
+}
+
+/// Runs `body` with `threads` writer threads, and waits for every queued
+/// file to be written before returning. One thread writes inline instead.
+pub(crate) fn with_writers<T>(threads: usize, body: impl FnOnce(&Writers) -> Result<T>) -> Result<T> {
+    let failure = Mutex::new(None);
+    if threads <= 1 {
+        return body(&Writers {
+            tx: None,
+            failure: &failure,
+        });
+    }
+
+    // Bounds the memory held by downloaded files not yet written
+    let (tx, rx) = mpsc::sync_channel::<FileJob>(threads * 2);
+    let rx = Mutex::new(rx);
+    let result = thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| drain(&rx, &failure));
+        }
+        body(&Writers {
+            tx: Some(tx),
+            failure: &failure,
+        })
+        // Dropping the sender ends the workers once the queue is empty
+    });
+    let value = result?;
+    match failure.into_inner().unwrap_or_else(|e| e.into_inner()) {
+        Some(e) => Err(e),
+        None => Ok(value),
+    }
+}
+
+fn drain(rx: &Mutex<Receiver<FileJob>>, failure: &Mutex<Option<Error>>) {
+    loop {
+        let job = match lock(rx).recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        if let Err(e) = write_file(job) {
+            lock(failure).get_or_insert(e);
+        }
+    }
+}
+
+fn write_file(job: FileJob) -> Result<()> {
+    let write = || -> Result<()> {
+        let mut file = File::create(&job.path)?;
+        file.write_all(&job.data)?;
+        if let Some(modified) = job.modified {
+            file.set_modified(modified.into())?;
+        }
+        Ok(())
+    };
+    write().context("write", &job.path.display().to_string())
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
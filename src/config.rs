@@ -0,0 +1,262 @@
+use crate::cli::{parse_size, OutputFormat};
+use crate::commands::OverwritePolicy;
+use crate::device::{RetryPolicy, TransferOptions, DEFAULT_CHUNK_SIZE};
+use crate::error::{Error, Result};
+use serde::{Deserialize, Deserializer, Serialize};
+use schemars::JsonSchema;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const CONFIG_FILE: &str = "config.toml";
+/// `sync`'s device marker (`kindle:/documents`), not usable as an alias.
+const SYNC_DEVICE_PREFIX: &str = "kindle";
+
+/// Settings read from `~/.config/kindle-mtp/config.toml`. Every section and
+/// key is optional; command-line flags override what is set here.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub defaults: DefaultsConfig,
+    pub performance: PerformanceConfig,
+    /// `[[sync]]` pairs run by a bare `kindle-mtp sync`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sync: Vec<SyncPair>,
+    /// `[aliases]`: short names for device folders, used as `docs:Book.epub`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub aliases: BTreeMap<String, String>,
+}
+
+/// The `[defaults]` section.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DefaultsConfig {
+    /// Where `pull` saves files when no local path is given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_dir: Option<PathBuf>,
+    /// Serial number of the Kindle to use when several are connected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    /// `human` or `json`; `--json` and `--format` override it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<OutputFormat>,
+    /// What `pull` and `push` do with existing files unless told otherwise
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overwrite: Option<OverwritePolicy>,
+    /// Device folder `ls`, `push`, `watch` and `shell` use when none is given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_root: Option<String>,
+    /// Space `push` and `sync` leave free on the device, e.g. `"50M"`
+    #[serde(deserialize_with = "optional_size", skip_serializing_if = "Option::is_none")]
+    pub free_space_margin: Option<u64>,
+    /// Whether `rm` keeps a local copy of what it deletes, as with `--trash`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trash: Option<bool>,
+}
+
+/// One configured sync, written exactly as its `sync` arguments would be,
+/// e.g. `source = "./books"`, `destination = "kindle:/documents"`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyncPair {
+    pub source: String,
+    pub destination: String,
+}
+
+/// The `[performance]` section.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct PerformanceConfig {
+    /// Transfer buffer size in bytes; also accepts `"512K"`, `"4M"`
+    #[serde(deserialize_with = "size")]
+    pub chunk_size: u64,
+    /// Chunks buffered between the USB side and the local side
+    pub queue_depth: usize,
+    /// Threads available for local hashing (1 disables overlap)
+    pub hashing_threads: usize,
+    /// Threads writing the small files of `pull -r` and `backup` to disk
+    /// (1 writes them between device reads)
+    pub io_threads: usize,
+    /// Devices worked on at once; only 1 is supported for now
+    pub device_parallelism: usize,
+    /// Open the device with libmtp's object cache: slower to connect,
+    /// faster to list large libraries
+    pub cached_open: bool,
+    /// Times a transient device error is retried; 0 fails at once
+    pub retries: u32,
+    /// Pause before the first retry in milliseconds, doubled after each one
+    pub retry_delay_ms: u64,
+    /// Seconds a single transfer or walk may take; unset waits indefinitely
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    /// Average transfer rate in bytes per second, e.g. `"5M"`; unset is
+    /// unlimited
+    #[serde(deserialize_with = "optional_size", skip_serializing_if = "Option::is_none")]
+    pub limit_rate: Option<u64>,
+}
+
+impl Default for PerformanceConfig {
+    fn default() -> Self {
+        let transfer = TransferOptions::default();
+        let retry = RetryPolicy::default();
+        Self {
+            chunk_size: DEFAULT_CHUNK_SIZE as u64,
+            queue_depth: transfer.queue_depth,
+            hashing_threads: transfer.hashing_threads,
+            io_threads: transfer.io_threads,
+            device_parallelism: 1,
+            cached_open: false,
+            retries: retry.retries,
+            retry_delay_ms: retry.delay.as_millis() as u64,
+            timeout_secs: None,
+            limit_rate: None,
+        }
+    }
+}
+
+impl PerformanceConfig {
+    pub fn transfer_options(&self) -> TransferOptions {
+        TransferOptions {
+            chunk_size: self.chunk_size.max(1) as usize,
+            queue_depth: self.queue_depth.max(1),
+            hashing_threads: self.hashing_threads.max(1),
+            io_threads: self.io_threads.max(1),
+            timeout: self.timeout_secs.map(Duration::from_secs),
+            rate_limit: self.limit_rate,
+        }
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retries,
+            delay: Duration::from_millis(self.retry_delay_ms),
+        }
+    }
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/kindle-mtp/config.toml`, falling back to
+    /// `~/.config` on every platform so the file is easy to find.
+    pub fn path() -> Option<PathBuf> {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .filter(|p| p.is_absolute())
+            .or_else(|| dirs::home_dir().map(|home| home.join(".config")))
+            .map(|dir| dir.join("kindle-mtp").join(CONFIG_FILE))
+    }
+
+    /// Loads the config file; a missing file yields the defaults.
+    pub fn load() -> Result<Self> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let config: Self = toml::from_str(&text)
+            .map_err(|e| Error::InvalidPath(format!("{}: {}", path.display(), e.message())))?;
+        config
+            .check_aliases()
+            .map_err(|e| Error::InvalidPath(format!("{}: {}", path.display(), e)))?;
+        Ok(config)
+    }
+
+    /// Alias names can't clash with sync's `kindle:` prefix or contain path
+    /// separators, and targets are absolute device folders.
+    fn check_aliases(&self) -> std::result::Result<(), String> {
+        for (name, target) in &self.aliases {
+            if name.is_empty() || name.contains(['/', ':']) || name == SYNC_DEVICE_PREFIX {
+                return Err(format!("invalid alias name '{}'", name));
+            }
+            if !target.starts_with('/') {
+                return Err(format!("alias '{}' must point to an absolute device path", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the config to `path`, creating its folder. Comments in an
+    /// existing file are not preserved.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = toml::to_string_pretty(self).map_err(|e| Error::Io(std::io::Error::other(e)))?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// Folder `pull` downloads into when no local path is given, and the
+    /// TUI's downloads go.
+    pub fn download_dir(&self) -> &Path {
+        self.defaults
+            .download_dir
+            .as_deref()
+            .unwrap_or(Path::new("."))
+    }
+
+    /// Expands `alias:rest` to the aliased folder joined with `rest`, e.g.
+    /// `docs:Book.epub` -> `/documents/Book.epub`. Anything else, including
+    /// names with an unknown prefix, is returned unchanged.
+    pub fn expand_alias(&self, path: &str) -> String {
+        if let Some((name, rest)) = path.split_once(':')
+            && let Some(target) = self.aliases.get(name)
+        {
+            let target = target.trim_end_matches('/');
+            let rest = rest.trim_start_matches('/');
+            return match (target.is_empty(), rest.is_empty()) {
+                (true, _) => format!("/{}", rest),
+                (false, true) => target.to_string(),
+                (false, false) => format!("{}/{}", target, rest),
+            };
+        }
+        path.to_string()
+    }
+
+    /// Like [`Config::expand_alias`], for `sync` arguments: an alias always
+    /// names the device, so the expansion gets the `kindle:` prefix.
+    pub fn expand_sync_endpoint(&self, endpoint: &str) -> String {
+        let expanded = self.expand_alias(endpoint);
+        if expanded == endpoint {
+            expanded
+        } else {
+            format!("{}:{}", SYNC_DEVICE_PREFIX, expanded)
+        }
+    }
+
+    /// Device folder to use when a command is given none; `None` keeps each
+    /// command's own default.
+    pub fn remote_root(&self) -> Option<&str> {
+        self.defaults.remote_root.as_deref()
+    }
+
+    /// Overwrite policy for transfers without `--force`/`--no-clobber`/
+    /// `--skip-existing`/`--newer`.
+    pub fn overwrite(&self) -> OverwritePolicy {
+        self.defaults.overwrite.unwrap_or_default()
+    }
+
+    /// Whether `rm` moves to the trash without `--trash`/`--no-trash`.
+    pub fn trash(&self) -> bool {
+        self.defaults.trash.unwrap_or(false)
+    }
+}
+
+fn optional_size<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<u64>, D::Error> {
+    size(deserializer).map(Some)
+}
+
+fn size<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(n) => Ok(n),
+        Size::Text(s) => parse_size(&s).map_err(serde::de::Error::custom),
+    }
+}
@@ -0,0 +1,72 @@
+use super::protocol::{socket_path, Request, Response, JSONRPC_VERSION};
+use crate::device::Kindle;
+use crate::error::{Error, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+
+/// Set to bypass a running daemon and open the device directly.
+const NO_DAEMON_ENV: &str = "KINDLE_MTP_NO_DAEMON";
+
+/// Connection to a running daemon. Calls are strictly request/response.
+pub struct Client {
+    reader: RefCell<BufReader<UnixStream>>,
+    writer: RefCell<UnixStream>,
+    next_id: Cell<u64>,
+}
+
+impl Client {
+    /// Connects if a daemon is listening; `None` means "use the device directly".
+    pub fn connect() -> Option<Self> {
+        if std::env::var_os(NO_DAEMON_ENV).is_some() {
+            return None;
+        }
+        let stream = UnixStream::connect(socket_path()).ok()?;
+        let writer = stream.try_clone().ok()?;
+        Some(Self {
+            reader: RefCell::new(BufReader::new(stream)),
+            writer: RefCell::new(writer),
+            next_id: Cell::new(1),
+        })
+    }
+
+    pub fn call<P: Serialize, T: DeserializeOwned>(&self, method: &str, params: P) -> Result<T> {
+        // The daemon finishes a request it has started; Ctrl-C stops the next one
+        if let Some(cancel) = Kindle::default_cancel_token() {
+            cancel.check()?;
+        }
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+
+        let request = Request {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id,
+            method: method.to_string(),
+            params: serde_json::to_value(params).map_err(protocol_error)?,
+        };
+        tracing::debug!(id, method, params = %request.params, "daemon request");
+        let mut line = serde_json::to_vec(&request).map_err(protocol_error)?;
+        line.push(b'\n');
+        {
+            let mut writer = self.writer.borrow_mut();
+            writer.write_all(&line)?;
+            writer.flush()?;
+        }
+
+        let mut reply = String::new();
+        if self.reader.borrow_mut().read_line(&mut reply)? == 0 {
+            return Err(Error::Mtp("daemon closed the connection".to_string()));
+        }
+        let response: Response = serde_json::from_str(&reply).map_err(protocol_error)?;
+        if let Some(error) = response.error {
+            return Err(error.into());
+        }
+        serde_json::from_value(response.result.unwrap_or_default()).map_err(protocol_error)
+    }
+}
+
+fn protocol_error(e: serde_json::Error) -> Error {
+    Error::Mtp(format!("daemon protocol error: {}", e))
+}
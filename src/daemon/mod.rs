@@ -0,0 +1,26 @@
+//! Daemon mode: one long-running process holds the MTP session open and
+//! serves newline-delimited JSON-RPC 2.0 over a Unix socket, so commands
+//! skip the multi-second device enumeration. [`Session`] is what commands
+//! use; it talks to the daemon when one is listening and opens the device
+//! directly otherwise.
+
+#[cfg(unix)]
+mod client;
+#[cfg(unix)]
+mod protocol;
+#[cfg(unix)]
+mod server;
+mod session;
+
+#[cfg(unix)]
+pub use server::serve;
+pub use session::Session;
+
+/// Whether a daemon is listening, whatever `KINDLE_MTP_NO_DAEMON` says. It
+/// holds the device lock for as long as it runs.
+#[cfg(unix)]
+pub(crate) fn is_running() -> bool {
+    // The daemon's own session must not find its socket
+    !server::SERVING.load(std::sync::atomic::Ordering::Relaxed)
+        && std::os::unix::net::UnixStream::connect(protocol::socket_path()).is_ok()
+}
@@ -0,0 +1,130 @@
+use crate::device::{KindleInfo, StorageInfo};
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::PathBuf;
+
+pub(crate) const JSONRPC_VERSION: &str = "2.0";
+/// JSON-RPC's reserved code for an unknown method.
+pub(crate) const METHOD_NOT_FOUND: i64 = -32601;
+/// JSON-RPC's reserved code for malformed parameters.
+pub(crate) const INVALID_PARAMS: i64 = -32602;
+
+/// `$XDG_RUNTIME_DIR/kindle-mtp.sock`, or a per-user file in the temp
+/// directory where there is no runtime dir (macOS).
+pub fn socket_path() -> PathBuf {
+    match dirs::runtime_dir() {
+        Some(dir) => dir.join("kindle-mtp.sock"),
+        None => {
+            let user = std::env::var("USER").unwrap_or_default();
+            std::env::temp_dir().join(format!("kindle-mtp-{}.sock", user))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Request {
+    pub jsonrpc: String,
+    pub id: u64,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Response {
+    pub jsonrpc: String,
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+/// Application errors use the CLI exit code as `code` and carry the error
+/// variant in `data.kind`, so the client can rebuild the same [`Error`] and
+/// callers that match on e.g. `FileNotFound` behave the same either way.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<ErrorData>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ErrorData {
+    pub kind: String,
+}
+
+impl From<&Error> for RpcError {
+    fn from(e: &Error) -> Self {
+        let message = match e {
+            Error::FileNotFound(m)
+            | Error::TransferFailed(m)
+            | Error::VerificationFailed(m)
+            | Error::DeviceBusy(m)
+            | Error::Timeout(m)
+            | Error::ObjectProtected(m)
+            | Error::Unsupported(m)
+            | Error::StorageFull(m)
+            | Error::InvalidPath(m) => m.clone(),
+            Error::DeviceNotFound | Error::PermissionDenied | Error::Cancelled => {
+                e.to_string()
+            }
+            _ => e.display_chain(),
+        };
+        Self {
+            code: i64::from(e.code()),
+            message,
+            data: Some(ErrorData {
+                kind: e.kind().to_string(),
+            }),
+        }
+    }
+}
+
+impl From<RpcError> for Error {
+    fn from(e: RpcError) -> Self {
+        match e.data.as_ref().map(|d| d.kind.as_str()) {
+            Some("file_not_found") => Error::FileNotFound(e.message),
+            Some("transfer_failed") => Error::TransferFailed(e.message),
+            Some("verification_failed") => Error::VerificationFailed(e.message),
+            Some("invalid_path") => Error::InvalidPath(e.message),
+            Some("device_not_found") => Error::DeviceNotFound,
+            Some("permission_denied") => Error::PermissionDenied,
+            Some("storage_full") => Error::StorageFull(e.message),
+            Some("cancelled") => Error::Cancelled,
+            Some("device_busy") => Error::DeviceBusy(e.message),
+            Some("timeout") => Error::Timeout(e.message),
+            Some("object_protected") => Error::ObjectProtected(e.message),
+            Some("unsupported") => Error::Unsupported(e.message),
+            _ => Error::Mtp(format!("daemon: {}", e.message)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PathParams {
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct RenameParams {
+    pub from: String,
+    pub to: String,
+}
+
+/// Local paths are absolute: the daemon's working directory isn't the
+/// caller's.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TransferParams {
+    pub remote: String,
+    pub local: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct InfoResult {
+    pub info: KindleInfo,
+    pub storage: StorageInfo,
+}
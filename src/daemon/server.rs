@@ -0,0 +1,220 @@
+use super::protocol::{
+    socket_path, InfoResult, PathParams, RenameParams, Request, Response, RpcError, TransferParams,
+    INVALID_PARAMS, JSONRPC_VERSION, METHOD_NOT_FOUND,
+};
+use crate::cli::Output;
+use crate::device::{Kindle, TransferOptions};
+use crate::error::{Error, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set while this process is the daemon.
+pub(super) static SERVING: AtomicBool = AtomicBool::new(false);
+
+/// Holds the device between requests. The session is opened lazily and
+/// dropped when the device goes away, so the daemon survives unplugging.
+struct State {
+    kindle: Option<Kindle>,
+    transfer: TransferOptions,
+}
+
+/// Serves requests until interrupted. Connections are handled one at a time:
+/// MTP allows a single operation in flight anyway.
+pub fn serve(output: &Output, transfer: TransferOptions) -> Result<()> {
+    let path = socket_path();
+    if path.exists() {
+        if UnixStream::connect(&path).is_ok() {
+            return Err(Error::InvalidPath(format!(
+                "a daemon is already listening on {}",
+                path.display()
+            )));
+        }
+        // Left behind by a daemon that was killed
+        std::fs::remove_file(&path)?;
+    }
+    SERVING.store(true, Ordering::Relaxed);
+    // Anyone who can connect can read and write the device, and on macOS the
+    // socket is in the shared temp dir, so it must not exist with looser
+    // permissions even briefly
+    // SAFETY: umask only swaps the process's file creation mask
+    let umask = unsafe { libc::umask(0o077) };
+    let bound = UnixListener::bind(&path);
+    // SAFETY: as above, restoring the previous mask
+    unsafe { libc::umask(umask) };
+    let listener = bound?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+
+    let mut state = State {
+        kindle: None,
+        transfer,
+    };
+    if let Err(e) = state.kindle() {
+        output.info(format!("No device yet ({}); will connect on first request", e));
+    }
+    output.info(format!("Listening on {} (Ctrl-C to stop)", path.display()));
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = handle_connection(&mut state, stream) {
+                    output.info(format!("Warning: connection closed: {}", e));
+                }
+            }
+            Err(e) => output.info(format!("Warning: accept failed: {}", e)),
+        }
+    }
+    Ok(())
+}
+
+impl State {
+    fn kindle(&mut self) -> Result<&Kindle> {
+        if self.kindle.is_none() {
+            let kindle = Kindle::open(self.transfer)?;
+            self.kindle = Some(kindle);
+        }
+        Ok(self.kindle.as_ref().expect("just connected"))
+    }
+}
+
+fn handle_connection(state: &mut State, stream: UnixStream) -> std::io::Result<()> {
+    // Paths are cached per client; the device may have changed in between
+    if let Some(kindle) = &state.kindle {
+        kindle.clear_path_cache();
+    }
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                let id = request.id;
+                match dispatch(state, request) {
+                    Ok(result) => Response {
+                        jsonrpc: JSONRPC_VERSION.to_string(),
+                        id,
+                        result: Some(result),
+                        error: None,
+                    },
+                    Err(error) => Response {
+                        jsonrpc: JSONRPC_VERSION.to_string(),
+                        id,
+                        result: None,
+                        error: Some(error),
+                    },
+                }
+            }
+            Err(e) => Response {
+                jsonrpc: JSONRPC_VERSION.to_string(),
+                id: 0,
+                result: None,
+                error: Some(RpcError {
+                    code: INVALID_PARAMS,
+                    message: format!("invalid request: {}", e),
+                    data: None,
+                }),
+            },
+        };
+        let mut out = serde_json::to_vec(&response).map_err(std::io::Error::other)?;
+        out.push(b'\n');
+        writer.write_all(&out)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+fn dispatch(state: &mut State, request: Request) -> std::result::Result<Value, RpcError> {
+    let result = call(state, &request.method, request.params);
+    if matches!(result, Err(CallError::Device(_))) && !Kindle::is_present() {
+        // Unplugged: reconnect on the next request instead of failing forever
+        state.kindle = None;
+    } else if result.is_err()
+        && let Some(kindle) = &state.kindle
+    {
+        kindle.clear_path_cache();
+    }
+    result.map_err(|e| match e {
+        CallError::Device(e) => RpcError::from(&e),
+        CallError::Rpc(e) => e,
+    })
+}
+
+enum CallError {
+    Device(Error),
+    Rpc(RpcError),
+}
+
+impl From<Error> for CallError {
+    fn from(e: Error) -> Self {
+        CallError::Device(e)
+    }
+}
+
+fn call(state: &mut State, method: &str, params: Value) -> std::result::Result<Value, CallError> {
+    match method {
+        "info" => {
+            let kindle = state.kindle()?;
+            to_value(InfoResult {
+                info: kindle.info(),
+                storage: kindle.storage_info()?,
+            })
+        }
+        "ls" => {
+            let p: PathParams = from_params(params)?;
+            to_value(state.kindle()?.list_files(&p.path)?)
+        }
+        "stat" => {
+            let p: PathParams = from_params(params)?;
+            to_value(state.kindle()?.stat(&p.path)?)
+        }
+        "walk" => {
+            let p: PathParams = from_params(params)?;
+            to_value(state.kindle()?.walk(&p.path)?)
+        }
+        "pull" => {
+            let p: TransferParams = from_params(params)?;
+            state.kindle()?.download_file(&p.remote, &p.local)?;
+            Ok(Value::Null)
+        }
+        "push" => {
+            let p: TransferParams = from_params(params)?;
+            state.kindle()?.upload_file(&p.local, &p.remote)?;
+            Ok(Value::Null)
+        }
+        "delete" => {
+            let p: PathParams = from_params(params)?;
+            state.kindle()?.delete(&p.path)?;
+            Ok(Value::Null)
+        }
+        "rename" => {
+            let p: RenameParams = from_params(params)?;
+            state.kindle()?.rename(&p.from, &p.to)?;
+            Ok(Value::Null)
+        }
+        _ => Err(CallError::Rpc(RpcError {
+            code: METHOD_NOT_FOUND,
+            message: format!("unknown method '{}'", method),
+            data: None,
+        })),
+    }
+}
+
+fn from_params<T: DeserializeOwned>(params: Value) -> std::result::Result<T, CallError> {
+    serde_json::from_value(params).map_err(|e| {
+        CallError::Rpc(RpcError {
+            code: INVALID_PARAMS,
+            message: e.to_string(),
+            data: None,
+        })
+    })
+}
+
+fn to_value<T: Serialize>(value: T) -> std::result::Result<Value, CallError> {
+    serde_json::to_value(value).map_err(|e| CallError::Device(Error::Io(std::io::Error::other(e))))
+}
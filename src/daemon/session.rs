@@ -0,0 +1,132 @@
+use crate::device::{FileEntry, Kindle, KindleInfo, StorageInfo, TransferOptions, WalkEntry};
+use crate::error::{Error, Result};
+use std::path::Path;
+
+#[cfg(unix)]
+use super::client::Client;
+#[cfg(unix)]
+use super::protocol::{InfoResult, PathParams, RenameParams, TransferParams};
+
+/// The device as commands see it: a session opened by this process, or one
+/// held by a running `kindle-mtp daemon`. Operations that stream data
+/// through the caller (`--stdout`, hashing) need the device directly.
+// One per command, so boxing the larger variant would save nothing
+#[allow(clippy::large_enum_variant)]
+pub enum Session {
+    Direct(Kindle),
+    #[cfg(unix)]
+    Daemon(Client),
+}
+
+impl Session {
+    /// Uses the daemon if one is listening, otherwise opens the device.
+    pub fn open(transfer: TransferOptions) -> Result<Self> {
+        #[cfg(unix)]
+        if let Some(client) = Client::connect() {
+            tracing::debug!("using the daemon's session");
+            return Ok(Self::Daemon(client));
+        }
+        let kindle = Kindle::open(transfer)?;
+        Ok(Self::Direct(kindle))
+    }
+
+    /// The directly opened device, for operations the daemon doesn't proxy.
+    pub fn direct(&self, operation: &str) -> Result<&Kindle> {
+        match self {
+            Self::Direct(kindle) => Ok(kindle),
+            #[cfg(unix)]
+            Self::Daemon(_) => Err(Error::InvalidPath(format!(
+                "{} is not available through the daemon; stop `kindle-mtp daemon` first",
+                operation
+            ))),
+        }
+    }
+
+    pub fn info(&self) -> Result<(KindleInfo, StorageInfo)> {
+        match self {
+            Self::Direct(kindle) => Ok((kindle.info(), kindle.storage_info()?)),
+            #[cfg(unix)]
+            Self::Daemon(client) => {
+                let result: InfoResult = client.call("info", ())?;
+                Ok((result.info, result.storage))
+            }
+        }
+    }
+
+    pub fn list_files(&self, path: &str) -> Result<Vec<FileEntry>> {
+        match self {
+            Self::Direct(kindle) => kindle.list_files(path),
+            #[cfg(unix)]
+            Self::Daemon(client) => client.call("ls", path_params(path)),
+        }
+    }
+
+    pub fn stat(&self, path: &str) -> Result<FileEntry> {
+        match self {
+            Self::Direct(kindle) => kindle.stat(path),
+            #[cfg(unix)]
+            Self::Daemon(client) => client.call("stat", path_params(path)),
+        }
+    }
+
+    pub fn walk(&self, root: &str) -> Result<Vec<WalkEntry>> {
+        match self {
+            Self::Direct(kindle) => kindle.walk(root),
+            #[cfg(unix)]
+            Self::Daemon(client) => client.call("walk", path_params(root)),
+        }
+    }
+
+    pub fn download_file(&self, remote: &str, local: &Path) -> Result<()> {
+        match self {
+            Self::Direct(kindle) => kindle.download_file(remote, local),
+            #[cfg(unix)]
+            Self::Daemon(client) => client.call("pull", transfer_params(remote, local)?),
+        }
+    }
+
+    pub fn upload_file(&self, local: &Path, remote: &str) -> Result<()> {
+        match self {
+            Self::Direct(kindle) => kindle.upload_file(local, remote),
+            #[cfg(unix)]
+            Self::Daemon(client) => client.call("push", transfer_params(remote, local)?),
+        }
+    }
+
+    pub fn delete(&self, path: &str) -> Result<()> {
+        match self {
+            Self::Direct(kindle) => kindle.delete(path),
+            #[cfg(unix)]
+            Self::Daemon(client) => client.call("delete", path_params(path)),
+        }
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        match self {
+            Self::Direct(kindle) => kindle.rename(from, to),
+            #[cfg(unix)]
+            Self::Daemon(client) => client.call(
+                "rename",
+                RenameParams {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                },
+            ),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn path_params(path: &str) -> PathParams {
+    PathParams {
+        path: path.to_string(),
+    }
+}
+
+#[cfg(unix)]
+fn transfer_params(remote: &str, local: &Path) -> Result<TransferParams> {
+    Ok(TransferParams {
+        remote: remote.to_string(),
+        local: std::path::absolute(local)?,
+    })
+}
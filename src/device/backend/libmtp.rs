@@ -0,0 +1,409 @@
+//! The libmtp backend, used everywhere but Windows.
+
+use super::MtpBackend;
+use crate::device::host;
+use crate::device::kindle::{
+    DetectOptions, DeviceFilter, FileEntry, KindleInfo, ObjectProperty, PropertyValue, StorageInfo,
+};
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
+use libmtp_rs::device::raw::{detect_raw_devices, RawDevice};
+use libmtp_rs::device::MtpDevice;
+use libmtp_rs::error::{Error as MtpLibError, MtpErrorKind};
+use libmtp_rs::object::filetypes::Filetype;
+use libmtp_rs::object::properties::Property;
+use libmtp_rs::object::Object;
+use libmtp_rs::storage::files::FileMetadata;
+use libmtp_rs::storage::{Parent, Storage, StoragePool};
+use libmtp_rs::util::HandlerReturn;
+use tracing::{debug, trace};
+
+// `raw_device` reads the libmtp handle out of `MtpDevice`. The size check
+// catches added fields, not a changed field; that is what the exact
+// version pin in Cargo.toml is for.
+const _: () = assert!(
+    std::mem::size_of::<MtpDevice>() == std::mem::size_of::<*mut libmtp_sys::LIBMTP_mtpdevice_t>()
+);
+
+/// How libmtp reads a property: it has one getter per integer width.
+#[derive(Clone, Copy)]
+enum PropertyType {
+    Text,
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+/// The properties `object_properties` asks for, with their MTP datatypes.
+/// 128-bit ones (the persistent unique ID) have no libmtp getter.
+const OBJECT_PROPERTIES: &[(Property, PropertyType)] = &[
+    (Property::StorageId, PropertyType::U32),
+    (Property::ParentObject, PropertyType::U32),
+    (Property::ObjectFormat, PropertyType::U16),
+    (Property::ObjectFileName, PropertyType::Text),
+    (Property::ObjectSize, PropertyType::U64),
+    (Property::ProtectionStatus, PropertyType::U16),
+    (Property::AssociationType, PropertyType::U16),
+    (Property::AssociationDesc, PropertyType::U32),
+    (Property::Hidden, PropertyType::U16),
+    (Property::SystemObject, PropertyType::U16),
+    (Property::NonConsumable, PropertyType::U8),
+    (Property::Name, PropertyType::Text),
+    (Property::DisplayName, PropertyType::Text),
+    (Property::DateCreated, PropertyType::Text),
+    (Property::DateModified, PropertyType::Text),
+    (Property::DateAdded, PropertyType::Text),
+    (Property::DateAuthored, PropertyType::Text),
+    (Property::LastAccessed, PropertyType::Text),
+    (Property::Keywords, PropertyType::Text),
+    (Property::SyncId, PropertyType::Text),
+    (Property::CreatedBy, PropertyType::Text),
+    (Property::Artist, PropertyType::Text),
+    (Property::Description, PropertyType::Text),
+    (Property::LanguageLocale, PropertyType::Text),
+    (Property::CopyrightInformation, PropertyType::Text),
+    (Property::Source, PropertyType::Text),
+    (Property::Genre, PropertyType::Text),
+    (Property::DrmStatus, PropertyType::U16),
+    (Property::UseCount, PropertyType::U32),
+];
+
+pub(crate) struct LibMtp {
+    device: MtpDevice,
+    /// USB vendor and product ID, from the raw device that was opened
+    usb_id: (u16, u16),
+}
+
+impl LibMtp {
+    /// `storage` from libmtp's list of storages read at open, so this costs
+    /// no USB round trip.
+    fn storage<'a>(storage_pool: &'a StoragePool<'a>, id: u32) -> Result<&'a Storage<'a>> {
+        storage_pool
+            .by_id(id)
+            .ok_or_else(|| Error::Mtp("No storage found".to_string()))
+    }
+
+    /// The libmtp device handle, `MtpDevice`'s only field, for the calls
+    /// libmtp-rs doesn't wrap (thumbnails, partial reads).
+    fn raw_device(&self) -> *mut libmtp_sys::LIBMTP_mtpdevice_t {
+        // SAFETY: in libmtp-rs 0.7.7, which Cargo.toml pins exactly,
+        // `MtpDevice` is a struct whose one field is this pointer
+        unsafe { *(&self.device as *const MtpDevice as *const *mut libmtp_sys::LIBMTP_mtpdevice_t) }
+    }
+}
+
+impl MtpBackend for LibMtp {
+    fn open(options: &DetectOptions, filter: &DeviceFilter) -> Result<Self> {
+        let raw_devices = detect_raw_devices().map_err(|e| match mtp_error(e) {
+            Error::DeviceNotFound => Error::DeviceNotFound,
+            other => host::busy_error(other),
+        })?;
+
+        debug!(count = raw_devices.len(), cached = options.cached, "found raw MTP devices");
+        let mut kindles = raw_devices.into_iter().filter(|d| {
+            let entry = d.device_entry();
+            filter.matches(entry.vendor_id, entry.product_id)
+        });
+        let open = |raw: RawDevice| {
+            let entry = raw.device_entry();
+            let usb_id = (entry.vendor_id, entry.product_id);
+            let device = if options.cached {
+                raw.open()
+            } else {
+                raw.open_uncached()
+            };
+            device.map(|device| Self { device, usb_id })
+        };
+
+        // The serial is only readable from an open session, so candidates are
+        // opened in turn; the ones that don't match are closed again on drop
+        match &options.serial {
+            None => {
+                let raw = kindles.next().ok_or(Error::DeviceNotFound)?;
+                open(raw).ok_or_else(|| {
+                    host::busy_error(Error::Mtp("Kindle is busy: could not open an MTP session".to_string()))
+                })
+            }
+            Some(serial) => kindles
+                .filter_map(open)
+                .find(|backend| {
+                    let found = backend.device.serial_number();
+                    debug!(serial = ?found, wanted = %serial, "checking Kindle serial");
+                    found.is_ok_and(|s| s == *serial)
+                })
+                .ok_or(Error::DeviceNotFound),
+        }
+    }
+
+    fn is_present(filter: &DeviceFilter) -> bool {
+        detect_raw_devices()
+            .map(|devices| {
+                devices.iter().any(|d| {
+                    let entry = d.device_entry();
+                    filter.matches(entry.vendor_id, entry.product_id)
+                })
+            })
+            .unwrap_or(false)
+    }
+
+    fn info(&self) -> KindleInfo {
+        KindleInfo {
+            manufacturer: self
+                .device
+                .manufacturer_name()
+                .unwrap_or_else(|_| "Unknown".to_string()),
+            model: self
+                .device
+                .model_name()
+                .unwrap_or_else(|_| "Unknown".to_string()),
+            serial: self
+                .device
+                .serial_number()
+                .unwrap_or_else(|_| "".to_string()),
+            friendly_name: self
+                .device
+                .get_friendly_name()
+                .unwrap_or_else(|_| "Kindle".to_string()),
+            vendor_id: self.usb_id.0,
+            product_id: self.usb_id.1,
+        }
+    }
+
+    fn storages(&self) -> Vec<StorageInfo> {
+        let storage_pool = self.device.storage_pool();
+        storage_pool.iter().map(|(_, storage)| storage_summary(storage)).collect()
+    }
+
+    /// libmtp issues a single GetObjectHandles filtered by parent, then one
+    /// ObjectInfo per child; see ADR-002 for why this is the cheapest
+    /// listing libmtp exposes.
+    fn list_children(&self, storage: u32, parent: Option<u32>) -> Result<Vec<FileEntry>> {
+        let storage_pool = self.device.storage_pool();
+        let storage = Self::storage(&storage_pool, storage)?;
+        Ok(storage
+            .files_and_folders(libmtp_parent(parent))
+            .into_iter()
+            .map(|f| FileEntry {
+                name: f.name().to_string(),
+                size: f.size(),
+                is_folder: matches!(f.ftype(), Filetype::Folder),
+                id: f.id(),
+                modified: f.modification_date(),
+            })
+            .collect())
+    }
+
+    /// Reads every property in `OBJECT_PROPERTIES` that the device says it
+    /// supports for the object's format. Each is one GetObjectPropValue
+    /// round trip, so this is for inspection, not for listings.
+    fn object_properties(&self, storage: u32, parent: Option<u32>, id: u32) -> Result<Vec<ObjectProperty>> {
+        let storage_pool = self.device.storage_pool();
+        let storage = Self::storage(&storage_pool, storage)?;
+        let filetype = storage
+            .files_and_folders(libmtp_parent(parent))
+            .into_iter()
+            .find(|f| f.id() == id)
+            .map(|f| f.ftype())
+            .ok_or_else(|| Error::FileNotFound(format!("object {} is gone", id)))?;
+        debug!(id, ?filetype, "reading object properties");
+
+        let object = self.device.dummy_object(id);
+        let mut properties = Vec::new();
+        for &(property, kind) in OBJECT_PROPERTIES {
+            if !self
+                .device
+                .is_property_supported(property, filetype.clone())
+                .unwrap_or(false)
+            {
+                continue;
+            }
+            let value = match kind {
+                PropertyType::Text => object.get_string(property).map(PropertyValue::Text),
+                PropertyType::U8 => object.get_u8(property).map(|v| PropertyValue::Integer(v.into())),
+                PropertyType::U16 => object.get_u16(property).map(|v| PropertyValue::Integer(v.into())),
+                PropertyType::U32 => object.get_u32(property).map(|v| PropertyValue::Integer(v.into())),
+                PropertyType::U64 => object.get_u64(property).map(PropertyValue::Integer),
+            };
+            match value {
+                Ok(value) => properties.push(ObjectProperty {
+                    name: format!("{:?}", property),
+                    value,
+                }),
+                Err(e) => trace!(?property, error = %e, "property not readable"),
+            }
+        }
+        Ok(properties)
+    }
+
+    fn thumbnail(&self, id: u32) -> Result<Vec<u8>> {
+        let mut data: *mut std::os::raw::c_uchar = std::ptr::null_mut();
+        let mut size: std::os::raw::c_uint = 0;
+        // SAFETY: libmtp-rs has no thumbnail call, so this goes straight to
+        // libmtp. On success libmtp hands over a malloc'd buffer of `size`
+        // bytes.
+        let status = unsafe { libmtp_sys::LIBMTP_Get_Thumbnail(self.raw_device(), id, &mut data, &mut size) };
+        if status != 0 || data.is_null() {
+            return Err(Error::FileNotFound("device has no thumbnail for this object".to_string()));
+        }
+        // SAFETY: see above
+        Ok(unsafe { take_buffer(data, size) })
+    }
+
+    /// GetPartialObject, so reading a book's header doesn't fetch the book.
+    fn read_partial(&self, _storage: u32, id: u32, offset: u64, len: u32) -> Result<Vec<u8>> {
+        let mut data: *mut std::os::raw::c_uchar = std::ptr::null_mut();
+        let mut size: std::os::raw::c_uint = 0;
+        // SAFETY: as in `thumbnail`; libmtp-rs has no partial reads either
+        let status = unsafe {
+            libmtp_sys::LIBMTP_GetPartialObject(self.raw_device(), id, offset, len, &mut data, &mut size)
+        };
+        if status != 0 {
+            return Err(Error::TransferFailed(format!("partial read of object {} failed", id)));
+        }
+        if data.is_null() {
+            return Ok(Vec::new());
+        }
+        // SAFETY: see above
+        Ok(unsafe { take_buffer(data, size) })
+    }
+
+    fn get_file(&self, storage: u32, id: u32, on_data: &mut dyn FnMut(&[u8]) -> bool) -> Result<()> {
+        let storage_pool = self.device.storage_pool();
+        let storage = Self::storage(&storage_pool, storage)?;
+        storage
+            .get_file_to_handler(id, |chunk| {
+                if !on_data(chunk) {
+                    return HandlerReturn::Cancel;
+                }
+                HandlerReturn::Ok(chunk.len() as u32)
+            })
+            .map_err(transfer_error)
+    }
+
+    fn send_file(
+        &self,
+        storage: u32,
+        parent: Option<u32>,
+        name: &str,
+        size: u64,
+        modified: DateTime<Utc>,
+        fill: &mut dyn FnMut(&mut [u8]) -> Option<usize>,
+    ) -> Result<()> {
+        let storage_pool = self.device.storage_pool();
+        let storage = Self::storage(&storage_pool, storage)?;
+        let metadata = FileMetadata {
+            file_size: size,
+            file_name: name,
+            file_type: Filetype::Unknown,
+            modification_date: modified,
+        };
+        storage
+            .send_file_from_handler(
+                |buf| match fill(buf) {
+                    Some(n) => HandlerReturn::Ok(n as u32),
+                    None => HandlerReturn::Cancel,
+                },
+                libmtp_parent(parent),
+                metadata,
+            )
+            .map(|_| ())
+            .map_err(transfer_error)
+    }
+
+    fn create_folder(&self, storage: u32, parent: Option<u32>, name: &str) -> Result<u32> {
+        let storage_pool = self.device.storage_pool();
+        let storage = Self::storage(&storage_pool, storage)?;
+        let (id, _) = storage
+            .create_folder(name, libmtp_parent(parent))
+            .map_err(mtp_error)?;
+        Ok(id)
+    }
+
+    fn delete(&self, id: u32) -> Result<()> {
+        self.device.dummy_object(id).delete().map_err(mtp_error)
+    }
+
+    fn move_object(&self, id: u32, storage: u32, parent: Option<u32>) -> Result<()> {
+        self.device
+            .dummy_object(id)
+            .move_to(storage, libmtp_parent(parent))
+            .map_err(mtp_error)
+    }
+
+    fn rename_object(&self, id: u32, name: &str) -> Result<()> {
+        self.device
+            .dummy_object(id)
+            .set_string(Property::ObjectFileName, name)
+            .map_err(mtp_error)
+    }
+}
+
+/// Copies a buffer libmtp malloc'd for the caller and frees it.
+///
+/// # Safety
+/// `data` must point to `size` bytes from libmtp, not yet freed.
+unsafe fn take_buffer(data: *mut std::os::raw::c_uchar, size: std::os::raw::c_uint) -> Vec<u8> {
+    // SAFETY: as the caller promises; freed exactly once, after the copy
+    unsafe {
+        let bytes = std::slice::from_raw_parts(data, size as usize).to_vec();
+        libc::free(data.cast());
+        bytes
+    }
+}
+
+fn libmtp_parent(parent: Option<u32>) -> Parent {
+    match parent {
+        None => Parent::Root,
+        Some(id) => Parent::Folder(id),
+    }
+}
+
+/// The error a libmtp failure stands for, falling back to `Error::Mtp`.
+fn mtp_error(e: MtpLibError) -> Error {
+    typed_mtp_error(&e).unwrap_or_else(|| Error::Mtp(format!("{}", e)))
+}
+
+/// Like `mtp_error`, for a failed file transfer.
+fn transfer_error(e: MtpLibError) -> Error {
+    typed_mtp_error(&e).unwrap_or_else(|| Error::TransferFailed(format!("{}", e)))
+}
+
+/// The typed error behind a libmtp failure, if it has one. libmtp reports
+/// a few kinds itself and hands PTP response codes on only as text.
+fn typed_mtp_error(e: &MtpLibError) -> Option<Error> {
+    let MtpLibError::MtpError { kind, text } = e else {
+        return None;
+    };
+    match kind {
+        MtpErrorKind::NoDeviceAttached => Some(Error::DeviceNotFound),
+        MtpErrorKind::StorageFull => Some(Error::StorageFull(text.clone())),
+        MtpErrorKind::Cancelled => Some(Error::Cancelled),
+        _ => {
+            // The PTP response names libmtp appends, e.g. "PTP Store Full"
+            let lower = text.to_ascii_lowercase();
+            if lower.contains("store full") {
+                Some(Error::StorageFull(text.clone()))
+            } else if lower.contains("store read only") || lower.contains("access denied") {
+                Some(Error::PermissionDenied)
+            } else if lower.contains("object write protected") {
+                Some(Error::ObjectProtected(text.clone()))
+            } else if lower.contains("device busy") {
+                Some(Error::DeviceBusy(text.clone()))
+            } else if lower.contains("invalid object handle") {
+                Some(Error::FileNotFound(text.clone()))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+fn storage_summary(storage: &Storage) -> StorageInfo {
+    StorageInfo {
+        id: storage.id(),
+        description: storage.description().unwrap_or("Internal Storage").to_string(),
+        total_bytes: storage.maximum_capacity(),
+        free_bytes: storage.free_space_in_bytes(),
+    }
+}
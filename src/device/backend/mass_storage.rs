@@ -0,0 +1,349 @@
+//! Older Kindles (Keyboard, DX, the first Touch) offer USB mass storage
+//! instead of MTP: the OS mounts them as a drive. This backend works on
+//! that mounted volume, so the same commands cover the whole family.
+//!
+//! Objects are files below the mount point; each path seen gets a number
+//! for the session, the way MTP handles would be.
+
+use super::MtpBackend;
+use crate::device::kindle::{
+    DetectOptions, DeviceFilter, FileEntry, KindleInfo, MtpCapabilities, ObjectProperty, RawResponse, StorageInfo,
+    AMAZON_VENDOR_ID,
+};
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// The one storage a mounted volume has, numbered like a first MTP storage.
+const STORAGE_ID: u32 = 0x0001_0001;
+/// Bytes read from a file per call of `get_file`'s handler.
+const READ_CHUNK: usize = 256 * 1024;
+
+pub(crate) struct MassStorage {
+    /// Where the Kindle is mounted, e.g. `/Volumes/Kindle`
+    root: PathBuf,
+    /// Paths below `root`, by object ID minus one
+    paths: RefCell<Vec<PathBuf>>,
+    /// Object IDs by path below `root`
+    ids: RefCell<HashMap<PathBuf, u32>>,
+}
+
+impl MassStorage {
+    /// The full path of object `id`.
+    fn path(&self, id: u32) -> Result<PathBuf> {
+        let paths = self.paths.borrow();
+        id.checked_sub(1)
+            .and_then(|index| paths.get(index as usize))
+            .map(|relative| self.root.join(relative))
+            .ok_or_else(|| Error::FileNotFound(format!("object {} is gone", id)))
+    }
+
+    /// The full path of a folder, `None` being the root of the volume.
+    fn folder(&self, parent: Option<u32>) -> Result<PathBuf> {
+        match parent {
+            None => Ok(self.root.clone()),
+            Some(id) => self.path(id),
+        }
+    }
+
+    /// The object ID of a path below `root`, handing out a new one the
+    /// first time it is seen.
+    fn id(&self, path: &Path) -> u32 {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path).to_path_buf();
+        if let Some(&id) = self.ids.borrow().get(&relative) {
+            return id;
+        }
+        let mut paths = self.paths.borrow_mut();
+        paths.push(relative.clone());
+        let id = paths.len() as u32;
+        self.ids.borrow_mut().insert(relative, id);
+        id
+    }
+
+    /// Renames `from` to `to` and moves the IDs of it and everything below
+    /// it along, so they stay valid.
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        if to.exists() {
+            return Err(Error::InvalidPath(format!("'{}' already exists", to.display())));
+        }
+        fs::rename(from, to).map_err(|e| io_error(e, from))?;
+        let (from, to) = (
+            from.strip_prefix(&self.root).unwrap_or(from).to_path_buf(),
+            to.strip_prefix(&self.root).unwrap_or(to).to_path_buf(),
+        );
+        let mut paths = self.paths.borrow_mut();
+        let mut ids = self.ids.borrow_mut();
+        for (index, path) in paths.iter_mut().enumerate() {
+            if let Ok(rest) = path.strip_prefix(&from) {
+                let moved = if rest.as_os_str().is_empty() { to.clone() } else { to.join(rest) };
+                ids.remove(path.as_path());
+                ids.insert(moved.clone(), index as u32 + 1);
+                *path = moved;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MtpBackend for MassStorage {
+    /// Looks for a mounted volume with a Kindle's `documents` and `system`
+    /// folders. The serial can't be read from a volume, so `options.serial`
+    /// is not checked; a filter asking for another vendor skips the search.
+    fn open(options: &DetectOptions, filter: &DeviceFilter) -> Result<Self> {
+        if !wants_kindle(filter) {
+            return Err(Error::DeviceNotFound);
+        }
+        let root = kindle_volume().ok_or(Error::DeviceNotFound)?;
+        if let Some(serial) = &options.serial {
+            debug!(%serial, "serial of a USB drive can't be checked");
+        }
+        debug!(root = %root.display(), "opened Kindle as a USB drive");
+        Ok(Self {
+            root,
+            paths: RefCell::default(),
+            ids: RefCell::default(),
+        })
+    }
+
+    fn is_present(filter: &DeviceFilter) -> bool {
+        wants_kindle(filter) && kindle_volume().is_some()
+    }
+
+    fn info(&self) -> KindleInfo {
+        KindleInfo {
+            manufacturer: "Amazon".to_string(),
+            model: "Kindle (USB drive)".to_string(),
+            serial: String::new(),
+            friendly_name: self
+                .root
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "Kindle".to_string()),
+            vendor_id: AMAZON_VENDOR_ID,
+            product_id: 0,
+        }
+    }
+
+    fn storages(&self) -> Vec<StorageInfo> {
+        let (total_bytes, free_bytes) = volume_space(&self.root);
+        vec![StorageInfo {
+            id: STORAGE_ID,
+            description: format!("USB drive at {}", self.root.display()),
+            total_bytes,
+            free_bytes,
+        }]
+    }
+
+    /// Hidden files (`.Trashes`, macOS `._` files) are left out: the
+    /// Kindle doesn't show them over MTP either.
+    fn list_children(&self, _storage: u32, parent: Option<u32>) -> Result<Vec<FileEntry>> {
+        let folder = self.folder(parent)?;
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&folder).map_err(|e| io_error(e, &folder))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            entries.push(FileEntry {
+                id: self.id(&entry.path()),
+                name,
+                size: if metadata.is_dir() { 0 } else { metadata.len() },
+                is_folder: metadata.is_dir(),
+                modified: metadata
+                    .modified()
+                    .map(DateTime::<Utc>::from)
+                    .unwrap_or(DateTime::UNIX_EPOCH),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn object_properties(&self, _storage: u32, _parent: Option<u32>, _id: u32) -> Result<Vec<ObjectProperty>> {
+        Err(Error::Unsupported("a Kindle mounted as a USB drive has no MTP properties".to_string()))
+    }
+
+    fn thumbnail(&self, _id: u32) -> Result<Vec<u8>> {
+        Err(Error::FileNotFound("device has no thumbnail for this object".to_string()))
+    }
+
+    fn get_file(&self, _storage: u32, id: u32, on_data: &mut dyn FnMut(&[u8]) -> bool) -> Result<()> {
+        let path = self.path(id)?;
+        let mut file = File::open(&path).map_err(|e| io_error(e, &path))?;
+        let mut buffer = vec![0u8; READ_CHUNK];
+        loop {
+            let n = file.read(&mut buffer).map_err(|e| Error::TransferFailed(e.to_string()))?;
+            if n == 0 {
+                return Ok(());
+            }
+            if !on_data(&buffer[..n]) {
+                return Err(Error::Cancelled);
+            }
+        }
+    }
+
+    fn read_partial(&self, _storage: u32, id: u32, offset: u64, len: u32) -> Result<Vec<u8>> {
+        let path = self.path(id)?;
+        let mut file = File::open(&path).map_err(|e| io_error(e, &path))?;
+        file.seek(SeekFrom::Start(offset)).map_err(|e| Error::TransferFailed(e.to_string()))?;
+        let mut data = Vec::with_capacity(len as usize);
+        file.take(u64::from(len))
+            .read_to_end(&mut data)
+            .map_err(|e| Error::TransferFailed(e.to_string()))?;
+        Ok(data)
+    }
+
+    /// Synced before returning, so the file is complete once the volume is
+    /// ejected.
+    fn send_file(
+        &self,
+        _storage: u32,
+        parent: Option<u32>,
+        name: &str,
+        size: u64,
+        modified: DateTime<Utc>,
+        fill: &mut dyn FnMut(&mut [u8]) -> Option<usize>,
+    ) -> Result<()> {
+        let path = self.folder(parent)?.join(name);
+        let mut file = File::create(&path).map_err(|e| io_error(e, &path))?;
+        let mut write = || -> Result<()> {
+            let mut buffer = vec![0u8; READ_CHUNK];
+            let mut sent = 0u64;
+            while sent < size {
+                let Some(n) = fill(&mut buffer) else {
+                    return Err(Error::Cancelled);
+                };
+                if n == 0 {
+                    break;
+                }
+                file.write_all(&buffer[..n]).map_err(|e| io_error(e, &path))?;
+                sent += n as u64;
+            }
+            file.set_modified(modified.into())?;
+            file.sync_all()?;
+            Ok(())
+        };
+        let written = write();
+        if written.is_err() {
+            drop(file);
+            let _ = fs::remove_file(&path);
+        }
+        written
+    }
+
+    fn create_folder(&self, _storage: u32, parent: Option<u32>, name: &str) -> Result<u32> {
+        let path = self.folder(parent)?.join(name);
+        fs::create_dir(&path).map_err(|e| io_error(e, &path))?;
+        Ok(self.id(&path))
+    }
+
+    fn delete(&self, id: u32) -> Result<()> {
+        let path = self.path(id)?;
+        let removed = if path.is_dir() {
+            fs::remove_dir(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        removed.map_err(|e| io_error(e, &path))
+    }
+
+    fn move_object(&self, id: u32, _storage: u32, parent: Option<u32>) -> Result<()> {
+        let from = self.path(id)?;
+        let name = from
+            .file_name()
+            .ok_or_else(|| Error::InvalidPath("can't move the root".to_string()))?;
+        let to = self.folder(parent)?.join(name);
+        self.rename(&from, &to)
+    }
+
+    fn rename_object(&self, id: u32, name: &str) -> Result<()> {
+        let from = self.path(id)?;
+        let to = from.with_file_name(name);
+        self.rename(&from, &to)
+    }
+
+    fn capabilities(&self) -> Result<MtpCapabilities> {
+        Err(Error::Unsupported("a Kindle mounted as a USB drive doesn't speak MTP".to_string()))
+    }
+
+    fn raw_operation(&self, _code: u16, _params: &[u32]) -> Result<RawResponse> {
+        Err(Error::Unsupported("a Kindle mounted as a USB drive doesn't speak MTP".to_string()))
+    }
+}
+
+/// Whether `filter` asks for Kindles, so a mounted one may stand in for
+/// an MTP device. The product ID of a volume is unknown, so any will do.
+fn wants_kindle(filter: &DeviceFilter) -> bool {
+    filter.any || filter.vendor_id.is_none_or(|vendor| vendor == AMAZON_VENDOR_ID)
+}
+
+/// The mount point of a Kindle in mass storage mode, if one is mounted.
+fn kindle_volume() -> Option<PathBuf> {
+    mount_points().into_iter().find(|root| is_kindle_volume(root))
+}
+
+/// A Kindle's drive has its `documents` and `system` folders at the top.
+fn is_kindle_volume(root: &Path) -> bool {
+    root.join("documents").is_dir() && root.join("system").is_dir()
+}
+
+/// Volumes that may be a Kindle: every mount on Linux, whatever is under
+/// `/Volumes` on macOS, drive letters on Windows.
+fn mount_points() -> Vec<PathBuf> {
+    if cfg!(windows) {
+        return (b'D'..=b'Z').map(|letter| PathBuf::from(format!("{}:\\", letter as char))).collect();
+    }
+    if let Ok(mounts) = fs::read_to_string("/proc/mounts") {
+        // Spaces in mount points are written as \040
+        return mounts
+            .lines()
+            .filter_map(|line| line.split_whitespace().nth(1))
+            .map(|mount| PathBuf::from(mount.replace("\\040", " ")))
+            .filter(|mount| mount != Path::new("/"))
+            .collect();
+    }
+    fs::read_dir("/Volumes")
+        .map(|entries| entries.filter_map(|entry| entry.ok().map(|e| e.path())).collect())
+        .unwrap_or_default()
+}
+
+/// Total and free bytes of the volume, or zeros where that can't be read.
+#[cfg(unix)]
+// statvfs field widths vary: u32 block counts on macOS and 32-bit targets,
+// u64 on 64-bit Linux, where the conversions are no-ops
+#[allow(clippy::useless_conversion)]
+fn volume_space(root: &Path) -> (u64, u64) {
+    use std::os::unix::ffi::OsStrExt;
+    let Ok(path) = std::ffi::CString::new(root.as_os_str().as_bytes()) else {
+        return (0, 0);
+    };
+    // SAFETY: statvfs is plain data, for which all zeroes is valid
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is NUL-terminated and `stat` is ours to fill
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return (0, 0);
+    }
+    let block = u64::from(stat.f_frsize);
+    (u64::from(stat.f_blocks) * block, u64::from(stat.f_bavail) * block)
+}
+
+#[cfg(not(unix))]
+fn volume_space(_root: &Path) -> (u64, u64) {
+    (0, 0)
+}
+
+/// The typed error behind a failed file operation.
+fn io_error(e: io::Error, path: &Path) -> Error {
+    match e.kind() {
+        io::ErrorKind::NotFound => Error::FileNotFound(path.display().to_string()),
+        io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => Error::PermissionDenied,
+        io::ErrorKind::StorageFull => Error::StorageFull(path.display().to_string()),
+        _ => Error::Io(e),
+    }
+}
@@ -0,0 +1,259 @@
+//! The platform's MTP stack under `Kindle`. `Kindle` works in paths and
+//! keeps the path cache, retries, pipelining and timeouts; a backend only
+//! knows object IDs and moves bytes. Which one is built is decided at
+//! compile time: libmtp everywhere but Windows, where Windows Portable
+//! Devices talks to the MTP driver the OS already installed. The `ptp`
+//! feature replaces either with MTP spoken over rusb, linking no libmtp.
+//! Whatever the build, a Kindle mounted as a USB drive is used when no MTP
+//! device is found; see `Device`.
+
+use super::kindle::{
+    DetectOptions, DeviceFilter, FileEntry, KindleInfo, MtpCapabilities, ObjectProperty, RawResponse, StorageInfo,
+};
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
+use mass_storage::MassStorage;
+
+#[cfg(all(not(windows), not(feature = "ptp")))]
+mod libmtp;
+mod mass_storage;
+#[cfg(feature = "ptp")]
+mod ptp;
+#[cfg(all(windows, not(feature = "ptp")))]
+mod wpd;
+
+#[cfg(all(not(windows), not(feature = "ptp")))]
+pub(crate) use libmtp::LibMtp as Backend;
+#[cfg(feature = "ptp")]
+pub(crate) use ptp::Ptp as Backend;
+#[cfg(all(windows, not(feature = "ptp")))]
+pub(crate) use wpd::Wpd as Backend;
+
+#[cfg(all(not(windows), not(feature = "libmtp"), not(feature = "ptp")))]
+compile_error!("no MTP backend: build with the `libmtp` (default) or the `ptp` feature");
+
+/// One open MTP session. Folders are given as `Option<u32>`, `None` being
+/// the root of the storage. Methods that move file data report failures as
+/// `Error::TransferFailed` unless the device said something more specific.
+pub(crate) trait MtpBackend: Sized {
+    /// Opens the device `filter` and `options.serial` pick: the first one
+    /// that matches, since the serial is only readable from a session.
+    fn open(options: &DetectOptions, filter: &DeviceFilter) -> Result<Self>;
+
+    /// Whether a matching device is connected, without opening a session.
+    fn is_present(filter: &DeviceFilter) -> bool;
+
+    fn info(&self) -> KindleInfo;
+
+    /// Every storage, in the order the device reports them.
+    fn storages(&self) -> Vec<StorageInfo>;
+
+    /// The direct children of `parent` on `storage`.
+    fn list_children(&self, storage: u32, parent: Option<u32>) -> Result<Vec<FileEntry>>;
+
+    /// The properties of object `id`, a child of `parent`.
+    fn object_properties(&self, storage: u32, parent: Option<u32>, id: u32) -> Result<Vec<ObjectProperty>>;
+
+    /// The device's thumbnail of an object, usually a JPEG.
+    fn thumbnail(&self, id: u32) -> Result<Vec<u8>>;
+
+    /// Streams the contents of `id` through `on_data`, which returns false
+    /// to stop the transfer.
+    fn get_file(&self, storage: u32, id: u32, on_data: &mut dyn FnMut(&[u8]) -> bool) -> Result<()>;
+
+    /// Up to `len` bytes of `id` from `offset`, fewer at the end of the
+    /// object. Backends without partial reads stream it from the start and
+    /// stop once past the range.
+    fn read_partial(&self, storage: u32, id: u32, offset: u64, len: u32) -> Result<Vec<u8>> {
+        let end = offset + u64::from(len);
+        let (mut position, mut data, mut done) = (0u64, Vec::new(), false);
+        let result = self.get_file(storage, id, &mut |chunk| {
+            let start = position;
+            position += chunk.len() as u64;
+            if position > offset {
+                let from = offset.saturating_sub(start) as usize;
+                let to = (end.min(position) - start) as usize;
+                data.extend_from_slice(&chunk[from..to]);
+            }
+            done = position >= end;
+            !done
+        });
+        match result {
+            Err(Error::Cancelled) if done => Ok(data),
+            result => result.map(|()| data),
+        }
+    }
+
+    /// Creates file `name` in `parent` with `size` bytes that `fill` hands
+    /// out; `fill` returns how much of the buffer it filled, or `None` to
+    /// stop the transfer.
+    fn send_file(
+        &self,
+        storage: u32,
+        parent: Option<u32>,
+        name: &str,
+        size: u64,
+        modified: DateTime<Utc>,
+        fill: &mut dyn FnMut(&mut [u8]) -> Option<usize>,
+    ) -> Result<()>;
+
+    /// Creates a folder and returns its object ID.
+    fn create_folder(&self, storage: u32, parent: Option<u32>, name: &str) -> Result<u32>;
+
+    /// Deletes a file or an empty folder.
+    fn delete(&self, id: u32) -> Result<()>;
+
+    /// Moves an object into another folder.
+    fn move_object(&self, id: u32, storage: u32, parent: Option<u32>) -> Result<()>;
+
+    /// Changes an object's file name.
+    fn rename_object(&self, id: u32, name: &str) -> Result<()>;
+
+    /// The DeviceInfo dataset's lists of codes. libmtp and WPD keep the
+    /// dataset to themselves.
+    fn capabilities(&self) -> Result<MtpCapabilities> {
+        Err(ptp_only("reading the DeviceInfo dataset"))
+    }
+
+    /// One operation with an optional data phase from the device.
+    fn raw_operation(&self, _code: u16, _params: &[u32]) -> Result<RawResponse> {
+        Err(ptp_only("raw MTP operations"))
+    }
+}
+
+/// `Error::Unsupported` for what only the `ptp` backend can do.
+fn ptp_only(what: &str) -> Error {
+    Error::Unsupported(format!(
+        "{} needs the ptp backend (cargo install --no-default-features --features ptp)",
+        what
+    ))
+}
+
+/// The device `Kindle` works on: the MTP backend, or for older Kindles
+/// that only offer USB mass storage, the volume the OS mounted.
+// One per open Kindle, so boxing the larger variant would save nothing
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Device {
+    Mtp(Backend),
+    MassStorage(MassStorage),
+}
+
+impl MtpBackend for Device {
+    /// An MTP device if there is one, else a mounted Kindle.
+    fn open(options: &DetectOptions, filter: &DeviceFilter) -> Result<Self> {
+        match Backend::open(options, filter) {
+            Err(Error::DeviceNotFound) => MassStorage::open(options, filter).map(Self::MassStorage),
+            other => other.map(Self::Mtp),
+        }
+    }
+
+    fn is_present(filter: &DeviceFilter) -> bool {
+        Backend::is_present(filter) || MassStorage::is_present(filter)
+    }
+
+    fn info(&self) -> KindleInfo {
+        match self {
+            Self::Mtp(device) => device.info(),
+            Self::MassStorage(device) => device.info(),
+        }
+    }
+
+    fn storages(&self) -> Vec<StorageInfo> {
+        match self {
+            Self::Mtp(device) => device.storages(),
+            Self::MassStorage(device) => device.storages(),
+        }
+    }
+
+    fn list_children(&self, storage: u32, parent: Option<u32>) -> Result<Vec<FileEntry>> {
+        match self {
+            Self::Mtp(device) => device.list_children(storage, parent),
+            Self::MassStorage(device) => device.list_children(storage, parent),
+        }
+    }
+
+    fn object_properties(&self, storage: u32, parent: Option<u32>, id: u32) -> Result<Vec<ObjectProperty>> {
+        match self {
+            Self::Mtp(device) => device.object_properties(storage, parent, id),
+            Self::MassStorage(device) => device.object_properties(storage, parent, id),
+        }
+    }
+
+    fn thumbnail(&self, id: u32) -> Result<Vec<u8>> {
+        match self {
+            Self::Mtp(device) => device.thumbnail(id),
+            Self::MassStorage(device) => device.thumbnail(id),
+        }
+    }
+
+    fn get_file(&self, storage: u32, id: u32, on_data: &mut dyn FnMut(&[u8]) -> bool) -> Result<()> {
+        match self {
+            Self::Mtp(device) => device.get_file(storage, id, on_data),
+            Self::MassStorage(device) => device.get_file(storage, id, on_data),
+        }
+    }
+
+    fn send_file(
+        &self,
+        storage: u32,
+        parent: Option<u32>,
+        name: &str,
+        size: u64,
+        modified: DateTime<Utc>,
+        fill: &mut dyn FnMut(&mut [u8]) -> Option<usize>,
+    ) -> Result<()> {
+        match self {
+            Self::Mtp(device) => device.send_file(storage, parent, name, size, modified, fill),
+            Self::MassStorage(device) => device.send_file(storage, parent, name, size, modified, fill),
+        }
+    }
+
+    fn create_folder(&self, storage: u32, parent: Option<u32>, name: &str) -> Result<u32> {
+        match self {
+            Self::Mtp(device) => device.create_folder(storage, parent, name),
+            Self::MassStorage(device) => device.create_folder(storage, parent, name),
+        }
+    }
+
+    fn delete(&self, id: u32) -> Result<()> {
+        match self {
+            Self::Mtp(device) => device.delete(id),
+            Self::MassStorage(device) => device.delete(id),
+        }
+    }
+
+    fn move_object(&self, id: u32, storage: u32, parent: Option<u32>) -> Result<()> {
+        match self {
+            Self::Mtp(device) => device.move_object(id, storage, parent),
+            Self::MassStorage(device) => device.move_object(id, storage, parent),
+        }
+    }
+
+    fn rename_object(&self, id: u32, name: &str) -> Result<()> {
+        match self {
+            Self::Mtp(device) => device.rename_object(id, name),
+            Self::MassStorage(device) => device.rename_object(id, name),
+        }
+    }
+
+    fn read_partial(&self, storage: u32, id: u32, offset: u64, len: u32) -> Result<Vec<u8>> {
+        match self {
+            Self::Mtp(device) => device.read_partial(storage, id, offset, len),
+            Self::MassStorage(device) => device.read_partial(storage, id, offset, len),
+        }
+    }
+
+    fn capabilities(&self) -> Result<MtpCapabilities> {
+        match self {
+            Self::Mtp(device) => device.capabilities(),
+            Self::MassStorage(device) => device.capabilities(),
+        }
+    }
+
+    fn raw_operation(&self, code: u16, params: &[u32]) -> Result<RawResponse> {
+        match self {
+            Self::Mtp(device) => device.raw_operation(code, params),
+            Self::MassStorage(device) => device.raw_operation(code, params),
+        }
+    }
+}
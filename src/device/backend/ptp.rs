@@ -0,0 +1,998 @@
+//! PTP/MTP spoken directly over rusb, for builds that shouldn't link the
+//! libmtp C library: static binaries and cross-compiles. rusb's vendored
+//! libusb is built from source, so the build host needs nothing installed.
+//!
+//! Only the operations `Kindle` needs are implemented, one transaction at a
+//! time over the MTP interface's bulk endpoints. Object handles are the
+//! device's own, the same numbers libmtp hands out.
+
+use super::MtpBackend;
+use crate::device::host;
+use crate::device::kindle::{
+    DetectOptions, DeviceFilter, FileEntry, KindleInfo, MtpCapabilities, ObjectProperty, PropertyValue, RawResponse,
+    StorageInfo,
+};
+use crate::error::{Error, Result};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rusb::{Device, DeviceHandle, Direction, GlobalContext, TransferType};
+use std::cell::Cell;
+use std::time::Duration;
+use tracing::{debug, trace};
+
+/// How long one USB transfer may take, libmtp's default.
+const USB_TIMEOUT: Duration = Duration::from_secs(20);
+/// Bytes asked of the device per bulk read, and sent per bulk write. A
+/// multiple of every bulk packet size, so only the last write is short.
+const CHUNK: usize = 512 * 1024;
+/// The session this process opens; any nonzero number will do.
+const SESSION_ID: u32 = 1;
+
+const HEADER_LEN: usize = 12;
+const CONTAINER_COMMAND: u16 = 1;
+const CONTAINER_DATA: u16 = 2;
+const CONTAINER_RESPONSE: u16 = 3;
+
+const OP_GET_DEVICE_INFO: u16 = 0x1001;
+const OP_OPEN_SESSION: u16 = 0x1002;
+const OP_CLOSE_SESSION: u16 = 0x1003;
+const OP_GET_STORAGE_IDS: u16 = 0x1004;
+const OP_GET_STORAGE_INFO: u16 = 0x1005;
+const OP_GET_OBJECT_HANDLES: u16 = 0x1007;
+const OP_GET_OBJECT_INFO: u16 = 0x1008;
+const OP_GET_OBJECT: u16 = 0x1009;
+const OP_GET_THUMB: u16 = 0x100A;
+const OP_DELETE_OBJECT: u16 = 0x100B;
+const OP_SEND_OBJECT_INFO: u16 = 0x100C;
+const OP_SEND_OBJECT: u16 = 0x100D;
+const OP_GET_DEVICE_PROP_VALUE: u16 = 0x1015;
+const OP_MOVE_OBJECT: u16 = 0x1019;
+const OP_GET_PARTIAL_OBJECT: u16 = 0x101B;
+const OP_GET_OBJECT_PROPS_SUPPORTED: u16 = 0x9801;
+const OP_GET_OBJECT_PROP_VALUE: u16 = 0x9803;
+const OP_SET_OBJECT_PROP_VALUE: u16 = 0x9804;
+/// GetPartialObject with a 64-bit offset, an Android extension
+const OP_GET_PARTIAL_OBJECT_64: u16 = 0x95C1;
+
+const RESPONSE_OK: u16 = 0x2001;
+const RESPONSE_OPERATION_NOT_SUPPORTED: u16 = 0x2005;
+const RESPONSE_INVALID_STORAGE_ID: u16 = 0x2008;
+const RESPONSE_INVALID_OBJECT_HANDLE: u16 = 0x2009;
+const RESPONSE_STORE_FULL: u16 = 0x200C;
+const RESPONSE_OBJECT_WRITE_PROTECTED: u16 = 0x200D;
+const RESPONSE_STORE_READ_ONLY: u16 = 0x200E;
+const RESPONSE_ACCESS_DENIED: u16 = 0x200F;
+const RESPONSE_DEVICE_BUSY: u16 = 0x2019;
+const RESPONSE_SESSION_ALREADY_OPEN: u16 = 0x201E;
+const RESPONSE_TRANSACTION_CANCELLED: u16 = 0x201F;
+
+const FORMAT_UNDEFINED: u16 = 0x3000;
+const FORMAT_ASSOCIATION: u16 = 0x3001;
+const ASSOCIATION_GENERIC_FOLDER: u16 = 0x0001;
+const DEVICE_PROP_FRIENDLY_NAME: u32 = 0xD402;
+const PROP_OBJECT_SIZE: u16 = 0xDC04;
+const PROP_OBJECT_FILE_NAME: u16 = 0xDC07;
+
+/// GetObjectHandles and SendObjectInfo take this parent for the root.
+const PARENT_ROOT: u32 = 0xFFFF_FFFF;
+/// A 32-bit size field that means "too big, ask ObjectSize".
+const SIZE_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// PTP still-image class requests on the interface, for cancelling.
+const REQUEST_CANCEL: u8 = 0x64;
+const REQUEST_GET_DEVICE_STATUS: u8 = 0x67;
+const CANCEL_EVENT: u16 = 0x4001;
+/// How often a cancel waits for the device to report itself idle.
+const CANCEL_STATUS_POLLS: u32 = 40;
+
+/// How a property's value is encoded: MTP datatypes the table below uses.
+#[derive(Clone, Copy)]
+enum PropertyType {
+    Text,
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+/// The properties `object_properties` asks for, named as the libmtp
+/// backend names them. 128-bit ones (the persistent unique ID) are left
+/// out there too.
+const OBJECT_PROPERTIES: &[(u16, &str, PropertyType)] = &[
+    (0xDC01, "StorageId", PropertyType::U32),
+    (0xDC0B, "ParentObject", PropertyType::U32),
+    (0xDC02, "ObjectFormat", PropertyType::U16),
+    (0xDC07, "ObjectFileName", PropertyType::Text),
+    (0xDC04, "ObjectSize", PropertyType::U64),
+    (0xDC03, "ProtectionStatus", PropertyType::U16),
+    (0xDC05, "AssociationType", PropertyType::U16),
+    (0xDC06, "AssociationDesc", PropertyType::U32),
+    (0xDC0D, "Hidden", PropertyType::U16),
+    (0xDC0E, "SystemObject", PropertyType::U16),
+    (0xDC4F, "NonConsumable", PropertyType::U8),
+    (0xDC44, "Name", PropertyType::Text),
+    (0xDCE0, "DisplayName", PropertyType::Text),
+    (0xDC08, "DateCreated", PropertyType::Text),
+    (0xDC09, "DateModified", PropertyType::Text),
+    (0xDC4E, "DateAdded", PropertyType::Text),
+    (0xDC47, "DateAuthored", PropertyType::Text),
+    (0xDC93, "LastAccessed", PropertyType::Text),
+    (0xDC0A, "Keywords", PropertyType::Text),
+    (0xDC42, "SyncId", PropertyType::Text),
+    (0xDC45, "CreatedBy", PropertyType::Text),
+    (0xDC46, "Artist", PropertyType::Text),
+    (0xDC48, "Description", PropertyType::Text),
+    (0xDC4A, "LanguageLocale", PropertyType::Text),
+    (0xDC4B, "CopyrightInformation", PropertyType::Text),
+    (0xDC4C, "Source", PropertyType::Text),
+    (0xDC8C, "Genre", PropertyType::Text),
+    (0xDC9D, "DrmStatus", PropertyType::U16),
+    (0xDC91, "UseCount", PropertyType::U32),
+];
+
+/// The parts of the DeviceInfo dataset kept, plus the friendly name.
+#[derive(Default)]
+struct DeviceInfo {
+    manufacturer: String,
+    model: String,
+    serial: String,
+    friendly_name: Option<String>,
+    capabilities: MtpCapabilities,
+}
+
+/// The parts of an ObjectInfo dataset kept.
+struct ObjectInfo {
+    format: u16,
+    size: u32,
+    name: String,
+    modified: Option<DateTime<Utc>>,
+}
+
+/// The MTP interface of a USB device and its bulk endpoints.
+struct MtpInterface {
+    number: u8,
+    bulk_in: u8,
+    bulk_out: u8,
+    /// Largest packet of the bulk-out endpoint
+    packet_size: usize,
+}
+
+pub(crate) struct Ptp {
+    handle: DeviceHandle<GlobalContext>,
+    interface: MtpInterface,
+    /// USB vendor and product ID, from the device descriptor
+    usb_id: (u16, u16),
+    info: DeviceInfo,
+    /// ID of the next transaction; 0 before the session is open
+    transaction: Cell<u32>,
+}
+
+impl Ptp {
+    fn open_device(device: &Device<GlobalContext>) -> Result<Self> {
+        let descriptor = device.device_descriptor().map_err(usb_error)?;
+        let interface = mtp_interface(device)?;
+        let handle = device.open().map_err(usb_error)?;
+        // Only Linux can detach a kernel driver; elsewhere this fails harmlessly
+        let _ = handle.set_auto_detach_kernel_driver(true);
+        handle.claim_interface(interface.number).map_err(|e| match e {
+            rusb::Error::Busy => host::busy_error(Error::DeviceBusy(
+                "another program has claimed the MTP interface".to_string(),
+            )),
+            other => usb_error(other),
+        })?;
+
+        let mut ptp = Self {
+            handle,
+            interface,
+            usb_id: (descriptor.vendor_id(), descriptor.product_id()),
+            info: DeviceInfo::default(),
+            transaction: Cell::new(0),
+        };
+        // DeviceInfo is the one dataset readable outside a session
+        ptp.info = parse_device_info(&ptp.read_data(OP_GET_DEVICE_INFO, &[])?)?;
+        ptp.transaction.set(0);
+        let transaction = ptp.send_command(OP_OPEN_SESSION, &[SESSION_ID])?;
+        match ptp.read_response_code(transaction)? {
+            // Left open by a process that died; it is ours now
+            (RESPONSE_SESSION_ALREADY_OPEN, _) => debug!("PTP session was already open"),
+            (code, params) => {
+                checked(code, params)?;
+            }
+        }
+        ptp.info.friendly_name = ptp
+            .read_data(OP_GET_DEVICE_PROP_VALUE, &[DEVICE_PROP_FRIENDLY_NAME])
+            .and_then(|data| Dataset::new(&data).string())
+            .ok();
+        debug!(model = %ptp.info.model, interface = ptp.interface.number, "opened PTP session");
+        Ok(ptp)
+    }
+
+    fn next_transaction(&self) -> u32 {
+        let id = self.transaction.get();
+        self.transaction.set(id.wrapping_add(1));
+        id
+    }
+
+    fn write(&self, data: &[u8]) -> Result<()> {
+        let sent = self
+            .handle
+            .write_bulk(self.interface.bulk_out, data, USB_TIMEOUT)
+            .map_err(usb_error)?;
+        if sent != data.len() {
+            return Err(Error::Mtp(format!("UsbLayer: short write ({} of {} bytes)", sent, data.len())));
+        }
+        Ok(())
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> Result<usize> {
+        self.handle
+            .read_bulk(self.interface.bulk_in, buffer, USB_TIMEOUT)
+            .map_err(usb_error)
+    }
+
+    /// Sends the command phase of operation `code` and returns its
+    /// transaction ID.
+    fn send_command(&self, code: u16, params: &[u32]) -> Result<u32> {
+        let transaction = self.next_transaction();
+        let mut command = header(HEADER_LEN + 4 * params.len(), CONTAINER_COMMAND, code, transaction).to_vec();
+        for param in params {
+            command.extend_from_slice(&param.to_le_bytes());
+        }
+        trace!(code = format_args!("{:#06x}", code), ?params, transaction, "PTP command");
+        self.write(&command)?;
+        Ok(transaction)
+    }
+
+    /// An operation without a data phase; returns the response parameters.
+    fn transaction(&self, code: u16, params: &[u32]) -> Result<Vec<u32>> {
+        let transaction = self.send_command(code, params)?;
+        self.read_response(transaction)
+    }
+
+    /// An operation whose data phase is read whole into memory.
+    fn read_data(&self, code: u16, params: &[u32]) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.transaction_in(code, params, &mut |chunk| {
+            data.extend_from_slice(chunk);
+            true
+        })?;
+        Ok(data)
+    }
+
+    /// An operation with data from the device, handed to `on_data` as it
+    /// arrives; `on_data` returning false cancels it with `Error::Cancelled`.
+    fn transaction_in(&self, code: u16, params: &[u32], on_data: &mut dyn FnMut(&[u8]) -> bool) -> Result<Vec<u32>> {
+        let (code, params) = self.transaction_in_code(code, params, on_data)?;
+        checked(code, params)
+    }
+
+    /// Like `transaction_in`, returning the response code whether it is OK
+    /// or not. Also takes operations the device answers without data.
+    fn transaction_in_code(
+        &self,
+        code: u16,
+        params: &[u32],
+        on_data: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> Result<(u16, Vec<u32>)> {
+        let transaction = self.send_command(code, params)?;
+        let mut buffer = vec![0u8; CHUNK];
+        let n = self.read(&mut buffer)?;
+        let first = parse_header(&buffer[..n])?;
+        if first.kind == CONTAINER_RESPONSE {
+            // No data phase, or the device refused before sending any
+            return Ok(response_params(&first, &buffer[..n]));
+        }
+        if first.kind != CONTAINER_DATA {
+            return Err(Error::Mtp(format!("PtpLayer: expected data, got container type {}", first.kind)));
+        }
+
+        // A length of 0xFFFFFFFF means more than 4 GiB: read until a short
+        // packet ends the data phase
+        let total = (first.length != u32::MAX).then(|| u64::from(first.length) - HEADER_LEN as u64);
+        let mut received = (n - HEADER_LEN) as u64;
+        let mut last = n;
+        if !on_data(&buffer[HEADER_LEN..n]) {
+            self.cancel(transaction);
+            return Err(Error::Cancelled);
+        }
+        while match total {
+            Some(total) => received < total,
+            None => last == buffer.len(),
+        } {
+            last = self.read(&mut buffer)?;
+            if last == 0 {
+                break;
+            }
+            received += last as u64;
+            if !on_data(&buffer[..last]) {
+                self.cancel(transaction);
+                return Err(Error::Cancelled);
+            }
+        }
+        self.read_response_code(transaction)
+    }
+
+    /// An operation sending `size` bytes that `fill` hands out; `fill`
+    /// returning `None` cancels it with `Error::Cancelled`.
+    fn transaction_out(
+        &self,
+        code: u16,
+        params: &[u32],
+        size: u64,
+        fill: &mut dyn FnMut(&mut [u8]) -> Option<usize>,
+    ) -> Result<Vec<u32>> {
+        let transaction = self.send_command(code, params)?;
+        let length = usize::try_from(size)
+            .ok()
+            .and_then(|size| size.checked_add(HEADER_LEN))
+            .filter(|&length| length < u32::MAX as usize)
+            .unwrap_or(u32::MAX as usize);
+        let mut buffer = vec![0u8; CHUNK];
+        buffer[..HEADER_LEN].copy_from_slice(&header(length, CONTAINER_DATA, code, transaction));
+        let (mut used, mut sent) = (HEADER_LEN, 0u64);
+        loop {
+            // Every write but the last fills the buffer: a short packet
+            // would end the data phase early
+            while used < buffer.len() && sent < size {
+                let want = (buffer.len() - used).min(usize::try_from(size - sent).unwrap_or(usize::MAX));
+                match fill(&mut buffer[used..used + want]) {
+                    None => {
+                        self.cancel(transaction);
+                        return Err(Error::Cancelled);
+                    }
+                    Some(0) => {
+                        self.cancel(transaction);
+                        return Err(Error::TransferFailed(format!(
+                            "source ended after {} of {} bytes",
+                            sent, size
+                        )));
+                    }
+                    Some(n) => {
+                        used += n;
+                        sent += n as u64;
+                    }
+                }
+            }
+            self.write(&buffer[..used])?;
+            if sent == size {
+                break;
+            }
+            used = 0;
+        }
+        // A container ending on a packet boundary needs a zero-length packet
+        if (size + HEADER_LEN as u64).is_multiple_of(self.interface.packet_size as u64) {
+            self.write(&[])?;
+        }
+        self.read_response(transaction)
+    }
+
+    fn read_response(&self, transaction: u32) -> Result<Vec<u32>> {
+        let (code, params) = self.read_response_code(transaction)?;
+        checked(code, params)
+    }
+
+    /// The response code and parameters, whether the code is OK or not.
+    fn read_response_code(&self, transaction: u32) -> Result<(u16, Vec<u32>)> {
+        let mut buffer = [0u8; 512];
+        // The zero-length packet after a data phase may still be queued
+        for _ in 0..2 {
+            let n = self.read(&mut buffer)?;
+            if n == 0 {
+                continue;
+            }
+            let response = parse_header(&buffer[..n])?;
+            if response.kind != CONTAINER_RESPONSE {
+                return Err(Error::Mtp(format!(
+                    "PtpLayer: expected a response, got container type {}",
+                    response.kind
+                )));
+            }
+            if response.transaction != transaction {
+                debug!(expected = transaction, got = response.transaction, "response to another transaction");
+            }
+            return Ok(response_params(&response, &buffer[..n]));
+        }
+        Err(Error::Mtp("PtpLayer: no response from the device".to_string()))
+    }
+
+    /// Aborts `transaction` with the still-image class Cancel request, drains
+    /// what the device had queued and waits for it to be idle again.
+    fn cancel(&self, transaction: u32) {
+        debug!(transaction, "cancelling PTP transaction");
+        let mut request = [0u8; 6];
+        request[..2].copy_from_slice(&CANCEL_EVENT.to_le_bytes());
+        request[2..].copy_from_slice(&transaction.to_le_bytes());
+        let index = u16::from(self.interface.number);
+        let _ = self
+            .handle
+            .write_control(0x21, REQUEST_CANCEL, 0, index, &request, USB_TIMEOUT);
+
+        let mut buffer = vec![0u8; CHUNK];
+        while matches!(
+            self.handle
+                .read_bulk(self.interface.bulk_in, &mut buffer, Duration::from_millis(200)),
+            Ok(n) if n > 0
+        ) {}
+        for _ in 0..CANCEL_STATUS_POLLS {
+            let mut status = [0u8; 32];
+            match self
+                .handle
+                .read_control(0xA1, REQUEST_GET_DEVICE_STATUS, 0, index, &mut status, USB_TIMEOUT)
+            {
+                Ok(n) if n >= 4 && u16::from_le_bytes([status[2], status[3]]) != RESPONSE_DEVICE_BUSY => break,
+                _ => std::thread::sleep(Duration::from_millis(50)),
+            }
+        }
+    }
+
+    fn object_info(&self, id: u32) -> Result<ObjectInfo> {
+        parse_object_info(&self.read_data(OP_GET_OBJECT_INFO, &[id])?)
+    }
+
+    /// The ObjectInfo dataset for a new object, sent with SendObjectInfo.
+    /// Returns the new object's handle.
+    fn send_object_info(
+        &self,
+        storage: u32,
+        parent: Option<u32>,
+        name: &str,
+        format: u16,
+        size: u64,
+        modified: Option<DateTime<Utc>>,
+    ) -> Result<u32> {
+        let mut dataset = Vec::new();
+        push_u32(&mut dataset, storage);
+        push_u16(&mut dataset, format);
+        push_u16(&mut dataset, 0); // protection status
+        push_u32(&mut dataset, u32::try_from(size).unwrap_or(SIZE_UNKNOWN));
+        push_u16(&mut dataset, 0); // thumbnail format
+        for _ in 0..6 {
+            // thumbnail size, thumbnail and image dimensions, bit depth
+            push_u32(&mut dataset, 0);
+        }
+        push_u32(&mut dataset, parent.unwrap_or(0));
+        push_u16(
+            &mut dataset,
+            if format == FORMAT_ASSOCIATION { ASSOCIATION_GENERIC_FOLDER } else { 0 },
+        );
+        push_u32(&mut dataset, 0); // association description
+        push_u32(&mut dataset, 0); // sequence number
+        push_string(&mut dataset, name)?;
+        push_string(&mut dataset, "")?; // date created
+        let modified = modified.map(|date| date.format("%Y%m%dT%H%M%S").to_string()).unwrap_or_default();
+        push_string(&mut dataset, &modified)?;
+        push_string(&mut dataset, "")?; // keywords
+
+        let mut remaining = dataset.as_slice();
+        let response = self.transaction_out(
+            OP_SEND_OBJECT_INFO,
+            &[storage, parent.unwrap_or(PARENT_ROOT)],
+            dataset.len() as u64,
+            &mut |buf| {
+                let n = buf.len().min(remaining.len());
+                buf[..n].copy_from_slice(&remaining[..n]);
+                remaining = &remaining[n..];
+                Some(n)
+            },
+        )?;
+        // Storage, parent and the new handle
+        response
+            .get(2)
+            .copied()
+            .ok_or_else(|| Error::Mtp("PtpLayer: SendObjectInfo returned no handle".to_string()))
+    }
+}
+
+impl Drop for Ptp {
+    fn drop(&mut self) {
+        let _ = self.transaction(OP_CLOSE_SESSION, &[]);
+        let _ = self.handle.release_interface(self.interface.number);
+    }
+}
+
+impl MtpBackend for Ptp {
+    fn open(options: &DetectOptions, filter: &DeviceFilter) -> Result<Self> {
+        let devices = rusb::devices().map_err(usb_error)?;
+        let candidates: Vec<Device<GlobalContext>> = devices
+            .iter()
+            .filter(|device| {
+                device
+                    .device_descriptor()
+                    .is_ok_and(|d| filter.matches(d.vendor_id(), d.product_id()))
+            })
+            .collect();
+        debug!(count = candidates.len(), "found USB devices");
+        if candidates.is_empty() {
+            return Err(Error::DeviceNotFound);
+        }
+
+        let mut last_error = Error::DeviceNotFound;
+        for device in candidates {
+            let ptp = match Self::open_device(&device) {
+                Ok(ptp) => ptp,
+                Err(e) => {
+                    debug!(bus = device.bus_number(), address = device.address(), error = %e, "could not open USB device");
+                    last_error = e;
+                    continue;
+                }
+            };
+            match &options.serial {
+                None => return Ok(ptp),
+                Some(serial) => {
+                    debug!(serial = %ptp.info.serial, wanted = %serial, "checking Kindle serial");
+                    if ptp.info.serial == *serial {
+                        return Ok(ptp);
+                    }
+                    last_error = Error::DeviceNotFound;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    fn is_present(filter: &DeviceFilter) -> bool {
+        rusb::devices()
+            .map(|devices| {
+                devices.iter().any(|device| {
+                    device
+                        .device_descriptor()
+                        .is_ok_and(|d| filter.matches(d.vendor_id(), d.product_id()))
+                })
+            })
+            .unwrap_or(false)
+    }
+
+    fn info(&self) -> KindleInfo {
+        let or = |text: &str, fallback: &str| {
+            if text.is_empty() { fallback.to_string() } else { text.to_string() }
+        };
+        KindleInfo {
+            manufacturer: or(&self.info.manufacturer, "Unknown"),
+            model: or(&self.info.model, "Unknown"),
+            serial: self.info.serial.clone(),
+            friendly_name: self.info.friendly_name.clone().unwrap_or_else(|| "Kindle".to_string()),
+            vendor_id: self.usb_id.0,
+            product_id: self.usb_id.1,
+        }
+    }
+
+    /// GetStorageIDs, then GetStorageInfo for each: read fresh every time,
+    /// unlike libmtp's list from when the device was opened.
+    fn storages(&self) -> Vec<StorageInfo> {
+        let read = || -> Result<Vec<StorageInfo>> {
+            let ids = Dataset::new(&self.read_data(OP_GET_STORAGE_IDS, &[])?).u32_array()?;
+            ids.into_iter()
+                .map(|id| parse_storage_info(id, &self.read_data(OP_GET_STORAGE_INFO, &[id])?))
+                .collect()
+        };
+        read().unwrap_or_else(|e| {
+            debug!(error = %e, "could not list storages");
+            Vec::new()
+        })
+    }
+
+    /// One GetObjectHandles filtered by parent, then one GetObjectInfo per
+    /// child: what libmtp does for the same listing (see ADR-002).
+    fn list_children(&self, storage: u32, parent: Option<u32>) -> Result<Vec<FileEntry>> {
+        let handles = self.read_data(OP_GET_OBJECT_HANDLES, &[storage, 0, parent.unwrap_or(PARENT_ROOT)])?;
+        let mut entries = Vec::new();
+        for id in Dataset::new(&handles).u32_array()? {
+            let info = self.object_info(id)?;
+            let size = if info.size == SIZE_UNKNOWN {
+                let data = self.read_data(OP_GET_OBJECT_PROP_VALUE, &[id, u32::from(PROP_OBJECT_SIZE)])?;
+                Dataset::new(&data).u64()?
+            } else {
+                u64::from(info.size)
+            };
+            entries.push(FileEntry {
+                name: info.name,
+                size,
+                is_folder: info.format == FORMAT_ASSOCIATION,
+                id,
+                modified: info.modified.unwrap_or(DateTime::UNIX_EPOCH),
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Every property in `OBJECT_PROPERTIES` the device supports for the
+    /// object's format, one GetObjectPropValue each.
+    fn object_properties(&self, _storage: u32, _parent: Option<u32>, id: u32) -> Result<Vec<ObjectProperty>> {
+        let format = self.object_info(id)?.format;
+        debug!(id, format = format_args!("{:#06x}", format), "reading object properties");
+        let supported = Dataset::new(&self.read_data(OP_GET_OBJECT_PROPS_SUPPORTED, &[u32::from(format)])?).u16_array()?;
+
+        let mut properties = Vec::new();
+        for &(code, name, kind) in OBJECT_PROPERTIES {
+            if !supported.contains(&code) {
+                continue;
+            }
+            let value = self
+                .read_data(OP_GET_OBJECT_PROP_VALUE, &[id, u32::from(code)])
+                .and_then(|data| {
+                    let mut dataset = Dataset::new(&data);
+                    Ok(match kind {
+                        PropertyType::Text => PropertyValue::Text(dataset.string()?),
+                        PropertyType::U8 => PropertyValue::Integer(dataset.u8()?.into()),
+                        PropertyType::U16 => PropertyValue::Integer(dataset.u16()?.into()),
+                        PropertyType::U32 => PropertyValue::Integer(dataset.u32()?.into()),
+                        PropertyType::U64 => PropertyValue::Integer(dataset.u64()?),
+                    })
+                });
+            match value {
+                Ok(value) => properties.push(ObjectProperty {
+                    name: name.to_string(),
+                    value,
+                }),
+                Err(e) => trace!(property = name, error = %e, "property not readable"),
+            }
+        }
+        Ok(properties)
+    }
+
+    fn thumbnail(&self, id: u32) -> Result<Vec<u8>> {
+        match self.read_data(OP_GET_THUMB, &[id]) {
+            Ok(data) if !data.is_empty() => Ok(data),
+            _ => Err(Error::FileNotFound("device has no thumbnail for this object".to_string())),
+        }
+    }
+
+    fn get_file(&self, _storage: u32, id: u32, on_data: &mut dyn FnMut(&[u8]) -> bool) -> Result<()> {
+        self.transaction_in(OP_GET_OBJECT, &[id], on_data)
+            .map(|_| ())
+            .map_err(transfer_error)
+    }
+
+    /// GetPartialObject; offsets past 4 GiB need the 64-bit variant, which
+    /// only some devices have.
+    fn read_partial(&self, _storage: u32, id: u32, offset: u64, len: u32) -> Result<Vec<u8>> {
+        let read = match u32::try_from(offset) {
+            Ok(offset) => self.read_data(OP_GET_PARTIAL_OBJECT, &[id, offset, len]),
+            Err(_) => self.read_data(OP_GET_PARTIAL_OBJECT_64, &[id, offset as u32, (offset >> 32) as u32, len]),
+        };
+        read.map_err(transfer_error)
+    }
+
+    fn send_file(
+        &self,
+        storage: u32,
+        parent: Option<u32>,
+        name: &str,
+        size: u64,
+        modified: DateTime<Utc>,
+        fill: &mut dyn FnMut(&mut [u8]) -> Option<usize>,
+    ) -> Result<()> {
+        let id = self
+            .send_object_info(storage, parent, name, FORMAT_UNDEFINED, size, Some(modified))
+            .map_err(transfer_error)?;
+        let sent = self.transaction_out(OP_SEND_OBJECT, &[], size, fill);
+        if sent.is_err() {
+            // The object exists from SendObjectInfo on; don't leave an
+            // empty one behind
+            let _ = self.transaction(OP_DELETE_OBJECT, &[id, 0]);
+        }
+        sent.map(|_| ()).map_err(transfer_error)
+    }
+
+    fn create_folder(&self, storage: u32, parent: Option<u32>, name: &str) -> Result<u32> {
+        self.send_object_info(storage, parent, name, FORMAT_ASSOCIATION, 0, None)
+    }
+
+    fn delete(&self, id: u32) -> Result<()> {
+        self.transaction(OP_DELETE_OBJECT, &[id, 0]).map(|_| ())
+    }
+
+    /// MoveObject takes 0 for the root, unlike GetObjectHandles.
+    fn move_object(&self, id: u32, storage: u32, parent: Option<u32>) -> Result<()> {
+        self.transaction(OP_MOVE_OBJECT, &[id, storage, parent.unwrap_or(0)])
+            .map(|_| ())
+    }
+
+    fn capabilities(&self) -> Result<MtpCapabilities> {
+        Ok(self.info.capabilities.clone())
+    }
+
+    fn raw_operation(&self, code: u16, params: &[u32]) -> Result<RawResponse> {
+        if params.len() > 5 {
+            return Err(Error::Unsupported("an MTP operation takes at most 5 parameters".to_string()));
+        }
+        let mut data = Vec::new();
+        let (code, params) = self.transaction_in_code(code, params, &mut |chunk| {
+            data.extend_from_slice(chunk);
+            true
+        })?;
+        Ok(RawResponse { code, params, data })
+    }
+
+    fn rename_object(&self, id: u32, name: &str) -> Result<()> {
+        let mut value = Vec::new();
+        push_string(&mut value, name)?;
+        let mut remaining = value.as_slice();
+        self.transaction_out(
+            OP_SET_OBJECT_PROP_VALUE,
+            &[id, u32::from(PROP_OBJECT_FILE_NAME)],
+            value.len() as u64,
+            &mut |buf| {
+                let n = buf.len().min(remaining.len());
+                buf[..n].copy_from_slice(&remaining[..n]);
+                remaining = &remaining[n..];
+                Some(n)
+            },
+        )
+        .map(|_| ())
+    }
+}
+
+/// The interface MTP is spoken on: a still-image class one, or a vendor
+/// specific one with the same bulk-in, bulk-out and interrupt endpoints
+/// (what Android and most e-readers expose).
+fn mtp_interface(device: &Device<GlobalContext>) -> Result<MtpInterface> {
+    let config = device.active_config_descriptor().map_err(usb_error)?;
+    let mut vendor_specific = None;
+    for interface in config.interfaces() {
+        for setting in interface.descriptors() {
+            let (mut bulk_in, mut bulk_out, mut interrupt) = (None, None, false);
+            for endpoint in setting.endpoint_descriptors() {
+                match (endpoint.transfer_type(), endpoint.direction()) {
+                    (TransferType::Bulk, Direction::In) => bulk_in = Some(endpoint.address()),
+                    (TransferType::Bulk, Direction::Out) => {
+                        bulk_out = Some((endpoint.address(), usize::from(endpoint.max_packet_size())))
+                    }
+                    (TransferType::Interrupt, Direction::In) => interrupt = true,
+                    _ => {}
+                }
+            }
+            let (Some(bulk_in), Some((bulk_out, packet_size)), true) = (bulk_in, bulk_out, interrupt) else {
+                continue;
+            };
+            let found = MtpInterface {
+                number: setting.interface_number(),
+                bulk_in,
+                bulk_out,
+                packet_size: packet_size.max(1),
+            };
+            match setting.class_code() {
+                0x06 => return Ok(found),
+                0xFF if vendor_specific.is_none() => vendor_specific = Some(found),
+                _ => {}
+            }
+        }
+    }
+    vendor_specific.ok_or_else(|| Error::Unsupported("device has no MTP interface".to_string()))
+}
+
+/// The 12-byte header every PTP container starts with.
+struct Header {
+    length: u32,
+    kind: u16,
+    code: u16,
+    transaction: u32,
+}
+
+fn header(length: usize, kind: u16, code: u16, transaction: u32) -> [u8; HEADER_LEN] {
+    let mut bytes = [0u8; HEADER_LEN];
+    bytes[..4].copy_from_slice(&u32::try_from(length).unwrap_or(u32::MAX).to_le_bytes());
+    bytes[4..6].copy_from_slice(&kind.to_le_bytes());
+    bytes[6..8].copy_from_slice(&code.to_le_bytes());
+    bytes[8..].copy_from_slice(&transaction.to_le_bytes());
+    bytes
+}
+
+fn parse_header(bytes: &[u8]) -> Result<Header> {
+    let mut dataset = Dataset::new(bytes);
+    Ok(Header {
+        length: dataset.u32()?,
+        kind: dataset.u16()?,
+        code: dataset.u16()?,
+        transaction: dataset.u32()?,
+    })
+}
+
+/// The code and parameters of a response container.
+fn response_params(response: &Header, bytes: &[u8]) -> (u16, Vec<u32>) {
+    let end = (response.length as usize).min(bytes.len());
+    let params = bytes[HEADER_LEN.min(end)..end]
+        .chunks_exact(4)
+        .map(|param| u32::from_le_bytes([param[0], param[1], param[2], param[3]]))
+        .collect();
+    (response.code, params)
+}
+
+/// The parameters of an OK response, or the error its code means.
+fn checked(code: u16, params: Vec<u32>) -> Result<Vec<u32>> {
+    if code != RESPONSE_OK {
+        return Err(response_error(code));
+    }
+    Ok(params)
+}
+
+/// The typed error a PTP response code stands for, falling back to
+/// `Error::Mtp` with the code.
+fn response_error(code: u16) -> Error {
+    let text = format!("PTP response {:#06x}", code);
+    match code {
+        RESPONSE_STORE_FULL => Error::StorageFull(text),
+        RESPONSE_STORE_READ_ONLY | RESPONSE_ACCESS_DENIED => Error::PermissionDenied,
+        RESPONSE_OBJECT_WRITE_PROTECTED => Error::ObjectProtected(text),
+        RESPONSE_DEVICE_BUSY => Error::DeviceBusy(text),
+        RESPONSE_INVALID_OBJECT_HANDLE | RESPONSE_INVALID_STORAGE_ID => Error::FileNotFound(text),
+        RESPONSE_TRANSACTION_CANCELLED => Error::Cancelled,
+        RESPONSE_OPERATION_NOT_SUPPORTED => Error::Unsupported(text),
+        _ => Error::Mtp(format!("PtpLayer: {}", text)),
+    }
+}
+
+/// The error a libusb failure stands for. The `UsbLayer` prefix is what
+/// libmtp reports, so `Error::is_transient` treats both alike.
+fn usb_error(e: rusb::Error) -> Error {
+    match e {
+        rusb::Error::NoDevice | rusb::Error::NotFound => Error::DeviceNotFound,
+        rusb::Error::Access => Error::PermissionDenied,
+        rusb::Error::Busy => Error::DeviceBusy(e.to_string()),
+        other => Error::Mtp(format!("UsbLayer: {}", other)),
+    }
+}
+
+/// Like the errors above, for a failed file transfer.
+fn transfer_error(e: Error) -> Error {
+    match e {
+        Error::Mtp(message) => Error::TransferFailed(message),
+        other => other,
+    }
+}
+
+/// Reads the little-endian fields of a PTP dataset in order.
+struct Dataset<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Dataset<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(Error::Mtp("PtpLayer: dataset ended early".to_string()));
+        }
+        let (field, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(field)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from(self.u32()?) | u64::from(self.u32()?) << 32)
+    }
+
+    /// A count of UTF-16 units, the terminating NUL included, then the units.
+    fn string(&mut self) -> Result<String> {
+        let count = usize::from(self.u8()?);
+        let units: Vec<u16> = (0..count).map(|_| self.u16()).collect::<Result<_>>()?;
+        let text = units.strip_suffix(&[0]).unwrap_or(&units);
+        Ok(String::from_utf16_lossy(text))
+    }
+
+    fn u16_array(&mut self) -> Result<Vec<u16>> {
+        let count = self.u32()?;
+        (0..count).map(|_| self.u16()).collect()
+    }
+
+    fn u32_array(&mut self) -> Result<Vec<u32>> {
+        let count = self.u32()?;
+        (0..count).map(|_| self.u32()).collect()
+    }
+}
+
+fn push_u16(data: &mut Vec<u8>, value: u16) {
+    data.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(data: &mut Vec<u8>, value: u32) {
+    data.extend_from_slice(&value.to_le_bytes());
+}
+
+/// A PTP string: at most 254 UTF-16 units plus the NUL.
+fn push_string(data: &mut Vec<u8>, text: &str) -> Result<()> {
+    if text.is_empty() {
+        data.push(0);
+        return Ok(());
+    }
+    let units: Vec<u16> = text.encode_utf16().chain(std::iter::once(0)).collect();
+    let count = u8::try_from(units.len())
+        .map_err(|_| Error::InvalidPath(format!("'{}' is too long for an MTP name", text)))?;
+    data.push(count);
+    for unit in units {
+        push_u16(data, unit);
+    }
+    Ok(())
+}
+
+fn parse_device_info(data: &[u8]) -> Result<DeviceInfo> {
+    let mut dataset = Dataset::new(data);
+    dataset.u16()?; // standard version
+    dataset.u32()?; // vendor extension ID
+    dataset.u16()?; // vendor extension version
+    let vendor_extension = dataset.string()?;
+    dataset.u16()?; // functional mode
+    let capabilities = MtpCapabilities {
+        vendor_extension,
+        operations: dataset.u16_array()?,
+        events: dataset.u16_array()?,
+        device_properties: dataset.u16_array()?,
+        capture_formats: dataset.u16_array()?,
+        playback_formats: dataset.u16_array()?,
+    };
+    let manufacturer = dataset.string()?;
+    let model = dataset.string()?;
+    dataset.string()?; // device version
+    Ok(DeviceInfo {
+        manufacturer,
+        model,
+        serial: dataset.string()?,
+        friendly_name: None,
+        capabilities,
+    })
+}
+
+fn parse_storage_info(id: u32, data: &[u8]) -> Result<StorageInfo> {
+    let mut dataset = Dataset::new(data);
+    dataset.u16()?; // storage type
+    dataset.u16()?; // filesystem type
+    dataset.u16()?; // access capability
+    let total_bytes = dataset.u64()?;
+    let free_bytes = dataset.u64()?;
+    dataset.u32()?; // free space in objects
+    let description = dataset.string()?;
+    Ok(StorageInfo {
+        id,
+        description: if description.is_empty() { "Internal Storage".to_string() } else { description },
+        total_bytes,
+        free_bytes,
+    })
+}
+
+fn parse_object_info(data: &[u8]) -> Result<ObjectInfo> {
+    let mut dataset = Dataset::new(data);
+    dataset.u32()?; // storage
+    let format = dataset.u16()?;
+    dataset.u16()?; // protection status
+    let size = dataset.u32()?;
+    dataset.u16()?; // thumbnail format
+    for _ in 0..6 {
+        // thumbnail size, thumbnail and image dimensions, bit depth
+        dataset.u32()?;
+    }
+    dataset.u32()?; // parent
+    dataset.u16()?; // association type
+    dataset.u32()?; // association description
+    dataset.u32()?; // sequence number
+    let name = dataset.string()?;
+    dataset.string()?; // date created
+    let modified = parse_date(&dataset.string()?);
+    Ok(ObjectInfo {
+        format,
+        size,
+        name,
+        modified,
+    })
+}
+
+/// An MTP date, `YYYYMMDDThhmmss` with optional tenths and zone, taken as
+/// UTC the way libmtp does.
+fn parse_date(text: &str) -> Option<DateTime<Utc>> {
+    let date = NaiveDateTime::parse_from_str(text.get(..15)?, "%Y%m%dT%H%M%S").ok()?;
+    Some(date.and_utc())
+}
@@ -0,0 +1,903 @@
+//! The Windows Portable Devices backend. Windows binds its own MTP driver
+//! to the Kindle, so going through WPD needs no libusb driver swap (Zadig)
+//! the way libmtp does. The few WPD interfaces used are declared here; the
+//! constants come from `windows-sys`.
+//!
+//! WPD names objects with strings. The MTP driver's are the object handle
+//! in hex behind an `o` (storages: `s`), which gives back the same `u32`
+//! handles libmtp uses; anything else gets a number for the session.
+
+#![allow(non_snake_case)]
+
+use super::MtpBackend;
+use crate::device::kindle::{
+    DetectOptions, DeviceFilter, FileEntry, KindleInfo, ObjectProperty, PropertyValue, StorageInfo,
+};
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::ptr;
+use tracing::{debug, trace};
+use windows_core::{interface, IUnknown, IUnknown_Vtbl, Interface, HRESULT};
+use windows_sys::core::GUID;
+use windows_sys::Win32::Devices::PortableDevices::*;
+use windows_sys::Win32::Foundation::PROPERTYKEY;
+use windows_sys::Win32::System::Com::StructuredStorage::PropVariantClear;
+use windows_sys::Win32::System::Com::{
+    CoCreateInstance, CoInitializeEx, CoTaskMemFree, CLSCTX_INPROC_SERVER, COINIT_MULTITHREADED,
+};
+
+#[interface("a1567595-4c2f-4574-a6fa-ecef917b9a40")]
+unsafe trait IPortableDeviceManager: IUnknown {
+    fn GetDevices(&self, ids: *mut *mut u16, count: *mut u32) -> HRESULT;
+}
+
+#[interface("625e2df8-6392-4cf0-9ad1-3cfa5f17775c")]
+unsafe trait IPortableDevice: IUnknown {
+    fn Open(&self, id: *const u16, client_info: *mut c_void) -> HRESULT;
+    fn SendCommand(&self, flags: u32, parameters: *mut c_void, results: *mut *mut c_void) -> HRESULT;
+    fn Content(&self, content: *mut *mut c_void) -> HRESULT;
+}
+
+#[interface("6a96ed84-7c73-4480-9938-bf5af477d426")]
+unsafe trait IPortableDeviceContent: IUnknown {
+    fn EnumObjects(&self, flags: u32, parent: *const u16, filter: *mut c_void, objects: *mut *mut c_void) -> HRESULT;
+    fn Properties(&self, properties: *mut *mut c_void) -> HRESULT;
+    fn Transfer(&self, resources: *mut *mut c_void) -> HRESULT;
+    fn CreateObjectWithPropertiesOnly(&self, values: *mut c_void, id: *mut *mut u16) -> HRESULT;
+    fn CreateObjectWithPropertiesAndData(
+        &self,
+        values: *mut c_void,
+        data: *mut *mut c_void,
+        optimal_buffer_size: *mut u32,
+        cookie: *mut *mut u16,
+    ) -> HRESULT;
+    fn Delete(&self, options: u32, ids: *mut c_void, results: *mut *mut c_void) -> HRESULT;
+    fn GetObjectIDsFromPersistentUniqueIDs(&self, unique_ids: *mut c_void, ids: *mut *mut c_void) -> HRESULT;
+    fn Cancel(&self) -> HRESULT;
+    fn Move(&self, ids: *mut c_void, destination: *const u16, results: *mut *mut c_void) -> HRESULT;
+}
+
+#[interface("10ece955-cf41-4728-bfa0-41eedf1bbf19")]
+unsafe trait IEnumPortableDeviceObjectIDs: IUnknown {
+    fn Next(&self, count: u32, ids: *mut *mut u16, fetched: *mut u32) -> HRESULT;
+}
+
+#[interface("7f6d695c-03df-4439-a809-59266beee3a6")]
+unsafe trait IPortableDeviceProperties: IUnknown {
+    fn GetSupportedProperties(&self, id: *const u16, keys: *mut *mut c_void) -> HRESULT;
+    fn GetPropertyAttributes(&self, id: *const u16, key: *const PROPERTYKEY, attributes: *mut *mut c_void) -> HRESULT;
+    fn GetValues(&self, id: *const u16, keys: *mut c_void, values: *mut *mut c_void) -> HRESULT;
+    fn SetValues(&self, id: *const u16, values: *mut c_void, results: *mut *mut c_void) -> HRESULT;
+}
+
+#[interface("fd8878ac-d841-4d17-891c-e6829cdb6934")]
+unsafe trait IPortableDeviceResources: IUnknown {
+    fn GetSupportedResources(&self, id: *const u16, keys: *mut *mut c_void) -> HRESULT;
+    fn GetResourceAttributes(&self, id: *const u16, key: *const PROPERTYKEY, attributes: *mut *mut c_void) -> HRESULT;
+    fn GetStream(
+        &self,
+        id: *const u16,
+        key: *const PROPERTYKEY,
+        mode: u32,
+        optimal_buffer_size: *mut u32,
+        stream: *mut *mut c_void,
+    ) -> HRESULT;
+}
+
+#[interface("6848f6f2-3155-4f86-b6f5-263eeeab3143")]
+unsafe trait IPortableDeviceValues: IUnknown {
+    fn GetCount(&self, count: *mut u32) -> HRESULT;
+    fn GetAt(&self, index: u32, key: *mut PROPERTYKEY, value: *mut PropVariant) -> HRESULT;
+    fn SetValue(&self, key: *const PROPERTYKEY, value: *const PropVariant) -> HRESULT;
+    fn GetValue(&self, key: *const PROPERTYKEY, value: *mut PropVariant) -> HRESULT;
+    fn SetStringValue(&self, key: *const PROPERTYKEY, value: *const u16) -> HRESULT;
+    fn GetStringValue(&self, key: *const PROPERTYKEY, value: *mut *mut u16) -> HRESULT;
+    fn SetUnsignedIntegerValue(&self, key: *const PROPERTYKEY, value: u32) -> HRESULT;
+    fn GetUnsignedIntegerValue(&self, key: *const PROPERTYKEY, value: *mut u32) -> HRESULT;
+    fn SetSignedIntegerValue(&self, key: *const PROPERTYKEY, value: i32) -> HRESULT;
+    fn GetSignedIntegerValue(&self, key: *const PROPERTYKEY, value: *mut i32) -> HRESULT;
+    fn SetUnsignedLargeIntegerValue(&self, key: *const PROPERTYKEY, value: u64) -> HRESULT;
+    fn GetUnsignedLargeIntegerValue(&self, key: *const PROPERTYKEY, value: *mut u64) -> HRESULT;
+    fn SetSignedLargeIntegerValue(&self, key: *const PROPERTYKEY, value: i64) -> HRESULT;
+    fn GetSignedLargeIntegerValue(&self, key: *const PROPERTYKEY, value: *mut i64) -> HRESULT;
+    fn SetFloatValue(&self, key: *const PROPERTYKEY, value: f32) -> HRESULT;
+    fn GetFloatValue(&self, key: *const PROPERTYKEY, value: *mut f32) -> HRESULT;
+    fn SetErrorValue(&self, key: *const PROPERTYKEY, value: HRESULT) -> HRESULT;
+    fn GetErrorValue(&self, key: *const PROPERTYKEY, value: *mut HRESULT) -> HRESULT;
+    fn SetKeyValue(&self, key: *const PROPERTYKEY, value: *const PROPERTYKEY) -> HRESULT;
+    fn GetKeyValue(&self, key: *const PROPERTYKEY, value: *mut PROPERTYKEY) -> HRESULT;
+    fn SetBoolValue(&self, key: *const PROPERTYKEY, value: i32) -> HRESULT;
+    fn GetBoolValue(&self, key: *const PROPERTYKEY, value: *mut i32) -> HRESULT;
+    fn SetIUnknownValue(&self, key: *const PROPERTYKEY, value: *mut c_void) -> HRESULT;
+    fn GetIUnknownValue(&self, key: *const PROPERTYKEY, value: *mut *mut c_void) -> HRESULT;
+    fn SetGuidValue(&self, key: *const PROPERTYKEY, value: *const GUID) -> HRESULT;
+    fn GetGuidValue(&self, key: *const PROPERTYKEY, value: *mut GUID) -> HRESULT;
+}
+
+#[interface("dada2357-e0ad-492e-98db-dd61c53ba353")]
+unsafe trait IPortableDeviceKeyCollection: IUnknown {
+    fn GetCount(&self, count: *mut u32) -> HRESULT;
+    fn GetAt(&self, index: u32, key: *mut PROPERTYKEY) -> HRESULT;
+    fn Add(&self, key: *const PROPERTYKEY) -> HRESULT;
+}
+
+#[interface("89b2e422-4f1b-4316-bcef-a44afea83eb3")]
+unsafe trait IPortableDevicePropVariantCollection: IUnknown {
+    fn GetCount(&self, count: *mut u32) -> HRESULT;
+    fn GetAt(&self, index: u32, value: *mut PropVariant) -> HRESULT;
+    fn Add(&self, value: *const PropVariant) -> HRESULT;
+}
+
+#[interface("0c733a30-2a1c-11ce-ade5-00aa0044773d")]
+unsafe trait ISequentialStream: IUnknown {
+    fn Read(&self, buffer: *mut c_void, len: u32, read: *mut u32) -> HRESULT;
+    fn Write(&self, buffer: *const c_void, len: u32, written: *mut u32) -> HRESULT;
+}
+
+#[interface("0000000c-0000-0000-c000-000000000046")]
+unsafe trait IStream: ISequentialStream {
+    fn Seek(&self, offset: i64, origin: u32, position: *mut u64) -> HRESULT;
+    fn SetSize(&self, size: u64) -> HRESULT;
+    fn CopyTo(&self, stream: *mut c_void, len: u64, read: *mut u64, written: *mut u64) -> HRESULT;
+    fn Commit(&self, flags: u32) -> HRESULT;
+    fn Revert(&self) -> HRESULT;
+}
+
+/// A PROPVARIANT with only the members read here; the union is 16 bytes.
+#[repr(C)]
+struct PropVariant {
+    vt: u16,
+    reserved: [u16; 3],
+    data: [u64; 2],
+}
+
+const VT_I2: u16 = 2;
+const VT_I4: u16 = 3;
+const VT_DATE: u16 = 7;
+const VT_BOOL: u16 = 11;
+const VT_I1: u16 = 16;
+const VT_UI1: u16 = 17;
+const VT_UI2: u16 = 18;
+const VT_UI4: u16 = 19;
+const VT_I8: u16 = 20;
+const VT_UI8: u16 = 21;
+const VT_INT: u16 = 22;
+const VT_UINT: u16 = 23;
+const VT_LPWSTR: u16 = 31;
+const VT_CLSID: u16 = 72;
+
+/// `STGM_READ`, for `GetStream`.
+const STGM_READ: u32 = 0;
+/// Days from the OLE automation epoch (1899-12-30) to the Unix epoch.
+const OLE_UNIX_EPOCH_DAYS: f64 = 25569.0;
+/// Most bytes asked of a stream at a time, whatever the driver suggests.
+const MAX_READ: u32 = 4 * 1024 * 1024;
+/// Object IDs `EnumObjects` hands out per call.
+const ENUM_BATCH: usize = 64;
+/// Numbers for object IDs that aren't the MTP driver's `o`/`s` + hex.
+const INTERNED_ID_BASE: u32 = 0x8000_0000;
+
+impl PropVariant {
+    fn empty() -> Self {
+        Self {
+            vt: 0,
+            reserved: [0; 3],
+            data: [0; 2],
+        }
+    }
+
+    /// A VT_LPWSTR borrowing `text`, which must outlive it. Not to be
+    /// cleared: the collections it is added to copy it.
+    fn borrowed_string(text: &[u16]) -> Self {
+        let mut value = Self::empty();
+        value.vt = VT_LPWSTR;
+        value.data[0] = text.as_ptr() as u64;
+        value
+    }
+
+    fn date(time: DateTime<Utc>) -> Self {
+        let mut value = Self::empty();
+        value.vt = VT_DATE;
+        let days = time.timestamp() as f64 / 86400.0 + OLE_UNIX_EPOCH_DAYS;
+        value.data[0] = days.to_bits();
+        value
+    }
+
+    /// The value as an `ObjectProperty` value, or `None` for types not read.
+    fn to_property(&self) -> Option<PropertyValue> {
+        let raw = self.data[0];
+        Some(match self.vt {
+            VT_UI1 | VT_UI2 | VT_UI4 | VT_UI8 | VT_UINT => PropertyValue::Integer(match self.vt {
+                VT_UI1 => raw & 0xff,
+                VT_UI2 => raw & 0xffff,
+                VT_UI8 => raw,
+                _ => raw & 0xffff_ffff,
+            }),
+            VT_I1 | VT_I2 | VT_I4 | VT_I8 | VT_INT | VT_BOOL => {
+                let value = match self.vt {
+                    VT_I1 => raw as u8 as i8 as i64,
+                    VT_I2 | VT_BOOL => raw as u16 as i16 as i64,
+                    VT_I8 => raw as i64,
+                    _ => raw as u32 as i32 as i64,
+                };
+                match u64::try_from(value) {
+                    Ok(value) => PropertyValue::Integer(value),
+                    Err(_) => PropertyValue::Text(value.to_string()),
+                }
+            }
+            VT_DATE => PropertyValue::Text(ole_date(f64::from_bits(raw))?.to_rfc3339()),
+            // SAFETY: VT_LPWSTR holds a NUL-terminated UTF-16 string
+            VT_LPWSTR => PropertyValue::Text(unsafe { from_wide(raw as *const u16) }),
+            // SAFETY: VT_CLSID holds a pointer to a GUID
+            VT_CLSID => PropertyValue::Text(guid_string(unsafe { &*(raw as *const GUID) })),
+            _ => return None,
+        })
+    }
+}
+
+impl Drop for PropVariant {
+    fn drop(&mut self) {
+        // Only values filled in by WPD own memory; borrowed strings are
+        // never handed back, so their type is cleared before the drop
+        if self.vt != 0 {
+            // SAFETY: the layout matches PROPVARIANT
+            unsafe { PropVariantClear(ptr::from_mut(self).cast()) };
+        }
+    }
+}
+
+pub(crate) struct Wpd {
+    /// Holds the session open; the interfaces below all come from it
+    _device: IPortableDevice,
+    content: IPortableDeviceContent,
+    properties: IPortableDeviceProperties,
+    resources: IPortableDeviceResources,
+    /// USB vendor and product ID, from the PnP device ID
+    usb_id: (u16, u16),
+    /// Object IDs that aren't the MTP driver's own, by the number given out
+    interned: RefCell<HashMap<u32, String>>,
+}
+
+impl Wpd {
+    /// The WPD object ID behind `id`.
+    fn object_id(&self, id: u32) -> Vec<u16> {
+        let name = match self.interned.borrow().get(&id) {
+            Some(name) => name.clone(),
+            None => format!("o{:X}", id),
+        };
+        wide(&name)
+    }
+
+    /// The WPD object ID of a folder: the storage for the root.
+    fn folder_id(&self, storage: u32, parent: Option<u32>) -> Vec<u16> {
+        match parent {
+            Some(id) => self.object_id(id),
+            None => match self.interned.borrow().get(&storage) {
+                Some(name) => wide(name),
+                None => wide(&format!("s{:X}", storage)),
+            },
+        }
+    }
+
+    /// The number for a WPD object ID: the MTP handle when there is one.
+    fn number(&self, name: &str) -> u32 {
+        if let Some(handle) = name
+            .strip_prefix(['o', 's'])
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .filter(|&handle| handle < INTERNED_ID_BASE)
+        {
+            return handle;
+        }
+        let mut interned = self.interned.borrow_mut();
+        if let Some((&id, _)) = interned.iter().find(|(_, known)| *known == name) {
+            return id;
+        }
+        let id = INTERNED_ID_BASE + interned.len() as u32;
+        interned.insert(id, name.to_string());
+        id
+    }
+
+    /// The object IDs of the children of `parent`.
+    fn children(&self, parent: &[u16]) -> Result<Vec<String>> {
+        let mut raw = ptr::null_mut();
+        // SAFETY: WPD returns an owned enumerator through `raw`
+        let objects = unsafe {
+            check(self.content.EnumObjects(0, parent.as_ptr(), ptr::null_mut(), &mut raw))?;
+            IEnumPortableDeviceObjectIDs::from_raw(raw)
+        };
+        let mut ids = Vec::new();
+        loop {
+            let mut batch = [ptr::null_mut::<u16>(); ENUM_BATCH];
+            let mut fetched = 0;
+            // SAFETY: the enumerator fills up to ENUM_BATCH strings we free
+            let hr = unsafe { objects.Next(ENUM_BATCH as u32, batch.as_mut_ptr(), &mut fetched) };
+            check(hr)?;
+            for &id in &batch[..fetched as usize] {
+                // SAFETY: each ID is a CoTaskMemAlloc'd string
+                ids.push(unsafe { take_wide(id) });
+            }
+            // S_FALSE: fewer left than asked for
+            if hr.0 != 0 || fetched == 0 {
+                break;
+            }
+        }
+        Ok(ids)
+    }
+
+    /// The values of `keys` for object `id`.
+    fn values(&self, id: &[u16], keys: &[PROPERTYKEY]) -> Result<IPortableDeviceValues> {
+        let collection: IPortableDeviceKeyCollection = create(&PortableDeviceKeyCollection)?;
+        for key in keys {
+            // SAFETY: the collection copies the key
+            check(unsafe { collection.Add(key) })?;
+        }
+        let mut raw = ptr::null_mut();
+        // SAFETY: WPD returns an owned value set through `raw`
+        unsafe {
+            check(self.properties.GetValues(id.as_ptr(), collection.as_raw(), &mut raw))?;
+            Ok(IPortableDeviceValues::from_raw(raw))
+        }
+    }
+
+    /// A read stream of one resource of object `id`, and the buffer size
+    /// the driver works best with.
+    fn stream(&self, id: u32, resource: &PROPERTYKEY) -> Result<(IStream, u32)> {
+        let object = self.object_id(id);
+        let (mut raw, mut optimal) = (ptr::null_mut(), 0);
+        // SAFETY: WPD returns an owned stream through `raw`
+        unsafe {
+            check(self.resources.GetStream(object.as_ptr(), resource, STGM_READ, &mut optimal, &mut raw))?;
+            Ok((IStream::from_raw(raw), optimal.clamp(64 * 1024, MAX_READ)))
+        }
+    }
+
+    /// One object ID in a new collection, for Delete and Move.
+    fn id_collection(&self, id: &[u16]) -> Result<IPortableDevicePropVariantCollection> {
+        let collection: IPortableDevicePropVariantCollection = create(&PortableDevicePropVariantCollection)?;
+        let mut value = PropVariant::borrowed_string(id);
+        // SAFETY: Add copies the string, which outlives the call
+        let added = check(unsafe { collection.Add(&value) });
+        value.vt = 0;
+        added.map(|()| collection)
+    }
+
+    /// The properties a new object in `parent` named `name` starts with.
+    fn new_object(&self, parent: &[u16], name: &str, content_type: &GUID) -> Result<IPortableDeviceValues> {
+        let values: IPortableDeviceValues = create(&PortableDeviceValues)?;
+        let name = wide(name);
+        // SAFETY: the value set copies each value
+        unsafe {
+            check(values.SetStringValue(&WPD_OBJECT_PARENT_ID, parent.as_ptr()))?;
+            check(values.SetStringValue(&WPD_OBJECT_NAME, name.as_ptr()))?;
+            check(values.SetStringValue(&WPD_OBJECT_ORIGINAL_FILE_NAME, name.as_ptr()))?;
+            check(values.SetGuidValue(&WPD_OBJECT_CONTENT_TYPE, content_type))?;
+        }
+        Ok(values)
+    }
+}
+
+impl MtpBackend for Wpd {
+    fn open(options: &DetectOptions, filter: &DeviceFilter) -> Result<Self> {
+        let candidates: Vec<(String, (u16, u16))> = device_ids()?
+            .into_iter()
+            .filter_map(|id| {
+                let usb_id = usb_id(&id)?;
+                filter.matches(usb_id.0, usb_id.1).then_some((id, usb_id))
+            })
+            .collect();
+        debug!(count = candidates.len(), "found WPD devices");
+        if candidates.is_empty() {
+            return Err(Error::DeviceNotFound);
+        }
+
+        let mut last_error = Error::DeviceNotFound;
+        for (id, usb_id) in candidates {
+            let wpd = match open_device(&id, usb_id) {
+                Ok(wpd) => wpd,
+                Err(e) => {
+                    debug!(device = %id, error = %e, "could not open WPD device");
+                    last_error = e;
+                    continue;
+                }
+            };
+            match &options.serial {
+                None => return Ok(wpd),
+                Some(serial) => {
+                    let found = wpd.info().serial;
+                    debug!(serial = %found, wanted = %serial, "checking Kindle serial");
+                    if found == *serial {
+                        return Ok(wpd);
+                    }
+                    last_error = Error::DeviceNotFound;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    fn is_present(filter: &DeviceFilter) -> bool {
+        device_ids()
+            .map(|ids| {
+                ids.iter()
+                    .filter_map(|id| usb_id(id))
+                    .any(|(vendor, product)| filter.matches(vendor, product))
+            })
+            .unwrap_or(false)
+    }
+
+    fn info(&self) -> KindleInfo {
+        let keys = [
+            WPD_DEVICE_MANUFACTURER,
+            WPD_DEVICE_MODEL,
+            WPD_DEVICE_SERIAL_NUMBER,
+            WPD_DEVICE_FRIENDLY_NAME,
+        ];
+        let values = self.values(&wide("DEVICE"), &keys).ok();
+        let text = |key: &PROPERTYKEY| values.as_ref().and_then(|values| string_value(values, key));
+        KindleInfo {
+            manufacturer: text(&WPD_DEVICE_MANUFACTURER).unwrap_or_else(|| "Unknown".to_string()),
+            model: text(&WPD_DEVICE_MODEL).unwrap_or_else(|| "Unknown".to_string()),
+            serial: text(&WPD_DEVICE_SERIAL_NUMBER).unwrap_or_default(),
+            friendly_name: text(&WPD_DEVICE_FRIENDLY_NAME).unwrap_or_else(|| "Kindle".to_string()),
+            vendor_id: self.usb_id.0,
+            product_id: self.usb_id.1,
+        }
+    }
+
+    /// The storages are the device object's functional objects of the
+    /// storage category; listing them is a round trip, unlike with libmtp.
+    fn storages(&self) -> Vec<StorageInfo> {
+        let keys = [
+            WPD_FUNCTIONAL_OBJECT_CATEGORY,
+            WPD_STORAGE_DESCRIPTION,
+            WPD_STORAGE_CAPACITY,
+            WPD_STORAGE_FREE_SPACE_IN_BYTES,
+        ];
+        let children = match self.children(&wide("DEVICE")) {
+            Ok(children) => children,
+            Err(e) => {
+                debug!(error = %e, "could not list storages");
+                return Vec::new();
+            }
+        };
+        children
+            .into_iter()
+            .filter_map(|name| {
+                let values = self.values(&wide(&name), &keys).ok()?;
+                let category = guid_value(&values, &WPD_FUNCTIONAL_OBJECT_CATEGORY)?;
+                if !same_guid(&category, &WPD_FUNCTIONAL_CATEGORY_STORAGE) {
+                    return None;
+                }
+                Some(StorageInfo {
+                    id: self.number(&name),
+                    description: string_value(&values, &WPD_STORAGE_DESCRIPTION)
+                        .unwrap_or_else(|| "Internal Storage".to_string()),
+                    total_bytes: u64_value(&values, &WPD_STORAGE_CAPACITY).unwrap_or(0),
+                    free_bytes: u64_value(&values, &WPD_STORAGE_FREE_SPACE_IN_BYTES).unwrap_or(0),
+                })
+            })
+            .collect()
+    }
+
+    /// One EnumObjects, then one GetValues per child, which the MTP driver
+    /// answers from the ObjectInfo dataset much like libmtp does.
+    fn list_children(&self, storage: u32, parent: Option<u32>) -> Result<Vec<FileEntry>> {
+        let keys = [
+            WPD_OBJECT_ORIGINAL_FILE_NAME,
+            WPD_OBJECT_NAME,
+            WPD_OBJECT_SIZE,
+            WPD_OBJECT_CONTENT_TYPE,
+            WPD_OBJECT_DATE_MODIFIED,
+        ];
+        let mut entries = Vec::new();
+        for name in self.children(&self.folder_id(storage, parent))? {
+            let values = self.values(&wide(&name), &keys)?;
+            let content_type = guid_value(&values, &WPD_OBJECT_CONTENT_TYPE);
+            let is_folder = content_type.is_some_and(|t| {
+                same_guid(&t, &WPD_CONTENT_TYPE_FOLDER) || same_guid(&t, &WPD_CONTENT_TYPE_FUNCTIONAL_OBJECT)
+            });
+            entries.push(FileEntry {
+                name: string_value(&values, &WPD_OBJECT_ORIGINAL_FILE_NAME)
+                    .or_else(|| string_value(&values, &WPD_OBJECT_NAME))
+                    .unwrap_or_else(|| name.clone()),
+                size: u64_value(&values, &WPD_OBJECT_SIZE).unwrap_or(0),
+                is_folder,
+                id: self.number(&name),
+                modified: date_value(&values, &WPD_OBJECT_DATE_MODIFIED).unwrap_or(DateTime::UNIX_EPOCH),
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Every property the driver reports for the object. WPD keys have no
+    /// MTP names, so the common ones are named and the rest shown as keys.
+    fn object_properties(&self, _storage: u32, _parent: Option<u32>, id: u32) -> Result<Vec<ObjectProperty>> {
+        let object = self.object_id(id);
+        let mut raw = ptr::null_mut();
+        // SAFETY: a null key collection asks for every property
+        let values = unsafe {
+            check(self.properties.GetValues(object.as_ptr(), ptr::null_mut(), &mut raw))?;
+            IPortableDeviceValues::from_raw(raw)
+        };
+        let mut count = 0;
+        // SAFETY: plain out parameter
+        check(unsafe { values.GetCount(&mut count) })?;
+        let mut properties = Vec::new();
+        for index in 0..count {
+            let mut key = PROPERTYKEY {
+                fmtid: GUID::from_u128(0),
+                pid: 0,
+            };
+            let mut value = PropVariant::empty();
+            // SAFETY: WPD fills `value`, which its drop clears
+            if unsafe { values.GetAt(index, &mut key, &mut value) }.0 < 0 {
+                continue;
+            }
+            match value.to_property() {
+                Some(value) => properties.push(ObjectProperty {
+                    name: property_name(&key),
+                    value,
+                }),
+                None => trace!(key = %property_name(&key), vt = value.vt, "property type not read"),
+            }
+        }
+        Ok(properties)
+    }
+
+    fn thumbnail(&self, id: u32) -> Result<Vec<u8>> {
+        let (stream, chunk) = self
+            .stream(id, &WPD_RESOURCE_THUMBNAIL)
+            .map_err(|_| Error::FileNotFound("device has no thumbnail for this object".to_string()))?;
+        let mut thumbnail = Vec::new();
+        read_stream(&stream, chunk, &mut |data| {
+            thumbnail.extend_from_slice(data);
+            true
+        })
+        .map_err(|_| Error::Mtp("could not read the thumbnail".to_string()))?;
+        Ok(thumbnail)
+    }
+
+    fn get_file(&self, _storage: u32, id: u32, on_data: &mut dyn FnMut(&[u8]) -> bool) -> Result<()> {
+        let (stream, chunk) = self.stream(id, &WPD_RESOURCE_DEFAULT).map_err(transfer_error)?;
+        read_stream(&stream, chunk, on_data)
+    }
+
+    fn send_file(
+        &self,
+        storage: u32,
+        parent: Option<u32>,
+        name: &str,
+        size: u64,
+        modified: DateTime<Utc>,
+        fill: &mut dyn FnMut(&mut [u8]) -> Option<usize>,
+    ) -> Result<()> {
+        let values = self.new_object(&self.folder_id(storage, parent), name, &WPD_CONTENT_TYPE_UNSPECIFIED)?;
+        // SAFETY: the value set copies each value
+        unsafe {
+            check(values.SetUnsignedLargeIntegerValue(&WPD_OBJECT_SIZE, size))?;
+            check(values.SetGuidValue(&WPD_OBJECT_FORMAT, &WPD_OBJECT_FORMAT_UNSPECIFIED))?;
+            // A date is set best-effort: not every device takes one
+            let _ = values.SetValue(&WPD_OBJECT_DATE_MODIFIED, &PropVariant::date(modified));
+        }
+        let (mut raw, mut optimal) = (ptr::null_mut(), 0);
+        // SAFETY: WPD returns an owned write stream through `raw`
+        let stream = unsafe {
+            check(self.content.CreateObjectWithPropertiesAndData(
+                values.as_raw(),
+                &mut raw,
+                &mut optimal,
+                ptr::null_mut(),
+            ))
+            .map_err(transfer_error)?;
+            IStream::from_raw(raw)
+        };
+
+        let mut buffer = vec![0u8; optimal.clamp(64 * 1024, MAX_READ) as usize];
+        loop {
+            let Some(n) = fill(&mut buffer) else {
+                // SAFETY: dropping an uncommitted stream discards the object
+                let _ = unsafe { stream.Revert() };
+                return Err(Error::Cancelled);
+            };
+            if n == 0 {
+                break;
+            }
+            let mut offset = 0;
+            while offset < n {
+                let mut written = 0;
+                // SAFETY: `buffer[offset..n]` is initialized
+                check(unsafe {
+                    stream.Write(buffer[offset..n].as_ptr().cast(), (n - offset) as u32, &mut written)
+                })
+                .map_err(transfer_error)?;
+                if written == 0 {
+                    return Err(Error::TransferFailed("device stopped taking data".to_string()));
+                }
+                offset += written as usize;
+            }
+        }
+        // SAFETY: STGC_DEFAULT; the object appears on the device on commit
+        check(unsafe { stream.Commit(0) }).map_err(transfer_error)
+    }
+
+    fn create_folder(&self, storage: u32, parent: Option<u32>, name: &str) -> Result<u32> {
+        let values = self.new_object(&self.folder_id(storage, parent), name, &WPD_CONTENT_TYPE_FOLDER)?;
+        let mut id = ptr::null_mut();
+        // SAFETY: WPD returns the new object's ID as an owned string
+        unsafe {
+            check(self.content.CreateObjectWithPropertiesOnly(values.as_raw(), &mut id))?;
+            Ok(self.number(&take_wide(id)))
+        }
+    }
+
+    fn delete(&self, id: u32) -> Result<()> {
+        let object = self.object_id(id);
+        let ids = self.id_collection(&object)?;
+        // SAFETY: the collection stays alive for the call
+        check(unsafe {
+            self.content
+                .Delete(PORTABLE_DEVICE_DELETE_NO_RECURSION as u32, ids.as_raw(), ptr::null_mut())
+        })
+    }
+
+    fn move_object(&self, id: u32, storage: u32, parent: Option<u32>) -> Result<()> {
+        let object = self.object_id(id);
+        let ids = self.id_collection(&object)?;
+        let destination = self.folder_id(storage, parent);
+        // SAFETY: the collection and the destination outlive the call
+        check(unsafe {
+            self.content
+                .Move(ids.as_raw(), destination.as_ptr(), ptr::null_mut())
+        })
+    }
+
+    fn rename_object(&self, id: u32, name: &str) -> Result<()> {
+        let object = self.object_id(id);
+        let values: IPortableDeviceValues = create(&PortableDeviceValues)?;
+        let name = wide(name);
+        let mut results = ptr::null_mut();
+        // SAFETY: the value set copies the name; results come back owned
+        let hr = unsafe {
+            check(values.SetStringValue(&WPD_OBJECT_ORIGINAL_FILE_NAME, name.as_ptr()))?;
+            let hr = self.properties.SetValues(object.as_ptr(), values.as_raw(), &mut results);
+            if !results.is_null() {
+                drop(IPortableDeviceValues::from_raw(results));
+            }
+            hr
+        };
+        check(hr)?;
+        // S_FALSE: the call went through but the name wasn't set
+        if hr.0 != 0 {
+            return Err(Error::ObjectProtected("device refused the new name".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Joins the multithreaded apartment; WPD's objects are free-threaded.
+/// A thread already in an apartment keeps it, which works as well.
+fn init_com() {
+    // SAFETY: no reserved pointer; repeated calls only count references
+    let _ = unsafe { CoInitializeEx(ptr::null(), COINIT_MULTITHREADED as u32) };
+}
+
+/// Creates the COM class `class` and asks it for `T`.
+fn create<T: Interface>(class: &GUID) -> Result<T> {
+    init_com();
+    let mut raw = ptr::null_mut();
+    let iid = ptr::from_ref(&T::IID).cast::<GUID>();
+    // SAFETY: on success `raw` is an owned `T`
+    unsafe {
+        check(HRESULT(CoCreateInstance(class, ptr::null_mut(), CLSCTX_INPROC_SERVER, iid, &mut raw)))?;
+        Ok(T::from_raw(raw))
+    }
+}
+
+/// The PnP IDs of every portable device Windows knows about.
+fn device_ids() -> Result<Vec<String>> {
+    let manager: IPortableDeviceManager = create(&PortableDeviceManager)?;
+    let mut count = 0;
+    // SAFETY: a null array asks for the count only
+    check(unsafe { manager.GetDevices(ptr::null_mut(), &mut count) })?;
+    let mut ids = vec![ptr::null_mut::<u16>(); count as usize];
+    // SAFETY: `ids` has room for `count` strings, which we then free
+    check(unsafe { manager.GetDevices(ids.as_mut_ptr(), &mut count) })?;
+    Ok(ids
+        .into_iter()
+        .take(count as usize)
+        // SAFETY: each ID is a CoTaskMemAlloc'd string
+        .map(|id| unsafe { take_wide(id) })
+        .collect())
+}
+
+/// Vendor and product ID from a PnP ID such as
+/// `\\?\usb#vid_1949&pid_0004#G000...#{6ac27878-...}`.
+fn usb_id(pnp_id: &str) -> Option<(u16, u16)> {
+    let lower = pnp_id.to_ascii_lowercase();
+    let hex = |tag: &str| {
+        let at = lower.find(tag)? + tag.len();
+        u16::from_str_radix(lower.get(at..at + 4)?, 16).ok()
+    };
+    Some((hex("vid_")?, hex("pid_")?))
+}
+
+fn open_device(pnp_id: &str, usb_id: (u16, u16)) -> Result<Wpd> {
+    let client: IPortableDeviceValues = create(&PortableDeviceValues)?;
+    let client_name = wide(env!("CARGO_PKG_NAME"));
+    // SAFETY: the value set copies the name
+    check(unsafe { client.SetStringValue(&WPD_CLIENT_NAME, client_name.as_ptr()) })?;
+
+    let device: IPortableDevice = create(&PortableDeviceFTM)?;
+    let id = wide(pnp_id);
+    // SAFETY: both arguments outlive the call
+    check(unsafe { device.Open(id.as_ptr(), client.as_raw()) })?;
+
+    let (mut content, mut properties, mut resources) = (ptr::null_mut(), ptr::null_mut(), ptr::null_mut());
+    // SAFETY: each call hands back an owned interface
+    unsafe {
+        check(device.Content(&mut content))?;
+        let content = IPortableDeviceContent::from_raw(content);
+        check(content.Properties(&mut properties))?;
+        check(content.Transfer(&mut resources))?;
+        debug!(device = %pnp_id, "opened WPD device");
+        Ok(Wpd {
+            properties: IPortableDeviceProperties::from_raw(properties),
+            resources: IPortableDeviceResources::from_raw(resources),
+            content,
+            _device: device,
+            usb_id,
+            interned: RefCell::default(),
+        })
+    }
+}
+
+/// Reads `stream` to the end in `chunk`-sized pieces; `on_data` returning
+/// false stops it with `Error::Cancelled`.
+fn read_stream(stream: &IStream, chunk: u32, on_data: &mut dyn FnMut(&[u8]) -> bool) -> Result<()> {
+    let mut buffer = vec![0u8; chunk as usize];
+    loop {
+        let mut read = 0;
+        // SAFETY: `buffer` has room for `chunk` bytes
+        check(unsafe { stream.Read(buffer.as_mut_ptr().cast(), chunk, &mut read) }).map_err(transfer_error)?;
+        if read == 0 {
+            return Ok(());
+        }
+        if !on_data(&buffer[..read as usize]) {
+            return Err(Error::Cancelled);
+        }
+    }
+}
+
+fn string_value(values: &IPortableDeviceValues, key: &PROPERTYKEY) -> Option<String> {
+    let mut text = ptr::null_mut();
+    // SAFETY: on success WPD hands over an owned string
+    unsafe {
+        if values.GetStringValue(key, &mut text).0 < 0 || text.is_null() {
+            return None;
+        }
+        Some(take_wide(text))
+    }
+}
+
+fn u64_value(values: &IPortableDeviceValues, key: &PROPERTYKEY) -> Option<u64> {
+    let mut value = 0;
+    // SAFETY: plain out parameter
+    (unsafe { values.GetUnsignedLargeIntegerValue(key, &mut value) }.0 >= 0).then_some(value)
+}
+
+fn guid_value(values: &IPortableDeviceValues, key: &PROPERTYKEY) -> Option<GUID> {
+    let mut value = GUID::from_u128(0);
+    // SAFETY: plain out parameter
+    (unsafe { values.GetGuidValue(key, &mut value) }.0 >= 0).then_some(value)
+}
+
+fn date_value(values: &IPortableDeviceValues, key: &PROPERTYKEY) -> Option<DateTime<Utc>> {
+    let mut value = PropVariant::empty();
+    // SAFETY: WPD fills `value`, which its drop clears
+    if unsafe { values.GetValue(key, &mut value) }.0 < 0 || value.vt != VT_DATE {
+        return None;
+    }
+    ole_date(f64::from_bits(value.data[0]))
+}
+
+/// An OLE automation date: days since 1899-12-30, in local time as WPD
+/// reports it from the device, taken as UTC the way libmtp does.
+fn ole_date(days: f64) -> Option<DateTime<Utc>> {
+    let seconds = (days - OLE_UNIX_EPOCH_DAYS) * 86400.0;
+    DateTime::from_timestamp(seconds.round() as i64, 0)
+}
+
+fn same_guid(a: &GUID, b: &GUID) -> bool {
+    (a.data1, a.data2, a.data3, a.data4) == (b.data1, b.data2, b.data3, b.data4)
+}
+
+fn guid_string(guid: &GUID) -> String {
+    let d = guid.data4;
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        guid.data1, guid.data2, guid.data3, d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]
+    )
+}
+
+/// Names for the WPD keys `stat --props` shows most often, after the MTP
+/// properties libmtp reports; others are shown as `{fmtid} pid`.
+fn property_name(key: &PROPERTYKEY) -> String {
+    const NAMES: &[(PROPERTYKEY, &str)] = &[
+        (WPD_OBJECT_ID, "ObjectId"),
+        (WPD_OBJECT_PARENT_ID, "ParentObject"),
+        (WPD_OBJECT_NAME, "Name"),
+        (WPD_OBJECT_PERSISTENT_UNIQUE_ID, "PersistentUniqueId"),
+        (WPD_OBJECT_FORMAT, "ObjectFormat"),
+        (WPD_OBJECT_CONTENT_TYPE, "ContentType"),
+        (WPD_OBJECT_SIZE, "ObjectSize"),
+        (WPD_OBJECT_ORIGINAL_FILE_NAME, "ObjectFileName"),
+        (WPD_OBJECT_DATE_MODIFIED, "DateModified"),
+    ];
+    NAMES
+        .iter()
+        .find(|(known, _)| same_guid(&known.fmtid, &key.fmtid) && known.pid == key.pid)
+        .map(|(_, name)| name.to_string())
+        .unwrap_or_else(|| format!("{{{}}} {}", guid_string(&key.fmtid), key.pid))
+}
+
+fn wide(text: &str) -> Vec<u16> {
+    text.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+/// Copies a NUL-terminated UTF-16 string.
+///
+/// # Safety
+/// `text` must be null or point to a NUL-terminated string.
+unsafe fn from_wide(text: *const u16) -> String {
+    if text.is_null() {
+        return String::new();
+    }
+    // SAFETY: the caller guarantees the terminator
+    unsafe {
+        let len = (0..).take_while(|&i| *text.add(i) != 0).count();
+        String::from_utf16_lossy(std::slice::from_raw_parts(text, len))
+    }
+}
+
+/// Copies and frees a string WPD allocated with CoTaskMemAlloc.
+///
+/// # Safety
+/// As for `from_wide`, and `text` must not be used afterwards.
+unsafe fn take_wide(text: *mut u16) -> String {
+    // SAFETY: the caller's guarantees
+    unsafe {
+        let copy = from_wide(text);
+        CoTaskMemFree(text.cast());
+        copy
+    }
+}
+
+/// `Ok` for any success code, S_FALSE included.
+fn check(hr: HRESULT) -> Result<()> {
+    if hr.0 >= 0 {
+        return Ok(());
+    }
+    // Win32 errors come wrapped as 0x8007xxxx
+    Err(match hr.0 as u32 {
+        0x8007_0005 => Error::PermissionDenied,
+        0x8007_0002 | 0x8007_0490 => Error::FileNotFound(hr.message()),
+        0x8007_0070 | 0x8007_0027 => Error::StorageFull(hr.message()),
+        0x8007_0013 => Error::ObjectProtected(hr.message()),
+        0x8007_00AA | 0x8007_0020 => Error::DeviceBusy(hr.message()),
+        0x8007_04C7 => Error::Cancelled,
+        0x8007_048F | 0x8007_001F => Error::DeviceNotFound,
+        0x8007_0032 => Error::Unsupported(hr.message()),
+        _ => Error::Mtp(format!("{} ({:#010x})", hr.message(), hr.0)),
+    })
+}
+
+/// Like `check`'s errors, for a failed file transfer.
+fn transfer_error(e: Error) -> Error {
+    match e {
+        Error::Mtp(message) => Error::TransferFailed(message),
+        other => other,
+    }
+}
@@ -0,0 +1,152 @@
+//! What the host does to a Kindle outside this process: other programs
+//! holding its MTP interface, and how Linux exposes it in sysfs.
+
+use super::kindle::DeviceFilter;
+use crate::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Programs that claim MTP devices as soon as they appear, by process name.
+const GRABBERS: &[(&str, Desktop)] = &[
+    ("gvfsd-mtp", Desktop::Gvfs),
+    ("gvfs-mtp-volume-monitor", Desktop::Gvfs),
+    ("kiod5", Desktop::Kio),
+    ("kiod6", Desktop::Kio),
+    ("Android File Transfer Agent", Desktop::AndroidFileTransfer),
+    ("Android File Transfer", Desktop::AndroidFileTransfer),
+    ("PTPCamera", Desktop::ImageCapture),
+];
+
+/// The desktop service a grabbing program belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Desktop {
+    Gvfs,
+    Kio,
+    AndroidFileTransfer,
+    ImageCapture,
+}
+
+impl Desktop {
+    pub fn label(self) -> &'static str {
+        match self {
+            Desktop::Gvfs => "GNOME (gvfs)",
+            Desktop::Kio => "KDE (kiod)",
+            Desktop::AndroidFileTransfer => "Android File Transfer",
+            Desktop::ImageCapture => "macOS Image Capture",
+        }
+    }
+}
+
+/// A running program that may hold the device.
+#[derive(Debug, Clone)]
+pub(crate) struct Grabber {
+    pub pid: u32,
+    pub name: String,
+    pub desktop: Desktop,
+}
+
+/// Running programs known to open MTP devices behind our back. Uses `ps`,
+/// which reads the same on Linux and macOS; if it can't run, reports none.
+pub(crate) fn mtp_grabbers() -> Vec<Grabber> {
+    let Ok(ps) = Command::new("ps").args(["-A", "-o", "pid=", "-o", "comm="]).output() else {
+        return Vec::new();
+    };
+    String::from_utf8_lossy(&ps.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (pid, command) = line.trim().split_once(char::is_whitespace)?;
+            // macOS prints the full executable path, Linux cuts names
+            // to 15 bytes
+            let name = command.trim().rsplit('/').next()?;
+            let &(grabber, desktop) = GRABBERS.iter().find(|(grabber, _)| {
+                name == *grabber || (name.len() == 15 && grabber.starts_with(name))
+            })?;
+            Some(Grabber {
+                pid: pid.parse().ok()?,
+                name: grabber.to_string(),
+                desktop,
+            })
+        })
+        .collect()
+}
+
+/// Asks gvfs to unmount every MTP mount, which makes `gvfsd-mtp` close its
+/// session with the device. Returns the URIs that were unmounted.
+pub(crate) fn unmount_gvfs() -> Vec<String> {
+    let Ok(list) = Command::new("gio").args(["mount", "-l"]).output() else {
+        return Vec::new();
+    };
+    // e.g. "Mount(0): Kindle -> mtp://Amazon_Kindle_G000XXXXXXXXXXXX/"
+    let mut uris: Vec<String> = String::from_utf8_lossy(&list.stdout)
+        .lines()
+        .filter_map(|line| line.split_once("-> ").map(|(_, uri)| uri.trim()))
+        .filter(|uri| uri.starts_with("mtp://"))
+        .map(str::to_string)
+        .collect();
+    uris.sort();
+    uris.dedup();
+    uris.retain(|uri| {
+        Command::new("gio")
+            .args(["mount", "-u", uri])
+            .status()
+            .is_ok_and(|status| status.success())
+    });
+    uris
+}
+
+/// `Error::DeviceBusy` naming whatever holds the device, or `fallback` if
+/// nothing known is running.
+pub(crate) fn busy_error(fallback: Error) -> Error {
+    let grabbers = mtp_grabbers();
+    let Some(first) = grabbers.first() else {
+        return fallback;
+    };
+    let running: Vec<String> = grabbers
+        .iter()
+        .map(|g| format!("{} (pid {})", g.name, g.pid))
+        .collect();
+    let hint = if first.desktop == Desktop::Gvfs {
+        "eject the Kindle in your file manager or retry with --claim"
+    } else {
+        "close it or see `kindle-mtp doctor`"
+    };
+    Error::DeviceBusy(format!(
+        "held by {}: {}; {}",
+        first.desktop.label(),
+        running.join(", "),
+        hint
+    ))
+}
+
+/// Sysfs directories of the Kindles on the bus (or the devices `filter`
+/// matches), e.g. `/sys/bus/usb/devices/1-2`. Always empty off Linux.
+pub(crate) fn kindle_sysfs_devices(filter: &DeviceFilter) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir("/sys/bus/usb/devices") else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|dir| {
+            // sysfs writes the IDs as four hex digits, e.g. "1949"
+            let id = |name| u16::from_str_radix(&read_attribute(dir, name)?, 16).ok();
+            match (id("idVendor"), id("idProduct")) {
+                (Some(vendor), Some(product)) => filter.matches(vendor, product),
+                _ => false,
+            }
+        })
+        .collect()
+}
+
+/// `/dev/bus/usb/BBB/DDD`, the node libusb opens for a sysfs device.
+pub(crate) fn usb_device_node(sysfs: &Path) -> Option<PathBuf> {
+    let bus: u32 = read_attribute(sysfs, "busnum")?.parse().ok()?;
+    let dev: u32 = read_attribute(sysfs, "devnum")?.parse().ok()?;
+    Some(PathBuf::from(format!("/dev/bus/usb/{:03}/{:03}", bus, dev)))
+}
+
+/// One sysfs attribute of a device, trimmed, e.g. `power/control`.
+pub(crate) fn read_attribute(sysfs: &Path, name: &str) -> Option<String> {
+    std::fs::read_to_string(sysfs.join(name))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
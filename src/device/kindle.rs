@@ -1,153 +1,580 @@
-use crate::error::{Error, Result};
-use libmtp_rs::device::raw::detect_raw_devices;
-use libmtp_rs::device::MtpDevice;
-use libmtp_rs::object::filetypes::Filetype;
-use libmtp_rs::object::Object;
-use libmtp_rs::storage::Parent;
+use super::backend::{Device, MtpBackend};
+use super::host;
+use super::lock::{lock_device, DeviceLock};
+use super::retry::RetryPolicy;
+use super::transfer::{pipelined_download, pipelined_upload, CancelToken, Deadline, TransferOptions};
+use crate::error::{Context, Error, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tracing::{debug, trace};
 
-const AMAZON_VENDOR_ID: u16 = 0x1949;
+pub(crate) const AMAZON_VENDOR_ID: u16 = 0x1949;
+/// Devices `detect` considers; see `prefer_device`.
+static DEVICE_FILTER: OnceLock<DeviceFilter> = OnceLock::new();
+/// Serial number `detect` looks for when several Kindles are connected.
+static PREFERRED_SERIAL: OnceLock<String> = OnceLock::new();
+/// Whether `detect` opens devices cached; see `DetectOptions::cached`.
+static PREFER_CACHED: AtomicBool = AtomicBool::new(false);
+/// Whether `detect` unmounts gvfs first; see `DetectOptions::claim`.
+static PREFER_CLAIM: AtomicBool = AtomicBool::new(false);
+/// Whether `detect` waits for other processes; see `DetectOptions::wait_lock`.
+static PREFER_WAIT_LOCK: AtomicBool = AtomicBool::new(false);
+/// Token `detect` gives every new `Kindle`; see `set_default_cancel_token`.
+static DEFAULT_CANCEL: OnceLock<CancelToken> = OnceLock::new();
+/// Retries `detect` and every new `Kindle` use; see `set_default_retry_policy`.
+static DEFAULT_RETRY: OnceLock<RetryPolicy> = OnceLock::new();
+/// How often `wait_for_device` rescans the USB bus.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, JsonSchema, Deserialize)]
 pub struct KindleInfo {
     pub manufacturer: String,
     pub model: String,
     pub serial: String,
     pub friendly_name: String,
+    /// USB vendor ID; 0x1949 for Kindles
+    #[serde(default)]
+    pub vendor_id: u16,
+    #[serde(default)]
+    pub product_id: u16,
 }
 
-#[derive(Debug, Clone)]
+impl KindleInfo {
+    /// Whether this is an Amazon device, with the Kindle's folder layout.
+    /// Other MTP devices only get the generic file commands.
+    pub fn is_kindle(&self) -> bool {
+        self.vendor_id == AMAZON_VENDOR_ID
+    }
+
+    /// Fails with `Error::Unsupported` unless `is_kindle`, naming `feature`.
+    pub fn require_kindle(&self, feature: &str) -> Result<()> {
+        if self.is_kindle() {
+            return Ok(());
+        }
+        Err(Error::Unsupported(format!(
+            "{} needs a Kindle, not {} {} ({:04x}:{:04x})",
+            feature, self.manufacturer, self.model, self.vendor_id, self.product_id
+        )))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, Deserialize)]
 pub struct StorageInfo {
+    /// MTP storage ID, for `Kindle::select_storage`
+    pub id: u32,
     pub description: String,
     pub total_bytes: u64,
     pub free_bytes: u64,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, JsonSchema, Deserialize)]
 pub struct FileEntry {
     pub name: String,
     pub size: u64,
     pub is_folder: bool,
-    #[allow(dead_code)]
     pub id: u32,
+    pub modified: DateTime<Utc>,
+}
+
+/// A file or folder found while walking a remote tree.
+#[derive(Debug, Clone, Serialize, JsonSchema, Deserialize)]
+pub struct WalkEntry {
+    pub path: String,
+    pub entry: FileEntry,
+}
+
+/// How `Kindle::detect_with` finds and opens the device.
+#[derive(Debug, Clone, Default)]
+pub struct DetectOptions {
+    /// Let libmtp enumerate every object on the device when it is opened.
+    /// Opening takes longer, but later listings are answered from memory,
+    /// which pays off on libraries with thousands of books. Only libmtp
+    /// has such a cache; the Windows and `ptp` backends ignore this.
+    pub cached: bool,
+    /// Open the Kindle with this serial number when several are connected
+    pub serial: Option<String>,
+    /// Ask gvfs to unmount MTP devices first, so a GNOME automount doesn't
+    /// keep the Kindle busy. Does nothing where gvfs isn't running.
+    pub claim: bool,
+    /// Wait for another kindle-mtp process using the device to finish
+    /// instead of failing with `Error::DeviceBusy`
+    pub wait_lock: bool,
+}
+
+/// Which USB devices `detect` opens. The default is any Amazon device;
+/// `--vendor-id`, `--product-id` and `--any-device` widen or narrow it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeviceFilter {
+    /// Vendor to look for instead of Amazon
+    pub vendor_id: Option<u16>,
+    /// Only devices with this product ID
+    pub product_id: Option<u16>,
+    /// Any MTP device, whatever its vendor
+    pub any: bool,
+}
+
+impl DeviceFilter {
+    pub fn matches(&self, vendor_id: u16, product_id: u16) -> bool {
+        let vendor = self.any || vendor_id == self.vendor_id.unwrap_or(AMAZON_VENDOR_ID);
+        vendor && self.product_id.is_none_or(|id| id == product_id)
+    }
+}
+
+/// One MTP object property, named after libmtp's `Property` variant
+/// (on Windows, after the WPD property key).
+#[derive(Debug, Clone, Serialize, JsonSchema, Deserialize)]
+pub struct ObjectProperty {
+    pub name: String,
+    pub value: PropertyValue,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, Deserialize)]
+#[serde(untagged)]
+pub enum PropertyValue {
+    Integer(u64),
+    Text(String),
+}
+
+/// What the device's DeviceInfo dataset says it supports, as MTP codes.
+#[derive(Debug, Clone, Default, Serialize, JsonSchema, Deserialize)]
+pub struct MtpCapabilities {
+    /// Vendor extension description, e.g. "microsoft.com: 1.0;"
+    pub vendor_extension: String,
+    pub operations: Vec<u16>,
+    pub events: Vec<u16>,
+    pub device_properties: Vec<u16>,
+    pub capture_formats: Vec<u16>,
+    pub playback_formats: Vec<u16>,
+}
+
+/// The device's answer to `Kindle::raw_operation`.
+#[derive(Debug, Clone, Serialize, JsonSchema, Deserialize)]
+pub struct RawResponse {
+    /// Response code; 0x2001 is OK
+    pub code: u16,
+    pub params: Vec<u32>,
+    /// The data phase, empty if the device sent none
+    pub data: Vec<u8>,
 }
 
 pub struct Kindle {
-    device: MtpDevice,
+    device: Device,
+    transfer: TransferOptions,
+    /// Storage every path refers to: the first one when the device was
+    /// opened, or the one `select_storage` picked. `None` if there was none.
+    storage: Option<u32>,
+    cancel: CancelToken,
+    retry: RetryPolicy,
+    /// Entries seen in folder listings, by absolute path, so resolving a
+    /// path only lists the levels not seen yet. Operations that change the
+    /// tree through this `Kindle` drop what they touch.
+    paths: RefCell<HashMap<String, FileEntry>>,
+    /// Two MTP sessions with one device corrupt each other, so other
+    /// processes stay off it while this is held.
+    _lock: DeviceLock,
 }
 
 impl Kindle {
+    /// Opens the connected Kindle, or the one `prefer_serial` names when
+    /// there are several, cached if `prefer_cached` asked for it.
     pub fn detect() -> Result<Self> {
-        let raw_devices = detect_raw_devices().map_err(|e| {
-            let err_str = format!("{}", e);
-            if err_str.contains("NoDeviceAttached") {
-                Error::DeviceNotFound
-            } else {
-                Error::Mtp(err_str)
+        Self::detect_with(DetectOptions {
+            cached: PREFER_CACHED.load(Ordering::Relaxed),
+            serial: PREFERRED_SERIAL.get().cloned(),
+            claim: PREFER_CLAIM.load(Ordering::Relaxed),
+            wait_lock: PREFER_WAIT_LOCK.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Opens a Kindle as `options` say. A Kindle that was just plugged in
+    /// often refuses the first session, so transient failures are retried
+    /// as `set_default_retry_policy` allows.
+    ///
+    /// The device is locked against other processes until the `Kindle` is
+    /// dropped: the one with `options.serial`, or without a serial every
+    /// Kindle, since any of them might be the one opened.
+    pub fn detect_with(options: DetectOptions) -> Result<Self> {
+        let lock = lock_device(options.serial.as_deref(), options.wait_lock)?;
+        let cancel = DEFAULT_CANCEL.get().cloned().unwrap_or_default();
+        let retry = DEFAULT_RETRY.get().copied().unwrap_or_default();
+        if options.claim {
+            for uri in host::unmount_gvfs() {
+                debug!(%uri, "unmounted from gvfs");
             }
-        })?;
+        }
+        let filter = Self::device_filter();
+        let device = retry.run("open", &cancel, || Device::open(&options, &filter))?;
+        let storage = device.storages().first().map(|storage| storage.id);
+        debug!(model = %device.info().model, ?storage, "opened device");
 
-        let kindle_raw = raw_devices
-            .into_iter()
-            .find(|d| d.device_entry().vendor_id == AMAZON_VENDOR_ID)
-            .ok_or(Error::DeviceNotFound)?;
+        Ok(Self {
+            device,
+            transfer: TransferOptions::default(),
+            storage,
+            cancel,
+            retry,
+            paths: RefCell::default(),
+            _lock: lock,
+        })
+    }
 
-        let device = kindle_raw.open_uncached().ok_or(Error::DeviceNotFound)?;
+    /// `detect`, then `set_transfer_options`. Commands open the device once
+    /// this way and pass the `Kindle` to every step.
+    pub fn open(transfer: TransferOptions) -> Result<Self> {
+        let mut kindle = Self::detect()?;
+        kindle.set_transfer_options(transfer);
+        Ok(kindle)
+    }
 
-        Ok(Self { device })
+    /// Makes `detect` pick the Kindle with this serial number. Set once at
+    /// startup, from `--serial` or the config file.
+    pub fn prefer_serial(serial: String) {
+        let _ = PREFERRED_SERIAL.set(serial);
     }
 
-    pub fn info(&self) -> KindleInfo {
-        KindleInfo {
-            manufacturer: self
-                .device
-                .manufacturer_name()
-                .unwrap_or_else(|_| "Unknown".to_string()),
-            model: self
-                .device
-                .model_name()
-                .unwrap_or_else(|_| "Unknown".to_string()),
-            serial: self
-                .device
-                .serial_number()
-                .unwrap_or_else(|_| "".to_string()),
-            friendly_name: self
-                .device
-                .get_friendly_name()
-                .unwrap_or_else(|_| "Kindle".to_string()),
+    /// Makes `detect` open the devices `filter` matches instead of only
+    /// Kindles. Set once at startup, from `--vendor-id`, `--product-id` and
+    /// `--any-device`.
+    pub fn prefer_device(filter: DeviceFilter) {
+        let _ = DEVICE_FILTER.set(filter);
+    }
+
+    pub(crate) fn device_filter() -> DeviceFilter {
+        DEVICE_FILTER.get().copied().unwrap_or_default()
+    }
+
+    /// Makes `detect` open devices cached. Set once at startup, from
+    /// `performance.cached_open` in the config file.
+    pub fn prefer_cached(cached: bool) {
+        PREFER_CACHED.store(cached, Ordering::Relaxed);
+    }
+
+    /// Makes `detect` unmount the Kindle from gvfs before opening it. Set
+    /// once at startup, from `--claim`.
+    pub fn prefer_claim(claim: bool) {
+        PREFER_CLAIM.store(claim, Ordering::Relaxed);
+    }
+
+    /// Makes `detect` wait while another process has the device open. Set
+    /// once at startup, from `--wait-lock`.
+    pub fn prefer_wait_lock(wait: bool) {
+        PREFER_WAIT_LOCK.store(wait, Ordering::Relaxed);
+    }
+
+    /// Makes `detect` attach this token to every `Kindle` it opens, so one
+    /// Ctrl-C handler installed at startup reaches whichever is in use.
+    pub fn set_default_cancel_token(token: CancelToken) {
+        let _ = DEFAULT_CANCEL.set(token);
+    }
+
+    /// Makes `detect` and every `Kindle` it opens retry transient failures
+    /// this way. Set once at startup, from `--retries`/`--retry-delay` or the
+    /// config file; `RetryPolicy::default()` applies otherwise.
+    pub fn set_default_retry_policy(policy: RetryPolicy) {
+        let _ = DEFAULT_RETRY.set(policy);
+    }
+
+    pub(crate) fn default_cancel_token() -> Option<&'static CancelToken> {
+        DEFAULT_CANCEL.get()
+    }
+
+    pub(crate) fn preferred_serial() -> Option<&'static str> {
+        PREFERRED_SERIAL.get().map(String::as_str)
+    }
+
+    /// Whether a Kindle (or what `prefer_device` asked for) is on the USB
+    /// bus, without opening a session. Any error counts as "not there";
+    /// `detect()` reports the details.
+    pub fn is_present() -> bool {
+        Device::is_present(&Self::device_filter())
+    }
+
+    /// Blocks until a Kindle shows up on the bus, polling the raw device list
+    /// (cheap: no session is opened). `None` waits forever. `on_wait` runs
+    /// once, only if the device isn't there on the first scan.
+    pub fn wait_for_device(timeout: Option<Duration>, on_wait: impl FnOnce()) -> Result<()> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut on_wait = Some(on_wait);
+        loop {
+            if Self::is_present() {
+                return Ok(());
+            }
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                return Err(Error::DeviceNotFound);
+            }
+            if let Some(notify) = on_wait.take() {
+                notify();
+            }
+            std::thread::sleep(WAIT_POLL_INTERVAL);
         }
     }
 
+    /// Sets the chunk size and pipelining used by later transfers.
+    pub fn set_transfer_options(&mut self, transfer: TransferOptions) {
+        self.transfer = transfer;
+    }
+
+    /// The options transfers currently use.
+    pub fn transfer_options(&self) -> TransferOptions {
+        self.transfer
+    }
+
+    /// Lets `token` stop later downloads, uploads and walks. Once it is
+    /// cancelled they fail with `Error::Cancelled`; create a new token to
+    /// use the device again.
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = token;
+    }
+
+    /// Sets how downloads retry transient failures. Uploads, deletes and
+    /// renames are never retried: a failed attempt may have half happened.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
+
+    /// Manufacturer, model, serial number and friendly name.
+    pub fn info(&self) -> KindleInfo {
+        self.device.info()
+    }
+
+    /// The storage paths currently refer to.
     pub fn storage_info(&self) -> Result<StorageInfo> {
-        let storage_pool = self.device.storage_pool();
-        let (_, storage) = storage_pool
-            .iter()
-            .next()
-            .ok_or_else(|| Error::Mtp("No storage found".to_string()))?;
-
-        Ok(StorageInfo {
-            description: storage.description().unwrap_or("Internal Storage").to_string(),
-            total_bytes: storage.maximum_capacity(),
-            free_bytes: storage.free_space_in_bytes(),
-        })
+        let id = self.storage()?;
+        self.device
+            .storages()
+            .into_iter()
+            .find(|storage| storage.id == id)
+            .ok_or_else(|| Error::Mtp("No storage found".to_string()))
     }
 
-    pub fn list_files(&self, path: &str) -> Result<Vec<FileEntry>> {
-        let storage_pool = self.device.storage_pool();
-        let (_, storage) = storage_pool
-            .iter()
-            .next()
-            .ok_or_else(|| Error::Mtp("No storage found".to_string()))?;
+    /// Every storage on the device, in the order the device reports them.
+    /// Kindles have one; other MTP devices may add an SD card.
+    pub fn storages(&self) -> Vec<StorageInfo> {
+        self.device.storages()
+    }
+
+    /// Makes every later path refer to the storage with this ID (see
+    /// `storages`) instead of the first one.
+    pub fn select_storage(&mut self, id: u32) -> Result<()> {
+        if !self.device.storages().iter().any(|storage| storage.id == id) {
+            return Err(Error::InvalidPath(format!("no storage with ID {:#x}", id)));
+        }
+        self.storage = Some(id);
+        Ok(())
+    }
+
+    /// The ID of the selected storage.
+    fn storage(&self) -> Result<u32> {
+        self.storage.ok_or_else(|| Error::Mtp("No storage found".to_string()))
+    }
 
+    /// Lists the direct children of a folder; `/` is the storage root.
+    pub fn list_files(&self, path: &str) -> Result<Vec<FileEntry>> {
         let parent = if path == "/" || path.is_empty() {
-            Parent::Root
+            None
         } else {
-            let obj_id = self.resolve_path(path)?;
-            Parent::Folder(obj_id)
+            Some(self.resolve_path(path)?)
         };
 
-        let files = storage.files_and_folders(parent);
-        Ok(files
-            .into_iter()
-            .map(|f| FileEntry {
-                name: f.name().to_string(),
-                size: f.size(),
-                is_folder: matches!(f.ftype(), Filetype::Folder),
-                id: f.id(),
-            })
-            .collect())
+        let entries = self.list_children(parent)?;
+        self.remember(path, &entries);
+        Ok(entries)
     }
 
+    /// The MTP object ID of a path.
     pub fn resolve_path(&self, path: &str) -> Result<u32> {
-        let storage_pool = self.device.storage_pool();
-        let (_, storage) = storage_pool
-            .iter()
-            .next()
-            .ok_or_else(|| Error::Mtp("No storage found".to_string()))?;
-
-        let path = path.trim_start_matches('/');
-        if path.is_empty() {
-            return Err(Error::InvalidPath("Cannot resolve root path to ID".to_string()));
+        self.resolve_entry(path).map(|entry| entry.id)
+    }
+
+    /// Lists everything below `root`, depth-first with each folder before its
+    /// contents. Children are listed by object ID, so paths are resolved once.
+    pub fn walk(&self, root: &str) -> Result<Vec<WalkEntry>> {
+        let mut entries = Vec::new();
+        let deadline = Deadline::after(self.transfer.timeout);
+        self.walk_from(root, deadline, &mut |entry| {
+            entries.push(entry);
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+
+    /// Like `walk`, handing each entry to `visit` as soon as its folder is
+    /// listed, so work on it (a download) can start before the whole tree
+    /// is known. An error from `visit` ends the walk. The timeout applies
+    /// to each listing rather than the walk, as `visit` takes its own time.
+    pub fn walk_each<F>(&self, root: &str, mut visit: F) -> Result<()>
+    where
+        F: FnMut(WalkEntry) -> Result<()>,
+    {
+        self.walk_from(root, Deadline::after(None), &mut visit)
+    }
+
+    fn walk_from(&self, root: &str, deadline: Deadline, visit: &mut dyn FnMut(WalkEntry) -> Result<()>) -> Result<()> {
+        let root_path = format!("/{}", root.trim_matches('/'));
+        let parent = if root_path == "/" {
+            None
+        } else {
+            let entry = self.resolve_entry(&root_path)?;
+            if !entry.is_folder {
+                return Err(Error::InvalidPath(format!("'{}' is not a directory", root)));
+            }
+            Some(entry.id)
+        };
+        self.walk_into(parent, &root_path, deadline, visit)
+    }
+
+    fn walk_into(
+        &self,
+        parent: Option<u32>,
+        path: &str,
+        deadline: Deadline,
+        visit: &mut dyn FnMut(WalkEntry) -> Result<()>,
+    ) -> Result<()> {
+        self.cancel.check()?;
+        deadline.check(&format!("walking {}", path))?;
+        let children = self.list_children(parent)?;
+        self.remember(path, &children);
+        for entry in children {
+            let child_path = format!("{}/{}", path.trim_end_matches('/'), entry.name);
+            let folder_id = entry.is_folder.then_some(entry.id);
+            visit(WalkEntry {
+                path: child_path.clone(),
+                entry,
+            })?;
+            if let Some(id) = folder_id {
+                self.walk_into(Some(id), &child_path, deadline, visit)?;
+            }
         }
+        Ok(())
+    }
+
+    /// Looks up a single entry by path.
+    pub fn stat(&self, path: &str) -> Result<FileEntry> {
+        self.resolve_entry(path)
+    }
+
+    /// Reads every MTP property the device says it supports for the
+    /// object's format. Each is one GetObjectPropValue round trip, so this
+    /// is for inspection, not for listings.
+    pub fn object_properties(&self, path: &str) -> Result<Vec<ObjectProperty>> {
+        let read = || -> Result<Vec<ObjectProperty>> {
+            let (parent_path, _) = split_remote_path(path)?;
+            let parent = if parent_path == "/" {
+                None
+            } else {
+                Some(self.resolve_path(parent_path)?)
+            };
+            let id = self.resolve_path(path)?;
+            self.device.object_properties(self.storage()?, parent, id)
+        };
+        read().context("read properties of", path)
+    }
+
+    /// Fetches the device's thumbnail of a file (MTP GetThumb), usually a
+    /// small JPEG of a book's cover. Much cheaper than downloading the book.
+    pub fn thumbnail(&self, path: &str) -> Result<Vec<u8>> {
+        let fetch = || -> Result<Vec<u8>> {
+            let id = self.resolve_path(path)?;
+            debug!(path, id, "fetching thumbnail");
+            self.device.thumbnail(id)
+        };
+        fetch().context("fetch thumbnail of", path)
+    }
+
+    /// Reads up to `len` bytes of a file from `offset` (MTP
+    /// GetPartialObject), fewer at the end of the file. For headers and
+    /// single records of large files.
+    pub fn read_range(&self, path: &str, offset: u64, len: u32) -> Result<Vec<u8>> {
+        let read = || -> Result<Vec<u8>> {
+            let entry = self.resolve_entry(path)?;
+            if entry.is_folder {
+                return Err(Error::InvalidPath(format!("'{}' is a folder", path)));
+            }
+            if offset >= entry.size {
+                return Ok(Vec::new());
+            }
+            let len = len.min(u32::try_from(entry.size - offset).unwrap_or(u32::MAX));
+            trace!(path, id = entry.id, offset, len, "partial read");
+            self.device.read_partial(self.storage()?, entry.id, offset, len)
+        };
+        read().context("read", path)
+    }
+
+    /// The operations, events, properties and formats the device lists in
+    /// its DeviceInfo dataset. Only the `ptp` backend can read it.
+    pub fn capabilities(&self) -> Result<MtpCapabilities> {
+        self.device.capabilities()
+    }
 
+    /// Issues MTP operation `code` as is, reading the data phase if the
+    /// device sends one. A failing response code is returned, not turned
+    /// into an error. For investigating device quirks: the path cache is
+    /// dropped, since nothing tells what the operation changed. Only the
+    /// `ptp` backend can do this.
+    pub fn raw_operation(&self, code: u16, params: &[u32]) -> Result<RawResponse> {
+        debug!(code = format_args!("{:#06x}", code), ?params, "raw MTP operation");
+        self.clear_path_cache();
+        self.device.raw_operation(code, params)
+    }
+
+    /// Walks `path` one segment at a time, listing only the folders on the way
+    /// down. Each level is converted to `FileEntry` immediately so libmtp's
+    /// per-object structs are released before the next request goes out.
+    fn resolve_entry(&self, path: &str) -> Result<FileEntry> {
         let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
-        let mut current_parent = Parent::Root;
+        if parts.is_empty() {
+            return Err(Error::InvalidPath("Cannot resolve root path to ID".to_string()));
+        }
+        let key = format!("/{}", parts.join("/"));
+        if let Some(entry) = self.paths.borrow().get(&key) {
+            trace!(path, id = entry.id, "resolved from path cache");
+            return Ok(entry.clone());
+        }
+
+        // Start listing below the deepest folder already known
+        let mut start = 0;
+        let mut current_parent = None;
+        {
+            let paths = self.paths.borrow();
+            for depth in (1..parts.len()).rev() {
+                if let Some(folder) = paths.get(&format!("/{}", parts[..depth].join("/")))
+                    && folder.is_folder
+                {
+                    start = depth;
+                    current_parent = Some(folder.id);
+                    break;
+                }
+            }
+        }
+        debug!(path, segments = parts.len(), cached = start, "resolving path");
 
-        for (i, part) in parts.iter().enumerate() {
-            let files = storage.files_and_folders(current_parent);
-            let found = files.into_iter().find(|f| f.name() == *part);
+        for (i, part) in parts.iter().enumerate().skip(start) {
+            let children = self.list_children(current_parent)?;
+            self.remember(&format!("/{}", parts[..i].join("/")), &children);
+            let found = children.into_iter().find(|f| f.name == *part);
+            trace!(segment = part, id = ?found.as_ref().map(|f| f.id), "resolved segment");
 
             match found {
-                Some(f) => {
+                Some(entry) => {
                     if i == parts.len() - 1 {
-                        return Ok(f.id());
+                        return Ok(entry);
                     }
-                    if !matches!(f.ftype(), Filetype::Folder) {
+                    if !entry.is_folder {
                         return Err(Error::InvalidPath(format!(
                             "'{}' is not a directory",
                             part
                         )));
                     }
-                    current_parent = Parent::Folder(f.id());
+                    current_parent = Some(entry.id);
                 }
                 None => {
                     return Err(Error::FileNotFound(format!("'{}' not found in path", part)));
@@ -158,20 +585,303 @@ impl Kindle {
         Err(Error::InvalidPath("Path resolution failed".to_string()))
     }
 
-    pub fn download_file(&self, remote_path: &str, local_path: &std::path::Path) -> Result<()> {
-        let file_id = self.resolve_path(remote_path)?;
+    /// Caches the children of `folder` from a fresh listing. With duplicate
+    /// names the first one wins, as in `resolve_entry`.
+    fn remember(&self, folder: &str, children: &[FileEntry]) {
+        let folder = folder.trim_end_matches('/');
+        let mut paths = self.paths.borrow_mut();
+        for child in children.iter().rev() {
+            paths.insert(format!("{}/{}", folder, child.name), child.clone());
+        }
+    }
+
+    /// Drops `path` and everything below it from the path cache.
+    fn forget(&self, path: &str) {
+        let key = format!("/{}", path.trim_matches('/'));
+        let below = format!("{}/", key);
+        self.paths
+            .borrow_mut()
+            .retain(|cached, _| *cached != key && !cached.starts_with(&below));
+    }
+
+    /// Forgets every cached path. Long-lived users (the daemon) call this
+    /// when the device may have changed behind their back, e.g. on the
+    /// Kindle itself.
+    pub fn clear_path_cache(&self) {
+        self.paths.borrow_mut().clear();
+    }
+
+    /// Lists the direct children of one folder, `None` being the root.
+    fn list_children(&self, parent: Option<u32>) -> Result<Vec<FileEntry>> {
+        let entries = self.device.list_children(self.storage()?, parent)?;
+        trace!(?parent, count = entries.len(), "listed folder");
+        Ok(entries)
+    }
+
+    /// Downloads `remote_path` into the local file `local_path`.
+    pub fn download_file(&self, remote_path: &str, local_path: &Path) -> Result<()> {
+        self.download_file_with_progress(remote_path, local_path, |_, _| {})
+    }
+
+    /// Like `download_file`, calling `progress(bytes written, file size)`
+    /// after each chunk reaches the local file. It runs on the transfer's
+    /// worker thread, so it should only record or forward the numbers.
+    ///
+    /// A transient failure starts the file over, as the retry policy allows.
+    pub fn download_file_with_progress<P>(&self, remote_path: &str, local_path: &Path, mut progress: P) -> Result<()>
+    where
+        P: FnMut(u64, u64) + Send,
+    {
+        let download = || -> Result<()> {
+            let mut file = File::create(local_path)?;
+            self.stream_file(remote_path, |chunk| file.write_all(chunk), &mut progress)?;
+            file.flush()?;
+            Ok(())
+        };
+        self.retry
+            .run("download", &self.cancel, download)
+            .context("download", remote_path)
+    }
+
+    /// Downloads `remote_path` into any writer, such as stdout or a socket,
+    /// without a temporary file. Returns the number of bytes written.
+    pub fn download_to_writer<W>(&self, remote_path: &str, writer: &mut W) -> Result<u64>
+    where
+        W: Write + Send,
+    {
+        self.stream_file(remote_path, |chunk| writer.write_all(chunk), |_, _| {})
+            .and_then(|bytes| writer.flush().map(|()| bytes).map_err(Error::from))
+            .context("download", remote_path)
+    }
+
+    /// Streams a remote file through `on_chunk` without touching the local disk.
+    /// Chunks are delivered on a worker thread while the next one is read over
+    /// USB. Returns the number of bytes read.
+    pub fn read_file<F>(&self, remote_path: &str, on_chunk: F) -> Result<u64>
+    where
+        F: FnMut(&[u8]) -> std::io::Result<()> + Send,
+    {
+        self.stream_file(remote_path, on_chunk, |_, _| {})
+            .context("read", remote_path)
+    }
 
-        let storage_pool = self.device.storage_pool();
-        let (_, storage) = storage_pool
-            .iter()
-            .next()
-            .ok_or_else(|| Error::Mtp("No storage found".to_string()))?;
+    fn stream_file<F, P>(&self, remote_path: &str, mut on_chunk: F, mut progress: P) -> Result<u64>
+    where
+        F: FnMut(&[u8]) -> std::io::Result<()> + Send,
+        P: FnMut(u64, u64) + Send,
+    {
+        let entry = self.resolve_entry(remote_path)?;
+        let (file_id, total) = (entry.id, entry.size);
+        debug!(remote_path, id = file_id, "download started");
+        let started = Instant::now();
 
-        storage
-            .get_file_to_path(file_id, local_path)
-            .map_err(|e| Error::TransferFailed(format!("{}", e)))?;
+        let storage = self.storage()?;
+        let mut bytes = 0u64;
+        let deadline = Deadline::after(self.transfer.timeout);
+        pipelined_download(
+            self.transfer,
+            |sink| {
+                self.device
+                    .get_file(storage, file_id, &mut |chunk| {
+                        !self.cancel.is_cancelled() && !deadline.passed() && sink.push(chunk)
+                    })
+                    .map_err(|e| self.transfer_error(e, deadline))
+            },
+            |chunk| {
+                on_chunk(chunk)?;
+                bytes += chunk.len() as u64;
+                progress(bytes, total);
+                Ok(())
+            },
+        )?;
 
+        debug!(remote_path, bytes, elapsed_ms = started.elapsed().as_millis() as u64, "download finished");
+        Ok(bytes)
+    }
+
+    /// A failed transfer, `Error::Cancelled` if the token stopped it, or
+    /// `Error::Timeout` if it ran past `deadline`.
+    fn transfer_error(&self, e: Error, deadline: Deadline) -> Error {
+        if self.cancel.is_cancelled() {
+            Error::Cancelled
+        } else if deadline.passed() {
+            // libmtp aborted the transfer cleanly, but an upload may have
+            // left a partial object: list folders afresh from here on
+            self.clear_path_cache();
+            Error::Timeout(format!("transfer took longer than {:?}", self.transfer.timeout.unwrap_or_default()))
+        } else {
+            e
+        }
+    }
+
+    /// Uploads a local file to `remote_path`, which must include the target file name.
+    pub fn upload_file(&self, local_path: &Path, remote_path: &str) -> Result<()> {
+        self.upload_file_with_progress(local_path, remote_path, |_, _| {})
+    }
+
+    /// Like `upload_file`, calling `progress(bytes sent, file size)` each
+    /// time the backend takes another block for the device.
+    pub fn upload_file_with_progress<P>(&self, local_path: &Path, remote_path: &str, progress: P) -> Result<()>
+    where
+        P: FnMut(u64, u64),
+    {
+        self.send_file(local_path, remote_path, progress)
+            .context("upload", remote_path)
+    }
+
+    /// Uploads `size` bytes read from `reader` to `remote_path`, e.g. stdin
+    /// or an HTTP request body. MTP announces the size before the first
+    /// byte, so it must be exact.
+    pub fn upload_from_reader<R>(&self, remote_path: &str, reader: &mut R, size: u64) -> Result<()>
+    where
+        R: Read + Send,
+    {
+        debug!(remote_path, bytes = size, "upload from reader started");
+        self.send_stream(reader, size, Utc::now(), remote_path, |_, _| {})
+            .context("upload", remote_path)
+    }
+
+    fn send_file(&self, local_path: &Path, remote_path: &str, progress: impl FnMut(u64, u64)) -> Result<()> {
+        let metadata = std::fs::metadata(local_path)?;
+        let modified = metadata
+            .modified()
+            .map(DateTime::<Utc>::from)
+            .unwrap_or_else(|_| Utc::now());
+        debug!(local = %local_path.display(), remote_path, bytes = metadata.len(), "upload started");
+        let file = File::open(local_path)?;
+        self.send_stream(file, metadata.len(), modified, remote_path, progress)
+    }
+
+    fn send_stream<R: Read + Send>(
+        &self,
+        reader: R,
+        size: u64,
+        modified: DateTime<Utc>,
+        remote_path: &str,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<()> {
+        let (parent_path, name) = split_remote_path(remote_path)?;
+        self.forget(remote_path);
+
+        let storage = self.storage()?;
+        let parent = if parent_path == "/" {
+            None
+        } else {
+            Some(self.resolve_path(parent_path)?)
+        };
+
+        let started = Instant::now();
+        let deadline = Deadline::after(self.transfer.timeout);
+        let (total, mut sent) = (size, 0u64);
+        pipelined_upload(self.transfer, reader, |source| {
+            let mut fill = |buf: &mut [u8]| {
+                if self.cancel.is_cancelled() || deadline.passed() {
+                    return None;
+                }
+                let n = source.fill(buf)?;
+                sent += n as u64;
+                progress(sent, total);
+                Some(n)
+            };
+            self.device
+                .send_file(storage, parent, name, size, modified, &mut fill)
+                .map_err(|e| self.transfer_error(e, deadline))
+        })?;
+
+        debug!(remote_path, elapsed_ms = started.elapsed().as_millis() as u64, "upload finished");
+        Ok(())
+    }
+
+    /// Creates a single folder; the parent must already exist. Returns the new object ID.
+    pub fn create_folder(&self, remote_path: &str) -> Result<u32> {
+        let (parent_path, name) = split_remote_path(remote_path)?;
+
+        let parent = if parent_path == "/" {
+            None
+        } else {
+            Some(
+                self.resolve_path(parent_path)
+                    .context("create folder", remote_path)?,
+            )
+        };
+
+        debug!(remote_path, "creating folder");
+        self.device
+            .create_folder(self.storage()?, parent, name)
+            .context("create folder", remote_path)
+    }
+
+    /// Creates `remote_path` and any missing parents, like `mkdir -p`.
+    pub fn create_folder_all(&self, remote_path: &str) -> Result<()> {
+        let mut current = String::new();
+        for part in remote_path.split('/').filter(|s| !s.is_empty()) {
+            current = format!("{}/{}", current, part);
+            match self.stat(&current) {
+                Ok(e) if e.is_folder => {}
+                Ok(_) => {
+                    return Err(Error::InvalidPath(format!("'{}' is not a directory", current)));
+                }
+                Err(Error::FileNotFound(_)) => {
+                    self.create_folder(&current)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
         Ok(())
     }
 
+    /// Deletes a file or an empty folder.
+    pub fn delete(&self, remote_path: &str) -> Result<()> {
+        let id = self.resolve_path(remote_path).context("delete", remote_path)?;
+        debug!(remote_path, id, "deleting");
+        self.forget(remote_path);
+        self.device.delete(id).context("delete", remote_path)
+    }
+
+    /// Renames or moves a file or folder. `to` must not exist yet. Moving
+    /// between folders needs MTP MoveObject, which not every device supports.
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let rename = || -> Result<()> {
+            let (from_parent, from_name) = split_remote_path(from)?;
+            let (to_parent, to_name) = split_remote_path(to)?;
+            let id = self.resolve_path(from)?;
+            debug!(from, to, id, "renaming");
+            if from_parent != to_parent {
+                let parent = if to_parent == "/" {
+                    None
+                } else {
+                    Some(self.resolve_path(to_parent)?)
+                };
+                self.device.move_object(id, self.storage()?, parent)?;
+            }
+            if from_name != to_name {
+                self.device.rename_object(id, to_name)?;
+            }
+            Ok(())
+        };
+        let renamed = rename();
+        self.forget(from);
+        self.forget(to);
+        renamed.context("rename", from)
+    }
+
+    /// Moves a file or folder into `folder`, keeping its name.
+    pub fn move_into(&self, path: &str, folder: &str) -> Result<()> {
+        let (_, name) = split_remote_path(path)?;
+        let to = format!("{}/{}", folder.trim_end_matches('/'), name);
+        self.rename(path, &to)
+    }
+}
+
+/// Splits a remote path into its parent folder and final component,
+/// e.g. `/documents/book.azw3` -> (`/documents`, `book.azw3`).
+fn split_remote_path(path: &str) -> Result<(&str, &str)> {
+    let trimmed = path.trim_end_matches('/');
+    match trimmed.rsplit_once('/') {
+        Some((parent, name)) if !name.is_empty() => {
+            Ok((if parent.is_empty() { "/" } else { parent }, name))
+        }
+        None if !trimmed.is_empty() => Ok(("/", trimmed)),
+        _ => Err(Error::InvalidPath(format!("'{}' has no file name", path))),
+    }
 }
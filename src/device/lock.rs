@@ -0,0 +1,144 @@
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard, Weak};
+use tracing::debug;
+
+/// Lock file taken by every session: exclusively when the serial isn't
+/// known up front (the session may end up on any Kindle), shared otherwise.
+const ANY_DEVICE: &str = "any";
+
+/// Lock files this process already holds, so a second `Kindle` for the same
+/// device (the daemon's, a command's) shares them instead of deadlocking.
+static HELD: LazyLock<Mutex<HashMap<String, Weak<File>>>> = LazyLock::new(Mutex::default);
+
+/// Keeps other kindle-mtp processes off the device until dropped.
+pub(crate) struct DeviceLock {
+    _files: Vec<Arc<File>>,
+}
+
+/// Locks the Kindle with `serial`, or every Kindle without one. Sessions
+/// with different serials can run side by side; anything else waits for
+/// the other process if `wait`, and fails with `Error::DeviceBusy` if not.
+pub(crate) fn lock_device(serial: Option<&str>, wait: bool) -> Result<DeviceLock> {
+    let Some(dir) = lock_dir() else {
+        debug!("no directory for lock files, not locking the device");
+        return Ok(DeviceLock { _files: Vec::new() });
+    };
+    std::fs::create_dir_all(&dir)?;
+    let files = match serial {
+        None => vec![lock_file(&dir, ANY_DEVICE, true, wait)?],
+        Some(serial) => vec![
+            lock_file(&dir, ANY_DEVICE, false, wait)?,
+            lock_file(&dir, &serial_file_name(serial), true, wait)?,
+        ],
+    };
+    Ok(DeviceLock { _files: files })
+}
+
+/// A serial number made safe to use as a file name. Serials are alphanumeric
+/// on every Kindle so far; anything else is replaced.
+pub(crate) fn serial_file_name(serial: &str) -> String {
+    serial
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+/// `$XDG_RUNTIME_DIR/kindle-mtp/locks` where there is one, so stale files
+/// vanish at logout, else under the platform data directory.
+fn lock_dir() -> Option<PathBuf> {
+    dirs::runtime_dir()
+        .or_else(dirs::data_local_dir)
+        .map(|dir| dir.join("kindle-mtp").join("locks"))
+}
+
+fn lock_file(dir: &Path, name: &str, exclusive: bool, wait: bool) -> Result<Arc<File>> {
+    if let Some(file) = held().get(name).and_then(Weak::upgrade) {
+        return Ok(file);
+    }
+
+    let path = dir.join(format!("{}.lock", name));
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+    if !flock(&file, exclusive, false)? {
+        // The daemon keeps its session until stopped, so waiting wouldn't end
+        if daemon_running() {
+            return Err(Error::DeviceBusy(
+                "`kindle-mtp daemon` has the Kindle open and this command needs it directly; stop the daemon first"
+                    .to_string(),
+            ));
+        }
+        if !wait {
+            return Err(Error::DeviceBusy(format!(
+                "another kindle-mtp{} is using the Kindle; pass --wait-lock to wait for it",
+                holder(&mut file).map(|pid| format!(" (pid {})", pid)).unwrap_or_default()
+            )));
+        }
+        debug!(path = %path.display(), "waiting for another kindle-mtp");
+        flock(&file, exclusive, true)?;
+    }
+    if exclusive {
+        // Only an exclusive holder is alone, so only it can name itself
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+    }
+    debug!(path = %path.display(), exclusive, "locked device");
+
+    let file = Arc::new(file);
+    held().insert(name.to_string(), Arc::downgrade(&file));
+    Ok(file)
+}
+
+fn held() -> MutexGuard<'static, HashMap<String, Weak<File>>> {
+    HELD.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The pid the exclusive holder wrote, if it is one.
+fn holder(file: &mut File) -> Option<u32> {
+    let mut pid = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut pid).ok()?;
+    pid.trim().parse().ok()
+}
+
+#[cfg(unix)]
+fn daemon_running() -> bool {
+    crate::daemon::is_running()
+}
+
+#[cfg(not(unix))]
+fn daemon_running() -> bool {
+    false
+}
+
+/// Takes an advisory lock on the whole file. Returns false if `wait` is off
+/// and another process holds a conflicting lock.
+#[cfg(unix)]
+fn flock(file: &File, exclusive: bool, wait: bool) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    let mut operation = if exclusive { libc::LOCK_EX } else { libc::LOCK_SH };
+    if !wait {
+        operation |= libc::LOCK_NB;
+    }
+    // SAFETY: flock only takes the descriptor, which `file` keeps open
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        return Ok(true);
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EWOULDBLOCK) => Ok(false),
+        _ => Err(error),
+    }
+}
+
+#[cfg(not(unix))]
+fn flock(_file: &File, _exclusive: bool, _wait: bool) -> io::Result<bool> {
+    Ok(true)
+}
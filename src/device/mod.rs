@@ -1,3 +1,13 @@
+pub(crate) mod host;
+mod backend;
 mod kindle;
+pub(crate) mod lock;
+mod retry;
+mod transfer;
 
-pub use kindle::{FileEntry, Kindle};
+pub use kindle::{
+    DetectOptions, DeviceFilter, FileEntry, Kindle, KindleInfo, MtpCapabilities, ObjectProperty, PropertyValue, RawResponse,
+    StorageInfo, WalkEntry,
+};
+pub use retry::RetryPolicy;
+pub use transfer::{bytes_transferred, CancelToken, TransferOptions, DEFAULT_CHUNK_SIZE};
@@ -0,0 +1,52 @@
+use super::transfer::CancelToken;
+use crate::error::Result;
+use std::time::Duration;
+use tracing::debug;
+
+/// How often to repeat a device call that failed with a transient error,
+/// see `Error::is_transient`. The delay doubles after every attempt.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Attempts after the first one; 0 disables retrying
+    pub retries: u32,
+    /// Pause before the first retry
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    /// Three retries over 3.5 seconds, enough for a Kindle that was just
+    /// plugged in to finish setting up its MTP responder.
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            delay: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retries.
+    pub const NONE: Self = Self {
+        retries: 0,
+        delay: Duration::ZERO,
+    };
+
+    /// Runs `op` until it succeeds, fails for good, or the retries are used
+    /// up. A cancelled `cancel` stops the waiting between attempts.
+    pub(crate) fn run<T>(&self, what: &str, cancel: &CancelToken, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut delay = self.delay;
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Err(e) if e.is_transient() && attempt < self.retries => {
+                    attempt += 1;
+                    debug!(attempt, ?delay, error = %e.display_chain(), "{} failed, retrying", what);
+                    std::thread::sleep(delay);
+                    cancel.check()?;
+                    delay = delay.saturating_mul(2);
+                }
+                result => return result,
+            }
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use crate::error::{Error, Result};
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::trace;
+
+/// Default buffer size for streamed transfers.
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Bytes moved over USB in either direction by this process.
+static BYTES_TRANSFERRED: AtomicU64 = AtomicU64::new(0);
+
+/// Total bytes downloaded and uploaded so far, for the run history.
+pub fn bytes_transferred() -> u64 {
+    BYTES_TRANSFERRED.load(Ordering::Relaxed)
+}
+
+/// Shared flag that stops a running download, upload or walk. Clone it,
+/// hand one copy to `Kindle::set_cancel_token` and call `cancel` on another
+/// from any thread; the operation then fails with `Error::Cancelled`.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Cancels the token on Ctrl-C or SIGTERM. A second signal exits at
+    /// once with status 130, in case the device stops responding.
+    pub fn cancel_on_interrupt(&self) -> io::Result<()> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        for signal in [SIGINT, SIGTERM] {
+            signal_hook::flag::register_conditional_shutdown(signal, 130, Arc::clone(&self.0))?;
+            signal_hook::flag::register(signal, Arc::clone(&self.0))?;
+        }
+        Ok(())
+    }
+
+    /// `Err(Error::Cancelled)` once `cancel` has been called.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(Error::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TransferOptions {
+    /// Bytes handed between the USB side and the local side at a time
+    pub chunk_size: usize,
+    /// Filled chunks allowed to wait for the other side of the pipeline
+    pub queue_depth: usize,
+    /// Threads for local hashing that can overlap with device reads
+    pub hashing_threads: usize,
+    /// Threads writing small files of a recursive download to disk while
+    /// the device sends the next ones; 1 writes them inline
+    pub io_threads: usize,
+    /// Longest a single transfer or walk may take before it is stopped with
+    /// `Error::Timeout`; `None` waits as long as the device keeps going
+    pub timeout: Option<Duration>,
+    /// Bytes per second a transfer may average, so bulk copies leave room
+    /// for other devices on the bus; `None` goes as fast as USB allows
+    pub rate_limit: Option<u64>,
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            queue_depth: 1,
+            hashing_threads: 2,
+            io_threads: 4,
+            timeout: None,
+            rate_limit: None,
+        }
+    }
+}
+
+/// When an operation started under `TransferOptions::timeout` has to stop.
+/// It is checked between chunks, so one USB request that stalls is only
+/// bounded by libmtp's own USB timeout.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Deadline(Option<Instant>);
+
+impl Deadline {
+    pub(crate) fn after(timeout: Option<Duration>) -> Self {
+        Self(timeout.map(|t| Instant::now() + t))
+    }
+
+    pub(crate) fn passed(&self) -> bool {
+        self.0.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// `Err(Error::Timeout)` once the deadline has passed.
+    pub(crate) fn check(&self, operation: &str) -> Result<()> {
+        if self.passed() {
+            Err(Error::Timeout(operation.to_string()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Holds a transfer to `TransferOptions::rate_limit` by sleeping whenever
+/// it gets ahead of the allowed average.
+pub(crate) struct Throttle {
+    rate: Option<u64>,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    pub(crate) fn new(rate: Option<u64>) -> Self {
+        Self {
+            rate: rate.filter(|&r| r > 0),
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Counts `n` more bytes and waits until they are within the limit.
+    pub(crate) fn pace(&mut self, n: usize) {
+        let Some(rate) = self.rate else {
+            return;
+        };
+        self.bytes += n as u64;
+        let due = Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+        if let Some(ahead) = due.checked_sub(self.started.elapsed()) {
+            trace!(?ahead, "rate limit reached, pausing");
+            thread::sleep(ahead);
+        }
+    }
+}
+
+/// Producer side of a download: collects bytes from libmtp into chunk-sized
+/// buffers and hands full ones to the consumer thread.
+pub(crate) struct ChunkSink {
+    current: Vec<u8>,
+    chunk_size: usize,
+    tx: SyncSender<Vec<u8>>,
+    recycled: Receiver<Vec<u8>>,
+    closed: bool,
+    throttle: Throttle,
+}
+
+impl ChunkSink {
+    /// Returns false once the consumer has stopped (e.g. a disk write failed),
+    /// so the caller can cancel the MTP transfer.
+    pub(crate) fn push(&mut self, data: &[u8]) -> bool {
+        if self.closed {
+            return false;
+        }
+        BYTES_TRANSFERRED.fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.throttle.pace(data.len());
+        self.current.extend_from_slice(data);
+        if self.current.len() >= self.chunk_size {
+            self.flush();
+        }
+        !self.closed
+    }
+
+    fn flush(&mut self) {
+        if self.current.is_empty() {
+            return;
+        }
+        let next = self
+            .recycled
+            .try_recv()
+            .unwrap_or_else(|_| Vec::with_capacity(self.chunk_size));
+        let full = std::mem::replace(&mut self.current, next);
+        trace!(bytes = full.len(), "read chunk from device");
+        if self.tx.send(full).is_err() {
+            self.closed = true;
+        }
+    }
+}
+
+/// Runs `produce` on the calling thread (which owns the MTP session) while
+/// `consume` drains filled chunks on a second thread, so the next USB read
+/// overlaps with writing or hashing the previous chunk. At most
+/// `queue_depth + 1` chunks are in flight.
+pub(crate) fn pipelined_download<P, C>(options: TransferOptions, produce: P, mut consume: C) -> Result<()>
+where
+    P: FnOnce(&mut ChunkSink) -> Result<()>,
+    C: FnMut(&[u8]) -> io::Result<()> + Send,
+{
+    let chunk_size = options.chunk_size;
+    let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(options.queue_depth);
+    let (recycle_tx, recycled) = mpsc::channel::<Vec<u8>>();
+
+    thread::scope(|scope| {
+        let consumer = scope.spawn(move || -> io::Result<()> {
+            for chunk in rx {
+                consume(&chunk)?;
+                let mut chunk = chunk;
+                chunk.clear();
+                // The producer may already be gone; losing the buffer is fine
+                let _ = recycle_tx.send(chunk);
+            }
+            Ok(())
+        });
+
+        let mut sink = ChunkSink {
+            current: Vec::with_capacity(chunk_size),
+            chunk_size,
+            tx,
+            recycled,
+            closed: false,
+            throttle: Throttle::new(options.rate_limit),
+        };
+        let produced = produce(&mut sink);
+        sink.flush();
+        drop(sink);
+
+        let consumed = consumer
+            .join()
+            .map_err(|_| Error::TransferFailed("Transfer worker panicked".to_string()))?;
+
+        // A local failure is the root cause of any cancelled MTP transfer
+        consumed?;
+        produced
+    })
+}
+
+/// Consumer side of an upload: hands out bytes that a reader thread has
+/// already pulled from disk.
+pub(crate) struct ChunkSource {
+    current: Vec<u8>,
+    offset: usize,
+    rx: Receiver<io::Result<Vec<u8>>>,
+    error: Option<io::Error>,
+    throttle: Throttle,
+}
+
+impl ChunkSource {
+    /// Fills as much of `buf` as possible. Returns `None` on a read error.
+    pub(crate) fn fill(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            if self.offset == self.current.len() {
+                match self.rx.recv() {
+                    Ok(Ok(chunk)) => {
+                        trace!(bytes = chunk.len(), "sending chunk to device");
+                        self.current = chunk;
+                        self.offset = 0;
+                    }
+                    Ok(Err(e)) => {
+                        self.error = Some(e);
+                        return None;
+                    }
+                    // Reader finished: end of file
+                    Err(_) => break,
+                }
+            }
+            let n = (self.current.len() - self.offset).min(buf.len() - written);
+            buf[written..written + n].copy_from_slice(&self.current[self.offset..self.offset + n]);
+            self.offset += n;
+            written += n;
+        }
+        BYTES_TRANSFERRED.fetch_add(written as u64, Ordering::Relaxed);
+        self.throttle.pace(written);
+        Some(written)
+    }
+}
+
+/// Upload counterpart of [`pipelined_download`]: a second thread reads the
+/// next chunk from `reader` while the current one is sent over USB.
+pub(crate) fn pipelined_upload<R, P, T>(options: TransferOptions, mut reader: R, produce: P) -> Result<T>
+where
+    R: Read + Send,
+    P: FnOnce(&mut ChunkSource) -> Result<T>,
+{
+    let chunk_size = options.chunk_size;
+    let (tx, rx) = mpsc::sync_channel::<io::Result<Vec<u8>>>(options.queue_depth);
+
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            loop {
+                let mut chunk = vec![0u8; chunk_size];
+                let result = read_full(&mut reader, &mut chunk).map(|n| {
+                    chunk.truncate(n);
+                    chunk
+                });
+                let done = matches!(&result, Ok(c) if c.is_empty()) || result.is_err();
+                if done {
+                    if let Err(e) = result {
+                        let _ = tx.send(Err(e));
+                    }
+                    break;
+                }
+                if tx.send(result).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut source = ChunkSource {
+            current: Vec::new(),
+            offset: 0,
+            rx,
+            error: None,
+            throttle: Throttle::new(options.rate_limit),
+        };
+        let result = produce(&mut source);
+        if let Some(e) = source.error.take() {
+            return Err(Error::Io(e));
+        }
+        // Dropping the receiver unblocks the reader if the transfer stopped early
+        drop(source);
+        result
+    })
+}
+
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
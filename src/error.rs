@@ -1,3 +1,5 @@
+use serde::Serialize;
+use schemars::JsonSchema;
 use std::process::ExitCode;
 
 #[derive(Debug, thiserror::Error)]
@@ -8,17 +10,36 @@ pub enum Error {
     #[error("File not found: {0}")]
     FileNotFound(String),
 
-    #[allow(dead_code)]
     #[error("Permission denied")]
     PermissionDenied,
 
-    #[allow(dead_code)]
-    #[error("Storage full")]
-    StorageFull,
+    #[error("Storage full: {0}")]
+    StorageFull(String),
+
+    #[error("Object is write-protected: {0}")]
+    ObjectProtected(String),
 
     #[error("Transfer failed: {0}")]
     TransferFailed(String),
 
+    #[error("Verification failed: {0}")]
+    VerificationFailed(String),
+
+    #[error("Assertion failed: {0}")]
+    AssertionFailed(String),
+
+    #[error("Cancelled")]
+    Cancelled,
+
+    #[error("Device busy: {0}")]
+    DeviceBusy(String),
+
+    #[error("Timed out: {0}")]
+    Timeout(String),
+
+    #[error("Not supported: {0}")]
+    Unsupported(String),
+
     #[error("MTP error: {0}")]
     Mtp(String),
 
@@ -27,19 +48,144 @@ pub enum Error {
 
     #[error("Path error: {0}")]
     InvalidPath(String),
+
+    #[error("failed to {operation} {path}")]
+    Operation {
+        operation: &'static str,
+        path: String,
+        #[source]
+        source: Box<Error>,
+    },
 }
 
 impl Error {
     pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from(self.code())
+    }
+
+    /// Numeric exit code, also used as the error code in daemon responses.
+    pub fn code(&self) -> u8 {
         match self {
-            Self::DeviceNotFound => ExitCode::from(2),
-            Self::FileNotFound(_) => ExitCode::from(3),
-            Self::PermissionDenied => ExitCode::from(4),
-            Self::StorageFull => ExitCode::from(5),
-            Self::TransferFailed(_) => ExitCode::from(6),
-            Self::Mtp(_) | Self::Io(_) | Self::InvalidPath(_) => ExitCode::from(1),
+            Self::DeviceNotFound => 2,
+            Self::FileNotFound(_) => 3,
+            Self::PermissionDenied => 4,
+            Self::StorageFull(_) => 5,
+            Self::TransferFailed(_) => 6,
+            Self::VerificationFailed(_) => 7,
+            Self::AssertionFailed(_) => 8,
+            Self::DeviceBusy(_) => 9,
+            Self::Timeout(_) => 10,
+            Self::ObjectProtected(_) => 11,
+            Self::Unsupported(_) => 12,
+            Self::Cancelled => 130,
+            Self::Mtp(_) | Self::Io(_) | Self::InvalidPath(_) => 1,
+            Self::Operation { source, .. } => source.code(),
         }
     }
+
+    /// Stable machine-readable name of the variant, for JSON consumers.
+    /// Context wrappers report the kind of the underlying error.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::DeviceNotFound => "device_not_found",
+            Self::FileNotFound(_) => "file_not_found",
+            Self::PermissionDenied => "permission_denied",
+            Self::StorageFull(_) => "storage_full",
+            Self::TransferFailed(_) => "transfer_failed",
+            Self::VerificationFailed(_) => "verification_failed",
+            Self::AssertionFailed(_) => "assertion_failed",
+            Self::Cancelled => "cancelled",
+            Self::DeviceBusy(_) => "device_busy",
+            Self::Timeout(_) => "timeout",
+            Self::ObjectProtected(_) => "object_protected",
+            Self::Unsupported(_) => "unsupported",
+            Self::Mtp(_) | Self::Io(_) => "mtp",
+            Self::InvalidPath(_) => "invalid_path",
+            Self::Operation { source, .. } => source.kind(),
+        }
+    }
+
+    /// Whether the same call may well succeed if repeated: USB and PTP layer
+    /// hiccups, timeouts, and a device still busy after being plugged in.
+    /// Untyped libmtp errors only carry text, so those go by the message.
+    pub fn is_transient(&self) -> bool {
+        const MARKERS: &[&str] = &["PtpLayer", "UsbLayer", "Connecting", "busy", "Busy", "timeout", "Timeout"];
+        match self {
+            Self::DeviceBusy(_) => true,
+            Self::Mtp(message) | Self::TransferFailed(message) => {
+                MARKERS.iter().any(|marker| message.contains(marker))
+            }
+            Self::Operation { source, .. } => source.is_transient(),
+            _ => false,
+        }
+    }
+
+    /// The remote path of the outermost operation that failed, if known.
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            Self::Operation { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    /// The error as printed in `--json` mode.
+    pub fn report(&self) -> ErrorReport {
+        ErrorReport {
+            schema_version: crate::cli::SCHEMA_VERSION,
+            error: ErrorDetail {
+                code: self.code(),
+                kind: self.kind(),
+                message: self.display_chain(),
+                path: self.path().map(str::to_string),
+            },
+        }
+    }
+
+    /// The error and all of its causes, e.g.
+    /// "failed to download /documents/x.azw3: Transfer failed: USB timeout".
+    pub fn display_chain(&self) -> String {
+        let mut message = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(cause) = source {
+            message.push_str(": ");
+            message.push_str(&cause.to_string());
+            source = cause.source();
+        }
+        message
+    }
+}
+
+/// `{"error": {"code", "kind", "message", "path"}}`, written to stderr when
+/// a command fails in `--json` mode. `code` is the exit code.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ErrorReport {
+    pub schema_version: u32,
+    pub error: ErrorDetail,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ErrorDetail {
+    pub code: u8,
+    pub kind: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+/// Attaches the operation and remote path to an error so bulk operations
+/// report which object failed.
+pub trait Context<T> {
+    fn context(self, operation: &'static str, path: &str) -> Result<T>;
+}
+
+impl<T> Context<T> for Result<T> {
+    fn context(self, operation: &'static str, path: &str) -> Result<T> {
+        self.map_err(|source| Error::Operation {
+            operation,
+            path: path.to_string(),
+            source: Box::new(source),
+        })
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
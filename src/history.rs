@@ -0,0 +1,60 @@
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+const HISTORY_FILE: &str = "history.jsonl";
+
+/// One CLI invocation, appended to the history after the command finishes.
+#[derive(Serialize, Deserialize)]
+pub struct RunRecord {
+    pub started: DateTime<Utc>,
+    pub command: String,
+    pub duration_ms: u64,
+    pub bytes: u64,
+    pub warnings: usize,
+    pub error: Option<String>,
+}
+
+/// `history.jsonl` in the platform data directory, e.g.
+/// `~/Library/Application Support/kindle-mtp/` on macOS.
+pub fn history_path() -> Result<PathBuf> {
+    dirs::data_local_dir()
+        .map(|dir| dir.join("kindle-mtp").join(HISTORY_FILE))
+        .ok_or_else(|| Error::InvalidPath("No local data directory for the run history".to_string()))
+}
+
+/// Appends one record. The history is plain JSON lines so a crash mid-write
+/// costs at most the last line, which [`load_since`] skips.
+pub fn record(run: &RunRecord) -> Result<()> {
+    let path = history_path()?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut line = serde_json::to_string(run).map_err(|e| Error::Io(std::io::Error::other(e)))?;
+    line.push('\n');
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Loads every run started at or after `since`, oldest first.
+pub fn load_since(since: DateTime<Utc>) -> Result<Vec<RunRecord>> {
+    let file = match std::fs::File::open(history_path()?) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut runs = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if let Ok(run) = serde_json::from_str::<RunRecord>(&line)
+            && run.started >= since
+        {
+            runs.push(run);
+        }
+    }
+    Ok(runs)
+}
@@ -0,0 +1,341 @@
+//! `kindle-mtp serve`: a small REST API for web frontends and home-automation
+//! scripts. Like the daemon it keeps one device session open and handles one
+//! request at a time, since MTP allows a single operation in flight.
+//!
+//! Endpoints: `GET /info`, `GET /files?path=`, `GET /download?path=` and
+//! `POST /upload?path=[&force=true]` with the file as the request body.
+//! Errors are `{"error": {"code", "kind", "message"}}` with `code` being the
+//! CLI exit code.
+//!
+//! `kindle-mtp opds --serve` uses the same server for an OPDS catalog:
+//! `GET /opds` is the feed, `GET /opds/download?path=` and
+//! `GET /opds/cover?path=` the books and covers it links to.
+
+use crate::cli::Output;
+use crate::commands::opds::{content_type, image_type, served_feed, Catalog, ACQUISITION_FEED_TYPE};
+use crate::commands::push::upload_atomically;
+use crate::commands::staging::StagingDir;
+use crate::daemon::Session;
+use crate::device::{Kindle, KindleInfo, StorageInfo, TransferOptions};
+use crate::metadata::read_cover;
+use crate::error::{Error, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::path::PathBuf;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+type HttpResponse = Response<Box<dyn Read + Send>>;
+
+/// Holds the device between requests; reconnects after it was unplugged.
+struct State {
+    /// Always `Session::Direct`: the server holds the device itself
+    session: Option<Session>,
+    transfer: TransferOptions,
+    /// Where uploads and downloads are staged
+    staging: StagingDir,
+    /// Counter for staging file names
+    staged: u64,
+}
+
+#[derive(Serialize)]
+struct InfoBody {
+    info: KindleInfo,
+    storage: StorageInfo,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: ErrorDetail<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail<'a> {
+    code: u8,
+    kind: &'a str,
+    message: String,
+}
+
+/// Serves requests on `addr` until interrupted.
+pub fn serve(output: &Output, addr: &str, transfer: TransferOptions) -> Result<()> {
+    run(output, addr, transfer, route)
+}
+
+/// Serves an OPDS feed of the books under `root` on `addr` until
+/// interrupted. Headers are read once per book and remembered; a changed
+/// file is read again.
+pub fn serve_opds(output: &Output, addr: &str, root: &str, transfer: TransferOptions) -> Result<()> {
+    let mut catalog = Catalog::default();
+    let root = root.trim_end_matches('/');
+    run(output, addr, transfer, |state, request| {
+        route_opds(output, state, &mut catalog, root, request)
+    })
+}
+
+fn run(
+    output: &Output,
+    addr: &str,
+    transfer: TransferOptions,
+    mut route: impl FnMut(&mut State, &mut Request) -> HttpResponse,
+) -> Result<()> {
+    let server = Server::http(addr).map_err(|e| {
+        Error::Io(std::io::Error::other(format!("cannot listen on {}: {}", addr, e)))
+    })?;
+
+    let mut state = State {
+        session: None,
+        transfer,
+        staging: StagingDir::new("serve")?,
+        staged: 0,
+    };
+    if let Err(e) = state.kindle() {
+        output.info(format!("No device yet ({}); will connect on first request", e));
+    }
+    output.info(format!("Serving on http://{} (Ctrl-C to stop)", addr));
+
+    for mut request in server.incoming_requests() {
+        let response = route(&mut state, &mut request);
+        let line = format!("{} {} {}", request.method(), request.url(), response.status_code().0);
+        output.info(&line);
+        if let Err(e) = request.respond(response) {
+            output.info(format!("Warning: {}: {}", line, e));
+        }
+    }
+    Ok(())
+}
+
+impl State {
+    fn session(&mut self) -> Result<&Session> {
+        if self.session.is_none() {
+            let kindle = Kindle::open(self.transfer)?;
+            self.session = Some(Session::Direct(kindle));
+        }
+        Ok(self.session.as_ref().expect("just connected"))
+    }
+
+    fn kindle(&mut self) -> Result<&Kindle> {
+        self.session()?.direct("serve")
+    }
+
+    fn staging_path(&mut self) -> PathBuf {
+        self.staged += 1;
+        self.staging.join(&self.staged.to_string())
+    }
+}
+
+fn route(state: &mut State, request: &mut Request) -> HttpResponse {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let params: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+
+    let result = match (request.method(), path) {
+        (Method::Get, "/info") => info(state),
+        (Method::Get, "/files") => files(state, &params),
+        (Method::Get, "/download") => {
+            required(&params, "path").and_then(|remote| download(state, remote, "application/octet-stream"))
+        }
+        (Method::Post, "/upload") => upload(state, &params, request),
+        (method, _) => {
+            let message = format!("no route for {} {}", method, path);
+            return error_response(404, "not_found", 1, message);
+        }
+    };
+    result.unwrap_or_else(|e| failure(state, e))
+}
+
+fn route_opds(
+    output: &Output,
+    state: &mut State,
+    catalog: &mut Catalog,
+    root: &str,
+    request: &mut Request,
+) -> HttpResponse {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let params: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+
+    let result = match (request.method(), path) {
+        (Method::Get, "/" | "/opds") => opds_feed(output, state, catalog, root),
+        (Method::Get, "/opds/download") => {
+            under_root(&params, root).and_then(|remote| download(state, remote, content_type(remote)))
+        }
+        (Method::Get, "/opds/cover") => under_root(&params, root).and_then(|remote| cover(state, remote)),
+        (method, _) => {
+            let message = format!("no route for {} {}", method, path);
+            return error_response(404, "not_found", 1, message);
+        }
+    };
+    result.unwrap_or_else(|e| failure(state, e))
+}
+
+/// The error response for `e`, dropping the device if it is gone.
+fn failure(state: &mut State, e: Error) -> HttpResponse {
+    if !Kindle::is_present() {
+        state.session = None;
+    } else if let Some(Ok(kindle)) = state.session.as_ref().map(|session| session.direct("serve")) {
+        // The failure may come from a stale cached path
+        kindle.clear_path_cache();
+    }
+    let status = match e.kind() {
+        "file_not_found" => 404,
+        "invalid_path" => 400,
+        "permission_denied" => 403,
+        "device_not_found" => 503,
+        "storage_full" => 507,
+        _ => 500,
+    };
+    error_response(status, e.kind(), e.code(), e.display_chain())
+}
+
+fn info(state: &mut State) -> Result<HttpResponse> {
+    let kindle = state.kindle()?;
+    json_response(
+        200,
+        &InfoBody {
+            info: kindle.info(),
+            storage: kindle.storage_info()?,
+        },
+    )
+}
+
+fn files(state: &mut State, params: &HashMap<String, String>) -> Result<HttpResponse> {
+    let path = params.get("path").map(String::as_str).unwrap_or("/");
+    json_response(200, &state.kindle()?.list_files(path)?)
+}
+
+/// Stages the file locally and streams it from there, so a slow client
+/// doesn't hold the USB transfer open.
+fn download(state: &mut State, remote: &str, content_type: &str) -> Result<HttpResponse> {
+    let staged = state.staging_path();
+    let result = state.kindle()?.download_file(remote, &staged);
+    let file = result.and_then(|_| Ok(File::open(&staged)?));
+    // The open handle keeps the data readable after the name is gone
+    let _ = std::fs::remove_file(&staged);
+    let file = file?;
+
+    // Header values must be plain ASCII
+    let name: String = remote
+        .rsplit('/')
+        .next()
+        .unwrap_or(remote)
+        .chars()
+        .map(|c| if (c.is_ascii_graphic() && c != '"') || c == ' ' { c } else { '_' })
+        .collect();
+    Ok(Response::from_file(file)
+        .with_header(header("Content-Type", content_type))
+        .with_header(header(
+            "Content-Disposition",
+            &format!("attachment; filename=\"{}\"", name),
+        ))
+        .boxed())
+}
+
+/// Writes the request body to `path`. An existing file is only replaced
+/// with `force=true`; MTP would otherwise create a second object with the
+/// same name.
+fn upload(
+    state: &mut State,
+    params: &HashMap<String, String>,
+    request: &mut Request,
+) -> Result<HttpResponse> {
+    let remote = required(params, "path")?.to_string();
+    let force = params.get("force").is_some_and(|v| v == "true" || v == "1");
+
+    let exists = match state.kindle()?.stat(&remote) {
+        Ok(_) => true,
+        Err(Error::FileNotFound(_)) => false,
+        Err(e) => return Err(e),
+    };
+    if exists && !force {
+        return Ok(error_response(
+            409,
+            "already_exists",
+            1,
+            format!("'{}' already exists (add force=true to replace it)", remote),
+        ));
+    }
+
+    let staged = state.staging_path();
+    let result = (|| {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&staged)?;
+        std::io::copy(request.as_reader(), &mut file)?;
+        let session = state.session()?;
+        // A replaced book stays until the new one is complete
+        upload_atomically(session, &remote, exists, |path| session.upload_file(&staged, path))?;
+        session.stat(&remote)
+    })();
+    let _ = std::fs::remove_file(&staged);
+    json_response(201, &result?)
+}
+
+/// The feed of the books under `root`, reading the headers of books it
+/// hasn't seen yet.
+fn opds_feed(output: &Output, state: &mut State, catalog: &mut Catalog, root: &str) -> Result<HttpResponse> {
+    let xml = served_feed(output, state.kindle()?, catalog, root)?;
+    Ok(Response::from_data(xml.into_bytes())
+        .with_header(header("Content-Type", ACQUISITION_FEED_TYPE))
+        .boxed())
+}
+
+/// The cover image stored in the book at `remote`.
+fn cover(state: &mut State, remote: &str) -> Result<HttpResponse> {
+    let cover = read_cover(state.kindle()?, remote)?
+        .ok_or_else(|| Error::FileNotFound(format!("no cover in {}", remote)))?;
+    Ok(Response::from_data(cover.data)
+        .with_header(header("Content-Type", image_type(cover.format)))
+        .boxed())
+}
+
+/// The `path` parameter, which must lie under `root`: the OPDS server
+/// only hands out the books its feed lists.
+fn under_root<'a>(params: &'a HashMap<String, String>, root: &str) -> Result<&'a str> {
+    let path = required(params, "path")?;
+    let inside = path
+        .strip_prefix(root)
+        .is_some_and(|rest| rest.starts_with('/'))
+        && !path.split('/').any(|segment| segment == "..");
+    if !inside {
+        return Err(Error::InvalidPath(format!("'{}' is not under {}", path, root)));
+    }
+    Ok(path)
+}
+
+fn required<'a>(params: &'a HashMap<String, String>, name: &str) -> Result<&'a str> {
+    params
+        .get(name)
+        .map(String::as_str)
+        .filter(|v| !v.is_empty())
+        .ok_or_else(|| Error::InvalidPath(format!("missing query parameter '{}'", name)))
+}
+
+fn json_response<T: Serialize>(status: u16, body: &T) -> Result<HttpResponse> {
+    let body = serde_json::to_vec(body).map_err(std::io::Error::other)?;
+    Ok(Response::from_data(body)
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+        .boxed())
+}
+
+fn error_response(status: u16, kind: &str, code: u8, message: String) -> HttpResponse {
+    let body = ErrorBody {
+        error: ErrorDetail {
+            code,
+            kind,
+            message,
+        },
+    };
+    let body = serde_json::to_vec(&body).unwrap_or_default();
+    Response::from_data(body)
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+        .boxed()
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name, value).expect("valid header")
+}
@@ -0,0 +1,126 @@
+use crate::device::lock::serial_file_name;
+use crate::device::{Kindle, WalkEntry};
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+const INDEX_DIR: &str = "index";
+
+/// Every file and folder on one device, as of the last `kindle-mtp index`.
+/// Object IDs are only meaningful while the device stays connected; paths,
+/// sizes and mtimes are what offline commands rely on.
+#[derive(Serialize, Deserialize)]
+pub struct DeviceIndex {
+    pub serial: String,
+    pub model: String,
+    pub created: DateTime<Utc>,
+    pub entries: Vec<WalkEntry>,
+}
+
+impl DeviceIndex {
+    /// Walks the whole device.
+    pub fn build(kindle: &Kindle) -> Result<Self> {
+        let info = kindle.info();
+        Ok(DeviceIndex {
+            serial: info.serial,
+            model: info.model,
+            created: Utc::now(),
+            entries: kindle.walk("/")?,
+        })
+    }
+
+    /// Writes the index next to the run history, replacing any earlier one
+    /// for the same serial. The file is renamed into place so an interrupted
+    /// write leaves the old index intact.
+    pub fn save(&self) -> Result<PathBuf> {
+        let path = index_path(&self.serial)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let json = serde_json::to_vec(self).map_err(|e| Error::Io(std::io::Error::other(e)))?;
+        let temp = path.with_extension("json.tmp");
+        std::fs::write(&temp, json)?;
+        std::fs::rename(&temp, &path)?;
+        Ok(path)
+    }
+
+    /// Loads the index for `serial`. Without one, falls back to `--serial`
+    /// and then to the only index on disk, so single-Kindle users never have
+    /// to name it.
+    pub fn load(serial: Option<&str>) -> Result<Self> {
+        let serial = match serial.or(Kindle::preferred_serial()) {
+            Some(serial) => serial.to_string(),
+            None => only_indexed_serial()?,
+        };
+        let path = index_path(&serial)?;
+        let json = match std::fs::read(&path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::FileNotFound(format!(
+                    "{} (run `kindle-mtp index` first)",
+                    path.display()
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_slice(&json).map_err(|e| {
+            Error::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            ))
+        })
+    }
+
+    /// The entries below `root`, like `Kindle::walk(root)` would return them.
+    pub fn walk<'a>(&'a self, root: &str) -> impl Iterator<Item = &'a WalkEntry> {
+        let prefix = match root.trim_matches('/') {
+            "" => "/".to_string(),
+            root => format!("/{}/", root),
+        };
+        self.entries
+            .iter()
+            .filter(move |item| item.path.starts_with(&prefix))
+    }
+}
+
+/// `index/` in the platform data directory, beside the run history.
+fn index_dir() -> Result<PathBuf> {
+    dirs::data_local_dir()
+        .map(|dir| dir.join("kindle-mtp").join(INDEX_DIR))
+        .ok_or_else(|| Error::InvalidPath("No local data directory for the device index".to_string()))
+}
+
+/// `index/<serial>.json` in the platform data directory.
+pub fn index_path(serial: &str) -> Result<PathBuf> {
+    Ok(index_dir()?.join(format!("{}.json", serial_file_name(serial))))
+}
+
+fn only_indexed_serial() -> Result<String> {
+    let dir = index_dir()?;
+    let mut serials = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(&dir) {
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json")
+                && let Some(stem) = path.file_stem().and_then(|s| s.to_str())
+            {
+                serials.push(stem.to_string());
+            }
+        }
+    }
+    match serials.len() {
+        0 => Err(Error::FileNotFound(format!(
+            "{} (run `kindle-mtp index` first)",
+            dir.display()
+        ))),
+        1 => Ok(serials.remove(0)),
+        _ => {
+            serials.sort();
+            Err(Error::InvalidPath(format!(
+                "several devices are indexed ({}); pick one with --serial",
+                serials.join(", ")
+            )))
+        }
+    }
+}
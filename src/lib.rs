@@ -1,4 +1,58 @@
+//! Kindle file management over MTP.
+//!
+//! The supported programmatic surface is [`Kindle`] and the types around
+//! it, re-exported here from [`device`], plus [`Error`] and [`Result`].
+//! Paths are absolute device paths such as `/documents/book.azw3`.
+//!
+//! ```no_run
+//! use kindle_mtp::Kindle;
+//! use std::path::Path;
+//!
+//! let kindle = Kindle::detect()?;
+//! for entry in kindle.list_files("/documents")? {
+//!     println!("{} {}", entry.name, entry.size);
+//! }
+//! kindle.create_folder_all("/documents/Novels")?;
+//! kindle.upload_file(Path::new("book.azw3"), "/documents/Novels/book.azw3")?;
+//! kindle.download_file("/documents/Novels/book.azw3", Path::new("copy.azw3"))?;
+//! kindle.rename("/documents/Novels/book.azw3", "/documents/Novels/Book.azw3")?;
+//! kindle.move_into("/documents/Novels/Book.azw3", "/documents")?;
+//! kindle.delete("/documents/Book.azw3")?;
+//! # Ok::<(), kindle_mtp::Error>(())
+//! ```
+//!
+//! With the `aio` feature, [`aio::AsyncKindle`] offers the same operations
+//! as futures for tokio applications.
+//!
+//! The other modules hold the `kindle-mtp` command line tool and may change
+//! between releases.
+
+#[cfg(feature = "aio")]
+pub mod aio;
+#[doc(hidden)]
 pub mod cli;
+#[doc(hidden)]
 pub mod commands;
+#[doc(hidden)]
+pub mod config;
+#[doc(hidden)]
+pub mod daemon;
 pub mod device;
 pub mod error;
+#[doc(hidden)]
+pub mod history;
+#[doc(hidden)]
+pub mod http;
+#[doc(hidden)]
+pub mod index;
+#[doc(hidden)]
+pub mod metadata;
+#[cfg(feature = "mount")]
+#[doc(hidden)]
+pub mod mount;
+
+pub use device::{
+    CancelToken, DetectOptions, FileEntry, Kindle, KindleInfo, ObjectProperty, PropertyValue,
+    RetryPolicy, StorageInfo, TransferOptions, WalkEntry,
+};
+pub use error::{Error, Result};
@@ -1,34 +1,476 @@
-mod cli;
-mod commands;
-mod device;
-mod error;
+use kindle_mtp::{cli, commands, config, device, error, history};
 
-use clap::Parser;
-use cli::{Args, Command, Output};
+use chrono::Utc;
+use clap::{CommandFactory, FromArgMatches};
+use cli::{Args, AudioAction, CalibreAction, CollectionsAction, Command, DictAction, HighlightsAction, Output, OutputFormat, ScreensaverAction, SnapshotAction, TrashAction, VocabAction};
+use config::{Config, SyncPair};
+use std::path::Path;
 use std::process::ExitCode;
+use std::time::Instant;
 
 fn main() -> ExitCode {
-    let args = Args::parse();
-    let output = Output::new(args.json, args.quiet);
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let command_name = matches.subcommand_name().unwrap_or_default().to_string();
+    let started = Utc::now();
+    let timer = Instant::now();
 
-    let result = match args.command {
+    // Until the config is read, only the flags say whether errors are JSON
+    let json_flag = args.json || args.ndjson || args.format.is_some_and(|f| f != OutputFormat::Human);
+    if let Err(e) = cli::init_logging(args.verbose, args.log_file.as_deref().map(Path::new)) {
+        let e = error::Error::Operation {
+            operation: "open log file",
+            path: args.log_file.clone().unwrap_or_default(),
+            source: Box::new(e.into()),
+        };
+        return report_error(&e, json_flag, args.quiet);
+    }
+    tracing::debug!(command = %command_name, version = env!("CARGO_PKG_VERSION"), "starting");
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => return report_error(&e, json_flag, args.quiet),
+    };
+    let format = match args.format {
+        Some(format) => format,
+        None if args.json => OutputFormat::Json,
+        None if args.ndjson => OutputFormat::Ndjson,
+        None => config.defaults.format.unwrap_or(OutputFormat::Human),
+    };
+    let output = Output::new(format, args.quiet).with_stats(args.stats && args.command.reports_stats());
+    cli::style::set_color(args.color);
+    if let Some(serial) = args.serial.clone().or_else(|| config.defaults.serial.clone()) {
+        device::Kindle::prefer_serial(serial);
+    }
+    device::Kindle::prefer_device(device::DeviceFilter {
+        vendor_id: args.vendor_id,
+        product_id: args.product_id,
+        any: args.any_device,
+    });
+    device::Kindle::prefer_cached(config.performance.cached_open);
+    device::Kindle::prefer_claim(args.claim);
+    device::Kindle::prefer_wait_lock(args.wait_lock);
+    commands::set_free_space_margin(
+        config.defaults.free_space_margin.unwrap_or(commands::DEFAULT_FREE_SPACE_MARGIN),
+    );
+    let mut retry = config.performance.retry_policy();
+    if let Some(retries) = args.retries {
+        retry.retries = retries;
+    }
+    if let Some(delay) = args.retry_delay {
+        retry.delay = delay.to_std().unwrap_or_default();
+    }
+    device::Kindle::set_default_retry_policy(retry);
+    if args.command.cancels_on_interrupt() {
+        let cancel = device::CancelToken::new();
+        match cancel.cancel_on_interrupt() {
+            Ok(()) => device::Kindle::set_default_cancel_token(cancel),
+            Err(e) => output.warn(format!("Ctrl-C will not stop transfers cleanly: {}", e)),
+        }
+    }
+    if config.performance.device_parallelism > 1 {
+        output.warn("performance.device_parallelism > 1 is not supported yet; using 1");
+    }
+
+    let mut transfer = config.performance.transfer_options();
+    if let Some(chunk_size) = args.chunk_size {
+        transfer.chunk_size = chunk_size.max(1) as usize;
+    }
+    if let Some(rate) = args.limit_rate {
+        transfer.rate_limit = Some(rate);
+    }
+    if let Some(timeout) = args.timeout {
+        transfer.timeout = timeout.to_std().ok();
+    }
+
+    if let Some(timeout) = args.wait
+        && args.command.needs_device()
+        && let Err(e) = wait_for_device(&output, timeout)
+    {
+        return report_error(&e, output.is_json(), args.quiet);
+    }
+
+    let mut command = args.command;
+    command.expand_aliases(&config);
+
+    let result = match command {
         Command::Status => commands::run_status(&output),
         Command::Info => commands::run_info(&output),
-        Command::Ls { path, long } => commands::run_ls(&output, &path, long),
+        Command::Init => commands::run_init(&output, config),
+        Command::Completions { shell } => commands::run_completions(shell),
+        Command::Schema { name } => commands::run_schema(name.as_deref()),
+        Command::Complete { line, bash } => {
+            // A non-zero exit tells the shell to use its static completion
+            return if commands::run_complete(&line, bash, &config, transfer) {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            };
+        }
+        Command::Ls {
+            path,
+            long,
+            recursive,
+            size,
+            time,
+            reverse,
+            ext,
+            only_dirs,
+            only_files,
+        } => {
+            let path = path.as_deref().or(config.remote_root()).unwrap_or("/");
+            let sort = if size {
+                commands::LsSort::Size
+            } else if time {
+                commands::LsSort::Time
+            } else {
+                commands::LsSort::Name
+            };
+            let options = commands::LsOptions {
+                long,
+                recursive,
+                sort,
+                reverse,
+                extensions: ext,
+                only_dirs,
+                only_files,
+            };
+            commands::run_ls(&output, path, options)
+        }
+        Command::Stat { path, props, meta } => commands::run_stat(&output, &path, props, meta),
+        Command::Thumb { remote, local } => commands::run_thumb(&output, &remote, Path::new(&local)),
+        Command::Cover { remote, local } => commands::run_cover(&output, &remote, Path::new(&local)),
+        Command::FwUpdate { file, force } => commands::run_fw_update(&output, &file, force, transfer),
+        Command::MtpDebug { op, params, out } => commands::run_mtp_debug(&output, op, &params, out.as_deref().map(Path::new)),
         Command::Pull {
-            remote,
+            paths,
+            recursive,
+            verify,
+            stdout,
+            keep_partial,
+            skip_sdr,
+            filter,
+            overwrite,
+        } => commands::run_pull(
+            &output,
+            &paths,
+            commands::PullOptions {
+                recursive,
+                verify,
+                to_stdout: stdout,
+                keep_partial,
+                overwrite: overwrite.policy(config.overwrite()),
+                skip_sdr,
+            },
+            &filter.filter(),
+            config.download_dir(),
+            transfer,
+        ),
+        Command::Push {
             local,
             recursive,
-        } => commands::run_pull(&output, &remote, &local, recursive),
+            filter,
+            remote,
+            verify,
+            overwrite,
+            sanitize,
+            rename_on_conflict,
+            no_atomic,
+        } => commands::run_push(
+            &output,
+            &local,
+            remote.as_deref().or(config.remote_root()).unwrap_or("/documents"),
+            commands::PushOptions {
+                recursive,
+                verify,
+                overwrite: overwrite.policy(config.overwrite()),
+                dry_run: args.dry_run,
+                sanitize,
+                rename_on_conflict,
+                atomic: !no_atomic,
+            },
+            &filter.filter(),
+            transfer,
+        ),
+        Command::Send {
+            files,
+            to,
+            format,
+            converter,
+            no_convert,
+            overwrite,
+        } => commands::run_send(
+            &output,
+            &files,
+            to.as_deref().or(config.remote_root()).unwrap_or("/documents"),
+            commands::SendOptions {
+                format,
+                converter,
+                no_convert,
+                push: commands::PushOptions {
+                    overwrite: overwrite.policy(config.overwrite()),
+                    dry_run: args.dry_run,
+                    ..Default::default()
+                },
+            },
+            transfer,
+        ),
+        Command::Calibre {
+            action:
+                CalibreAction::Import {
+                    query,
+                    ids,
+                    authors,
+                    tags,
+                    series,
+                    all: _,
+                    library,
+                    to,
+                    format,
+                    converter,
+                    no_convert,
+                    overwrite,
+                },
+        } => commands::run_calibre_import(
+            &output,
+            &library
+                .or_else(|| dirs::home_dir().map(|home| home.join("Calibre Library")))
+                .unwrap_or_else(|| "Calibre Library".into()),
+            &commands::LibraryFilter {
+                query,
+                ids,
+                authors,
+                tags,
+                series,
+            },
+            to.as_deref().or(config.remote_root()).unwrap_or("/documents"),
+            commands::SendOptions {
+                format,
+                converter,
+                no_convert,
+                push: commands::PushOptions {
+                    overwrite: overwrite.policy(config.overwrite()),
+                    dry_run: args.dry_run,
+                    ..Default::default()
+                },
+            },
+            transfer,
+        ),
+        Command::Rm {
+            paths,
+            recursive,
+            trash,
+            no_trash,
+        } => commands::run_rm(&output, &paths, recursive, trash || (config.trash() && !no_trash), args.dry_run),
+        Command::Trash { action } => match action {
+            TrashAction::List => commands::run_trash_list(&output),
+            TrashAction::Restore { ids } => commands::run_trash_restore(&output, &ids, transfer),
+        },
+        Command::Sync {
+            source: Some(source),
+            destination: Some(destination),
+            sanitize,
+            newer,
+            delete,
+            filter,
+        } => commands::run_sync(
+            &output,
+            &source,
+            &destination,
+            commands::SyncOptions {
+                dry_run: args.dry_run,
+                sanitize,
+                newer,
+                delete,
+            },
+            &filter.filter(),
+            transfer,
+        ),
+        Command::Sync {
+            sanitize,
+            newer,
+            delete,
+            filter,
+            ..
+        } => {
+            let options = commands::SyncOptions {
+                dry_run: args.dry_run,
+                sanitize,
+                newer,
+                delete,
+            };
+            let pairs: Vec<SyncPair> = config
+                .sync
+                .iter()
+                .map(|pair| SyncPair {
+                    source: config.expand_sync_endpoint(&pair.source),
+                    destination: config.expand_sync_endpoint(&pair.destination),
+                })
+                .collect();
+            commands::run_sync_pairs(&output, &pairs, options, &filter.filter(), transfer)
+        }
+        Command::Watch { local, remote } => {
+            let remote = remote.as_deref().or(config.remote_root()).unwrap_or("/documents");
+            commands::run_watch(&output, &local, remote, transfer)
+        }
+        Command::Monitor {
+            on_connect,
+            on_disconnect,
+        } => commands::run_monitor(&output, on_connect.as_deref(), on_disconnect.as_deref()),
+        Command::Daemon => commands::run_daemon(&output, transfer),
+        Command::Batch {
+            script,
+            keep_going,
+            ..
+        } => commands::run_batch(&output, &script, keep_going, &config, transfer),
+        Command::Shell => commands::run_shell(&output, &config, transfer),
+        Command::Tui { download_dir } => {
+            let download_dir = download_dir.unwrap_or_else(|| config.download_dir().to_path_buf());
+            commands::run_tui(&config, download_dir, transfer)
+        }
+        Command::Serve { addr } => commands::run_serve(&output, &addr, transfer),
+        Command::Opds { out, serve, path } => match serve {
+            Some(addr) => commands::run_opds_serve(&output, &addr, &path, transfer),
+            None => commands::run_opds_export(&output, out.as_deref().expect("clap requires --out or --serve"), &path),
+        },
+        Command::Mount { mountpoint } => commands::run_mount(&output, &mountpoint),
+        Command::Diff {
+            local,
+            remote,
+            index,
+            refresh,
+        } => commands::run_diff(&output, &local, &remote, index, refresh),
+        Command::Backup {
+            backup_dir,
+            path,
+            full,
+            skip_sdr,
+            filter,
+        } => commands::run_backup(&output, &backup_dir, &path, full, skip_sdr, &filter.filter(), transfer),
+        Command::Restore { backup_dir, path } => {
+            commands::run_restore(&output, &backup_dir, path.as_deref(), args.dry_run, transfer)
+        }
+        Command::Index => commands::run_index(&output),
+        Command::Search { query, regex, path } => {
+            commands::run_search(&output, &query, regex, &path)
+        }
+        Command::Usage { path, filter } => commands::run_usage(&output, &path, &filter.filter()),
+        Command::Largest { path, count } => commands::run_largest(&output, &path, count),
+        Command::CleanSdr { path, yes } => commands::run_clean_sdr(&output, &path, args.dry_run, yes),
+        Command::Books { path, from_names } => commands::run_books(&output, &path, from_names),
+        Command::Catalog {
+            file,
+            format,
+            path,
+            from_names,
+        } => commands::run_catalog(&output, &file, format, &path, from_names),
+        Command::Clippings { file, by_book, path } => {
+            commands::run_clippings(&output, &path, file.as_deref(), by_book)
+        }
+        Command::Collections { action } => match action {
+            CollectionsAction::List => commands::run_collections_list(&output),
+            CollectionsAction::Add { name, books } => commands::run_collections_add(&output, &name, &books),
+            CollectionsAction::Remove { name, books } => commands::run_collections_remove(&output, &name, &books),
+        },
+        Command::Audio { action } => match action {
+            AudioAction::Ls { path } => commands::run_audio_ls(&output, path.as_deref(), transfer),
+            AudioAction::Pull { remote, local } => commands::run_audio_pull(&output, &remote, &local, transfer),
+            AudioAction::Push { files, to, force } => commands::run_audio_push(&output, &files, to.as_deref(), force, transfer),
+        },
+        Command::Dict { action } => match action {
+            DictAction::Install { file, force } => commands::run_dict_install(&output, &file, force, transfer),
+            DictAction::List => commands::run_dict_list(&output, transfer),
+        },
+        Command::Screensaver { action, folder } => match action {
+            ScreensaverAction::Push { image, size, fit } => {
+                commands::run_screensaver_push(&output, &image, &folder, size, fit, transfer)
+            }
+            ScreensaverAction::List => commands::run_screensaver_list(&output, &folder, transfer),
+            ScreensaverAction::Rm { names } => commands::run_screensaver_rm(&output, &folder, &names, args.dry_run, transfer),
+        },
+        Command::Highlights {
+            action: HighlightsAction::Export { dir, full, path },
+        } => commands::run_highlights_export(&output, &dir, &path, full),
+        Command::Vocab {
+            action: VocabAction::Export { file, path },
+        } => commands::run_vocab_export(&output, &file, &path),
+        Command::Snapshot {
+            action: SnapshotAction::Export { file, path },
+        } => commands::run_snapshot_export(&output, &file, &path),
+        Command::Introspect => commands::run_introspect(&output),
+        Command::Stats { last } => commands::run_stats(&output, last),
+        Command::Assert {
+            free_space_min,
+            exists,
+            missing,
+            count_max,
+            count_min,
+        } => commands::run_assert(
+            &output,
+            &commands::Assertions {
+                free_space_min,
+                exists,
+                missing,
+                count_max,
+                count_min,
+            },
+        ),
+        Command::Doctor { tune } => commands::run_doctor(&output, &config, tune, transfer),
+        Command::Bench { size, rounds, dir } => commands::run_bench(&output, &dir, size, rounds, transfer),
+        Command::Hash { remote, md5, .. } => {
+            let algorithm = if md5 {
+                commands::HashAlgorithm::Md5
+            } else {
+                commands::HashAlgorithm::Sha256
+            };
+            commands::run_hash(&output, &remote, algorithm, transfer)
+        }
     };
 
+    tracing::debug!(
+        elapsed_ms = timer.elapsed().as_millis() as u64,
+        error = result.as_ref().err().map(|e| e.display_chain()),
+        "finished"
+    );
+
+    // Reading the history (or describing the CLI) isn't device work worth tracking
+    if !matches!(command_name.as_str(), "stats" | "introspect" | "completions" | "schema") {
+        let run = history::RunRecord {
+            started,
+            command: command_name,
+            duration_ms: timer.elapsed().as_millis() as u64,
+            bytes: device::bytes_transferred(),
+            warnings: output.warning_count(),
+            error: result.as_ref().err().map(|e| e.display_chain()),
+        };
+        if let Err(e) = history::record(&run) {
+            output.warn(format!("could not record run history: {}", e));
+        }
+    }
+
+    output.finish();
+
     match result {
         Ok(()) => ExitCode::SUCCESS,
-        Err(e) => {
-            if !args.quiet {
-                eprintln!("Error: {}", e);
-            }
-            e.exit_code()
+        Err(e) => report_error(&e, output.is_json(), args.quiet),
+    }
+}
+
+/// Prints a failure to stderr, as an `{"error": ...}` object in JSON mode so
+/// scripts can branch on `kind` instead of parsing the message.
+fn report_error(e: &error::Error, json: bool, quiet: bool) -> ExitCode {
+    if !quiet {
+        if json {
+            eprintln!("{}", serde_json::to_string(&e.report()).unwrap_or_default());
+        } else {
+            eprintln!("Error: {}", e.display_chain());
         }
     }
+    e.exit_code()
+}
+
+fn wait_for_device(output: &Output, timeout: Option<chrono::Duration>) -> error::Result<()> {
+    device::Kindle::wait_for_device(timeout.map(|t| t.to_std().unwrap_or_default()), || {
+        output.info("Waiting for a Kindle to be connected...")
+    })
 }
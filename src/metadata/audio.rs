@@ -0,0 +1,68 @@
+//! Durations of MP4-family audiobooks (Audible `.aax`, `.m4b`, `.m4a`).
+//!
+//! An MP4 file is a sequence of boxes: a 32-bit size, a four-letter type,
+//! and the contents (a size of 1 means a 64-bit size follows the type, 0
+//! that the box runs to the end of the file). The `mvhd` box inside the
+//! top-level `moov` box holds the time scale and the duration in it. The
+//! media data box before `moov` is most of the file, so it is skipped by
+//! its size rather than read.
+
+use super::{be_u32, be_u64, ReadRange};
+use crate::error::Result;
+
+/// Top-level boxes looked at before giving up on finding `moov`.
+const MAX_BOXES: usize = 64;
+
+/// The duration in seconds, or `None` if the file isn't an MP4 or has no
+/// movie header.
+pub(super) fn read_duration(read: &ReadRange) -> Result<Option<u64>> {
+    let Some((moov_start, moov_end)) = find_box(read, 0, u64::MAX, b"moov")? else {
+        return Ok(None);
+    };
+    let Some((mvhd_start, _)) = find_box(read, moov_start, moov_end, b"mvhd")? else {
+        return Ok(None);
+    };
+    // Version and flags, then the creation and modification times, which
+    // are 64-bit in version 1
+    let header = read(mvhd_start, 32)?;
+    let (timescale, duration) = match header.first() {
+        Some(0) => (be_u32(&header, 12), be_u32(&header, 16).map(u64::from)),
+        Some(1) => (be_u32(&header, 20), be_u64(&header, 24)),
+        _ => return Ok(None),
+    };
+    Ok(match (timescale, duration) {
+        (Some(timescale), Some(duration)) if timescale > 0 => Some(duration / u64::from(timescale)),
+        _ => None,
+    })
+}
+
+/// The contents of the first box of type `kind` among the boxes between
+/// `start` and `end`, as offsets of where they start and end.
+fn find_box(read: &ReadRange, start: u64, end: u64, kind: &[u8; 4]) -> Result<Option<(u64, u64)>> {
+    let mut offset = start;
+    for _ in 0..MAX_BOXES {
+        if offset >= end {
+            break;
+        }
+        let header = read(offset, 16)?;
+        let (Some(size), Some(box_kind)) = (be_u32(&header, 0), header.get(4..8)) else {
+            break;
+        };
+        let (header_len, size) = match size {
+            0 => (8, end.saturating_sub(offset)),
+            1 => match be_u64(&header, 8) {
+                Some(size) => (16, size),
+                None => break,
+            },
+            size => (8, u64::from(size)),
+        };
+        if size < header_len {
+            break;
+        }
+        if box_kind == kind {
+            return Ok(Some((offset + header_len, offset.saturating_add(size).min(end))));
+        }
+        offset = offset.saturating_add(size);
+    }
+    Ok(None)
+}
@@ -0,0 +1,265 @@
+//! EPUB files: zip archives whose package document (the OPF) names the
+//! cover image. Only the zip directory and the entries needed are read.
+
+use super::{le_u16, le_u32};
+use crate::device::Kindle;
+use crate::error::{Error, Result};
+use regex::Regex;
+use std::collections::HashMap;
+
+/// Fixed part of the end of central directory record.
+const EOCD_LEN: u32 = 22;
+/// Longest zip comment, which may follow that record.
+const MAX_COMMENT_LEN: u32 = 0xFFFF;
+const EOCD_SIGNATURE: &[u8] = b"PK\x05\x06";
+const CENTRAL_SIGNATURE: u32 = 0x0201_4b50;
+/// Fixed part of a central directory entry, before the name.
+const CENTRAL_ENTRY_LEN: usize = 46;
+/// Fixed part of a local file header, before the name.
+const LOCAL_HEADER_LEN: u32 = 30;
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+/// Largest entry inflated; a cover or package document is far smaller.
+const ENTRY_MAX: usize = 32 * 1024 * 1024;
+const CONTAINER_PATH: &str = "META-INF/container.xml";
+const IMAGE_EXTENSIONS: &[&str] = &[".jpg", ".jpeg", ".png", ".gif"];
+
+/// A file in the zip's central directory.
+struct Entry {
+    name: String,
+    method: u16,
+    compressed_size: u32,
+    /// Where its local header starts
+    offset: u32,
+}
+
+/// The image the package document names as cover, or failing that the
+/// first image with "cover" in its name.
+pub(super) fn read_cover(kindle: &Kindle, path: &str) -> Result<Option<Vec<u8>>> {
+    let Some(entries) = read_directory(kindle, path)? else {
+        return Ok(None);
+    };
+    let by_name: HashMap<&str, &Entry> = entries.iter().map(|e| (e.name.as_str(), e)).collect();
+    let named = match package_cover(kindle, path, &by_name)? {
+        Some(name) => by_name.get(name.as_str()).copied(),
+        None => None,
+    };
+    let entry = named.or_else(|| {
+        entries.iter().find(|e| {
+            let name = e.name.to_lowercase();
+            name.contains("cover") && IMAGE_EXTENSIONS.iter().any(|ext| name.ends_with(ext))
+        })
+    });
+    entry.map(|entry| read_entry(kindle, path, entry)).transpose()
+}
+
+/// The zip path of the cover image the package document names: the item
+/// with the `cover-image` property (EPUB 3), or the one a
+/// `<meta name="cover">` points at (EPUB 2).
+fn package_cover(kindle: &Kindle, path: &str, entries: &HashMap<&str, &Entry>) -> Result<Option<String>> {
+    let Some(container) = entries.get(CONTAINER_PATH) else {
+        return Ok(None);
+    };
+    let container = String::from_utf8_lossy(&read_entry(kindle, path, container)?).into_owned();
+    let Some(package_path) = tags(&container, "rootfile").into_iter().find_map(|mut a| a.remove("full-path")) else {
+        return Ok(None);
+    };
+    let Some(package) = entries.get(package_path.as_str()) else {
+        return Ok(None);
+    };
+    let package = String::from_utf8_lossy(&read_entry(kindle, path, package)?).into_owned();
+
+    let items = tags(&package, "item");
+    let cover_id = tags(&package, "meta")
+        .into_iter()
+        .find(|meta| meta.get("name").is_some_and(|name| name == "cover"))
+        .and_then(|mut meta| meta.remove("content"));
+    let href = items
+        .iter()
+        .find(|item| {
+            item.get("properties").is_some_and(|p| p.split_whitespace().any(|p| p == "cover-image"))
+        })
+        .or_else(|| items.iter().find(|item| cover_id.is_some() && item.get("id") == cover_id.as_ref()))
+        .and_then(|item| item.get("href"));
+    let base = package_path.rsplit_once('/').map_or("", |(dir, _)| dir);
+    Ok(href.map(|href| resolve(base, &unescape(href))))
+}
+
+/// Reads the central directory through the end of central directory
+/// record at the end of the file. `None` if the file isn't a zip.
+fn read_directory(kindle: &Kindle, path: &str) -> Result<Option<Vec<Entry>>> {
+    let size = kindle.stat(path)?.size;
+    let tail_len = size.min(u64::from(EOCD_LEN + MAX_COMMENT_LEN));
+    let tail = kindle.read_range(path, size - tail_len, tail_len as u32)?;
+    let Some(eocd) = tail.windows(4).rposition(|w| w == EOCD_SIGNATURE) else {
+        return Ok(None);
+    };
+    let (Some(count), Some(directory_size), Some(directory_offset)) = (
+        le_u16(&tail, eocd + 10),
+        le_u32(&tail, eocd + 12),
+        le_u32(&tail, eocd + 16),
+    ) else {
+        return Ok(None);
+    };
+    let directory = kindle.read_range(path, u64::from(directory_offset), directory_size)?;
+
+    let mut entries = Vec::with_capacity(count as usize);
+    let mut position = 0;
+    while le_u32(&directory, position) == Some(CENTRAL_SIGNATURE) {
+        let field = |offset| le_u16(&directory, position + offset).unwrap_or(0) as usize;
+        let (name_len, extra_len, comment_len) = (field(28), field(30), field(32));
+        let name_start = position + CENTRAL_ENTRY_LEN;
+        let Some(name) = directory.get(name_start..name_start + name_len) else {
+            break;
+        };
+        entries.push(Entry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            method: le_u16(&directory, position + 10).unwrap_or(0),
+            compressed_size: le_u32(&directory, position + 20).unwrap_or(0),
+            offset: le_u32(&directory, position + 42).unwrap_or(0),
+        });
+        position = name_start + name_len + extra_len + comment_len;
+    }
+    Ok(Some(entries))
+}
+
+/// The contents of one zip entry, inflated if need be.
+fn read_entry(kindle: &Kindle, path: &str, entry: &Entry) -> Result<Vec<u8>> {
+    let local = kindle.read_range(path, u64::from(entry.offset), LOCAL_HEADER_LEN)?;
+    let (Some(name_len), Some(extra_len)) = (le_u16(&local, 26), le_u16(&local, 28)) else {
+        return Err(corrupt(path, &entry.name));
+    };
+    let start = u64::from(entry.offset) + u64::from(LOCAL_HEADER_LEN) + u64::from(name_len) + u64::from(extra_len);
+    let data = kindle.read_range(path, start, entry.compressed_size)?;
+    match entry.method {
+        METHOD_STORED => Ok(data),
+        METHOD_DEFLATED => miniz_oxide::inflate::decompress_to_vec_with_limit(&data, ENTRY_MAX)
+            .map_err(|_| corrupt(path, &entry.name)),
+        method => Err(Error::Unsupported(format!(
+            "{} in {} uses zip compression method {}",
+            entry.name, path, method
+        ))),
+    }
+}
+
+fn corrupt(path: &str, name: &str) -> Error {
+    Error::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("{} in {} is damaged", name, path),
+    ))
+}
+
+/// The attributes of every `<name ...>` tag in `xml`, namespace prefix
+/// or not. Enough for container.xml and the package document.
+fn tags(xml: &str, name: &str) -> Vec<HashMap<String, String>> {
+    let tag = Regex::new(&format!(r"<(?:\w+:)?{}\b([^>]*)>", regex::escape(name))).expect("valid tag pattern");
+    let attribute = Regex::new(r#"([\w:-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).expect("valid attribute pattern");
+    tag.captures_iter(xml)
+        .map(|tag| {
+            attribute
+                .captures_iter(&tag[1])
+                .map(|a| {
+                    let value = a.get(2).or_else(|| a.get(3)).map_or("", |v| v.as_str());
+                    (a[1].to_string(), value.to_string())
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// An href as a zip path: XML entities and percent escapes decoded.
+fn unescape(href: &str) -> String {
+    let href = href
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&");
+    let bytes = href.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| href.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// `href` relative to the folder `base`, with `..` and `.` resolved.
+fn resolve(base: &str, href: &str) -> String {
+    let mut parts: Vec<&str> = base.split('/').filter(|p| !p.is_empty()).collect();
+    for part in href.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_read_both_quote_styles_and_prefixes() {
+        let xml = r#"<opf:item id="cover" href='images/cover.jpg' properties="cover-image"/><item id="ch1" href="ch1.xhtml"/>"#;
+        let items = tags(xml, "item");
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0]["href"], "images/cover.jpg");
+        assert_eq!(items[0]["properties"], "cover-image");
+        assert_eq!(items[1]["id"], "ch1");
+    }
+
+    #[test]
+    fn tags_match_whole_names_only() {
+        let xml = r#"<itemref idref="ch1"/><item id="ch1"/>"#;
+        let items = tags(xml, "item");
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0]["id"], "ch1");
+    }
+
+    #[test]
+    fn rootfile_is_found_in_container() {
+        let container = r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#;
+        let path = tags(container, "rootfile").into_iter().find_map(|mut a| a.remove("full-path"));
+        assert_eq!(path.as_deref(), Some("OEBPS/content.opf"));
+    }
+
+    #[test]
+    fn unescape_decodes_entities_and_percent_escapes() {
+        assert_eq!(unescape("Cover%20Art.jpg"), "Cover Art.jpg");
+        assert_eq!(unescape("a&amp;b.png"), "a&b.png");
+        assert_eq!(unescape("caf%C3%A9.jpg"), "café.jpg");
+        assert_eq!(unescape("100%.jpg"), "100%.jpg");
+        assert_eq!(unescape("%zz.jpg"), "%zz.jpg");
+    }
+
+    #[test]
+    fn resolve_is_relative_to_the_package_folder() {
+        assert_eq!(resolve("OEBPS", "images/cover.jpg"), "OEBPS/images/cover.jpg");
+        assert_eq!(resolve("OEBPS/text", "../images/cover.jpg"), "OEBPS/images/cover.jpg");
+        assert_eq!(resolve("", "./cover.jpg"), "cover.jpg");
+        assert_eq!(resolve("OEBPS", "../../cover.jpg"), "cover.jpg");
+    }
+}
@@ -0,0 +1,417 @@
+//! MOBI, AZW and AZW3 files: Palm databases with a table of record
+//! offsets, then record 0 with the PalmDOC and MOBI headers and the EXTH
+//! block that holds author, ASIN, language and which image is the cover.
+
+use super::{be_u16, be_u32, BookMetadata, DictionaryInfo, ReadRange};
+use crate::error::Result;
+
+/// Length of the Palm database header, up to the record table.
+const PDB_HEADER_LEN: u32 = 78;
+/// Bytes per entry of the record table.
+const RECORD_ENTRY_LEN: u32 = 8;
+/// Most of record 0 read; the EXTH block comes long before this.
+const RECORD0_MAX: u32 = 64 * 1024;
+/// Largest cover record read; covers are a few hundred KB at most.
+const COVER_MAX: u32 = 16 * 1024 * 1024;
+/// Bit in the MOBI header's EXTH flags saying an EXTH block follows.
+const EXTH_PRESENT: u32 = 0x40;
+/// MOBI text encoding for UTF-8; the other one in use is CP1252.
+const ENCODING_UTF8: u32 = 65001;
+/// First image index meaning the book has no images.
+const NO_IMAGES: u32 = u32::MAX;
+/// Record 0 offsets of a dictionary's orthographic (headword) index and
+/// its input and output languages, as Windows locale IDs.
+const ORTH_INDEX_OFFSET: usize = 56;
+const INPUT_LANGUAGE_OFFSET: usize = 96;
+const OUTPUT_LANGUAGE_OFFSET: usize = 100;
+/// Index record number meaning there is none.
+const NO_INDEX: u32 = u32::MAX;
+
+const EXTH_AUTHOR: u32 = 100;
+const EXTH_PUBLISHER: u32 = 101;
+const EXTH_ASIN: u32 = 113;
+const EXTH_COVER_OFFSET: u32 = 201;
+const EXTH_UPDATED_TITLE: u32 = 503;
+const EXTH_CDE_ASIN: u32 = 504;
+const EXTH_LANGUAGE: u32 = 524;
+const EXTH_DICTIONARY_INPUT: u32 = 531;
+const EXTH_DICTIONARY_OUTPUT: u32 = 532;
+
+/// Languages by the Windows language ID in the MOBI header's locale, for
+/// books without an EXTH language record.
+const LOCALE_LANGUAGES: &[(u32, &str)] = &[
+    (4, "zh"),
+    (7, "de"),
+    (9, "en"),
+    (10, "es"),
+    (11, "fi"),
+    (12, "fr"),
+    (16, "it"),
+    (17, "ja"),
+    (18, "ko"),
+    (19, "nl"),
+    (20, "no"),
+    (21, "pl"),
+    (22, "pt"),
+    (25, "ru"),
+    (29, "sv"),
+];
+
+/// The start of a MOBI family file: its Palm database name, number of
+/// records and record 0.
+struct Header {
+    name: String,
+    record_count: u32,
+    record0: Vec<u8>,
+}
+
+/// Reads the header of a MOBI family file, or `None` if it is something
+/// else.
+fn read_header(read: &ReadRange) -> Result<Option<Header>> {
+    let header = read(0, PDB_HEADER_LEN)?;
+    if header.len() < PDB_HEADER_LEN as usize || &header[60..68] != b"BOOKMOBI" {
+        return Ok(None);
+    }
+    let record_count = u32::from(be_u16(&header, 76).unwrap_or(0));
+    if record_count == 0 {
+        return Ok(None);
+    }
+    // Record 0 ends where record 1 starts
+    let Some((start, end)) = record_range(read, 0, record_count)? else {
+        return Ok(None);
+    };
+    let len = end.map_or(RECORD0_MAX, |end| end.saturating_sub(start).min(RECORD0_MAX));
+    Ok(Some(Header {
+        name: pdb_name(&header),
+        record_count,
+        record0: read(u64::from(start), len)?,
+    }))
+}
+
+/// Where record `index` starts, and where it ends unless it is the last.
+fn record_range(read: &ReadRange, index: u32, record_count: u32) -> Result<Option<(u32, Option<u32>)>> {
+    let entries = if index + 1 < record_count { 2 } else { 1 };
+    let offset = PDB_HEADER_LEN + RECORD_ENTRY_LEN * index;
+    let table = read(u64::from(offset), RECORD_ENTRY_LEN * entries)?;
+    Ok(be_u32(&table, 0).map(|start| (start, be_u32(&table, RECORD_ENTRY_LEN as usize))))
+}
+
+pub(super) fn read_metadata(read: &ReadRange) -> Result<Option<BookMetadata>> {
+    Ok(read_header(read)?.map(|header| parse_record0(&header.record0, &header.name)))
+}
+
+/// The title and languages of a dictionary, or `None` if the file isn't
+/// one: a MOBI file with an orthographic index or EXTH dictionary
+/// languages.
+pub(super) fn read_dictionary(read: &ReadRange) -> Result<Option<DictionaryInfo>> {
+    let Some(header) = read_header(read)? else {
+        return Ok(None);
+    };
+    let record = &header.record0;
+    if !has_mobi_header(record) {
+        return Ok(None);
+    }
+    let metadata = parse_record0(record, &header.name);
+    let utf8 = be_u32(record, 28) == Some(ENCODING_UTF8);
+    let mut input = None;
+    let mut output = None;
+    for (kind, data) in exth_records(record) {
+        let value = decode_text(data, utf8);
+        match kind {
+            EXTH_DICTIONARY_INPUT if !value.is_empty() => input = Some(value),
+            EXTH_DICTIONARY_OUTPUT if !value.is_empty() => output = Some(value),
+            _ => {}
+        }
+    }
+    let has_index = be_u32(record, ORTH_INDEX_OFFSET).is_some_and(|index| index != NO_INDEX && index != 0);
+    if !has_index && input.is_none() {
+        return Ok(None);
+    }
+    let header_language = |offset| be_u32(record, offset).and_then(locale_language);
+    Ok(Some(DictionaryInfo {
+        title: metadata.title,
+        input_language: input.or_else(|| header_language(INPUT_LANGUAGE_OFFSET)),
+        output_language: output.or_else(|| header_language(OUTPUT_LANGUAGE_OFFSET)),
+    }))
+}
+
+/// The cover record: the first image record plus the EXTH cover offset.
+pub(super) fn read_cover(read: &ReadRange) -> Result<Option<Vec<u8>>> {
+    let Some(header) = read_header(read)? else {
+        return Ok(None);
+    };
+    let Some(index) = cover_record(&header.record0).filter(|&index| index < header.record_count) else {
+        return Ok(None);
+    };
+    let Some((start, end)) = record_range(read, index, header.record_count)? else {
+        return Ok(None);
+    };
+    let len = end.map_or(COVER_MAX, |end| end.saturating_sub(start).min(COVER_MAX));
+    Ok(Some(read(u64::from(start), len)?))
+}
+
+/// The index of the cover record, from the MOBI header's first image
+/// index and the EXTH cover offset.
+fn cover_record(record: &[u8]) -> Option<u32> {
+    if !has_mobi_header(record) {
+        return None;
+    }
+    let first_image = be_u32(record, 108).filter(|&index| index != NO_IMAGES && index != 0)?;
+    let offset = exth_records(record)
+        .into_iter()
+        .find(|(kind, _)| *kind == EXTH_COVER_OFFSET)
+        .and_then(|(_, data)| be_u32(data, 0))?;
+    first_image.checked_add(offset)
+}
+
+/// The metadata in record 0 of a MOBI file. `fallback_title` is the Palm
+/// database name, used when the MOBI header has no full name.
+fn parse_record0(record: &[u8], fallback_title: &str) -> BookMetadata {
+    let mut metadata = BookMetadata {
+        title: fallback_title.to_string(),
+        ..Default::default()
+    };
+    if !has_mobi_header(record) {
+        return metadata;
+    }
+    let utf8 = be_u32(record, 28) == Some(ENCODING_UTF8);
+    let decode = |bytes: &[u8]| decode_text(bytes, utf8);
+
+    if let (Some(offset), Some(len)) = (be_u32(record, 84), be_u32(record, 88))
+        && let Some(name) = record.get(offset as usize..offset as usize + len as usize)
+        && !name.is_empty()
+    {
+        metadata.title = decode(name);
+    }
+    metadata.language = be_u32(record, 92).and_then(locale_language);
+
+    let mut authors = Vec::new();
+    for (kind, data) in exth_records(record) {
+        let value = decode(data);
+        if value.is_empty() {
+            continue;
+        }
+        match kind {
+            EXTH_AUTHOR => authors.push(value),
+            EXTH_PUBLISHER => metadata.publisher = Some(value),
+            EXTH_ASIN => metadata.asin = Some(value),
+            EXTH_CDE_ASIN => {
+                metadata.asin.get_or_insert(value);
+            }
+            EXTH_UPDATED_TITLE => metadata.title = value,
+            EXTH_LANGUAGE => metadata.language = Some(value),
+            _ => {}
+        }
+    }
+    if !authors.is_empty() {
+        metadata.author = Some(authors.join(" & "));
+    }
+    metadata
+}
+
+/// The language code of a Windows locale ID; only the language part of it
+/// is looked at.
+fn locale_language(locale: u32) -> Option<String> {
+    LOCALE_LANGUAGES
+        .iter()
+        .find(|(id, _)| *id == locale & 0xFF)
+        .map(|(_, code)| code.to_string())
+}
+
+/// Whether record 0 has a MOBI header; PalmDOC-only files (TEXtREAd)
+/// don't.
+fn has_mobi_header(record: &[u8]) -> bool {
+    record.get(16..20) == Some(b"MOBI".as_slice())
+}
+
+/// The (type, data) records of the EXTH block after the MOBI header, if
+/// the header says there is one; a truncated block yields the records
+/// that are whole.
+fn exth_records(record: &[u8]) -> Vec<(u32, &[u8])> {
+    let mut records = Vec::new();
+    let header_len = be_u32(record, 20).unwrap_or(0) as usize;
+    let has_exth = header_len >= 116 && be_u32(record, 128).is_some_and(|flags| flags & EXTH_PRESENT != 0);
+    let offset = 16 + header_len;
+    if !has_mobi_header(record) || !has_exth || record.get(offset..offset + 4) != Some(b"EXTH".as_slice()) {
+        return records;
+    }
+    let count = be_u32(record, offset + 8).unwrap_or(0);
+    let mut position = offset + 12;
+    for _ in 0..count {
+        let (Some(kind), Some(len)) = (be_u32(record, position), be_u32(record, position + 4)) else {
+            break;
+        };
+        let len = len as usize;
+        let Some(data) = record.get(position + 8..position + len.max(8)) else {
+            break;
+        };
+        records.push((kind, data));
+        position += len.max(8);
+    }
+    records
+}
+
+/// The Palm database name: the first 32 bytes, NUL padded, with
+/// underscores where the title had spaces.
+fn pdb_name(header: &[u8]) -> String {
+    let name = &header[..32];
+    let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    decode_text(&name[..end], false).replace('_', " ").trim().to_string()
+}
+
+/// Text in the book's encoding. CP1252 is read as Latin-1, which differs
+/// only in rarely used punctuation.
+fn decode_text(bytes: &[u8], utf8: bool) -> String {
+    let text = if utf8 {
+        String::from_utf8_lossy(bytes).into_owned()
+    } else {
+        bytes.iter().map(|&b| b as char).collect()
+    };
+    text.trim_end_matches('\0').trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Length of the MOBI header in the test books, which puts the EXTH
+    /// block at 16 + 232.
+    const MOBI_HEADER_LEN: u32 = 232;
+
+    fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+        data[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    /// Record 0 of a UTF-8 book called `full_name` in locale `locale`,
+    /// with `exth` as its EXTH records.
+    fn record0(full_name: &str, locale: u32, exth: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut record = vec![0; 16 + MOBI_HEADER_LEN as usize];
+        record[16..20].copy_from_slice(b"MOBI");
+        put_u32(&mut record, 20, MOBI_HEADER_LEN);
+        put_u32(&mut record, 28, ENCODING_UTF8);
+        put_u32(&mut record, 92, locale);
+        put_u32(&mut record, 108, 2);
+        put_u32(&mut record, 128, EXTH_PRESENT);
+
+        record.extend_from_slice(b"EXTH\0\0\0\0");
+        record.extend_from_slice(&(exth.len() as u32).to_be_bytes());
+        for (kind, data) in exth {
+            record.extend_from_slice(&kind.to_be_bytes());
+            record.extend_from_slice(&(data.len() as u32 + 8).to_be_bytes());
+            record.extend_from_slice(data);
+        }
+        let name_offset = record.len() as u32;
+        record.extend_from_slice(full_name.as_bytes());
+        put_u32(&mut record, 84, name_offset);
+        put_u32(&mut record, 88, full_name.len() as u32);
+        record
+    }
+
+    /// A Palm database named `name` holding `records`.
+    fn book(name: &str, records: &[&[u8]]) -> Vec<u8> {
+        let mut data = vec![0; PDB_HEADER_LEN as usize];
+        data[..name.len()].copy_from_slice(name.as_bytes());
+        data[60..68].copy_from_slice(b"BOOKMOBI");
+        data[76..78].copy_from_slice(&(records.len() as u16).to_be_bytes());
+        let mut start = PDB_HEADER_LEN + RECORD_ENTRY_LEN * records.len() as u32;
+        for record in records {
+            data.extend_from_slice(&start.to_be_bytes());
+            data.extend_from_slice(&[0; 4]);
+            start += record.len() as u32;
+        }
+        for record in records {
+            data.extend_from_slice(record);
+        }
+        data
+    }
+
+    fn reader(data: &[u8]) -> impl Fn(u64, u32) -> Result<Vec<u8>> + '_ {
+        move |offset, len| {
+            let start = (offset as usize).min(data.len());
+            let end = (start + len as usize).min(data.len());
+            Ok(data[start..end].to_vec())
+        }
+    }
+
+    #[test]
+    fn reads_full_name_and_exth_records() {
+        let record0 = record0(
+            "Der Zauberberg",
+            7,
+            &[
+                (EXTH_AUTHOR, b"Thomas Mann"),
+                (EXTH_AUTHOR, b"John Woods"),
+                (EXTH_PUBLISHER, b"Vintage"),
+                (EXTH_CDE_ASIN, b"B000FC0Q2M"),
+                (EXTH_ASIN, b"B000FA5T8S"),
+            ],
+        );
+        let data = book("Der_Zauberberg", &[&record0, b"text"]);
+        let metadata = read_metadata(&reader(&data)).unwrap().unwrap();
+        assert_eq!(metadata.title, "Der Zauberberg");
+        assert_eq!(metadata.author.as_deref(), Some("Thomas Mann & John Woods"));
+        assert_eq!(metadata.publisher.as_deref(), Some("Vintage"));
+        assert_eq!(metadata.asin.as_deref(), Some("B000FA5T8S"));
+        assert_eq!(metadata.language.as_deref(), Some("de"));
+    }
+
+    #[test]
+    fn exth_title_and_language_win_over_the_header() {
+        let record0 = record0(
+            "Old Title",
+            9,
+            &[(EXTH_UPDATED_TITLE, b"New Title"), (EXTH_LANGUAGE, b"en-GB")],
+        );
+        let data = book("Old_Title", &[&record0]);
+        let metadata = read_metadata(&reader(&data)).unwrap().unwrap();
+        assert_eq!(metadata.title, "New Title");
+        assert_eq!(metadata.language.as_deref(), Some("en-GB"));
+    }
+
+    #[test]
+    fn no_mobi_header_falls_back_to_the_database_name() {
+        let data = book("Plain_Text_Book", &[&[0; 16]]);
+        let metadata = read_metadata(&reader(&data)).unwrap().unwrap();
+        assert_eq!(metadata.title, "Plain Text Book");
+        assert_eq!(metadata.author, None);
+    }
+
+    #[test]
+    fn other_files_are_not_read() {
+        let mut data = book("Book", &[b"record"]);
+        data[60..68].copy_from_slice(b"TEXtREAd");
+        assert!(read_metadata(&reader(&data)).unwrap().is_none());
+        assert!(read_metadata(&reader(b"%PDF-1.7")).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_exth_keeps_whole_records() {
+        let mut record0 = record0("Title", 9, &[(EXTH_AUTHOR, b"Author"), (EXTH_PUBLISHER, b"Publisher")]);
+        let exth = 16 + MOBI_HEADER_LEN as usize;
+        put_u32(&mut record0, exth + 8, 3);
+        let records = exth_records(&record0);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0], (EXTH_AUTHOR, b"Author".as_slice()));
+    }
+
+    #[test]
+    fn cover_is_the_first_image_plus_the_exth_offset() {
+        let record0 = record0("Title", 9, &[(EXTH_COVER_OFFSET, &1u32.to_be_bytes())]);
+        let data = book("Title", &[&record0, b"text", b"first image", b"cover"]);
+        assert_eq!(read_cover(&reader(&data)).unwrap().as_deref(), Some(b"cover".as_slice()));
+    }
+
+    #[test]
+    fn cover_past_the_last_record_is_none() {
+        let record0 = record0("Title", 9, &[(EXTH_COVER_OFFSET, &5u32.to_be_bytes())]);
+        let data = book("Title", &[&record0, b"text", b"image"]);
+        assert!(read_cover(&reader(&data)).unwrap().is_none());
+    }
+
+    #[test]
+    fn locale_uses_only_the_language_part() {
+        assert_eq!(locale_language(0x0809).as_deref(), Some("en"));
+        assert_eq!(locale_language(0x0407).as_deref(), Some("de"));
+        assert_eq!(locale_language(0), None);
+    }
+}
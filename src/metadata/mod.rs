@@ -0,0 +1,149 @@
+//! Book metadata and covers read from files on the device, a few small
+//! partial reads per book instead of a download.
+//!
+//! MOBI, AZW and AZW3 files are Palm databases (see `mobi`), and so are
+//! Kindle dictionaries; EPUBs are zip archives (see `epub`). KFX and PDF
+//! aren't read. Audiobooks only give their duration (see `audio`).
+
+mod audio;
+mod epub;
+mod mobi;
+
+use crate::device::Kindle;
+use crate::error::Result;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Reads up to the given number of bytes of a file from an offset, fewer
+/// at its end: partial reads on the device, or a local file.
+type ReadRange<'a> = dyn Fn(u64, u32) -> Result<Vec<u8>> + 'a;
+
+/// What a book's header says about it.
+#[derive(Debug, Clone, Default, Serialize, JsonSchema)]
+pub struct BookMetadata {
+    pub title: String,
+    /// Authors, joined with `&` when there are several
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asin: Option<String>,
+    /// Language code, e.g. `en` or `en-GB`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub publisher: Option<String>,
+}
+
+/// What a dictionary's header says about it.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct DictionaryInfo {
+    pub title: String,
+    /// Language of the headwords, e.g. `en`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_language: Option<String>,
+    /// Language of the definitions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_language: Option<String>,
+}
+
+/// A cover image as stored in the book.
+pub struct Cover {
+    pub data: Vec<u8>,
+    /// `jpeg`, `png` or `gif`, from the image's first bytes
+    pub format: &'static str,
+}
+
+/// Reads the metadata of the book at `path`, or `None` if it isn't a MOBI
+/// family file. Needs the device directly, for partial reads.
+pub fn read_metadata(kindle: &Kindle, path: &str) -> Result<Option<BookMetadata>> {
+    mobi::read_metadata(&|offset, len| kindle.read_range(path, offset, len))
+}
+
+/// Reads the header of the dictionary at `path` on the device, or `None`
+/// if it isn't a MOBI dictionary.
+pub fn read_dictionary(kindle: &Kindle, path: &str) -> Result<Option<DictionaryInfo>> {
+    mobi::read_dictionary(&|offset, len| kindle.read_range(path, offset, len))
+}
+
+/// [`read_dictionary`] for a local file, to check it before uploading.
+pub fn read_local_dictionary(path: &Path) -> Result<Option<DictionaryInfo>> {
+    let file = RefCell::new(File::open(path)?);
+    mobi::read_dictionary(&|offset, len| read_local(&file, offset, len))
+}
+
+/// [`read_metadata`] for a local file, before it is uploaded.
+pub fn read_local_metadata(path: &Path) -> Result<Option<BookMetadata>> {
+    let file = RefCell::new(File::open(path)?);
+    mobi::read_metadata(&|offset, len| read_local(&file, offset, len))
+}
+
+fn read_local(file: &RefCell<File>, offset: u64, len: u32) -> Result<Vec<u8>> {
+    let mut file = file.borrow_mut();
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::with_capacity(len as usize);
+    file.by_ref().take(u64::from(len)).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Reads the duration in seconds of the audiobook at `path` from its MP4
+/// movie header, or `None` for other formats (`.aa`, MP3).
+pub fn read_audio_duration(kindle: &Kindle, path: &str) -> Result<Option<u64>> {
+    audio::read_duration(&|offset, len| kindle.read_range(path, offset, len))
+}
+
+/// Reads the cover image embedded in the book at `path`: the cover record
+/// of a MOBI family file, or the image an EPUB's package names as cover.
+/// `None` if the book has none or is in another format.
+pub fn read_cover(kindle: &Kindle, path: &str) -> Result<Option<Cover>> {
+    let data = if is_epub(path) {
+        epub::read_cover(kindle, path)?
+    } else {
+        mobi::read_cover(&|offset, len| kindle.read_range(path, offset, len))?
+    };
+    Ok(data.and_then(|data| {
+        let format = image_format(&data)?;
+        Some(Cover { data, format })
+    }))
+}
+
+fn is_epub(path: &str) -> bool {
+    path.rsplit_once('.').is_some_and(|(_, extension)| extension.eq_ignore_ascii_case("epub"))
+}
+
+/// The image type by its magic bytes; `None` for anything else, such as
+/// the placeholder records some converters leave.
+fn image_format(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("jpeg")
+    } else if data.starts_with(b"\x89PNG") {
+        Some("png")
+    } else if data.starts_with(b"GIF8") {
+        Some("gif")
+    } else {
+        None
+    }
+}
+
+fn be_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn be_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn be_u64(data: &[u8], offset: usize) -> Option<u64> {
+    data.get(offset..offset + 8).map(|b| u64::from_be_bytes(b.try_into().expect("8 bytes")))
+}
+
+fn le_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn le_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
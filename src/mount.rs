@@ -0,0 +1,702 @@
+//! `kindle-mtp mount`: the device as a FUSE filesystem backed by [`Kindle`].
+//!
+//! MTP transfers whole objects, so files are staged locally: opening a file
+//! downloads it to a temporary file, reads and writes go to that copy, and a
+//! modified file is uploaded again when it is flushed or closed. Folder
+//! listings are cached briefly because every `stat` from a file manager
+//! would otherwise be a USB round trip.
+
+use crate::cli::Output;
+use crate::commands::staging::StagingDir;
+use crate::device::{FileEntry, Kindle};
+use crate::error::{Error, Result};
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request,
+    TimeOrNow, FUSE_ROOT_ID,
+};
+use libc::c_int;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long the kernel may cache attributes and name lookups.
+const ATTR_TTL: Duration = Duration::from_secs(1);
+/// How long a folder listing is reused before the device is asked again.
+const LISTING_TTL: Duration = Duration::from_secs(2);
+const BLOCK_SIZE: u32 = 4096;
+
+/// Mounts the device at `mountpoint` and serves it until unmounted with
+/// `umount` (or `fusermount -u` on Linux).
+pub fn mount(output: &Output, kindle: Kindle, mountpoint: &Path) -> Result<()> {
+    let owner = std::fs::metadata(mountpoint)?;
+    let staging = StagingDir::new("mount")?;
+
+    let fs = KindleFs {
+        kindle,
+        output,
+        inodes: Inodes::new(),
+        listings: HashMap::new(),
+        handles: HashMap::new(),
+        next_fh: 1,
+        staging: staging.path().to_path_buf(),
+        uid: owner.uid(),
+        gid: owner.gid(),
+        mounted_at: SystemTime::now(),
+    };
+    let options = [
+        MountOption::FSName("kindle".to_string()),
+        MountOption::Subtype("kindle-mtp".to_string()),
+        MountOption::DefaultPermissions,
+        MountOption::NoAtime,
+    ];
+
+    output.info(format!(
+        "Kindle mounted at {} (unmount with `umount {}` to stop)",
+        mountpoint.display(),
+        mountpoint.display()
+    ));
+    Ok(fuser::mount2(fs, mountpoint, &options)?)
+}
+
+/// Inode numbers handed to the kernel. MTP object IDs are not used because
+/// an upload replaces the object, and with it the ID, under the same path.
+struct Inodes {
+    paths: Vec<String>,
+    by_path: HashMap<String, u64>,
+}
+
+impl Inodes {
+    fn new() -> Self {
+        Self {
+            paths: vec!["/".to_string()],
+            by_path: HashMap::from([("/".to_string(), FUSE_ROOT_ID)]),
+        }
+    }
+
+    fn path(&self, ino: u64) -> Result<String> {
+        ino.checked_sub(1)
+            .and_then(|i| self.paths.get(i as usize))
+            .cloned()
+            .ok_or_else(|| Error::FileNotFound(format!("inode {}", ino)))
+    }
+
+    fn ino(&mut self, path: &str) -> u64 {
+        if let Some(&ino) = self.by_path.get(path) {
+            return ino;
+        }
+        self.paths.push(path.to_string());
+        let ino = self.paths.len() as u64;
+        self.by_path.insert(path.to_string(), ino);
+        ino
+    }
+
+    /// Points `from` and everything below it at `to`, keeping inode numbers.
+    fn rename(&mut self, from: &str, to: &str) {
+        let prefix = format!("{}/", from);
+        for (ino, path) in self.paths.iter_mut().enumerate() {
+            let renamed = if path == from {
+                to.to_string()
+            } else if let Some(rest) = path.strip_prefix(&prefix) {
+                format!("{}/{}", to, rest)
+            } else {
+                continue;
+            };
+            self.by_path.remove(path.as_str());
+            self.by_path.insert(renamed.clone(), ino as u64 + 1);
+            *path = renamed;
+        }
+    }
+}
+
+/// A file open through the mount, staged in a local temporary file.
+struct Handle {
+    ino: u64,
+    file: File,
+    staged: PathBuf,
+    /// Written to since the last upload
+    dirty: bool,
+}
+
+struct KindleFs<'a> {
+    kindle: Kindle,
+    output: &'a Output,
+    inodes: Inodes,
+    listings: HashMap<String, (Instant, Vec<FileEntry>)>,
+    handles: HashMap<u64, Handle>,
+    next_fh: u64,
+    staging: PathBuf,
+    uid: u32,
+    gid: u32,
+    mounted_at: SystemTime,
+}
+
+impl KindleFs<'_> {
+    fn list(&mut self, path: &str) -> Result<&[FileEntry]> {
+        let fresh = self
+            .listings
+            .get(path)
+            .is_some_and(|(fetched, _)| fetched.elapsed() < LISTING_TTL);
+        if !fresh {
+            let entries = self.kindle.list_files(path)?;
+            self.listings.insert(path.to_string(), (Instant::now(), entries));
+        }
+        Ok(&self.listings[path].1)
+    }
+
+    /// Looks `path` up in its parent's (possibly cached) listing.
+    fn entry(&mut self, path: &str) -> Result<FileEntry> {
+        let (parent, name) = split(path);
+        self.list(parent)?
+            .iter()
+            .find(|e| e.name == name)
+            .cloned()
+            .ok_or_else(|| Error::FileNotFound(path.to_string()))
+    }
+
+    /// Drops the cached listing that contains `path`.
+    fn invalidate(&mut self, path: &str) {
+        self.listings.remove(split(path).0);
+    }
+
+    fn attr(&self, ino: u64, entry: &FileEntry) -> FileAttr {
+        let (kind, perm, nlink) = if entry.is_folder {
+            (FileType::Directory, 0o755, 2)
+        } else {
+            (FileType::RegularFile, 0o644, 1)
+        };
+        let mtime = SystemTime::from(entry.modified);
+        FileAttr {
+            ino,
+            size: entry.size,
+            blocks: entry.size.div_ceil(512),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind,
+            perm,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: BLOCK_SIZE,
+            flags: 0,
+        }
+    }
+
+    fn root_attr(&self) -> FileAttr {
+        FileAttr {
+            ino: FUSE_ROOT_ID,
+            size: 0,
+            blocks: 0,
+            atime: self.mounted_at,
+            mtime: self.mounted_at,
+            ctime: self.mounted_at,
+            crtime: self.mounted_at,
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 2,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: BLOCK_SIZE,
+            flags: 0,
+        }
+    }
+
+    /// Attributes for `ino`. A file open for writing reports its staged size,
+    /// so tools see their writes before the upload happens.
+    fn getattr_of(&mut self, ino: u64) -> Result<FileAttr> {
+        if ino == FUSE_ROOT_ID {
+            return Ok(self.root_attr());
+        }
+        let path = self.inodes.path(ino)?;
+        let staged = self
+            .handles
+            .values()
+            .find(|h| h.ino == ino && h.dirty)
+            .map(|h| h.file.metadata())
+            .transpose()?;
+        let entry = match staged {
+            Some(metadata) => FileEntry {
+                name: split(&path).1.to_string(),
+                size: metadata.len(),
+                is_folder: false,
+                id: 0,
+                modified: metadata.modified()?.into(),
+            },
+            None => self.entry(&path)?,
+        };
+        Ok(self.attr(ino, &entry))
+    }
+
+    /// Stages `ino` locally, downloading its contents unless `truncate`.
+    fn open_handle(&mut self, ino: u64, truncate: bool) -> Result<u64> {
+        let path = self.inodes.path(ino)?;
+        let fh = self.next_fh;
+        self.next_fh += 1;
+
+        let staged = self.staging.join(fh.to_string());
+        if !truncate {
+            self.kindle.download_file(&path, &staged)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(truncate)
+            .open(&staged)?;
+        self.handles.insert(
+            fh,
+            Handle {
+                ino,
+                file,
+                staged,
+                dirty: truncate,
+            },
+        );
+        Ok(fh)
+    }
+
+    /// Uploads a modified handle, replacing the file on the device. MTP
+    /// cannot overwrite in place, so the old object is deleted first.
+    fn commit(&mut self, fh: u64) -> Result<()> {
+        let Some(handle) = self.handles.get(&fh) else {
+            return Ok(());
+        };
+        if !handle.dirty {
+            return Ok(());
+        }
+        let path = self.inodes.path(handle.ino)?;
+        let staged = handle.staged.clone();
+
+        match self.kindle.stat(&path) {
+            Ok(_) => self.kindle.delete(&path)?,
+            Err(Error::FileNotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+        self.invalidate(&path);
+        self.kindle.upload_file(&staged, &path)?;
+        if let Some(handle) = self.handles.get_mut(&fh) {
+            handle.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn close_handle(&mut self, fh: u64) -> Result<()> {
+        let result = self.commit(fh);
+        if let Some(handle) = self.handles.remove(&fh) {
+            let _ = std::fs::remove_file(&handle.staged);
+        }
+        result
+    }
+
+    fn truncate(&mut self, ino: u64, fh: Option<u64>, size: u64) -> Result<()> {
+        let open = fh
+            .filter(|fh| self.handles.contains_key(fh))
+            .or_else(|| self.handles.iter().find(|(_, h)| h.ino == ino).map(|(&fh, _)| fh));
+        let (fh, temporary) = match open {
+            Some(fh) => (fh, false),
+            None => (self.open_handle(ino, size == 0)?, true),
+        };
+        if let Some(handle) = self.handles.get_mut(&fh) {
+            handle.file.set_len(size)?;
+            handle.dirty = true;
+        }
+        if temporary {
+            self.close_handle(fh)?;
+        }
+        Ok(())
+    }
+
+    fn rename_entry(&mut self, from: &str, to: &str) -> Result<()> {
+        let source = self.entry(from)?;
+        match self.entry(to) {
+            Ok(target) if target.is_folder != source.is_folder => {
+                return Err(Error::InvalidPath(format!("cannot replace {}", to)));
+            }
+            Ok(target) => {
+                if target.is_folder && !self.kindle.list_files(to)?.is_empty() {
+                    return Err(Error::InvalidPath(format!("{} is not empty", to)));
+                }
+                self.kindle.delete(to)?;
+            }
+            Err(Error::FileNotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+
+        self.kindle.rename(from, to)?;
+        self.inodes.rename(from, to);
+        self.invalidate(from);
+        self.invalidate(to);
+        let prefix = format!("{}/", from);
+        self.listings
+            .retain(|path, _| path != from && !path.starts_with(&prefix));
+        Ok(())
+    }
+
+    /// Maps an error to an errno, reporting unexpected device failures on
+    /// stderr since the kernel only passes the number on.
+    fn errno(&self, error: Error) -> c_int {
+        let code = errno(&error);
+        if code == libc::EIO {
+            self.output.info(format!("Error: {}", error.display_chain()));
+        }
+        code
+    }
+}
+
+impl Filesystem for KindleFs<'_> {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let result = self.inodes.path(parent).and_then(|parent| {
+            let path = join(&parent, name)?;
+            let ino = self.inodes.ino(&path);
+            self.getattr_of(ino)
+        });
+        match result {
+            Ok(attr) => reply.entry(&ATTR_TTL, &attr, 0),
+            Err(e) => reply.error(self.errno(e)),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.getattr_of(ino) {
+            Ok(attr) => reply.attr(&ATTR_TTL, &attr),
+            Err(e) => reply.error(self.errno(e)),
+        }
+    }
+
+    /// Only size changes reach the device; MTP has no permissions or owners,
+    /// and timestamps are set by the upload. The rest is accepted and
+    /// ignored so `cp -p` and rsync don't fail.
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let result = match size {
+            Some(size) => self.truncate(ino, fh, size),
+            None => Ok(()),
+        };
+        match result.and_then(|_| self.getattr_of(ino)) {
+            Ok(attr) => reply.attr(&ATTR_TTL, &attr),
+            Err(e) => reply.error(self.errno(e)),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let result = self.inodes.path(parent).and_then(|parent| {
+            let path = join(&parent, name)?;
+            self.kindle.create_folder(&path)?;
+            self.invalidate(&path);
+            let ino = self.inodes.ino(&path);
+            self.getattr_of(ino)
+        });
+        match result {
+            Ok(attr) => reply.entry(&ATTR_TTL, &attr, 0),
+            Err(e) => reply.error(self.errno(e)),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let result = self.inodes.path(parent).and_then(|parent| {
+            let path = join(&parent, name)?;
+            self.kindle.delete(&path)?;
+            self.invalidate(&path);
+            Ok(())
+        });
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(self.errno(e)),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let result = self.inodes.path(parent).and_then(|parent| {
+            let path = join(&parent, name)?;
+            if !self.kindle.list_files(&path)?.is_empty() {
+                return Ok(Some(libc::ENOTEMPTY));
+            }
+            self.kindle.delete(&path)?;
+            self.invalidate(&path);
+            self.listings.remove(&path);
+            Ok(None)
+        });
+        match result {
+            Ok(None) => reply.ok(),
+            Ok(Some(code)) => reply.error(code),
+            Err(e) => reply.error(self.errno(e)),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        // RENAME_NOREPLACE and RENAME_EXCHANGE can't be done atomically over MTP
+        if flags != 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let result = (|| {
+            let from = join(&self.inodes.path(parent)?, name)?;
+            let to = join(&self.inodes.path(newparent)?, newname)?;
+            self.rename_entry(&from, &to)
+        })();
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(self.errno(e)),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self.open_handle(ino, flags & libc::O_TRUNC != 0) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(self.errno(e)),
+        }
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let result = self.inodes.path(parent).and_then(|parent| {
+            let path = join(&parent, name)?;
+            let ino = self.inodes.ino(&path);
+            let fh = self.open_handle(ino, true)?;
+            Ok((self.getattr_of(ino)?, fh))
+        });
+        match result {
+            Ok((attr, fh)) => reply.created(&ATTR_TTL, &attr, 0, fh, 0),
+            Err(e) => reply.error(self.errno(e)),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let Some(handle) = self.handles.get(&fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+        let mut buf = vec![0; size as usize];
+        let mut filled = 0;
+        while filled < buf.len() {
+            match handle
+                .file
+                .read_at(&mut buf[filled..], offset as u64 + filled as u64)
+            {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) => {
+                    reply.error(self.errno(e.into()));
+                    return;
+                }
+            }
+        }
+        reply.data(&buf[..filled]);
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let Some(handle) = self.handles.get_mut(&fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+        let result = handle.file.write_all_at(data, offset as u64);
+        if result.is_ok() {
+            handle.dirty = true;
+        }
+        match result {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(e) => reply.error(self.errno(e.into())),
+        }
+    }
+
+    /// Uploads on flush as well as on release: errors from `flush` reach the
+    /// caller's `close()`, errors from `release` are lost.
+    fn flush(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        match self.commit(fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(self.errno(e)),
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        match self.commit(fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(self.errno(e)),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.close_handle(fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(self.errno(e)),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let result = self.inodes.path(ino).and_then(|path| {
+            let children: Vec<(String, bool)> = self
+                .list(&path)?
+                .iter()
+                .map(|e| (e.name.clone(), e.is_folder))
+                .collect();
+            let parent_ino = self.inodes.ino(split(&path).0);
+
+            let mut entries = vec![
+                (ino, FileType::Directory, ".".to_string()),
+                (parent_ino, FileType::Directory, "..".to_string()),
+            ];
+            for (name, is_folder) in children {
+                let child = self.inodes.ino(&join(&path, OsStr::new(&name))?);
+                let kind = if is_folder {
+                    FileType::Directory
+                } else {
+                    FileType::RegularFile
+                };
+                entries.push((child, kind, name));
+            }
+            Ok(entries)
+        });
+
+        match result {
+            Ok(entries) => {
+                for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+                    if reply.add(ino, i as i64 + 1, kind, name) {
+                        break;
+                    }
+                }
+                reply.ok();
+            }
+            Err(e) => reply.error(self.errno(e)),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        match self.kindle.storage_info() {
+            Ok(storage) => {
+                let block = BLOCK_SIZE as u64;
+                reply.statfs(
+                    storage.total_bytes / block,
+                    storage.free_bytes / block,
+                    storage.free_bytes / block,
+                    0,
+                    0,
+                    BLOCK_SIZE,
+                    255,
+                    BLOCK_SIZE,
+                );
+            }
+            Err(e) => reply.error(self.errno(e)),
+        }
+    }
+}
+
+fn errno(error: &Error) -> c_int {
+    match error {
+        Error::FileNotFound(_) => libc::ENOENT,
+        Error::PermissionDenied | Error::ObjectProtected(_) => libc::EACCES,
+        Error::StorageFull(_) => libc::ENOSPC,
+        Error::DeviceNotFound => libc::ENODEV,
+        Error::DeviceBusy(_) => libc::EBUSY,
+        Error::Timeout(_) => libc::ETIMEDOUT,
+        Error::InvalidPath(_) => libc::EINVAL,
+        Error::Unsupported(_) => libc::ENOTSUP,
+        Error::Io(e) => e.raw_os_error().unwrap_or(libc::EIO),
+        Error::Operation { source, .. } => errno(source),
+        _ => libc::EIO,
+    }
+}
+
+/// Splits a mount path into its parent folder and name; `/` is its own parent.
+fn split(path: &str) -> (&str, &str) {
+    match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((parent, name)) => (parent, name),
+        None => ("/", path),
+    }
+}
+
+fn join(parent: &str, name: &OsStr) -> Result<String> {
+    let name = name
+        .to_str()
+        .ok_or_else(|| Error::InvalidPath(format!("{:?} is not valid UTF-8", name)))?;
+    Ok(if parent == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", parent, name)
+    })
+}